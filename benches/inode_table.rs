@@ -0,0 +1,98 @@
+//! Compares storing inodes in `Slab` with the hash map and inode counter it
+//! replaced, with several threads looking up and forgetting inodes at once.
+//!
+//! Run with `cargo bench --bench inode_table`.
+
+use concurrent_hashmap::ConcHashMap;
+use envfs::slab::Slab;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Lookups per run, each inserts an inode, reads it back and removes it again.
+const LOOKUPS: usize = 1_000_000;
+
+trait Table: Send + Sync + 'static {
+    fn insert(&self, value: u64) -> u64;
+    fn get(&self, ino: u64) -> Option<u64>;
+    fn remove(&self, ino: u64);
+}
+
+struct HashTable {
+    inodes: ConcHashMap<u64, Arc<u64>>,
+    next_number: AtomicU64,
+}
+
+impl Table for HashTable {
+    fn insert(&self, value: u64) -> u64 {
+        let ino = self.next_number.fetch_add(1, Ordering::Relaxed);
+        self.inodes.insert(ino, Arc::new(value));
+        ino
+    }
+
+    fn get(&self, ino: u64) -> Option<u64> {
+        self.inodes.find(&ino).map(|v| **v.get())
+    }
+
+    fn remove(&self, ino: u64) {
+        self.inodes.remove(&ino);
+    }
+}
+
+impl Table for Slab<u64> {
+    fn insert(&self, value: u64) -> u64 {
+        self.insert_with(|_| value).unwrap()
+    }
+
+    fn get(&self, ino: u64) -> Option<u64> {
+        Slab::get(self, ino).map(|v| *v)
+    }
+
+    fn remove(&self, ino: u64) {
+        Slab::remove(self, ino);
+    }
+}
+
+/// Runs `LOOKUPS` lookups spread over `threads` threads.
+fn run<T: Table>(threads: usize, table: T) -> Duration {
+    let table = Arc::new(table);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let table = Arc::clone(&table);
+            thread::spawn(move || {
+                for i in 0..LOOKUPS / threads {
+                    let ino = table.insert(i as u64);
+                    std::hint::black_box(table.get(ino));
+                    table.remove(ino);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    println!("{:>8} {:>12} {:>12}", "threads", "hashmap", "slab");
+    for threads in [1, 2, 4, 8, 16].iter().copied() {
+        let hash_time = run(
+            threads,
+            HashTable {
+                inodes: ConcHashMap::<u64, Arc<u64>>::new(),
+                next_number: AtomicU64::new(3),
+            },
+        );
+        let slab_time = run(threads, Slab::<u64>::new());
+
+        println!(
+            "{:>8} {:>9.1} ns {:>9.1} ns",
+            threads,
+            hash_time.as_nanos() as f64 / LOOKUPS as f64,
+            slab_time.as_nanos() as f64 / LOOKUPS as f64,
+        );
+    }
+}
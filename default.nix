@@ -10,6 +10,7 @@ let
     postInstall = ''
       ln -s envfs $out/bin/mount.envfs
       ln -s envfs $out/bin/mount.fuse.envfs
+      install -Dm644 dbus/org.envfs.Manager.conf $out/share/dbus-1/system.d/org.envfs.Manager.conf
     '';
   };
 in
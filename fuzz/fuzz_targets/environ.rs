@@ -0,0 +1,10 @@
+#![no_main]
+
+//! `/proc/<pid>/environ` of an arbitrary process.
+
+use envfs::resolve::parse_environ;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_environ(data);
+});
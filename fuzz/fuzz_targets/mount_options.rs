@@ -0,0 +1,11 @@
+#![no_main]
+
+//! Mount options come from fstab and the command line of mount(8).
+
+use envfs::options::{parse_mount_options, Options};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let mut opts = Options::new(true);
+    let _ = parse_mount_options(data, &mut opts);
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+//! Arguments as passed by mount(8), NUL separated in the input.
+
+use envfs::options::parse_options;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let args: Vec<String> = data.split('\0').map(String::from).collect();
+    let _ = parse_options(&args, true);
+    let _ = parse_options(&args, false);
+});
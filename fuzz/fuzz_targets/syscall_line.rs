@@ -0,0 +1,10 @@
+#![no_main]
+
+//! `/proc/<pid>/syscall` of an arbitrary process.
+
+use envfs::resolve::parse_syscall_line;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = parse_syscall_line(data);
+});
@@ -0,0 +1,59 @@
+//! Opt-in record of every successful resolution for security reviews.
+
+use log::warn;
+use nix::unistd::Pid;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::result::Result;
+use crate::rotate::RotatingFile;
+
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_KEEP: usize = 5;
+
+pub struct AuditLog {
+    file: Mutex<RotatingFile>,
+}
+
+/// Formats a unix timestamp as RFC 3339 in UTC.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::gmtime_r(&secs, &mut tm) };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        since_epoch.subsec_millis()
+    )
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> Result<AuditLog> {
+        Ok(AuditLog {
+            file: Mutex::new(RotatingFile::open(path, max_size, keep)?),
+        })
+    }
+
+    pub fn record(&self, pid: Pid, uid: u32, comm: &str, name: &OsStr, target: &Path) {
+        let line = format!(
+            "{} pid={} uid={} comm={:?} name={:?} path={:?}",
+            format_timestamp(SystemTime::now()),
+            pid,
+            uid,
+            comm,
+            name.to_string_lossy(),
+            target.to_string_lossy()
+        );
+        if let Err(e) = self.file.lock().unwrap().write_line(&line) {
+            warn!("cannot write audit log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Minimal capability(7) management through the raw capget/capset system calls.
+
+use nix::errno::Errno;
+use simple_error::try_with;
+use std::fs;
+
+use crate::result::Result;
+
+pub const CAP_DAC_READ_SEARCH: u32 = 2;
+pub const CAP_SYS_PTRACE: u32 = 19;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Effective and permitted sets of `caps` in the layout of capset(2).
+fn cap_data(caps: &[u32]) -> [CapData; 2] {
+    // version 3 splits the 64 bit sets into two words
+    let mut data = [CapData::default(); 2];
+    for cap in caps {
+        let word = &mut data[(*cap / 32) as usize];
+        word.effective |= 1 << (cap % 32);
+        word.permitted |= 1 << (cap % 32);
+    }
+    data
+}
+
+/// Sets the effective and permitted capabilities of the calling thread to
+/// `caps`, the inheritable ones are cleared.
+pub fn set(caps: &[u32]) -> nix::Result<()> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = cap_data(caps);
+    let res = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    Errno::result(res).map(drop)
+}
+
+/// Removes all capabilities but `keep` from the bounding set, so that they
+/// cannot be regained by executing other programs.
+pub fn drop_bounding_set(keep: &[u32]) -> Result<()> {
+    let last_cap = try_with!(
+        fs::read_to_string("/proc/sys/kernel/cap_last_cap"),
+        "cannot read /proc/sys/kernel/cap_last_cap"
+    );
+    let last_cap = try_with!(
+        last_cap.trim().parse::<u32>(),
+        "invalid cap_last_cap '{}'",
+        last_cap.trim()
+    );
+    for cap in (0..=last_cap).filter(|cap| !keep.contains(cap)) {
+        let res = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) };
+        try_with!(
+            Errno::result(res),
+            "cannot drop capability {} from bounding set",
+            cap
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cap_data() {
+        let data = cap_data(&[CAP_SYS_PTRACE, CAP_DAC_READ_SEARCH]);
+        let bits = 1 << 19 | 1 << 2;
+        assert_eq!(
+            data[0],
+            CapData {
+                effective: bits,
+                permitted: bits,
+                inheritable: 0
+            }
+        );
+        assert_eq!(data[1], CapData::default());
+        // CAP_CHECKPOINT_RESTORE is in the second word
+        assert_eq!(cap_data(&[40])[1].effective, 1 << 8);
+        assert_eq!(cap_data(&[]), [CapData::default(); 2]);
+    }
+}
@@ -0,0 +1,436 @@
+//! Subcommands that operate on a running envfs instance through its control socket.
+
+use nix::unistd::{self, Pid};
+use simple_error::{bail, try_with};
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, RwLock};
+
+use crate::doctor;
+use envfs::control;
+use envfs::ioctl;
+use envfs::options::{parse_log_level, CommandOptions};
+use envfs::resolve::{read_environment, read_uid, which, CandidatePolicy, Trace};
+use envfs::resolver::{EnvResolver, FallbackResolver, Priority, RequestCtx, Resolver, Stack};
+use envfs::result::Result;
+
+const DEFAULT_MOUNTPOINT: &str = "/usr/bin";
+
+pub fn is_command(name: &str) -> bool {
+    matches!(
+        name,
+        "umount"
+            | "status"
+            | "flush-cache"
+            | "invalidate"
+            | "resolve"
+            | "log-level"
+            | "stats"
+            | "upgrade"
+            | "snapshot"
+            | "trace"
+            | "explain"
+            | "doctor"
+    )
+}
+
+pub fn show_help(prog_name: &str) {
+    eprintln!("USAGE: {} COMMAND [options] [args]", prog_name);
+    eprintln!("Commands:");
+    eprintln!("  mount [options] MOUNTPOINT   mount envfs (default if no command is given)");
+    eprintln!("  umount [MOUNTPOINT]          unmount a running instance");
+    eprintln!("  status [MOUNTPOINT]          show state of a running instance");
+    eprintln!("  flush-cache [MOUNTPOINT]     drop cached resolutions");
+    eprintln!("  invalidate NAME              resolve NAME again on its next use");
+    eprintln!("  stats [MOUNTPOINT]           show the most looked up names");
+    eprintln!("  resolve NAME                 show what NAME resolves to for a process");
+    eprintln!("  trace [MOUNTPOINT]           stream resolutions of a running instance");
+    eprintln!("  log-level LEVEL              change the log level of a running instance");
+    eprintln!("  upgrade [MOUNTPOINT]         replace a running instance with this binary");
+    eprintln!(
+        "  doctor [MOUNTPOINT]          check the system for problems that keep envfs from working"
+    );
+    eprintln!("  snapshot OUTPUT_DIR          create symlinks to what every name in PATH and");
+    eprintln!("                               the fallback paths resolves to");
+    eprintln!("Options:");
+    eprintln!("  --mountpoint PATH            mountpoint of the instance (default: /usr/bin)");
+    eprintln!("  --socket PATH                control socket of the instance");
+    eprintln!("  --top N                      number of names shown by stats (default: 10)");
+    eprintln!("  --recent                     status: also show the last resolutions");
+    eprintln!("  --pid PID                    process to resolve for (default: this process)");
+    eprintln!("  --local                      resolve in this process instead of the instance");
+    eprintln!("  --path PATH                  resolve against PATH instead of the environment");
+    eprintln!("                               of a process, implies --local");
+    eprintln!(
+        "                               (snapshot: default is the PATH of --pid or this process)"
+    );
+    eprintln!("  --fallback-path PATH         fallback path for --local resolution and snapshot");
+    eprintln!("                               (can be passed multiple times)");
+}
+
+fn mountpoint_path<'a>(opts: &'a CommandOptions, mountpoint: Option<&'a str>) -> &'a Path {
+    match (mountpoint, &opts.mountpoint) {
+        (Some(mountpoint), _) => Path::new(mountpoint),
+        (None, Some(mountpoint)) => mountpoint.as_path(),
+        (None, None) => Path::new(DEFAULT_MOUNTPOINT),
+    }
+}
+
+fn socket(opts: &CommandOptions, mountpoint: Option<&str>) -> PathBuf {
+    if let Some(ref socket) = opts.socket {
+        return socket.clone();
+    }
+    control::socket_path(mountpoint_path(opts, mountpoint))
+}
+
+/// Sends `command` over the control socket, or `fallback` as ioctl on the
+/// mountpoint if there is no socket, e.g. in a container that only sees the mountpoint.
+fn request_or_ioctl(
+    opts: &CommandOptions,
+    mountpoint: Option<&str>,
+    command: &str,
+    arg: &str,
+    fallback: ioctl::Command,
+) -> Result<Vec<String>> {
+    let path = socket(opts, mountpoint);
+    if opts.socket.is_some() || path.exists() {
+        return control::request(&path, command, arg);
+    }
+    fallback.send(mountpoint_path(opts, mountpoint))?;
+    Ok(vec![])
+}
+
+pub fn run_command(command: &str, opts: &CommandOptions) -> Result<()> {
+    let lines = match command {
+        "umount" | "status" => {
+            if opts.args.len() > 1 {
+                bail!("too many arguments");
+            }
+            let mountpoint = opts.args.first().map(|m| m.as_str());
+            let arg = if command == "status" && opts.recent {
+                "recent"
+            } else {
+                ""
+            };
+            control::request(&socket(opts, mountpoint), command, arg)?
+        }
+        "flush-cache" => {
+            if opts.args.len() > 1 {
+                bail!("too many arguments");
+            }
+            let mountpoint = opts.args.first().map(|m| m.as_str());
+            request_or_ioctl(opts, mountpoint, command, "", ioctl::Command::FlushCache)?
+        }
+        "invalidate" => {
+            let name = match opts.args.as_slice() {
+                [name] => name,
+                [] => bail!("invalidate requires a NAME"),
+                _ => bail!("too many arguments"),
+            };
+            let fallback = ioctl::Command::Invalidate(OsString::from(name));
+            request_or_ioctl(opts, None, command, name, fallback)?
+        }
+        "stats" => {
+            if opts.args.len() > 1 {
+                bail!("too many arguments");
+            }
+            let mountpoint = opts.args.first().map(|m| m.as_str());
+            control::request(&socket(opts, mountpoint), command, &opts.top.to_string())?
+        }
+        "upgrade" => {
+            if opts.args.len() > 1 {
+                bail!("too many arguments");
+            }
+            let exe = try_with!(env::current_exe(), "cannot find own executable");
+            let mountpoint = opts.args.first().map(|m| m.as_str());
+            control::request(&socket(opts, mountpoint), command, &exe.to_string_lossy())?
+        }
+        "trace" => {
+            if opts.args.len() > 1 {
+                bail!("too many arguments");
+            }
+            let mountpoint = opts.args.first().map(String::as_str);
+            let stdout = io::stdout();
+            return control::stream(&socket(opts, mountpoint), command, "", |line| {
+                let mut out = stdout.lock();
+                // stop quietly when piped into e.g. head
+                if writeln!(out, "{}", line).and_then(|_| out.flush()).is_err() {
+                    process::exit(0);
+                }
+            });
+        }
+        "resolve" => return resolve(opts),
+        "doctor" => {
+            if opts.args.len() > 1 {
+                bail!("too many arguments");
+            }
+            let mountpoint = mountpoint_path(opts, opts.args.first().map(String::as_str));
+            if !doctor::run(mountpoint) {
+                bail!("found problems that keep envfs from working");
+            }
+            return Ok(());
+        }
+        "explain" => {
+            let name = match opts.args.as_slice() {
+                [name] => name,
+                [] => bail!("explain requires a NAME"),
+                _ => bail!("too many arguments"),
+            };
+            let pid = opts.pid.unwrap_or_else(|| unistd::getpid().as_raw());
+            let arg = format!("{} {}", pid, name);
+            control::request(&socket(opts, None), command, &arg)?
+        }
+        "snapshot" => return snapshot(opts),
+        "log-level" => {
+            let level = match opts.args.as_slice() {
+                [level] => level,
+                [] => bail!("log-level requires a LEVEL"),
+                _ => bail!("too many arguments"),
+            };
+            let fallback = ioctl::Command::LogLevel(parse_log_level(level)?);
+            request_or_ioctl(opts, None, command, level, fallback)?
+        }
+        _ => bail!("unknown command '{}'", command),
+    };
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Prints the decision trace to stderr and the resolved path to stdout.
+/// Resolves against the PATH given with `--path` instead of the one of a process.
+struct CommandLinePath(OsString);
+
+impl Resolver for CommandLinePath {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        ctx.trace
+            .add(|| format!("PATH from command line: {}", self.0.to_string_lossy()));
+        which(&self.0, name, &[], ctx.mountpoints, ctx.policy, ctx.trace)
+    }
+}
+
+/// Resolves like an instance in process mode would, with `process` in place
+/// of the PATH of the requesting process.
+fn local_resolver<R: Resolver + 'static>(opts: &CommandOptions, process: R) -> Stack {
+    let fallback_paths = Arc::new(RwLock::new(opts.fallback_paths.clone()));
+    let mut resolver = Stack::default();
+    resolver.push(FallbackResolver::new(
+        Arc::clone(&fallback_paths),
+        Priority::Before,
+    ));
+    resolver.push(process);
+    resolver.push(FallbackResolver::new(fallback_paths, Priority::After));
+    resolver
+}
+
+fn resolve(opts: &CommandOptions) -> Result<()> {
+    let name = match opts.args.as_slice() {
+        [name] => name,
+        [] => bail!("resolve requires a NAME"),
+        _ => bail!("too many arguments"),
+    };
+    let pid = opts.pid.unwrap_or_else(|| unistd::getpid().as_raw());
+
+    let res = if opts.local || opts.path.is_some() {
+        let mountpoints: Vec<PathBuf> = opts.mountpoint.iter().cloned().collect();
+        let trace = Trace::new();
+        let pid = Pid::from_raw(pid);
+        let ctx = RequestCtx {
+            pid,
+            uid: read_uid(pid).unwrap_or(0),
+            mountpoints: &mountpoints,
+            policy: &CandidatePolicy::default(),
+            resolve_always: true,
+            deadline: None,
+            trace: &trace,
+        };
+        let resolver = match opts.path {
+            Some(ref path) => local_resolver(opts, CommandLinePath(OsString::from(path))),
+            None => local_resolver(opts, EnvResolver::default()),
+        };
+        let res = resolver.resolve(&ctx, OsStr::new(name));
+        if let Err(e) = res {
+            trace.add(|| e.desc().to_string());
+        }
+        for line in trace.into_lines() {
+            eprintln!("{}", line);
+        }
+        res.ok().map(|path| path.display().to_string())
+    } else {
+        let arg = format!("{} {}", pid, name);
+        let mut res = None;
+        for line in control::request(&socket(opts, None), "resolve", &arg)? {
+            match line.strip_prefix(control::TRACE_PREFIX) {
+                Some(trace) => eprintln!("{}", trace),
+                None => res = Some(line),
+            }
+        }
+        res
+    };
+
+    match res {
+        Some(path) => {
+            println!("{}", path);
+            Ok(())
+        }
+        None => bail!("{} not found", name),
+    }
+}
+
+/// PATH for `snapshot`: `--path`, the one of `--pid` or the one of this process.
+fn snapshot_path(opts: &CommandOptions) -> Result<OsString> {
+    if let Some(ref path) = opts.path {
+        return Ok(OsString::from(path));
+    }
+    match opts.pid {
+        Some(pid) => {
+            let env = try_with!(
+                read_environment(Pid::from_raw(pid)),
+                "cannot read environment of process {}",
+                pid
+            );
+            Ok(env.get(OsStr::new("PATH")).cloned().unwrap_or_default())
+        }
+        None => Ok(env::var_os("PATH").unwrap_or_default()),
+    }
+}
+
+/// Creates a symlink in the output directory for every name in PATH and
+/// the fallback paths, pointing to what envfs would serve for it.
+///
+/// Meant as a replacement of the mount where FUSE is not available and to
+/// compare envfs with a static directory.
+fn snapshot(opts: &CommandOptions) -> Result<()> {
+    let output = match opts.args.as_slice() {
+        [output] => Path::new(output),
+        [] => bail!("snapshot requires an OUTPUT_DIR"),
+        _ => bail!("too many arguments"),
+    };
+    let path = snapshot_path(opts)?;
+    let dirs: Vec<PathBuf> = opts
+        .fallback_paths
+        .get(Priority::Before)
+        .iter()
+        .cloned()
+        .chain(env::split_paths(&path).filter(|dir| dir.is_absolute()))
+        .chain(opts.fallback_paths.get(Priority::After).iter().cloned())
+        .collect();
+    let mut names = BTreeSet::new();
+    for dir in &dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        names.extend(
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_ok_and(|t| !t.is_dir()))
+                .map(|entry| entry.file_name()),
+        );
+    }
+
+    try_with!(
+        fs::create_dir_all(output),
+        "cannot create {}",
+        output.display()
+    );
+    let not_empty = try_with!(fs::read_dir(output), "cannot read {}", output.display())
+        .next()
+        .is_some();
+    if not_empty {
+        bail!("{} is not empty", output.display());
+    }
+
+    let mountpoints: Vec<PathBuf> = opts.mountpoint.iter().cloned().collect();
+    let resolver = local_resolver(opts, CommandLinePath(path));
+    let trace = Trace::disabled();
+    let ctx = RequestCtx {
+        pid: unistd::getpid(),
+        uid: unistd::geteuid().as_raw(),
+        mountpoints: &mountpoints,
+        policy: &CandidatePolicy::default(),
+        resolve_always: true,
+        deadline: None,
+        trace: &trace,
+    };
+    let mut created = 0;
+    for name in &names {
+        let target = match resolver.resolve(&ctx, name) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        try_with!(
+            symlink(&target, output.join(name)),
+            "cannot create symlink for {}",
+            name.to_string_lossy()
+        );
+        created += 1;
+    }
+    println!("{} symlinks created in {}", created, output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use envfs::options::parse_command_options;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn options(args: &[&str]) -> CommandOptions {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_command_options(&args).unwrap()
+    }
+
+    #[test]
+    fn test_socket() {
+        let opts = options(&[]);
+        assert_eq!(
+            socket(&opts, None),
+            control::socket_path(Path::new("/usr/bin"))
+        );
+        assert_eq!(
+            socket(&opts, Some("/bin")),
+            control::socket_path(Path::new("/bin"))
+        );
+        let opts = options(&["--mountpoint", "/bin"]);
+        assert_eq!(socket(&opts, None), control::socket_path(Path::new("/bin")));
+        let opts = options(&["--socket", "/run/envfs.sock", "--mountpoint", "/bin"]);
+        assert_eq!(
+            socket(&opts, Some("/sbin")),
+            PathBuf::from("/run/envfs.sock")
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = env::temp_dir().join(format!("envfs-snapshot-{}", process::id()));
+        let bin = dir.join("bin");
+        fs::create_dir_all(bin.join("subdir")).unwrap();
+        let prog = bin.join("prog");
+        fs::write(&prog, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&prog, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(bin.join("data"), "").unwrap();
+        let output = dir.join("out");
+        let output_arg = output.to_str().unwrap();
+
+        snapshot(&options(&["--path", bin.to_str().unwrap(), output_arg])).unwrap();
+        let names: Vec<OsString> = fs::read_dir(&output)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        // neither directories nor files that cannot be executed
+        assert_eq!(names, [OsString::from("prog")]);
+        assert_eq!(fs::read_link(output.join("prog")).unwrap(), prog);
+        // refuses to mix with an older snapshot
+        assert!(snapshot(&options(&["--path", bin.to_str().unwrap(), output_arg])).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
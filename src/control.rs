@@ -0,0 +1,419 @@
+//! Unix socket used to talk to a running envfs instance.
+//!
+//! The protocol is line based: a client sends a single line consisting of a
+//! command and its argument separated by a space. The server answers with any
+//! number of payload lines followed by a status line, which is either `ok` or
+//! `error <message>`. The `trace` command is the exception: it streams payload
+//! lines until the client disconnects.
+
+use log::{debug, warn};
+use nix::sys::signal;
+use nix::unistd::{self, Pid};
+use simple_error::{bail, try_with, SimpleError};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+
+use crate::fs::EnvFs;
+use crate::logger;
+use crate::options::{parse_log_level, parse_mount_options, Options};
+use crate::procdir::{self, PidNamespace};
+use crate::resolve::Trace;
+use crate::resolver::Source;
+use crate::result::Result;
+use crate::upgrade;
+
+const SOCKET_DIR: &str = "/run/envfs";
+
+/// Marks lines of a `resolve` reply that describe the decision trace rather than the result.
+pub const TRACE_PREFIX: &str = "trace: ";
+
+/// Turns a mountpoint into a file name, i.e. `usr-bin` for `/usr/bin`.
+pub fn escape_mountpoint(mountpoint: &Path) -> String {
+    let escaped = mountpoint
+        .to_string_lossy()
+        .trim_matches('/')
+        .replace('/', "-");
+    if escaped.is_empty() {
+        String::from("-")
+    } else {
+        escaped
+    }
+}
+
+/// Returns the default control socket for a mountpoint, i.e. `/run/envfs/usr-bin.sock` for `/usr/bin`.
+pub fn socket_path(mountpoint: &Path) -> PathBuf {
+    PathBuf::from(SOCKET_DIR).join(format!("{}.sock", escape_mountpoint(mountpoint)))
+}
+
+pub struct ControlServer {
+    path: PathBuf,
+    /// Moved to the serving thread by `serve`
+    listener: Option<UnixListener>,
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Listens on `path` and serves requests in a background thread.
+pub fn spawn(path: &Path, fs: EnvFs) -> Result<ControlServer> {
+    let mut server = bind(path)?;
+    server.serve(fs);
+    Ok(server)
+}
+
+/// Creates the socket without serving requests yet, see `ControlServer::serve`.
+pub fn bind(path: &Path) -> Result<ControlServer> {
+    if let Some(parent) = path.parent() {
+        try_with!(
+            fs::create_dir_all(parent),
+            "cannot create {}",
+            parent.display()
+        );
+    }
+    // A left-over socket from a previous instance would make bind fail.
+    let _ = fs::remove_file(path);
+    let listener = try_with!(
+        UnixListener::bind(path),
+        "cannot listen on {}",
+        path.display()
+    );
+    try_with!(
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)),
+        "cannot restrict permissions of {}",
+        path.display()
+    );
+
+    Ok(ControlServer {
+        path: path.to_path_buf(),
+        listener: Some(listener),
+    })
+}
+
+impl ControlServer {
+    /// Serves requests in a background thread.
+    pub fn serve(&mut self, fs: EnvFs) {
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => return,
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let fs = fs.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle_client(stream, &fs) {
+                                debug!("control client failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("failed to accept control connection: {}", e),
+                }
+            }
+        });
+    }
+}
+
+fn handle_client(stream: UnixStream, fs: &EnvFs) -> Result<()> {
+    let mut line = String::new();
+    let mut reader = BufReader::new(try_with!(stream.try_clone(), "cannot clone stream"));
+    try_with!(reader.read_line(&mut line), "cannot read request");
+    let mut writer = stream;
+
+    let line = line.trim_end_matches('\n');
+    let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+    debug!("control command: {} {}", command, arg);
+    if command == "trace" {
+        return stream_events(writer, fs);
+    }
+
+    let reply = match execute(fs, command, arg) {
+        Ok(lines) => {
+            let mut reply = lines.join("\n");
+            if !reply.is_empty() {
+                reply.push('\n');
+            }
+            reply + "ok\n"
+        }
+        Err(e) => format!("error {}\n", e),
+    };
+    try_with!(writer.write_all(reply.as_bytes()), "cannot write reply");
+    Ok(())
+}
+
+/// Runs a control command and returns the payload lines of its reply, also
+/// used by the varlink service.
+pub(crate) fn execute(fs: &EnvFs, command: &str, arg: &str) -> Result<Vec<String>> {
+    match command {
+        "remount" => remount(fs, arg).map(|_| vec![]),
+        "status" if arg.is_empty() => Ok(status(fs)),
+        "status" if arg == "recent" => {
+            let mut lines = status(fs);
+            lines.extend(
+                fs.recent_events()
+                    .into_iter()
+                    .map(|e| format!("recent: {}", e)),
+            );
+            Ok(lines)
+        }
+        "status" => Err(SimpleError::new(format!(
+            "unknown status argument '{}'",
+            arg
+        ))),
+        "flush-cache" => {
+            fs.flush_caches();
+            Ok(vec![])
+        }
+        "invalidate" if arg.is_empty() => Err(SimpleError::new("usage: invalidate NAME")),
+        "invalidate" => {
+            let count = fs.invalidate_name(OsStr::new(arg));
+            Ok(vec![format!("invalidated: {}", count)])
+        }
+        "resolve" => resolve(fs, arg),
+        "explain" => explain(fs, arg),
+        "stats" => match arg.parse::<usize>() {
+            Ok(n) => Ok(fs.stats().report(n)),
+            Err(_) => Err(SimpleError::new(format!("invalid number '{}'", arg))),
+        },
+        "log-level" => parse_log_level(arg).map(|level| {
+            logger::set_level(level);
+            vec![]
+        }),
+        "umount" => umount(),
+        "upgrade" => upgrade::upgrade(fs, Path::new(arg)).map(|_| vec![]),
+        // sent by an instance started with -o conflict=takeover once it is mounted
+        "retire" => upgrade::retire(fs).map(|_| vec![]),
+        _ => Err(SimpleError::new(format!("unknown command '{}'", command))),
+    }
+}
+
+/// How often a `trace` client without events is checked for having disconnected.
+const TRACE_IDLE_CHECK: Duration = Duration::from_secs(1);
+
+/// Whether the peer of `stream` closed the connection, without reading from it.
+fn peer_closed(stream: &UnixStream) -> bool {
+    let mut buf = [0u8; 1];
+    let res = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    res == 0
+}
+
+fn stream_events(mut writer: UnixStream, fs: &EnvFs) -> Result<()> {
+    let events = fs.subscribe_events();
+    loop {
+        match events.recv_timeout(TRACE_IDLE_CHECK) {
+            Ok(line) => {
+                // fails once the client is gone, which also unsubscribes
+                try_with!(writeln!(writer, "{}", line), "cannot write event");
+            }
+            Err(RecvTimeoutError::Timeout) if peer_closed(&writer) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+fn status(fs: &EnvFs) -> Vec<String> {
+    let mut lines = vec![format!("pid: {}", unistd::getpid())];
+    for mountpoint in fs.mountpoints() {
+        lines.push(format!("mountpoint: {}", mountpoint.display()));
+    }
+    let fallback_paths = fs.fallback_paths();
+    for path in fallback_paths.before {
+        lines.push(format!("fallback-path: {}:priority=before", path.display()));
+    }
+    for path in fallback_paths.after {
+        lines.push(format!("fallback-path: {}", path.display()));
+    }
+    for (key, value) in counters(fs) {
+        lines.push(format!("{}: {}", key, value));
+    }
+    if let Some(access) = fs.proc_access() {
+        let readable = [
+            ("environ", access.environ),
+            ("syscall", access.syscall),
+            ("mem", access.mem),
+        ];
+        let readable: Vec<&str> = readable
+            .iter()
+            .filter(|(_, ok)| *ok)
+            .map(|(name, _)| *name)
+            .collect();
+        lines.push(format!("proc-access: {}", readable.join(" ")));
+    }
+    match procdir::pid_namespace() {
+        PidNamespace::Same => {}
+        PidNamespace::Translated => lines.push(String::from("pid-namespace: translated")),
+        PidNamespace::Foreign => lines.push(String::from("pid-namespace: foreign")),
+    }
+    if fs.is_early_boot() {
+        lines.push(String::from("early-boot: waiting for /proc"));
+    }
+    lines.push(format!("log-level: {}", log::max_level()));
+    lines
+}
+
+/// Counters reported by `status`, also served over D-Bus.
+pub fn counters(fs: &EnvFs) -> Vec<(&'static str, u64)> {
+    let stats = fs.stats();
+    vec![
+        ("inodes", fs.inode_count() as u64),
+        ("inodes-evicted", stats.evicted()),
+        ("queued-lookups", fs.queued_lookups() as u64),
+        ("interned-strings", fs.interned_count() as u64),
+        ("watchdog-hangs", stats.hangs()),
+        ("rate-limit-trips", stats.trips()),
+        ("throttled-lookups", stats.throttled()),
+        ("degraded-lookups", stats.degraded()),
+        ("bind-mounts-repaired", stats.repaired()),
+        ("answered-from-path", stats.from_source(Source::Path)),
+        (
+            "answered-from-fallback",
+            stats.from_source(Source::Fallback),
+        ),
+        ("answered-from-static", stats.from_source(Source::Static)),
+        ("answered-from-other", stats.from_source(Source::Other)),
+    ]
+}
+
+fn parse_pid_name<'a>(command: &str, arg: &'a str) -> Result<(Pid, &'a str)> {
+    let (pid, name) = match arg.split_once(' ') {
+        Some(v) => v,
+        None => bail!("usage: {} PID NAME", command),
+    };
+    match pid.parse::<i32>() {
+        Ok(pid) => Ok((Pid::from_raw(pid), name)),
+        Err(_) => bail!("invalid pid '{}'", pid),
+    }
+}
+
+fn resolve(fs: &EnvFs, arg: &str) -> Result<Vec<String>> {
+    let (pid, name) = parse_pid_name("resolve", arg)?;
+    let trace = Trace::new();
+    let res = fs.resolve(pid, OsStr::new(name), &trace);
+    // Without a result line the client reports the name as not found.
+    let mut lines: Vec<String> = trace
+        .into_lines()
+        .into_iter()
+        .map(|line| format!("{}{}", TRACE_PREFIX, line))
+        .collect();
+    match res {
+        Ok(path) => lines.push(path.display().to_string()),
+        Err(e) => lines.push(format!("{}{}", TRACE_PREFIX, e.desc())),
+    }
+    Ok(lines)
+}
+
+/// Every decision of a lookup of `NAME` by `PID`, followed by a `result:` or
+/// `error:` line.
+fn explain(fs: &EnvFs, arg: &str) -> Result<Vec<String>> {
+    let (pid, name) = parse_pid_name("explain", arg)?;
+    let trace = Trace::new();
+    let (res, shared) = fs.explain(pid, OsStr::new(name), &trace);
+    let mut lines = trace.into_lines();
+    match res {
+        Ok(path) => {
+            if shared {
+                lines.push(String::from("cached by the kernel for every caller"));
+            }
+            lines.push(format!("result: {}", path.display()));
+        }
+        Err(e) => lines.push(format!("error: {:?} ({})", e, e.desc())),
+    }
+    Ok(lines)
+}
+
+/// Shuts down through the same path as a `SIGTERM` from outside.
+fn umount() -> Result<Vec<String>> {
+    try_with!(
+        signal::kill(unistd::getpid(), signal::SIGTERM),
+        "cannot signal main thread"
+    );
+    Ok(vec![])
+}
+
+fn remount(fs: &EnvFs, mount_options: &str) -> Result<()> {
+    let mut opts = Options::new(false);
+    parse_mount_options(mount_options, &mut opts)?;
+
+    if let Some(level) = opts.log_level {
+        logger::set_level(level);
+    }
+    // Keep the current fallback paths when none were passed, mount(8) only
+    // forwards them if the mountpoint has an fstab entry.
+    if !opts.fallback_paths.is_empty() {
+        fs.set_fallback_paths(opts.fallback_paths);
+    }
+    Ok(())
+}
+
+/// Sends `command` to the instance listening on `path` and calls `f` for
+/// every line it streams back, until the connection is closed.
+pub fn stream<F: FnMut(&str)>(path: &Path, command: &str, arg: &str, mut f: F) -> Result<()> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) => bail!(
+            "cannot connect to envfs instance at {}: {}",
+            path.display(),
+            e
+        ),
+    };
+    try_with!(
+        writeln!(stream, "{} {}", command, arg),
+        "cannot send request"
+    );
+    for line in BufReader::new(stream).lines() {
+        let line = try_with!(line, "cannot read reply");
+        if let Some(msg) = line.strip_prefix("error ") {
+            bail!("{}", msg);
+        }
+        f(&line);
+    }
+    Ok(())
+}
+
+/// Sends `command` to the instance listening on `path` and returns the payload lines of the reply.
+pub fn request(path: &Path, command: &str, arg: &str) -> Result<Vec<String>> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) => bail!(
+            "cannot connect to envfs instance at {}: {}",
+            path.display(),
+            e
+        ),
+    };
+    try_with!(
+        writeln!(stream, "{} {}", command, arg),
+        "cannot send request"
+    );
+
+    let mut lines = vec![];
+    for line in BufReader::new(stream).lines() {
+        let line = try_with!(line, "cannot read reply");
+        if line == "ok" {
+            return Ok(lines);
+        }
+        if let Some(msg) = line.strip_prefix("error ") {
+            bail!("{}", msg);
+        }
+        lines.push(line);
+    }
+    bail!("connection closed before receiving a reply")
+}
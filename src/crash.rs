@@ -0,0 +1,148 @@
+//! Unmounts envfs when the process crashes.
+//!
+//! A mountpoint whose FUSE server is gone fails every access with
+//! `ENOTCONN`, which for `/usr/bin` leaves most of the system unusable.
+
+use log::error;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use simple_error::try_with;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::panic;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+
+use crate::result::Result;
+use crate::workers::WORKER_THREAD_NAME;
+
+/// Mountpoints in the order they are unmounted, prepared up front for the signal handler.
+static MOUNTPOINTS: OnceLock<Vec<CString>> = OnceLock::new();
+/// Set once the mountpoints were handed over to another instance.
+static DISARMED: AtomicBool = AtomicBool::new(false);
+
+const FATAL_SIGNALS: [Signal; 5] = [
+    Signal::SIGSEGV,
+    Signal::SIGBUS,
+    Signal::SIGILL,
+    Signal::SIGFPE,
+    Signal::SIGABRT,
+];
+
+/// Calls `unmount` for each of `mountpoints` unless `disarmed` is set.
+fn unmount_each(mountpoints: &[CString], disarmed: &AtomicBool, mut unmount: impl FnMut(&CStr)) {
+    if disarmed.load(Ordering::SeqCst) {
+        return;
+    }
+    for mountpoint in mountpoints {
+        unmount(mountpoint);
+    }
+}
+
+fn unmount_all() {
+    if let Some(mountpoints) = MOUNTPOINTS.get() {
+        unmount_each(mountpoints, &DISARMED, |mountpoint| {
+            // only async-signal-safe calls in here
+            unsafe { libc::umount2(mountpoint.as_ptr(), libc::MNT_DETACH) };
+        });
+    }
+}
+
+/// Whether a panic on the thread named `thread_name` only fails its request
+/// instead of taking down the process.
+fn survives_panic(thread_name: Option<&str>, abort_on_panic: bool) -> bool {
+    let worker = thread_name.is_some_and(|name| name.starts_with(WORKER_THREAD_NAME));
+    worker && !abort_on_panic
+}
+
+extern "C" fn handle_fatal_signal(sig: libc::c_int) {
+    unmount_all();
+    // SA_RESETHAND restored the default action, which terminates the process now
+    unsafe { libc::raise(sig) };
+}
+
+/// Detaches `mountpoints` before the process dies from a panic or fatal signal.
+///
+/// With `abort_on_panic` unset, panics in worker threads only fail the
+/// request that caused them and the remaining threads keep serving. The hook
+/// then returns and the unwind is caught around each job in `workers`; a
+/// panic anywhere else, e.g. on the session thread, still aborts.
+///
+/// The handlers for `SIGSEGV` and `SIGBUS` replace the ones of the Rust
+/// runtime, so a stack overflow is no longer reported as such before the
+/// process dies.
+pub fn install(mountpoints: &[PathBuf], abort_on_panic: bool) -> Result<()> {
+    let paths = mountpoints
+        .iter()
+        .map(|m| CString::new(m.as_os_str().as_bytes()))
+        .collect::<std::result::Result<Vec<_>, _>>();
+    let paths = try_with!(paths, "mountpoint contains a null byte");
+    // a second call keeps the mountpoints of the first one
+    let _ = MOUNTPOINTS.set(paths);
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if survives_panic(thread::current().name(), abort_on_panic) {
+            error!("worker thread panicked, failing its request");
+            return;
+        }
+        error!("panic, unmounting and aborting");
+        unmount_all();
+        process::abort();
+    }));
+
+    let action = SigAction::new(
+        SigHandler::Handler(handle_fatal_signal),
+        SaFlags::SA_RESETHAND | SaFlags::SA_NODEFER | SaFlags::SA_ONSTACK,
+        SigSet::empty(),
+    );
+    for sig in FATAL_SIGNALS {
+        try_with!(
+            unsafe { signal::sigaction(sig, &action) },
+            "cannot install handler for {}",
+            sig
+        );
+    }
+    Ok(())
+}
+
+/// Stops unmounting on crashes, the mountpoints are now served by a different process.
+pub fn disarm() {
+    DISARMED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unmount_each() {
+        let mountpoints = [
+            CString::new("/usr/bin").unwrap(),
+            CString::new("/bin").unwrap(),
+        ];
+        let disarmed = AtomicBool::new(false);
+        let mut unmounted = vec![];
+        unmount_each(&mountpoints, &disarmed, |m| unmounted.push(m.to_owned()));
+        assert_eq!(unmounted, mountpoints);
+
+        // handed over to another instance
+        disarm();
+        unmounted.clear();
+        unmount_each(&mountpoints, &DISARMED, |m| unmounted.push(m.to_owned()));
+        assert!(unmounted.is_empty());
+    }
+
+    #[test]
+    fn test_survives_panic() {
+        let worker = format!("{}-3", WORKER_THREAD_NAME);
+        assert!(survives_panic(Some(&worker), false));
+        assert!(!survives_panic(Some(&worker), true));
+        // the session thread and unnamed threads always abort
+        assert!(!survives_panic(Some("envfs-session"), false));
+        assert!(!survives_panic(None, false));
+    }
+}
@@ -0,0 +1,293 @@
+//! Filesystem credentials of the requesting process, so that permission checks
+//! are answered from its perspective instead of the one of the daemon.
+
+use nix::errno::Errno;
+use nix::unistd::{self, Pid};
+use simple_error::{bail, try_with};
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use crate::result::Result;
+
+pub struct Creds {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub groups: Vec<libc::gid_t>,
+}
+
+impl Creds {
+    /// Credentials of the daemon itself, used if the ones of the caller are unknown.
+    pub fn root() -> Creds {
+        Creds {
+            uid: 0,
+            gid: 0,
+            groups: vec![],
+        }
+    }
+}
+
+fn status_field<'a>(status: &'a str, key: &str) -> impl Iterator<Item = u32> + 'a {
+    let prefix = format!("{}:", key);
+    status
+        .lines()
+        .find_map(move |line| line.strip_prefix(prefix.as_str()))
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter_map(|id| id.parse().ok())
+}
+
+/// Reads the filesystem uid, gid and supplementary groups of a process from `/proc/<pid>/status`.
+pub fn read_creds(pid: Pid) -> Result<Creds> {
+    let path = format!("/proc/{}/status", pid.as_raw());
+    let status = try_with!(fs::read_to_string(&path), "failed to read {}", path);
+    // Uid and Gid list the real, effective, saved and filesystem id
+    let uid = status_field(&status, "Uid").nth(3);
+    let gid = status_field(&status, "Gid").nth(3);
+    match (uid, gid) {
+        (Some(uid), Some(gid)) => Ok(Creds {
+            uid,
+            gid,
+            groups: status_field(&status, "Groups").collect(),
+        }),
+        _ => bail!("no Uid or Gid in {}", path),
+    }
+}
+
+/// Sets the supplementary groups of the calling thread only, unlike `setgroups(3)`
+/// which changes them for all threads of the process.
+fn set_thread_groups(groups: &[libc::gid_t]) -> nix::Result<()> {
+    let res = unsafe { libc::syscall(libc::SYS_setgroups, groups.len(), groups.as_ptr()) };
+    Errno::result(res).map(drop)
+}
+
+/// Restores the credentials of the daemon when dropped.
+pub struct CredsGuard {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+}
+
+impl Drop for CredsGuard {
+    fn drop(&mut self) {
+        let _ = set_thread_groups(&self.groups);
+        unsafe {
+            libc::setfsgid(self.gid);
+            libc::setfsuid(self.uid);
+        }
+    }
+}
+
+/// Switches the filesystem uid, gid and supplementary groups of the calling thread.
+///
+/// Returns `None` if envfs does not run as root and cannot change credentials.
+pub fn switch_creds(creds: &Creds) -> Result<Option<CredsGuard>> {
+    if !unistd::geteuid().is_root() {
+        return Ok(None);
+    }
+    let old_groups = try_with!(unistd::getgroups(), "cannot get groups");
+    let guard = CredsGuard {
+        uid: unistd::geteuid().as_raw(),
+        gid: unistd::getegid().as_raw(),
+        groups: old_groups.iter().map(|g| g.as_raw()).collect(),
+    };
+    try_with!(set_thread_groups(&creds.groups), "cannot set groups");
+    // setfsuid/setfsgid do not report errors, they are only rejected without CAP_SETUID/CAP_SETGID
+    unsafe {
+        libc::setfsgid(creds.gid);
+        libc::setfsuid(creds.uid);
+    }
+    Ok(Some(guard))
+}
+
+fn check_access(path: &Path, mode: libc::c_int) -> nix::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_faccessat2,
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            mode,
+            libc::AT_EACCESS,
+        )
+    };
+    match Errno::result(res) {
+        // kernels before 5.8
+        Err(Errno::ENOSYS) => check_mode(path, mode),
+        res => res.map(drop),
+    }
+}
+
+/// Filesystem uid, gid and supplementary groups of the calling thread, as set
+/// by `switch_creds`.
+fn thread_creds() -> Creds {
+    // -1 is no valid id, so these only return the current ones
+    let uid = unsafe { libc::setfsuid(libc::uid_t::MAX) } as libc::uid_t;
+    let gid = unsafe { libc::setfsgid(libc::gid_t::MAX) } as libc::gid_t;
+    let groups = unistd::getgroups()
+        .map(|groups| groups.iter().map(|g| g.as_raw()).collect())
+        .unwrap_or_default();
+    Creds { uid, gid, groups }
+}
+
+/// Whether the permission bits of a file with `st_mode`, owned by `owner` and
+/// `group`, grant `mode` to `creds`, like the kernel's `generic_permission`.
+fn mode_allows(
+    st_mode: u32,
+    owner: libc::uid_t,
+    group: libc::gid_t,
+    creds: &Creds,
+    mode: libc::c_int,
+) -> bool {
+    let wanted = (mode & (libc::R_OK | libc::W_OK | libc::X_OK)) as u32;
+    if creds.uid == 0 {
+        // root may execute anything that has an execute bit, or search directories
+        let is_dir = st_mode & libc::S_IFMT == libc::S_IFDIR;
+        return wanted & libc::X_OK as u32 == 0 || is_dir || st_mode & 0o111 != 0;
+    }
+    let granted = if creds.uid == owner {
+        st_mode >> 6
+    } else if creds.gid == group || creds.groups.contains(&group) {
+        st_mode >> 3
+    } else {
+        st_mode
+    };
+    granted & wanted == wanted
+}
+
+/// Checks `mode` against the permission bits of `path` and the credentials of
+/// the calling thread, for kernels without `faccessat2`. `access(2)` would
+/// check against the real uid of the daemon and `AT_EACCESS` emulations
+/// against its effective uid, both root.
+fn check_mode(path: &Path, mode: libc::c_int) -> nix::Result<()> {
+    // stat already fails if a directory on the way cannot be searched with the
+    // filesystem credentials of the thread
+    let stat =
+        fs::metadata(path).map_err(|e| Errno::from_raw(e.raw_os_error().unwrap_or(libc::EIO)))?;
+    if mode_allows(stat.mode(), stat.uid(), stat.gid(), &thread_creds(), mode) {
+        Ok(())
+    } else {
+        Err(Errno::EACCES)
+    }
+}
+
+/// Whether every user may execute `path`, i.e. the file and all directories
+/// leading to it can be executed and searched by others. Which executable is
+/// found then does not depend on the credentials of the caller.
+pub fn executable_by_everyone(path: &Path) -> bool {
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let others = |p: &Path| fs::metadata(p).is_ok_and(|stat| stat.mode() & 0o001 != 0);
+    fs::metadata(&path).is_ok_and(|stat| stat.is_file()) && path.ancestors().all(others)
+}
+
+/// Checks whether `path` is executable with the filesystem credentials of the
+/// calling thread. `access(2)` would use the real uid of the daemon instead.
+pub fn check_executable(path: &Path) -> nix::Result<()> {
+    check_access(path, libc::X_OK)
+}
+
+/// Checks whether `path` is a regular file readable with the filesystem
+/// credentials of the calling thread, like `check_executable`.
+pub fn check_readable(path: &Path) -> nix::Result<()> {
+    check_access(path, libc::R_OK)?;
+    match fs::metadata(path) {
+        Ok(stat) if stat.is_file() => Ok(()),
+        Ok(_) => Err(Errno::ENOENT),
+        Err(e) => Err(Errno::from_raw(e.raw_os_error().unwrap_or(libc::EIO))),
+    }
+}
+
+/// Like `check_executable`, but for `name` in the directory opened as `dirfd`.
+///
+/// Only the permissions of that directory and of `name` are checked, not the
+/// ones of the directories above it.
+pub fn check_executable_at(dirfd: RawFd, name: &Path) -> nix::Result<()> {
+    let c_name = CString::new(name.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_faccessat2,
+            dirfd,
+            c_name.as_ptr(),
+            libc::X_OK,
+            libc::AT_EACCESS,
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_allows() {
+        let user = Creds {
+            uid: 1000,
+            gid: 100,
+            groups: vec![10],
+        };
+        let file = libc::S_IFREG;
+        // root-only executable
+        assert!(!mode_allows(file | 0o700, 0, 0, &user, libc::X_OK));
+        assert!(mode_allows(file | 0o700, 0, 0, &Creds::root(), libc::X_OK));
+        assert!(!mode_allows(file | 0o644, 0, 0, &Creds::root(), libc::X_OK));
+        assert!(mode_allows(file | 0o644, 0, 0, &Creds::root(), libc::R_OK));
+        // the owner class applies even if the other classes would allow more
+        assert!(!mode_allows(file | 0o077, 1000, 0, &user, libc::R_OK));
+        assert!(mode_allows(file | 0o750, 0, 10, &user, libc::X_OK));
+        assert!(mode_allows(
+            file | 0o750,
+            0,
+            100,
+            &user,
+            libc::R_OK | libc::X_OK
+        ));
+        assert!(!mode_allows(file | 0o750, 0, 20, &user, libc::X_OK));
+        assert!(mode_allows(file | 0o755, 0, 20, &user, libc::X_OK));
+    }
+
+    #[test]
+    fn test_executable_by_everyone() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("envfs-creds-{}", std::process::id()));
+        let private = dir.join("private");
+        fs::create_dir_all(&private).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o700)).unwrap();
+        let user = Creds {
+            uid: 1000,
+            gid: 100,
+            groups: vec![],
+        };
+        let owner = Creds {
+            uid: unistd::geteuid().as_raw(),
+            gid: unistd::getegid().as_raw(),
+            groups: vec![],
+        };
+        for (path, mode, shared) in [
+            (dir.join("public"), 0o755, true),
+            (dir.join("owner-only"), 0o700, false),
+            (private.join("public"), 0o755, false),
+        ] {
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            assert_eq!(executable_by_everyone(&path), shared, "{}", path.display());
+            // the owner may execute all of them, another user only the shared ones
+            let allowed = |creds: &Creds| {
+                path.ancestors().all(|p| {
+                    let stat = fs::metadata(p).unwrap();
+                    mode_allows(stat.mode(), stat.uid(), stat.gid(), creds, libc::X_OK)
+                })
+            };
+            assert!(allowed(&owner));
+            assert_eq!(allowed(&user), shared, "{}", path.display());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
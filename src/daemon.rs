@@ -0,0 +1,169 @@
+//! Backgrounding of the filesystem process.
+//!
+//! The parent process only exits once the daemon reported whether mounting
+//! succeeded, so that mount(8) and systemd see the real outcome.
+
+use log::warn;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::sys::wait::waitpid;
+use nix::unistd::{self, ForkResult};
+use simple_error::{bail, try_with};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use envfs::logger;
+use envfs::result::Result;
+
+const READY_MESSAGE: &[u8] = b"ok";
+
+/// Write end of the pipe to the waiting parent process.
+pub struct ReadyPipe(File);
+
+impl ReadyPipe {
+    /// Lets the parent process exit successfully.
+    pub fn succeed(mut self) {
+        let _ = self.0.write_all(READY_MESSAGE);
+    }
+
+    /// Passes `err` to the parent process, which reports it, and exits.
+    pub fn fail(mut self, err: &dyn std::fmt::Display, exit_code: i32) -> ! {
+        let _ = self.0.write_all(err.to_string().as_bytes());
+        process::exit(exit_code);
+    }
+}
+
+pub enum Forked {
+    /// The daemon signaled a successful mount.
+    Parent,
+    Child(ReadyPipe),
+}
+
+/// Detaches from the terminal with the usual double fork. In the parent this
+/// blocks until the daemon calls `ReadyPipe::succeed` or `ReadyPipe::fail`.
+pub fn daemonize() -> Result<Forked> {
+    let (read_end, write_end) = try_with!(unistd::pipe(), "cannot create pipe");
+
+    match try_with!(unsafe { unistd::fork() }, "cannot fork") {
+        ForkResult::Parent { child } => {
+            drop(write_end);
+            // The intermediate process exits right after the second fork.
+            let _ = waitpid(child, None);
+            wait_daemon(read_end)?;
+            Ok(Forked::Parent)
+        }
+        ForkResult::Child => {
+            drop(read_end);
+            let ready = ReadyPipe(File::from(write_end));
+            if let Err(e) = unistd::setsid() {
+                ready.fail(&format!("cannot create new session: {}", e), 1);
+            }
+            // Forking again ensures the daemon can never reacquire a controlling terminal.
+            match unsafe { unistd::fork() } {
+                Ok(ForkResult::Parent { .. }) => process::exit(0),
+                Ok(ForkResult::Child) => {}
+                Err(e) => ready.fail(&format!("cannot fork: {}", e), 1),
+            }
+            if let Err(e) = detach_stdio() {
+                ready.fail(&e, 1);
+            }
+            Ok(Forked::Child(ready))
+        }
+    }
+}
+
+fn detach_stdio() -> Result<()> {
+    try_with!(unistd::chdir("/"), "cannot change directory to /");
+    let null = try_with!(
+        open("/dev/null", OFlag::O_RDWR, Mode::empty()),
+        "cannot open /dev/null"
+    );
+    logger::log_to_syslog();
+    for fd in 0..3 {
+        try_with!(unistd::dup2(null, fd), "cannot redirect fd {}", fd);
+    }
+    if null > 2 {
+        let _ = unistd::close(null);
+    }
+    Ok(())
+}
+
+fn wait_daemon(read_end: OwnedFd) -> Result<()> {
+    let mut msg = vec![];
+    try_with!(
+        File::from(read_end).read_to_end(&mut msg),
+        "cannot read status of background process"
+    );
+    if msg == READY_MESSAGE {
+        return Ok(());
+    }
+    if msg.is_empty() {
+        bail!("background process exited before mounting");
+    }
+    bail!("{}", String::from_utf8_lossy(&msg))
+}
+
+/// Removes the pidfile when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("cannot remove pidfile {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+pub fn write_pidfile(path: &Path) -> Result<PidFile> {
+    let mut file = try_with!(
+        File::create(path),
+        "cannot create pidfile {}",
+        path.display()
+    );
+    try_with!(
+        writeln!(file, "{}", unistd::getpid()),
+        "cannot write pidfile {}",
+        path.display()
+    );
+    Ok(PidFile {
+        path: path.to_path_buf(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_daemon() {
+        let (read_end, write_end) = unistd::pipe().unwrap();
+        ReadyPipe(File::from(write_end)).succeed();
+        assert!(wait_daemon(read_end).is_ok());
+
+        let (read_end, write_end) = unistd::pipe().unwrap();
+        let _ = File::from(write_end).write_all(b"cannot mount /usr/bin: EPERM");
+        let err = wait_daemon(read_end).unwrap_err();
+        assert_eq!(err.to_string(), "cannot mount /usr/bin: EPERM");
+
+        // daemon died without reporting anything
+        let (read_end, write_end) = unistd::pipe().unwrap();
+        drop(write_end);
+        let err = wait_daemon(read_end).unwrap_err();
+        assert_eq!(err.to_string(), "background process exited before mounting");
+    }
+
+    #[test]
+    fn test_write_pidfile() {
+        let path = std::env::temp_dir().join(format!("envfs-pidfile-{}", process::id()));
+        let pidfile = write_pidfile(&path).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content, format!("{}\n", process::id()));
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
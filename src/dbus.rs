@@ -0,0 +1,731 @@
+//! D-Bus service `org.envfs.Manager` for desktop environments and system
+//! management tools.
+//!
+//! The object `/org/envfs/Manager` offers the operations of the control socket
+//! with the interface `org.envfs.Manager`:
+//!
+//! ```text
+//! Resolve(u pid, s name) -> (s path)
+//! FlushCache()
+//! GetStats() -> (a{st} counters)
+//! SetLogLevel(s level)
+//! ```
+//!
+//! Like the control socket, which only root can connect to, the methods are
+//! only answered for root. Only the part of the wire protocol these methods
+//! need is implemented: EXTERNAL authentication, messages in either byte
+//! order (replies are little endian) and the types `y`, `u`, `t`, `s`, `o`,
+//! `g` and arrays of them. Messages that cannot be parsed are skipped.
+
+use log::{debug, info, warn};
+use nix::unistd::{self, Pid};
+use simple_error::{bail, try_with};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::env;
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use crate::control;
+use crate::fs::EnvFs;
+use crate::logger;
+use crate::options::parse_log_level;
+use crate::resolve::Trace;
+use crate::result::Result;
+
+pub const BUS_NAME: &str = "org.envfs.Manager";
+const OBJECT_PATH: &str = "/org/envfs/Manager";
+const INTERFACE: &str = "org.envfs.Manager";
+
+const DEFAULT_SYSTEM_BUS: &str = "unix:path=/run/dbus/system_bus_socket";
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+/// Largest message the specification allows.
+const MAX_MESSAGE: usize = 128 * 1024 * 1024;
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.envfs.Manager">
+    <method name="Resolve">
+      <arg name="pid" type="u" direction="in"/>
+      <arg name="name" type="s" direction="in"/>
+      <arg name="path" type="s" direction="out"/>
+    </method>
+    <method name="FlushCache"/>
+    <method name="GetStats">
+      <arg name="counters" type="a{st}" direction="out"/>
+    </method>
+    <method name="SetLogLevel">
+      <arg name="level" type="s" direction="in"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="data" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusKind {
+    System,
+    Session,
+}
+
+/// Serializes values aligned relative to the start of the buffer.
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, n: usize) {
+        let len = self.buf.len().div_ceil(n) * n;
+        self.buf.resize(len, 0);
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.align(8);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn sig(&mut self, s: &str) {
+        self.u8(s.len() as u8);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    /// Writes an array whose elements are aligned to `align` and written by `f`.
+    fn array<F: FnOnce(&mut Writer)>(&mut self, align: usize, f: F) {
+        self.u32(0);
+        let len_at = self.buf.len() - 4;
+        self.align(align);
+        let start = self.buf.len();
+        f(self);
+        let len = (self.buf.len() - start) as u32;
+        self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    /// The message declared big-endian byte order with 'B'
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader {
+            buf,
+            pos: 0,
+            big_endian: false,
+        }
+    }
+
+    fn align(&mut self, n: usize) {
+        self.pos = self.pos.div_ceil(n) * n;
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4);
+        let bytes = self.take(4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.u32()? as usize;
+        let s = self.take(len)?;
+        self.take(1)?;
+        std::str::from_utf8(s).ok()
+    }
+
+    fn sig(&mut self) -> Option<&'a str> {
+        let len = self.u8()? as usize;
+        let s = self.take(len)?;
+        self.take(1)?;
+        std::str::from_utf8(s).ok()
+    }
+}
+
+#[derive(Default, Debug)]
+struct Message {
+    kind: u8,
+    flags: u8,
+    serial: u32,
+    reply_serial: Option<u32>,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    destination: Option<String>,
+    sender: Option<String>,
+    signature: String,
+    body: Vec<u8>,
+    /// Byte order of the body, messages are sent little-endian
+    big_endian: bool,
+}
+
+/// Whether a message starting with `fixed` is big-endian, `None` for an
+/// unknown byte order.
+fn is_big_endian(fixed: &[u8; 16]) -> Option<bool> {
+    match fixed[0] {
+        b'l' => Some(false),
+        b'B' => Some(true),
+        _ => None,
+    }
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.u8(b'l');
+        w.u8(self.kind);
+        w.u8(self.flags);
+        w.u8(1);
+        w.u32(self.body.len() as u32);
+        w.u32(self.serial);
+        let strings = [
+            (1, "o", &self.path),
+            (2, "s", &self.interface),
+            (3, "s", &self.member),
+            (4, "s", &self.error_name),
+            (6, "s", &self.destination),
+        ];
+        w.array(8, |w| {
+            for (code, sig, value) in strings.iter() {
+                if let Some(value) = value {
+                    w.align(8);
+                    w.u8(*code);
+                    w.sig(sig);
+                    w.str(value);
+                }
+            }
+            if let Some(serial) = self.reply_serial {
+                w.align(8);
+                w.u8(5);
+                w.sig("u");
+                w.u32(serial);
+            }
+            if !self.signature.is_empty() {
+                w.align(8);
+                w.u8(8);
+                w.sig("g");
+                w.sig(&self.signature);
+            }
+        });
+        w.align(8);
+        w.buf.extend_from_slice(&self.body);
+        w.buf
+    }
+
+    /// Parses the header fields and the body that follow the fixed part of the header.
+    fn decode(fixed: &[u8; 16], rest: &[u8]) -> Option<Message> {
+        let big_endian = is_big_endian(fixed)?;
+        // offsets are relative to the start of the message
+        let mut data = fixed.to_vec();
+        data.extend_from_slice(rest);
+        let mut r = Reader::new(&data);
+        r.big_endian = big_endian;
+        r.pos = 8;
+        let mut msg = Message {
+            kind: fixed[1],
+            flags: fixed[2],
+            serial: r.u32()?,
+            big_endian,
+            ..Message::default()
+        };
+        let fields_len = r.u32()? as usize;
+        let end = r.pos.checked_add(fields_len)?;
+        while r.pos < end {
+            r.align(8);
+            let code = r.u8()?;
+            match (code, r.sig()?) {
+                (5, "u") => msg.reply_serial = Some(r.u32()?),
+                (8, "g") => msg.signature = r.sig()?.to_string(),
+                (_, "s") | (_, "o") => {
+                    let value = Some(r.str()?.to_string());
+                    match code {
+                        1 => msg.path = value,
+                        2 => msg.interface = value,
+                        3 => msg.member = value,
+                        4 => msg.error_name = value,
+                        6 => msg.destination = value,
+                        7 => msg.sender = value,
+                        _ => {}
+                    }
+                }
+                (_, "u") => {
+                    r.u32()?;
+                }
+                (_, "g") => {
+                    r.sig()?;
+                }
+                _ => return None,
+            }
+        }
+        r.align(8);
+        msg.body = data.get(r.pos..)?.to_vec();
+        Some(msg)
+    }
+
+    fn body_reader(&self) -> Reader<'_> {
+        let mut r = Reader::new(&self.body);
+        r.big_endian = self.big_endian;
+        r
+    }
+}
+
+/// A connection to a message bus.
+pub struct Bus {
+    stream: UnixStream,
+    serial: u32,
+    /// Calls received while waiting for a reply
+    pending: VecDeque<Message>,
+}
+
+/// Path of the socket of the first `unix:path=` address in `address`.
+fn socket_path(address: &str) -> Option<&str> {
+    address.split(';').find_map(|address| {
+        let params = address.strip_prefix("unix:")?;
+        params.split(',').find_map(|p| p.strip_prefix("path="))
+    })
+}
+
+fn bus_address(kind: BusKind) -> Result<String> {
+    match kind {
+        BusKind::System => {
+            Ok(env::var("DBUS_SYSTEM_BUS_ADDRESS").unwrap_or_else(|_| DEFAULT_SYSTEM_BUS.into()))
+        }
+        BusKind::Session => match env::var("DBUS_SESSION_BUS_ADDRESS") {
+            Ok(address) => Ok(address),
+            Err(_) => bail!("DBUS_SESSION_BUS_ADDRESS is not set"),
+        },
+    }
+}
+
+impl Bus {
+    /// Connects and authenticates to the bus of `kind` and takes the name `org.envfs.Manager`.
+    pub fn connect(kind: BusKind) -> Result<Bus> {
+        let address = bus_address(kind)?;
+        let path = match socket_path(&address) {
+            Some(path) => path,
+            None => bail!("unsupported D-Bus address {}", address),
+        };
+        let stream = try_with!(UnixStream::connect(path), "cannot connect to {}", path);
+        let mut bus = Bus {
+            stream,
+            serial: 0,
+            pending: VecDeque::new(),
+        };
+        bus.authenticate()?;
+        bus.call("Hello", "", vec![])?;
+        let mut w = Writer::default();
+        w.str(BUS_NAME);
+        // DBUS_NAME_FLAG_DO_NOT_QUEUE
+        w.u32(4);
+        let reply = bus.call("RequestName", "su", w.buf)?;
+        // DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+        if Reader::new(&reply.body).u32() != Some(1) {
+            bail!("{} is already taken", BUS_NAME);
+        }
+        info!("registered {} on the D-Bus", BUS_NAME);
+        Ok(bus)
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = vec![];
+        let mut byte = [0u8; 1];
+        while !line.ends_with(b"\r\n") {
+            try_with!(self.stream.read_exact(&mut byte), "cannot read from bus");
+            line.push(byte[0]);
+            if line.len() > 4096 {
+                bail!("authentication line too long");
+            }
+        }
+        line.truncate(line.len() - 2);
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    fn authenticate(&mut self) -> Result<()> {
+        let uid = unistd::geteuid().to_string();
+        let hex: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        try_with!(
+            write!(self.stream, "\0AUTH EXTERNAL {}\r\n", hex),
+            "cannot write to bus"
+        );
+        let line = self.read_line()?;
+        if !line.starts_with("OK ") {
+            bail!("bus refused authentication: {}", line);
+        }
+        try_with!(self.stream.write_all(b"BEGIN\r\n"), "cannot write to bus");
+        Ok(())
+    }
+
+    fn send(&mut self, mut msg: Message) -> Result<u32> {
+        self.serial += 1;
+        msg.serial = self.serial;
+        try_with!(self.stream.write_all(&msg.encode()), "cannot write to bus");
+        Ok(msg.serial)
+    }
+
+    fn receive(&mut self) -> Result<Message> {
+        loop {
+            let mut fixed = [0u8; 16];
+            try_with!(self.stream.read_exact(&mut fixed), "cannot read from bus");
+            let big_endian = match is_big_endian(&fixed) {
+                Some(big_endian) => big_endian,
+                // without the byte order the length of the message is unknown
+                None => bail!("message with unknown byte order from bus"),
+            };
+            let mut r = Reader::new(&fixed);
+            r.big_endian = big_endian;
+            r.pos = 4;
+            let body_len = r.u32().unwrap_or_default() as usize;
+            r.pos = 12;
+            let fields_len = r.u32().unwrap_or_default() as usize;
+            // the header fields start at offset 16 and are followed by padding to 8 bytes
+            let len = fields_len.div_ceil(8) * 8 + body_len;
+            if len > MAX_MESSAGE {
+                debug!("ignoring D-Bus message of {} bytes", len);
+                let skipped = std::io::copy(
+                    &mut (&mut self.stream).take(len as u64),
+                    &mut std::io::sink(),
+                );
+                if try_with!(skipped, "cannot read from bus") != len as u64 {
+                    bail!("bus closed the connection");
+                }
+                continue;
+            }
+            let mut rest = vec![0u8; len];
+            try_with!(self.stream.read_exact(&mut rest), "cannot read from bus");
+            match Message::decode(&fixed, &rest) {
+                Some(msg) => return Ok(msg),
+                None => debug!("ignoring malformed D-Bus message"),
+            }
+        }
+    }
+
+    /// Calls `member` of the bus itself and waits for the reply.
+    fn call(&mut self, member: &str, signature: &str, body: Vec<u8>) -> Result<Message> {
+        let serial = self.send(Message {
+            kind: METHOD_CALL,
+            path: Some("/org/freedesktop/DBus".into()),
+            interface: Some("org.freedesktop.DBus".into()),
+            member: Some(member.into()),
+            destination: Some("org.freedesktop.DBus".into()),
+            signature: signature.into(),
+            body,
+            ..Message::default()
+        })?;
+        loop {
+            let msg = self.receive()?;
+            if msg.reply_serial != Some(serial) {
+                if msg.kind == METHOD_CALL {
+                    self.pending.push_back(msg);
+                }
+                continue;
+            }
+            if msg.kind == ERROR {
+                let text = msg.body_reader().str().unwrap_or("").to_string();
+                bail!(
+                    "{} failed: {} {}",
+                    member,
+                    msg.error_name.unwrap_or_default(),
+                    text
+                );
+            }
+            return Ok(msg);
+        }
+    }
+
+    /// Uid of the connection `sender`, as seen by the bus.
+    fn sender_uid(&mut self, sender: &str) -> Result<u32> {
+        let mut w = Writer::default();
+        w.str(sender);
+        let reply = self.call("GetConnectionUnixUser", "s", w.buf)?;
+        match reply.body_reader().u32() {
+            Some(uid) => Ok(uid),
+            None => bail!("invalid reply to GetConnectionUnixUser"),
+        }
+    }
+
+    fn reply(&mut self, call: &Message, res: Reply) {
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            return;
+        }
+        let mut msg = Message {
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            ..Message::default()
+        };
+        match res {
+            Ok((signature, body)) => {
+                msg.kind = METHOD_RETURN;
+                msg.signature = signature.into();
+                msg.body = body;
+            }
+            Err((name, text)) => {
+                let mut w = Writer::default();
+                w.str(&text);
+                msg.kind = ERROR;
+                msg.error_name = Some(name.into());
+                msg.signature = "s".into();
+                msg.body = w.buf;
+            }
+        }
+        if let Err(e) = self.send(msg) {
+            warn!("{}", e);
+        }
+    }
+
+    fn handle(&mut self, fs: &EnvFs, call: &Message) {
+        let member = call.member.as_deref().unwrap_or("");
+        let interface = call.interface.as_deref();
+        if call.path.as_deref() != Some(OBJECT_PATH) {
+            let text = format!("no object {}", call.path.as_deref().unwrap_or(""));
+            return self.reply(
+                call,
+                Err(("org.freedesktop.DBus.Error.UnknownObject", text)),
+            );
+        }
+        if member == "Introspect"
+            && matches!(
+                interface,
+                None | Some("org.freedesktop.DBus.Introspectable")
+            )
+        {
+            let mut w = Writer::default();
+            w.str(INTROSPECTION);
+            return self.reply(call, Ok(("s", w.buf)));
+        }
+        if !matches!(interface, None | Some(INTERFACE)) {
+            let text = format!("unknown interface {}", interface.unwrap_or(""));
+            return self.reply(
+                call,
+                Err(("org.freedesktop.DBus.Error.UnknownInterface", text)),
+            );
+        }
+        let uid = match call.sender.clone() {
+            Some(sender) => self.sender_uid(&sender),
+            None => Err(simple_error::SimpleError::new("message without sender")),
+        };
+        match uid {
+            Ok(0) => {}
+            Ok(uid) => {
+                debug!("refusing D-Bus call {} of uid {}", member, uid);
+                let text = String::from("only root may call envfs");
+                return self.reply(call, Err(("org.freedesktop.DBus.Error.AccessDenied", text)));
+            }
+            Err(e) => {
+                warn!("cannot check D-Bus caller: {}", e);
+                let text = e.to_string();
+                return self.reply(call, Err(("org.freedesktop.DBus.Error.AccessDenied", text)));
+            }
+        }
+        let res = dispatch(fs, member, &call.signature, call.body_reader());
+        self.reply(call, res);
+    }
+
+    /// Answers method calls in a background thread until the bus connection is lost.
+    pub fn serve(mut self, fs: EnvFs) {
+        let res = thread::Builder::new()
+            .name(String::from("envfs-dbus"))
+            .spawn(move || loop {
+                let call = match self.pending.pop_front() {
+                    Some(call) => call,
+                    None => match self.receive() {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            warn!("D-Bus connection lost: {}", e);
+                            return;
+                        }
+                    },
+                };
+                if call.kind == METHOD_CALL {
+                    self.handle(&fs, &call);
+                }
+            });
+        if let Err(e) = res {
+            warn!("cannot start D-Bus thread: {}", e);
+        }
+    }
+}
+
+type Reply<'a> = std::result::Result<(&'a str, Vec<u8>), (&'a str, String)>;
+
+fn invalid_args(signature: &str) -> Reply<'static> {
+    Err((
+        "org.freedesktop.DBus.Error.InvalidArgs",
+        format!("invalid arguments {}", signature),
+    ))
+}
+
+fn failed(text: String) -> Reply<'static> {
+    Err(("org.freedesktop.DBus.Error.Failed", text))
+}
+
+fn dispatch<'a>(fs: &EnvFs, member: &str, signature: &str, mut r: Reader) -> Reply<'a> {
+    match (member, signature) {
+        ("Resolve", "us") => {
+            let (pid, name) = match (r.u32(), r.str()) {
+                (Some(pid), Some(name)) => (pid, name),
+                _ => return invalid_args(signature),
+            };
+            let res = fs.resolve(
+                Pid::from_raw(pid as i32),
+                OsStr::new(name),
+                &Trace::disabled(),
+            );
+            match res {
+                Ok(path) => {
+                    let mut w = Writer::default();
+                    w.str(&path.to_string_lossy());
+                    Ok(("s", w.buf))
+                }
+                Err(e) => failed(format!("cannot resolve {}: {}", name, e.desc())),
+            }
+        }
+        ("FlushCache", "") => {
+            fs.flush_caches();
+            Ok(("", vec![]))
+        }
+        ("GetStats", "") => {
+            let mut w = Writer::default();
+            w.array(8, |w| {
+                for (key, value) in control::counters(fs) {
+                    w.align(8);
+                    w.str(key);
+                    w.u64(value);
+                }
+            });
+            Ok(("a{st}", w.buf))
+        }
+        ("SetLogLevel", "s") => match r.str().map(parse_log_level) {
+            Some(Ok(level)) => {
+                logger::set_level(level);
+                Ok(("", vec![]))
+            }
+            Some(Err(e)) => failed(e.to_string()),
+            None => invalid_args(signature),
+        },
+        ("Resolve", _) | ("FlushCache", _) | ("GetStats", _) | ("SetLogLevel", _) => {
+            invalid_args(signature)
+        }
+        _ => Err((
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            format!("unknown method {}", member),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let mut body = Writer::default();
+        body.u32(42);
+        body.str("sh");
+        let msg = Message {
+            kind: METHOD_CALL,
+            serial: 7,
+            path: Some(OBJECT_PATH.into()),
+            interface: Some(INTERFACE.into()),
+            member: Some("Resolve".into()),
+            destination: Some(BUS_NAME.into()),
+            signature: "us".into(),
+            body: body.buf,
+            ..Message::default()
+        };
+        let data = msg.encode();
+        let fixed: [u8; 16] = data[..16].try_into().unwrap();
+        let parsed = Message::decode(&fixed, &data[16..]).unwrap();
+        assert_eq!(parsed.serial, 7);
+        assert_eq!(parsed.path.as_deref(), Some(OBJECT_PATH));
+        assert_eq!(parsed.member.as_deref(), Some("Resolve"));
+        assert_eq!(parsed.signature, "us");
+        let mut r = Reader::new(&parsed.body);
+        assert_eq!((r.u32(), r.str()), (Some(42), Some("sh")));
+        assert_eq!(
+            socket_path("unix:abstract=/tmp/x;unix:path=/run/bus,guid=1"),
+            Some("/run/bus")
+        );
+    }
+
+    #[test]
+    fn test_big_endian_message() {
+        fn pad(buf: &mut Vec<u8>, n: usize) {
+            while !buf.len().is_multiple_of(n) {
+                buf.push(0);
+            }
+        }
+        let mut fields = vec![3, 1, b's', 0];
+        fields.extend_from_slice(&7u32.to_be_bytes());
+        fields.extend_from_slice(b"Resolve\0");
+        pad(&mut fields, 8);
+        fields.extend_from_slice(&[8, 1, b'g', 0, 2, b'u', b's', 0]);
+        let mut body = 42u32.to_be_bytes().to_vec();
+        body.extend_from_slice(&2u32.to_be_bytes());
+        body.extend_from_slice(b"sh\0");
+
+        let mut data = vec![b'B', METHOD_CALL, 0, 1];
+        data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        data.extend_from_slice(&9u32.to_be_bytes());
+        data.extend_from_slice(&(fields.len() as u32).to_be_bytes());
+        data.extend_from_slice(&fields);
+        pad(&mut data, 8);
+        data.extend_from_slice(&body);
+
+        let fixed: [u8; 16] = data[..16].try_into().unwrap();
+        let parsed = Message::decode(&fixed, &data[16..]).unwrap();
+        assert_eq!(parsed.serial, 9);
+        assert_eq!(parsed.member.as_deref(), Some("Resolve"));
+        assert_eq!(parsed.signature, "us");
+        let mut r = parsed.body_reader();
+        assert_eq!((r.u32(), r.str()), (Some(42), Some("sh")));
+
+        let mut unknown = fixed;
+        unknown[0] = b'x';
+        assert!(Message::decode(&unknown, &data[16..]).is_none());
+    }
+}
@@ -0,0 +1,141 @@
+//! Open directories of PATH entries, so that checking a candidate does not
+//! walk the whole path of the directory again.
+//!
+//! Entries are checked against the path again after `REVALIDATE_INTERVAL`
+//! and reopened if it now points to another directory, e.g. after
+//! `/run/current-system` was switched to a new generation.
+
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::{fstat, stat, FileStat, Mode};
+use std::collections::BTreeMap;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::creds::{check_executable, check_executable_at};
+
+const REVALIDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of directories kept open.
+const DIR_CACHE_SIZE: usize = 1024;
+
+pub struct Dir {
+    fd: OwnedFd,
+    /// Whether every directory above is searchable by everyone, so that a
+    /// check relative to `fd` gives the same answer as one by path
+    shared: bool,
+    pub dev: u64,
+    pub nlink: u64,
+}
+
+struct Entry {
+    /// `None` if the directory does not exist
+    dir: Option<Arc<Dir>>,
+    /// Device, inode and mtime of the directory when it was opened
+    id: Option<(u64, u64, i64, i64)>,
+    checked: Instant,
+}
+
+static DIR_CACHE: Mutex<BTreeMap<PathBuf, Entry>> = Mutex::new(BTreeMap::new());
+
+fn identity(st: &FileStat) -> (u64, u64, i64, i64) {
+    (st.st_dev, st.st_ino, st.st_mtime, st.st_mtime_nsec)
+}
+
+/// Whether all directories above `path` can be searched by other users.
+fn ancestors_searchable(path: &Path) -> bool {
+    path.ancestors().skip(1).all(|dir| match stat(dir) {
+        Ok(st) => st.st_mode & libc::S_IXOTH != 0,
+        Err(_) => false,
+    })
+}
+
+fn open_dir(path: &Path) -> nix::Result<(Dir, (u64, u64, i64, i64))> {
+    let raw = open(
+        path,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+    let st = fstat(fd.as_raw_fd())?;
+    // symlinks in `path` are resolved by the walk, so the directories they live in count as well
+    let shared = ancestors_searchable(path)
+        && match std::fs::canonicalize(path) {
+            Ok(real) => ancestors_searchable(&real),
+            Err(_) => false,
+        };
+    let dir = Dir {
+        fd,
+        shared,
+        dev: st.st_dev,
+        nlink: st.st_nlink,
+    };
+    Ok((dir, identity(&st)))
+}
+
+/// Returns the open directory at `path`, `Err(ENOENT)` if it does not exist.
+///
+/// Errors that depend on the credentials of the caller are not cached.
+pub fn lookup(path: &Path) -> nix::Result<Arc<Dir>> {
+    {
+        let cache = DIR_CACHE.lock().unwrap();
+        if let Some(entry) = cache.get(path) {
+            if entry.checked.elapsed() < REVALIDATE_INTERVAL {
+                return entry.dir.clone().ok_or(Errno::ENOENT);
+            }
+        }
+    }
+
+    let current = stat(path).map(|st| identity(&st));
+    let mut cache = DIR_CACHE.lock().unwrap();
+    if let Some(entry) = cache.get_mut(path) {
+        if entry.id == current.ok() {
+            entry.checked = Instant::now();
+            return entry.dir.clone().ok_or(Errno::ENOENT);
+        }
+    }
+    drop(cache);
+
+    let entry = match open_dir(path) {
+        Ok((dir, id)) => Entry {
+            dir: Some(Arc::new(dir)),
+            id: Some(id),
+            checked: Instant::now(),
+        },
+        Err(Errno::ENOENT) | Err(Errno::ENOTDIR) => Entry {
+            dir: None,
+            id: None,
+            checked: Instant::now(),
+        },
+        Err(e) => return Err(e),
+    };
+    let dir = entry.dir.clone();
+    let mut cache = DIR_CACHE.lock().unwrap();
+    if cache.len() >= DIR_CACHE_SIZE && !cache.contains_key(path) {
+        cache.clear();
+    }
+    cache.insert(path.to_path_buf(), entry);
+    dir.ok_or(Errno::ENOENT)
+}
+
+impl Dir {
+    /// Checks whether `name` in this directory is executable for the caller,
+    /// `full_path` is checked instead where the answer could differ.
+    pub fn check_executable(&self, name: &Path, full_path: &Path) -> nix::Result<()> {
+        if !self.shared {
+            return check_executable(full_path);
+        }
+        match check_executable_at(self.fd.as_raw_fd(), name) {
+            // kernels before 5.8
+            Err(Errno::ENOSYS) => check_executable(full_path),
+            res => res,
+        }
+    }
+}
+
+/// Closes all cached directories.
+pub fn clear_dir_cache() {
+    DIR_CACHE.lock().unwrap().clear();
+}
@@ -0,0 +1,330 @@
+//! `envfs doctor`: checks the system for the problems that keep envfs from working.
+
+use nix::sys::stat::{major, minor, stat, SFlag};
+use nix::unistd::{self, AccessFlags};
+use std::fs;
+use std::path::Path;
+
+use envfs::fs::{is_envfs_mountpoint, DEFAULT_NOFILE};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Ok,
+    Warning,
+    Error,
+}
+
+struct Finding {
+    level: Level,
+    message: String,
+    /// What to do about it, for warnings and errors
+    hint: Option<String>,
+}
+
+fn ok(message: String) -> Finding {
+    Finding {
+        level: Level::Ok,
+        message,
+        hint: None,
+    }
+}
+
+fn problem(level: Level, message: String, hint: &str) -> Finding {
+    Finding {
+        level,
+        message,
+        hint: Some(hint.to_string()),
+    }
+}
+
+const CAP_SYS_PTRACE: u32 = 19;
+const CAP_SYS_RESOURCE: u32 = 24;
+const CAP_SYS_ADMIN: u32 = 21;
+
+fn check_fuse_module() -> Finding {
+    let filesystems = fs::read_to_string("/proc/filesystems").unwrap_or_default();
+    if filesystems
+        .lines()
+        .any(|l| l.split_whitespace().last() == Some("fuse"))
+    {
+        ok(String::from("the kernel supports FUSE"))
+    } else {
+        problem(
+            Level::Error,
+            String::from("the kernel does not support FUSE"),
+            "load it with 'modprobe fuse' or boot a kernel with CONFIG_FUSE_FS",
+        )
+    }
+}
+
+fn check_dev_fuse() -> Finding {
+    let st = match stat("/dev/fuse") {
+        Ok(st) => st,
+        Err(e) => {
+            return problem(
+                Level::Error,
+                format!("cannot find /dev/fuse: {}", e),
+                "create it with 'mknod -m 0666 /dev/fuse c 10 229' or load the fuse module",
+            )
+        }
+    };
+    let is_char = SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFCHR;
+    if !is_char || major(st.st_rdev) != 10 || minor(st.st_rdev) != 229 {
+        return problem(
+            Level::Error,
+            String::from("/dev/fuse is not the FUSE character device 10:229"),
+            "remove it and run 'mknod -m 0666 /dev/fuse c 10 229'",
+        );
+    }
+    match unistd::access("/dev/fuse", AccessFlags::R_OK | AccessFlags::W_OK) {
+        Ok(()) => ok(String::from("/dev/fuse is readable and writable")),
+        Err(e) => problem(
+            Level::Error,
+            format!("cannot open /dev/fuse for reading and writing: {}", e),
+            "run envfs as root or fix the permissions of /dev/fuse",
+        ),
+    }
+}
+
+/// Effective capabilities of this process as read from `/proc/self/status`.
+fn effective_caps() -> u64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .unwrap_or(0)
+}
+
+fn has_cap(caps: u64, cap: u32) -> bool {
+    caps & (1 << cap) != 0
+}
+
+fn check_privileges(caps: u64) -> Finding {
+    if has_cap(caps, CAP_SYS_ADMIN) {
+        ok(String::from(
+            "running with CAP_SYS_ADMIN, mounting is allowed",
+        ))
+    } else {
+        problem(
+            Level::Error,
+            String::from("missing CAP_SYS_ADMIN, envfs cannot mount"),
+            "run envfs and this check as root",
+        )
+    }
+}
+
+fn check_ptrace_scope(caps: u64) -> Finding {
+    let scope = fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope")
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok());
+    let scope = match scope {
+        Some(scope) => scope,
+        None => {
+            return ok(String::from(
+                "Yama is not active, process memory is readable",
+            ))
+        }
+    };
+    match scope {
+        0 | 1 if has_cap(caps, CAP_SYS_PTRACE) => ok(format!(
+            "Yama ptrace_scope is {}, envfs can read the PATH passed to execve",
+            scope
+        )),
+        3 => problem(
+            Level::Warning,
+            String::from("Yama ptrace_scope is 3, envfs cannot read the PATH passed to execve"),
+            "lookups fall back to /proc/<pid>/environ; set kernel.yama.ptrace_scope to 2 or less (needs a reboot), or use -o no-mem-read to not even try",
+        ),
+        _ if has_cap(caps, CAP_SYS_PTRACE) => ok(format!(
+            "Yama ptrace_scope is {} and CAP_SYS_PTRACE is available",
+            scope
+        )),
+        _ => problem(
+            Level::Warning,
+            format!(
+                "Yama ptrace_scope is {} and CAP_SYS_PTRACE is missing, envfs cannot read the PATH passed to execve",
+                scope
+            ),
+            "run envfs as root, with -o run-as it keeps CAP_SYS_PTRACE",
+        ),
+    }
+}
+
+fn check_nofile(caps: u64) -> Finding {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return problem(
+            Level::Warning,
+            String::from("cannot read the file descriptor limit"),
+            "check 'ulimit -n'",
+        );
+    }
+    let max = limit.rlim_max;
+    if max >= DEFAULT_NOFILE || has_cap(caps, CAP_SYS_RESOURCE) {
+        ok(format!(
+            "the file descriptor limit can be raised to {}",
+            DEFAULT_NOFILE
+        ))
+    } else {
+        problem(
+            Level::Warning,
+            format!(
+                "the hard file descriptor limit is {}, envfs wants {}",
+                max, DEFAULT_NOFILE
+            ),
+            "lookups may fail under load, raise it with LimitNOFILE= in the service or 'ulimit -Hn'",
+        )
+    }
+}
+
+struct Mount {
+    mountpoint: String,
+    fstype: String,
+    /// Options of the superblock, e.g. `hidepid=2` for `/proc`
+    options: String,
+}
+
+fn mounts() -> Vec<Mount> {
+    parse_mounts(&fs::read_to_string("/proc/self/mountinfo").unwrap_or_default())
+}
+
+fn parse_mounts(mountinfo: &str) -> Vec<Mount> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (fields, rest) = line.split_once(" - ")?;
+            let mountpoint = fields.split(' ').nth(4)?;
+            let mut rest = rest.split(' ');
+            Some(Mount {
+                mountpoint: mountpoint.replace("\\040", " "),
+                fstype: rest.next()?.to_string(),
+                options: rest.nth(1).unwrap_or("").to_string(),
+            })
+        })
+        .collect()
+}
+
+fn check_mountpoint(mountpoint: &Path) -> Finding {
+    match fs::metadata(mountpoint) {
+        Ok(meta) if !meta.is_dir() => {
+            return problem(
+                Level::Error,
+                format!("{} is not a directory", mountpoint.display()),
+                "envfs can only be mounted on directories",
+            )
+        }
+        Ok(_) if is_envfs_mountpoint(mountpoint).unwrap_or(false) => {
+            return ok(format!("{} is served by envfs", mountpoint.display()))
+        }
+        Ok(_) => {}
+        Err(e) => {
+            return problem(
+                Level::Error,
+                format!("cannot access {}: {}", mountpoint.display(), e),
+                "create the directory before mounting",
+            )
+        }
+    }
+    let target = mountpoint.to_string_lossy();
+    let stacked: Vec<String> = mounts()
+        .into_iter()
+        .filter(|m| m.mountpoint == target)
+        .map(|m| m.fstype)
+        .collect();
+    if stacked.is_empty() {
+        return ok(format!("nothing is mounted on {}", mountpoint.display()));
+    }
+    problem(
+        Level::Warning,
+        format!(
+            "{} already has mounts of type {}",
+            mountpoint.display(),
+            stacked.join(", ")
+        ),
+        "envfs hides them, unmount them first or use -o underlay for their files",
+    )
+}
+
+/// Whether `/proc` hides the processes of other users.
+fn hides_pids(mounts: &[Mount]) -> bool {
+    mounts.iter().any(|m| {
+        m.mountpoint == "/proc"
+            && m.options.split(',').any(|o| {
+                o.strip_prefix("hidepid=")
+                    .is_some_and(|v| v != "0" && v != "off")
+            })
+    })
+}
+
+fn check_proc() -> Finding {
+    if hides_pids(&mounts()) {
+        problem(
+            Level::Warning,
+            String::from("/proc is mounted with hidepid"),
+            "envfs needs the /proc entries of all callers, run it as root, with -o proc-helper or in the gid= group of /proc",
+        )
+    } else {
+        ok(String::from("/proc shows all processes"))
+    }
+}
+
+/// Runs all checks and prints the findings, returns whether none of them is an error.
+pub fn run(mountpoint: &Path) -> bool {
+    let caps = effective_caps();
+    let findings = [
+        check_fuse_module(),
+        check_dev_fuse(),
+        check_privileges(caps),
+        check_ptrace_scope(caps),
+        check_nofile(caps),
+        check_proc(),
+        check_mountpoint(mountpoint),
+    ];
+    for finding in &findings {
+        let label = match finding.level {
+            Level::Ok => "ok",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        };
+        println!("{:<8}{}", label, finding.message);
+        if let Some(ref hint) = finding.hint {
+            println!("        -> {}", hint);
+        }
+    }
+    findings.iter().all(|f| f.level != Level::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mounts() {
+        let mountinfo = "\
+22 1 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:5 - proc proc rw,hidepid=invisible
+23 1 0:22 / /mnt/my\\040bin rw,relatime - tmpfs tmpfs rw,size=1024k
+garbage
+";
+        let mounts = parse_mounts(mountinfo);
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[1].mountpoint, "/mnt/my bin");
+        assert_eq!(mounts[1].fstype, "tmpfs");
+        assert_eq!(mounts[1].options, "rw,size=1024k");
+        assert!(hides_pids(&mounts));
+        assert!(!hides_pids(&mounts[1..]));
+        let visible = parse_mounts("22 1 0:21 / /proc rw - proc proc rw,hidepid=0\n");
+        assert!(!hides_pids(&visible));
+    }
+
+    #[test]
+    fn test_check_privileges() {
+        assert!(check_privileges(1 << CAP_SYS_ADMIN).level == Level::Ok);
+        let finding = check_privileges(1 << CAP_SYS_PTRACE);
+        assert!(finding.level == Level::Error);
+        assert!(finding.hint.is_some());
+    }
+}
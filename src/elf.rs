@@ -0,0 +1,106 @@
+//! The parts of ELF headers envfs looks at.
+
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Bytes of the header up to and including `e_machine`.
+const HEADER_LEN: usize = 20;
+
+/// Bytes read to find the interpreter, linkers put it right after the program headers.
+const INTERP_SEARCH_LEN: u64 = 16 * 1024;
+
+/// `p_type` of the program header naming the interpreter
+const PT_INTERP: u32 = 3;
+
+/// Class and machine of an ELF file, i.e. which processes can run it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ElfArch {
+    /// `ELFCLASS32` or `ELFCLASS64`
+    pub class: u8,
+    pub machine: u16,
+}
+
+impl ElfArch {
+    /// Parses the start of a file, `None` if it is not ELF.
+    pub fn parse(header: &[u8]) -> Option<ElfArch> {
+        if header.len() < HEADER_LEN || &header[..4] != b"\x7fELF" {
+            return None;
+        }
+        // e_ident[EI_DATA]: 1 little endian, 2 big endian
+        let machine = if header[5] == 2 {
+            u16::from_be_bytes([header[18], header[19]])
+        } else {
+            u16::from_le_bytes([header[18], header[19]])
+        };
+        Some(ElfArch {
+            class: header[4],
+            machine,
+        })
+    }
+
+    /// Reads the header of `path`, `None` if it cannot be read or is not ELF,
+    /// e.g. for scripts.
+    pub fn of_file(path: &Path) -> Option<ElfArch> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        File::open(path)
+            .and_then(|f| f.take(HEADER_LEN as u64).read_to_end(&mut header))
+            .ok()?;
+        ElfArch::parse(&header)
+    }
+}
+
+/// Returns the interpreter (`PT_INTERP`) named in the ELF file starting with
+/// `data`, `None` for static executables, scripts and truncated headers.
+pub fn interpreter(data: &[u8]) -> Option<PathBuf> {
+    let arch = ElfArch::parse(data)?;
+    let big_endian = data[5] == 2;
+    let int = |offset: usize, len: usize| -> Option<u64> {
+        let bytes = data.get(offset..offset.checked_add(len)?)?;
+        let mut buf = [0; 8];
+        if big_endian {
+            buf[8 - len..].copy_from_slice(bytes);
+            Some(u64::from_be_bytes(buf))
+        } else {
+            buf[..len].copy_from_slice(bytes);
+            Some(u64::from_le_bytes(buf))
+        }
+    };
+    // offsets of e_phoff, e_phentsize and e_phnum and of p_offset and p_filesz
+    let (phoff, phentsize, phnum, offset, filesz, word) = if arch.class == 1 {
+        (int(28, 4)?, int(42, 2)?, int(44, 2)?, 4, 16, 4)
+    } else {
+        (int(32, 8)?, int(54, 2)?, int(56, 2)?, 8, 32, 8)
+    };
+    for i in 0..phnum {
+        let header = usize::try_from(phoff.checked_add(i * phentsize)?).ok()?;
+        if int(header, 4)? != u64::from(PT_INTERP) {
+            continue;
+        }
+        let start = usize::try_from(int(header.checked_add(offset)?, word)?).ok()?;
+        let len = usize::try_from(int(header.checked_add(filesz)?, word)?).ok()?;
+        let name = data.get(start..start.checked_add(len)?)?;
+        let name = name.split(|b| *b == 0).next()?;
+        return Some(PathBuf::from(OsStr::from_bytes(name)));
+    }
+    None
+}
+
+/// Reads the interpreter of the ELF file `path`, see `interpreter`.
+pub fn interpreter_of_file(path: &Path) -> Option<PathBuf> {
+    let mut data = vec![];
+    File::open(path)
+        .and_then(|f| f.take(INTERP_SEARCH_LEN).read_to_end(&mut data))
+        .ok()?;
+    interpreter(&data)
+}
+
+impl std::fmt::Display for ElfArch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let bits = if self.class == 1 { 32 } else { 64 };
+        write!(f, "{}-bit machine {}", bits, self.machine)
+    }
+}
@@ -0,0 +1,106 @@
+//! Resolutions streamed to `envfs trace` clients through the control socket,
+//! and the most recent ones kept for `envfs status --recent`.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+
+use crate::logger;
+
+/// Events buffered per client, later ones are dropped until it catches up.
+const QUEUE_LEN: usize = 1024;
+
+#[derive(Default)]
+pub struct Subscribers {
+    senders: Mutex<Vec<SyncSender<String>>>,
+    /// Length of `senders`, checked without locking on every lookup
+    count: AtomicUsize,
+}
+
+impl Subscribers {
+    /// Returns the events published from now on, until the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<String> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        let mut senders = self.senders.lock().unwrap();
+        senders.push(sender);
+        self.count.store(senders.len(), Ordering::Relaxed);
+        receiver
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count.load(Ordering::Relaxed) == 0
+    }
+
+    /// Sends `line` to every subscriber, never blocks.
+    pub fn publish(&self, line: &str) {
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|sender| match sender.try_send(line.to_string()) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        self.count.store(senders.len(), Ordering::Relaxed);
+    }
+}
+
+/// Events kept by default, independent of the log level.
+pub const DEFAULT_RECENT_EVENTS: usize = 256;
+
+/// The last events, so that they can be looked at after something went wrong
+/// without having had debug logging enabled.
+pub struct Recent {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+}
+
+impl Recent {
+    pub fn new(capacity: usize) -> Recent {
+        Recent {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Remembers `line` with the current time, dropping the oldest event if full.
+    pub fn push(&self, line: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let line = format!("{} {}", logger::timestamp(), line);
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// The kept events, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_keeps_the_last_events() {
+        let recent = Recent::new(2);
+        for name in ["a", "b", "c"] {
+            recent.push(&format!("lookup name={:?}", name));
+        }
+        let lines = recent.lines();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" lookup name=\"b\""));
+        assert!(lines[1].ends_with(" lookup name=\"c\""));
+
+        let disabled = Recent::new(0);
+        disabled.push("lookup");
+        assert!(disabled.lines().is_empty());
+    }
+}
@@ -1,40 +1,70 @@
-use concurrent_hashmap::ConcHashMap;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyStatfs,
-    ReplyXattr, Request,
+    consts, FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyOpen, ReplyStatfs, ReplyXattr, Request,
 };
-use libc::{endmntent, getmntent, setmntent, FILE};
-use libc::{ENODATA, ENOENT};
-use log::{debug, warn};
+use libc::{c_int, ENODATA, ENOENT};
+use log::{debug, info, warn};
 use nix::errno::Errno;
-use nix::mount::mount;
-use nix::unistd::{self, Pid};
-use simple_error::try_with;
-use std::collections::HashMap;
-use std::env;
-use std::ffi::{CStr, CString};
+use nix::mount::{mount, umount2, MntFlags};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CString;
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::fs::File;
-use std::io::Seek;
-use std::io::{BufRead, BufReader};
-use std::io::{Read, SeekFrom};
-use std::mem::size_of;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::ptr;
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use crate::audit::AuditLog;
+use crate::creds::{executable_by_everyone, read_creds, switch_creds, Creds};
+use crate::elf::ElfArch;
+use crate::events::{Recent, Subscribers, DEFAULT_RECENT_EVENTS};
+use crate::intern;
+use crate::ioctl;
+use crate::library::LibraryResolver;
+use crate::logger::{self, Field};
+use crate::num_cpus;
+use crate::policy::PolicyFile;
+use crate::procdir::{self, PidNamespace};
+use crate::ratelimit::{Decision, RateLimiter};
+use crate::rescache;
+use crate::resolve::{
+    clear_env_cache, describe_syscall, read_comm, resolve_symlinks, which, worse_miss,
+    CandidatePolicy, Deadline, EmptyPath, EnvConfig, ProcAccess, Trace,
+};
+use crate::resolver::{
+    EnvResolver, FallbackPaths, FallbackResolver, HookResolver, Priority, RequestCtx, Resolver,
+    Source, Stack, StaticResolver,
+};
 use crate::result::Result;
-use crate::setrlimit::{setrlimit, Rlimit};
+use crate::sandbox::Ruleset;
+use crate::setrlimit::{nr_open, raise};
+use crate::slab::Slab;
+use crate::spans;
+use crate::stats::Stats;
+use crate::syscalls::AllowedSyscalls;
+use crate::underlay::Underlay;
+use crate::workers::WorkerPool;
 
 const TTL: Duration = Duration::from_secs(1);
+/// How long the kernel may keep entries whose resolution does not depend on the caller.
+const SHARED_TTL: Duration = Duration::from_secs(10);
+
+/// How often lookups look for `/proc` with `early_boot`.
+const EARLY_BOOT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-const ENVFS_MAGIC: u32 = 0xc7653a76;
+pub const ENVFS_MAGIC: u32 = 0xc7653a76;
+const STATFS_BLOCK_SIZE: u32 = 4096;
+const NAME_MAX: u32 = 255;
 const ENVFS_NAME: &str = "envfs";
-const ENVFS_NAME_C: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"envfs\0") };
 
 const ROOT_DIR_ATTR: FileAttr = FileAttr {
     ino: fuser::FUSE_ROOT_ID,
@@ -55,131 +85,1675 @@ const ROOT_DIR_ATTR: FileAttr = FileAttr {
     flags: 0,
 };
 
-struct InodeCounter {
-    next_number: u64,
-    generation: u64,
+/// Hidden directory with the state of the instance, not listed in the root.
+const STATE_DIR: &str = ".envfs";
+/// Inode numbers of `STATE_DIR` and its files, below those of the slab and
+/// the numbers reported by readdir.
+const STATE_DIR_INO: u64 = 2;
+const RECENT_INO: u64 = 3;
+const RECENT_FILE: &str = "recent";
+
+fn recent_attr() -> FileAttr {
+    FileAttr {
+        ino: RECENT_INO,
+        kind: FileType::RegularFile,
+        // the events show what other users executed
+        perm: 0o400,
+        nlink: 1,
+        ..ROOT_DIR_ATTR
+    }
+}
+
+fn is_state_ino(ino: u64) -> bool {
+    ino == STATE_DIR_INO || ino == RECENT_INO
+}
+
+/// How long resolutions to executables below `prefix` are cached.
+#[derive(Clone, Debug)]
+pub struct CacheRule {
+    pub prefix: PathBuf,
+    /// `Duration::MAX` keeps them until the caches are flushed
+    pub ttl: Duration,
 }
 
 pub struct Inode {
-    pub name: PathBuf,
-    pub path: PathBuf,
+    pub name: Arc<OsStr>,
+    pub path: Arc<Path>,
     pub pid: Pid,
+    /// `Directory` for a virtual subdirectory, whose `path` is where it was
+    /// found for the caller, otherwise `Symlink`
     pub kind: FileType,
+    /// Filesystem uid of the process that looked up the inode
+    pub uid: u32,
     pub ino: u64,
+    /// Value of `EnvFs::cache_epoch` when `path` was resolved
+    pub epoch: u64,
     pub nlookup: RwLock<u64>,
+    /// Milliseconds since `START` when the kernel last asked about the inode
+    last_used: AtomicU64,
+    /// Set by `EnvFs::invalidate_name`, `path` is resolved again on the next readlink
+    stale: AtomicBool,
+    /// `path` is the same for every caller, so it is served to all of them
+    shared: bool,
+    /// Milliseconds since `START` after which `path` is resolved again, see `CacheRule`
+    expires: u64,
 }
 
-pub struct EnvFs {
-    inodes: Arc<ConcHashMap<u64, Arc<Inode>>>,
-    inode_counter: Arc<RwLock<InodeCounter>>,
-    fallback_paths: Arc<Vec<PathBuf>>,
-    mountpoints: Vec<PathBuf>,
+static START: OnceLock<Instant> = OnceLock::new();
+
+fn now_millis() -> u64 {
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
 }
 
-fn open_mntent(path: &str) -> Result<*mut FILE> {
-    let mtab_path = CString::new(path).expect("CString::new failed");
-    let mtab_ptr = mtab_path.as_ptr();
+/// Inodes kept by default before the least recently used ones are dropped.
+pub const DEFAULT_MAX_INODES: usize = 65536;
+/// File descriptor limit envfs raises itself to unless configured otherwise.
+pub const DEFAULT_NOFILE: u64 = 1_048_576;
+
+/// Inodes used more recently are kept even if the table is over its limit.
+const MIN_INODE_AGE: Duration = Duration::from_secs(1);
+
+/// The process that sent a request, `Request` itself cannot be passed to worker threads.
+#[derive(Clone, Copy)]
+struct Caller {
+    pid: Pid,
+    uid: u32,
+    gid: u32,
+    /// When the request was read from the kernel, before it waited for a worker
+    received: Instant,
+}
 
-    let mtab_file: *mut FILE =
-        unsafe { setmntent(mtab_ptr, b"r\0".as_ptr() as *const libc::c_char) };
-    if mtab_file.is_null() {
-        return Err("Failed to open mtab".into());
+/// Pid of the sender of `req` under `/proc`, 0 like for requests from the
+/// kernel if it is not visible there.
+fn request_pid(req: &Request) -> Pid {
+    procdir::translate_pid(Pid::from_raw(req.pid() as i32)).unwrap_or(Pid::from_raw(0))
+}
+
+impl Caller {
+    fn new(req: &Request) -> Caller {
+        Caller {
+            pid: request_pid(req),
+            uid: req.uid(),
+            gid: req.gid(),
+            received: Instant::now(),
+        }
     }
-    Ok(mtab_file)
 }
 
-fn is_envfs_mountpoint(path: &Path) -> Result<bool> {
-    let c_path = try_with!(
-        CString::new(path.as_os_str().as_bytes()),
-        "Failed to convert path to CString"
-    );
-    let mtab_file = match open_mntent("/etc/mtab") {
-        Ok(mtab_file) => mtab_file,
-        Err(_) => match open_mntent("/proc/mounts") {
-            Ok(mtab_file) => mtab_file,
-            Err(_) => return Err("Failed to open mtab".into()),
-        },
-    };
+/// How names are resolved.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Mode {
+    /// Resolve against the PATH of the requesting process, then the fallback paths.
+    #[default]
+    Process,
+    /// Only resolve against the fallback paths, without inspecting the requesting process.
+    System,
+    /// Resolve shared libraries like the dynamic linker of the requesting process, see [`LibraryResolver`].
+    Library,
+}
 
-    let mut mnt: *mut libc::mntent = ptr::null_mut();
-    let mut result = false;
-
-    unsafe {
-        while !mnt.is_null() {
-            mnt = getmntent(mtab_file);
-            if !mnt.is_null() {
-                let mnt_dir = CStr::from_ptr((*mnt).mnt_dir);
-                let fs_name = CStr::from_ptr((*mnt).mnt_fsname);
-                if mnt_dir == c_path.as_c_str() && fs_name == ENVFS_NAME_C {
-                    result = true;
-                    break;
+/// Builder for [`EnvFs`], obtained from [`EnvFs::builder`].
+#[derive(Default)]
+pub struct EnvFsBuilder {
+    fallback_paths: FallbackPaths,
+    mode: Mode,
+    env_config: EnvConfig,
+    policy: CandidatePolicy,
+    policy_file: Option<PolicyFile>,
+    ignore_comms: Vec<String>,
+    rate_limiter: Option<RateLimiter>,
+    max_inodes: Option<usize>,
+    resolve_symlinks: bool,
+    mirror_attr: bool,
+    subdirs: bool,
+    expose_dirs: bool,
+    resolvers: Vec<Box<dyn Resolver>>,
+    static_entries: Option<StaticResolver>,
+    underlay: Option<Underlay>,
+    strip_suffixes: Vec<OsString>,
+    cache_rules: Vec<CacheRule>,
+    resolve_hook: Option<PathBuf>,
+    hook_sandbox: Option<Ruleset>,
+    audit_log: Option<AuditLog>,
+    recent_events: Option<usize>,
+    early_boot: bool,
+    threads: Option<usize>,
+    lookup_deadline: Option<Duration>,
+    cache_file: Option<PathBuf>,
+    nofile: Option<u64>,
+    memlock: Option<u64>,
+    mount_over: bool,
+}
+
+impl EnvFsBuilder {
+    /// Directories searched when a name is not found in the PATH of the requesting process.
+    pub fn fallback_paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.fallback_paths
+            .after
+            .extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Directories searched before the PATH of the requesting process.
+    pub fn fallback_paths_before<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.fallback_paths
+            .before
+            .extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// How empty and relative entries in the PATH of the requesting process are treated.
+    pub fn empty_path(mut self, empty_path: EmptyPath) -> Self {
+        self.env_config.empty_path = empty_path;
+        self
+    }
+
+    /// PATH used when the environment of the requesting process cannot be read.
+    pub fn default_path<S: Into<OsString>>(mut self, path: S) -> Self {
+        self.env_config.default_path = Some(path.into());
+        self
+    }
+
+    /// How long to wait for the requesting process to enter a system call
+    /// before using the PATH of its environment regardless of the call.
+    pub fn syscall_timeout(mut self, timeout: Duration) -> Self {
+        self.env_config.syscall_timeout = timeout;
+        self
+    }
+
+    /// System calls during which the PATH of the requesting process is used.
+    pub fn allowed_syscalls(mut self, allowed: AllowedSyscalls) -> Self {
+        self.env_config.allowed_syscalls = allowed;
+        self
+    }
+
+    /// Reads the files of `/proc/<pid>` that every lookup needs in one batch with io_uring.
+    ///
+    /// Threads that cannot create a ring, e.g. because of seccomp, read them one by one.
+    pub fn io_uring(mut self, io_uring: bool) -> Self {
+        self.env_config.io_uring = io_uring;
+        self
+    }
+
+    /// Whether the PATH passed to execve is read from the memory of the
+    /// calling process, otherwise only `/proc/<pid>/environ` is used.
+    ///
+    /// Enabled by default.
+    pub fn read_mem(mut self, read_mem: bool) -> Self {
+        self.env_config.read_mem = read_mem;
+        self
+    }
+
+    /// Returns the final target of executables that are symlinks instead of the link itself.
+    pub fn resolve_symlinks(mut self, resolve_symlinks: bool) -> Self {
+        self.resolve_symlinks = resolve_symlinks;
+        self
+    }
+
+    /// Reports size, owner, mode and timestamps of the resolved executable for its symlink.
+    pub fn mirror_attr(mut self, mirror_attr: bool) -> Self {
+        self.mirror_attr = mirror_attr;
+        self
+    }
+
+    /// Serves a directory found in PATH as a virtual subdirectory, whose
+    /// entries are looked up as `DIR/NAME` in every directory of PATH.
+    /// Otherwise names containing '/' and directories are never resolved.
+    pub fn subdirs(mut self, subdirs: bool) -> Self {
+        self.subdirs = subdirs;
+        self
+    }
+
+    /// Serves a directory found in PATH as a symlink to it if no executable
+    /// of that name exists, e.g. for tools that expect `git-core` next to
+    /// their executables. `subdirs` takes precedence.
+    pub fn expose_dirs(mut self, expose_dirs: bool) -> Self {
+        self.expose_dirs = expose_dirs;
+        self
+    }
+
+    /// Caches resolutions to executables below the prefix of a rule for its
+    /// TTL instead of the default, the longest matching prefix applies.
+    pub fn cache_rules(mut self, rules: Vec<CacheRule>) -> Self {
+        self.cache_rules = rules;
+        self
+    }
+
+    /// Adds a resolver that is tried after the PATH of the requesting process
+    /// and before the fallback paths.
+    pub fn resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolvers.push(Box::new(resolver));
+        self
+    }
+
+    /// Names that are always served and listed in the mountpoint, see [`StaticResolver`].
+    pub fn static_entries(mut self, entries: StaticResolver) -> Self {
+        self.static_entries = Some(entries);
+        self
+    }
+
+    /// Serves the files of the directory envfs is mounted over if the PATH
+    /// of the caller has no match, see [`Underlay`].
+    pub fn underlay(mut self, underlay: Underlay) -> Self {
+        self.underlay = Some(underlay);
+        self
+    }
+
+    /// Resolves names ending with one of `suffixes` without it if the full name
+    /// cannot be resolved, e.g. `foo` for `foo.exe`.
+    pub fn strip_suffixes(mut self, suffixes: Vec<OsString>) -> Self {
+        self.strip_suffixes = suffixes;
+        self
+    }
+
+    /// Runs `program` for names that cannot be resolved otherwise, see [`HookResolver`].
+    pub fn resolve_hook<P: Into<PathBuf>>(mut self, program: P) -> Self {
+        self.resolve_hook = Some(program.into());
+        self
+    }
+
+    /// Confines the resolve hook to `ruleset`.
+    pub fn hook_sandbox(mut self, ruleset: Ruleset) -> Self {
+        self.hook_sandbox = Some(ruleset);
+        self
+    }
+
+    /// Records all successful resolutions in `audit_log`.
+    pub fn audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Restricts which executables found in PATH entries are served, see [`CandidatePolicy`].
+    pub fn candidate_policy(mut self, policy: CandidatePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Applies per-user rules from `policy_file` on top of the candidate policy.
+    pub fn policy_file(mut self, policy_file: PolicyFile) -> Self {
+        self.policy_file = Some(policy_file);
+        self
+    }
+
+    /// Resolves names for processes called one of `comms` from the fallback
+    /// paths only, without reading their environment or system call.
+    pub fn ignore_comms(mut self, comms: Vec<String>) -> Self {
+        self.ignore_comms = comms;
+        self
+    }
+
+    /// Serves processes that exceed the limit from the fallback paths only for a while.
+    pub fn rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Number of resolutions kept for `envfs status --recent` and
+    /// `.envfs/recent`, defaults to `DEFAULT_RECENT_EVENTS`, 0 keeps none.
+    pub fn recent_events(mut self, count: usize) -> Self {
+        self.recent_events = Some(count);
+        self
+    }
+
+    /// Serves only static entries and fallback paths without touching `/proc`
+    /// until it is mounted, e.g. in an initramfs.
+    pub fn early_boot(mut self, early_boot: bool) -> Self {
+        self.early_boot = early_boot;
+        self
+    }
+
+    /// Number of inodes kept before the least recently used ones are dropped,
+    /// defaults to `DEFAULT_MAX_INODES`.
+    pub fn max_inodes(mut self, max_inodes: usize) -> Self {
+        self.max_inodes = Some(max_inodes);
+        self
+    }
+
+    /// Number of threads resolving lookups in parallel, defaults to the number of CPUs.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Time after which a lookup stops waiting for the requesting process to
+    /// enter a system call, including the time it waited for a worker. Lookups
+    /// that still wait for a worker by then only search the fallback paths.
+    pub fn lookup_deadline(mut self, deadline: Duration) -> Self {
+        self.lookup_deadline = Some(deadline);
+        self
+    }
+
+    /// Keeps resolutions in `file` across restarts, see [`crate::rescache`].
+    pub fn cache_file<P: Into<PathBuf>>(mut self, file: P) -> Self {
+        self.cache_file = Some(file.into());
+        self
+    }
+
+    /// File descriptor limit to raise to, [`DEFAULT_NOFILE`] by default and
+    /// `u64::MAX` for the highest the kernel allows. Without the privileges to
+    /// raise the hard limit, envfs runs with the hard limit instead.
+    pub fn nofile(mut self, nofile: u64) -> Self {
+        self.nofile = Some(nofile);
+        self
+    }
+
+    /// Locked memory limit to raise to when io_uring is used, which kernels
+    /// before 5.12 charge the memory of each ring to.
+    pub fn memlock(mut self, memlock: u64) -> Self {
+        self.memlock = Some(memlock);
+        self
+    }
+
+    /// Mounts on top of envfs instances already mounted on the bind mountpoints
+    /// instead of leaving them alone, used to replace a running instance.
+    pub fn mount_over(mut self, mount_over: bool) -> Self {
+        self.mount_over = mount_over;
+        self
+    }
+
+    pub fn build(mut self) -> Result<EnvFs> {
+        let nofile = self.nofile.unwrap_or(DEFAULT_NOFILE).min(nr_open());
+        let raised = try_with!(
+            raise(libc::RLIMIT_NOFILE, nofile),
+            "Cannot raise file descriptor limit"
+        );
+        if raised < nofile {
+            warn!(
+                "file descriptor limit is {} instead of {}, lookups may fail under load",
+                raised, nofile
+            );
+        }
+        if let (Some(memlock), true) = (self.memlock, self.env_config.io_uring) {
+            match raise(libc::RLIMIT_MEMLOCK, memlock) {
+                Ok(raised) if raised < memlock => {
+                    warn!("locked memory limit is {} instead of {}", raised, memlock)
                 }
+                Ok(_) => {}
+                Err(e) => warn!("cannot raise locked memory limit: {}", e),
+            }
+        }
+
+        if let Some(ref file) = self.cache_file {
+            rescache::enable(file);
+        }
+        let default_path = self.env_config.default_path.clone();
+        let mut proc_access = None;
+        let early_boot = self.early_boot && !procdir::is_mounted();
+        if early_boot {
+            info!("/proc is not mounted yet, serving static entries and fallback paths only");
+        } else {
+            log_pid_namespace(procdir::detect_pid_namespace());
+        }
+        if !early_boot && self.mode == Mode::Process {
+            let access = ProcAccess::probe();
+            if access.environ && access.syscall {
+                info!("resolution strategy: {}", access.strategy());
+            } else {
+                warn!("resolution strategy: {}", access.strategy());
+            }
+            self.env_config.proc_access = access;
+            proc_access = Some(access);
+        }
+
+        let fallback_paths = Arc::new(RwLock::new(self.fallback_paths));
+        // per-user rules, hooks and custom resolvers may answer differently for each caller
+        let caller_independent = self.mode == Mode::System
+            && !self.policy.prefer_arch
+            && !self.policy.check_interp
+            && self.policy_file.is_none()
+            && self.resolve_hook.is_none()
+            && self.resolvers.is_empty();
+        let mut resolver = Stack::default();
+        let mut fallback_resolver = Stack::default();
+        let mut static_names = vec![];
+        if let Some(entries) = self.static_entries {
+            static_names = entries.names().map(OsStr::to_os_string).collect();
+            let entries = Arc::new(entries);
+            resolver.push(Arc::clone(&entries));
+            fallback_resolver.push(entries);
+        }
+        let mut listed_names = static_names.clone();
+        let mut underlay_mount = None;
+        let underlay = self.underlay.map(|underlay| {
+            listed_names.extend(underlay.names());
+            underlay_mount = underlay.mount_path().map(Path::to_path_buf);
+            Arc::new(underlay)
+        });
+        if self.mode == Mode::Library {
+            // the fallback paths are searched around the linker directories
+            fallback_resolver.push(LibraryResolver::new(Arc::clone(&fallback_paths), false));
+            if let Some(ref underlay) = underlay {
+                fallback_resolver.push(Arc::clone(underlay));
+            }
+            resolver.push(LibraryResolver::new(Arc::clone(&fallback_paths), true));
+            for r in self.resolvers {
+                resolver.push_boxed(r);
+            }
+            if let Some(underlay) = underlay {
+                resolver.push(underlay);
+            }
+        } else {
+            fallback_resolver.push(FallbackResolver::new(
+                Arc::clone(&fallback_paths),
+                Priority::Before,
+            ));
+            if let Some(ref underlay) = underlay {
+                fallback_resolver.push(Arc::clone(underlay));
+            }
+            fallback_resolver.push(FallbackResolver::new(
+                Arc::clone(&fallback_paths),
+                Priority::After,
+            ));
+            resolver.push(FallbackResolver::new(
+                Arc::clone(&fallback_paths),
+                Priority::Before,
+            ));
+            if self.mode == Mode::Process {
+                resolver.push(EnvResolver {
+                    config: self.env_config,
+                });
+            }
+            for r in self.resolvers {
+                resolver.push_boxed(r);
+            }
+            if let Some(underlay) = underlay {
+                resolver.push(underlay);
+            }
+            resolver.push(FallbackResolver::new(
+                Arc::clone(&fallback_paths),
+                Priority::After,
+            ));
+        }
+        if let Some(program) = self.resolve_hook {
+            let mut hook = HookResolver::new(program);
+            if let Some(ruleset) = self.hook_sandbox {
+                hook = hook.sandbox(ruleset);
+            }
+            resolver.push(hook);
+        }
+
+        let threads = self.threads.unwrap_or_else(num_cpus::get);
+        let workers = if threads > 1 {
+            Some(Arc::new(WorkerPool::new(threads)))
+        } else {
+            None
+        };
+
+        Ok(EnvFs {
+            inodes: Arc::new(Slab::new()),
+            max_inodes: self.max_inodes.unwrap_or(DEFAULT_MAX_INODES),
+            gc_lock: Arc::new(Mutex::new(())),
+            fallback_paths,
+            resolver: Arc::new(resolver),
+            fallback_resolver: Arc::new(fallback_resolver),
+            ignore_comms: Arc::new(self.ignore_comms),
+            rate_limiter: self.rate_limiter.map(Arc::new),
+            policy: Arc::new(self.policy),
+            policy_file: self.policy_file.map(Arc::new),
+            policy_reloads: Arc::new(AtomicU64::new(0)),
+            static_names: Arc::new(static_names),
+            listed_names: Arc::new(listed_names),
+            dir_handles: Arc::new(Mutex::new(BTreeMap::new())),
+            // 0 is what the kernel passes without opendir
+            next_dir_handle: Arc::new(AtomicU64::new(1)),
+            underlay_mount,
+            mode: self.mode,
+            strip_suffixes: Arc::new(self.strip_suffixes),
+            cache_rules: Arc::new(self.cache_rules),
+            resolve_symlinks: self.resolve_symlinks,
+            mirror_attr: self.mirror_attr,
+            subdirs: self.subdirs,
+            expose_dirs: self.expose_dirs,
+            cache_epoch: Arc::new(AtomicU64::new(0)),
+            stats: Arc::new(Stats::default()),
+            events: Arc::new(Subscribers::default()),
+            recent: Arc::new(Recent::new(
+                self.recent_events.unwrap_or(DEFAULT_RECENT_EVENTS),
+            )),
+            recent_handles: Arc::new(Mutex::new(BTreeMap::new())),
+            audit_log: self.audit_log.map(Arc::new),
+            mountpoints: Arc::new(vec![]),
+            bind_mounts: Arc::new(Mutex::new(vec![])),
+            mount_over: self.mount_over,
+            retired: Arc::new(AtomicBool::new(false)),
+            workers,
+            lookup_deadline: self.lookup_deadline,
+            default_path,
+            notifier: Arc::new(OnceLock::new()),
+            caller_independent,
+            proc_access,
+            early_boot: Arc::new(AtomicBool::new(early_boot)),
+            early_boot_checked: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Builds the filesystem and mounts it on `mountpoints[0]`, the remaining
+    /// mountpoints become bind mounts of the first one.
+    pub fn mount(self, mountpoints: &[PathBuf]) -> Result<(EnvFs, fuser::BackgroundSession)> {
+        let mut fs = self.build()?;
+        let session = fs.mount(mountpoints)?;
+        Ok((fs, session))
+    }
+}
+
+#[derive(Clone)]
+pub struct EnvFs {
+    inodes: Arc<Slab<Inode>>,
+    max_inodes: usize,
+    /// Held while evicting inodes
+    gc_lock: Arc<Mutex<()>>,
+    /// Shared with the `FallbackResolver` in `resolver`
+    fallback_paths: Arc<RwLock<FallbackPaths>>,
+    /// PATH for processes whose environment cannot be read
+    default_path: Option<OsString>,
+    resolver: Arc<Stack>,
+    /// Used instead of `resolver` for processes in `ignore_comms`
+    fallback_resolver: Arc<Stack>,
+    ignore_comms: Arc<Vec<String>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    policy: Arc<CandidatePolicy>,
+    policy_file: Option<Arc<PolicyFile>>,
+    /// `PolicyFile::reloads` when the resolutions were last invalidated for a changed policy
+    policy_reloads: Arc<AtomicU64>,
+    /// Served the same to every caller unless a policy applies
+    static_names: Arc<Vec<OsString>>,
+    /// Listed by readdir, the static names and those of the underlay
+    listed_names: Arc<Vec<OsString>>,
+    /// Names of the root directory as of `opendir`, by handle
+    dir_handles: Arc<Mutex<BTreeMap<u64, Arc<Vec<OsString>>>>>,
+    next_dir_handle: Arc<AtomicU64>,
+    /// Bind mount of the underlay, removed on unmount
+    underlay_mount: Option<PathBuf>,
+    mode: Mode,
+    strip_suffixes: Arc<Vec<OsString>>,
+    cache_rules: Arc<Vec<CacheRule>>,
+    resolve_symlinks: bool,
+    mirror_attr: bool,
+    /// Serve directories of PATH entries as virtual subdirectories
+    subdirs: bool,
+    /// Serve directories of PATH entries as symlinks to them
+    expose_dirs: bool,
+    /// Incremented to invalidate resolutions stored in inodes
+    cache_epoch: Arc<AtomicU64>,
+    stats: Arc<Stats>,
+    events: Arc<Subscribers>,
+    /// Last resolutions, independent of the log level
+    recent: Arc<Recent>,
+    /// Content of `.envfs/recent` as of `open`, by handle
+    recent_handles: Arc<Mutex<BTreeMap<u64, Arc<Vec<u8>>>>>,
+    audit_log: Option<Arc<AuditLog>>,
+    mountpoints: Arc<Vec<PathBuf>>,
+    /// Bind mounts created by `mount`, in the order they were created
+    bind_mounts: Arc<Mutex<Vec<PathBuf>>>,
+    mount_over: bool,
+    /// Set once another instance took over the mountpoints, see `upgrade`
+    retired: Arc<AtomicBool>,
+    /// Runs lookups that inspect the calling process, `None` to run them on the session thread
+    workers: Option<Arc<WorkerPool>>,
+    /// Time budget of lookups and readlinks, see [`Deadline`]
+    lookup_deadline: Option<Duration>,
+    /// Set once the session is created
+    notifier: Arc<OnceLock<fuser::Notifier>>,
+    /// Names resolve the same for all callers apart from the permission checks,
+    /// so the kernel may cache symlinks of shared inodes
+    caller_independent: bool,
+    /// What could be read of other processes at startup, `None` unless in process mode
+    proc_access: Option<ProcAccess>,
+    /// Set while `/proc` is not mounted with `early_boot`, see `before_proc`
+    early_boot: Arc<AtomicBool>,
+    /// When `before_proc` last looked at `/proc`, in milliseconds
+    early_boot_checked: Arc<AtomicU64>,
+}
+
+fn log_pid_namespace(ns: PidNamespace) {
+    match ns {
+        PidNamespace::Same => {}
+        PidNamespace::Translated => {
+            info!("/proc belongs to another pid namespace, translating the pids of requests")
+        }
+        PidNamespace::Foreign => warn!(
+            "/proc belongs to another pid namespace and the kernel cannot translate pids, the environment of callers is not read"
+        ),
+    }
+}
+
+/// Decodes the octal escapes of spaces, tabs, newlines and backslashes in mountinfo.
+fn unescape_mountinfo(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match code {
+            Some(c) => {
+                out.push(c);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
             }
         }
     }
+    PathBuf::from(OsString::from_vec(out))
+}
+
+/// Device numbers and mountpoints of envfs in the format of
+/// `/proc/self/mountinfo`, in the order they were mounted.
+fn envfs_mounts(mountinfo: &str) -> Vec<(u64, PathBuf)> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (fields, rest) = line.split_once(" - ")?;
+            let mut fields = fields.split(' ');
+            let (major, minor) = fields.nth(2)?.split_once(':')?;
+            let dev = libc::makedev(major.parse().ok()?, minor.parse().ok()?);
+            let mountpoint = fields.nth(1)?;
+            let mut rest = rest.split(' ');
+            let fstype = rest.next()?;
+            let source = rest.next()?;
+            let fuse = fstype == "fuse" || fstype.starts_with("fuse.");
+            if fuse && source == ENVFS_NAME {
+                Some((dev, unescape_mountinfo(mountpoint)))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// How long the device numbers of envfs mounts are used before mountinfo is read again.
+const ENVFS_DEVICES_TTL_MS: u64 = 1000;
+
+/// Device numbers of envfs mounts, replaced as a whole when mountinfo was read again.
+static ENVFS_DEVICES: RwLock<Option<Arc<Vec<u64>>>> = RwLock::new(None);
+
+/// `now_millis` when `ENVFS_DEVICES` was last refreshed.
+static ENVFS_DEVICES_READ: AtomicU64 = AtomicU64::new(0);
 
-    unsafe { endmntent(mtab_file) };
-    Ok(result)
+fn read_envfs_devices() -> Vec<u64> {
+    let mounts = fs::read_to_string("/proc/self/mountinfo")
+        .map(|mountinfo| envfs_mounts(&mountinfo))
+        .unwrap_or_default();
+    mounts.into_iter().map(|(dev, _)| dev).collect()
+}
+
+/// Device numbers of envfs mounts as of at most `ENVFS_DEVICES_TTL_MS` ago.
+fn envfs_devices() -> Arc<Vec<u64>> {
+    let now = now_millis();
+    let read = ENVFS_DEVICES_READ.load(Ordering::Relaxed);
+    let snapshot = ENVFS_DEVICES.read().unwrap().clone();
+    match snapshot {
+        Some(ref devices) if now.saturating_sub(read) < ENVFS_DEVICES_TTL_MS => {
+            return Arc::clone(devices)
+        }
+        // only one thread reads mountinfo again, the others keep using the old snapshot
+        Some(devices)
+            if ENVFS_DEVICES_READ
+                .compare_exchange(read, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err() =>
+        {
+            return devices
+        }
+        Some(_) => {}
+        None => ENVFS_DEVICES_READ.store(now, Ordering::Relaxed),
+    }
+    let devices = Arc::new(read_envfs_devices());
+    *ENVFS_DEVICES.write().unwrap() = Some(Arc::clone(&devices));
+    devices
+}
+
+/// Whether a directory with device number `dev` and link count `nlink` is
+/// served by envfs, e.g. another instance in PATH. The kernel reports the same
+/// `f_type` for all FUSE filesystems, so the device is looked up in mountinfo.
+/// `ENVFS_MAGIC` as link count still identifies instances in other mount
+/// namespaces.
+pub(crate) fn is_envfs_dir(dev: u64, nlink: u64) -> bool {
+    envfs_devices().contains(&dev) || nlink as u32 == ENVFS_MAGIC
+}
+
+/// Whether an envfs is mounted on `path` in our mount namespace. It may be
+/// covered by other mounts.
+pub fn is_envfs_mountpoint(path: &Path) -> Result<bool> {
+    let mountinfo = try_with!(
+        fs::read_to_string("/proc/self/mountinfo"),
+        "cannot read /proc/self/mountinfo"
+    );
+    Ok(envfs_mounts(&mountinfo).iter().any(|(_, m)| m == path))
 }
 
 impl EnvFs {
-    pub fn new(fallback_paths: &[PathBuf]) -> Result<EnvFs> {
-        let limit = Rlimit {
-            rlim_cur: 1_048_576,
-            rlim_max: 1_048_576,
+    pub fn builder() -> EnvFsBuilder {
+        EnvFsBuilder::default()
+    }
+
+    pub fn fallback_paths(&self) -> FallbackPaths {
+        self.fallback_paths.read().unwrap().clone()
+    }
+
+    pub fn mountpoints(&self) -> &[PathBuf] {
+        &self.mountpoints
+    }
+
+    pub fn proc_access(&self) -> Option<ProcAccess> {
+        self.proc_access
+    }
+
+    /// Whether only static entries and fallback paths are served because
+    /// `/proc` is not mounted yet.
+    pub fn is_early_boot(&self) -> bool {
+        self.early_boot.load(Ordering::Relaxed)
+    }
+
+    /// Like `is_early_boot`, but looks at `/proc` again at most every
+    /// `EARLY_BOOT_CHECK_INTERVAL` and switches to resolving for each process
+    /// once it is mounted.
+    fn before_proc(&self) -> bool {
+        if !self.is_early_boot() {
+            return false;
+        }
+        let now = now_millis();
+        let checked = self.early_boot_checked.load(Ordering::Relaxed);
+        let interval = EARLY_BOOT_CHECK_INTERVAL.as_millis() as u64;
+        if now.saturating_sub(checked) < interval
+            || self
+                .early_boot_checked
+                .compare_exchange(checked, now, Ordering::SeqCst, Ordering::Relaxed)
+                .is_err()
+        {
+            return true;
+        }
+        if !procdir::is_mounted() {
+            return true;
+        }
+        if self.early_boot.swap(false, Ordering::SeqCst) {
+            info!("/proc is mounted, resolving names for each process from now on");
+            log_pid_namespace(procdir::detect_pid_namespace());
+            // fallback resolutions would otherwise be kept for processes with a PATH
+            self.invalidate_all();
+        }
+        false
+    }
+
+    /// `comm` of `pid` for logs, empty while `/proc` must not be touched.
+    fn comm(&self, pid: Pid) -> String {
+        if self.is_early_boot() {
+            return String::new();
+        }
+        read_comm(pid).unwrap_or_default()
+    }
+
+    fn audit(&self, caller: &Caller, name: &OsStr, target: &Path) {
+        if let Some(ref audit_log) = self.audit_log {
+            let comm = self.comm(caller.pid);
+            audit_log.record(caller.pid, caller.uid, &comm, name, target);
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn inode_count(&self) -> usize {
+        self.inodes.len()
+    }
+
+    /// Number of lookups waiting for a worker thread.
+    pub fn queued_lookups(&self) -> usize {
+        self.workers.as_ref().map_or(0, |workers| workers.queued())
+    }
+
+    /// Number of distinct names and symlink targets shared by the inodes.
+    pub fn interned_count(&self) -> usize {
+        intern::count()
+    }
+
+    /// Forces symlinks that are still referenced by the kernel to be resolved again
+    /// and drops cached environments.
+    pub fn flush_caches(&self) {
+        self.invalidate_all();
+        clear_env_cache();
+    }
+
+    /// Forces all symlinks to be resolved again, e.g. after the configuration changed.
+    fn invalidate_all(&self) {
+        self.cache_epoch.fetch_add(1, Ordering::SeqCst);
+        let mut names = BTreeSet::new();
+        let mut shared = vec![];
+        self.inodes.for_each(|ino, inode| {
+            names.insert(inode.name.to_os_string());
+            if inode.shared {
+                shared.push(ino);
+            }
+        });
+        self.inval_kernel_cache(names.into_iter().collect(), shared);
+    }
+
+    /// Forces symlinks named `name` to be resolved again, returns how many there were.
+    pub fn invalidate_name(&self, name: &OsStr) -> usize {
+        let mut count = 0;
+        let mut shared = vec![];
+        self.inodes.for_each(|ino, inode| {
+            if *inode.name == *name {
+                inode.stale.store(true, Ordering::Relaxed);
+                count += 1;
+                if inode.shared {
+                    shared.push(ino);
+                }
+            }
+        });
+        if count > 0 {
+            self.inval_kernel_cache(vec![name.to_os_string()], shared);
+        }
+        count
+    }
+
+    /// Drops the dentries of `names` and the symlink targets of the inodes
+    /// `shared` from the kernel's cache.
+    ///
+    /// The notifications are sent from a new thread: the kernel locks the
+    /// directory for them, which a lookup waiting for the calling thread may hold.
+    fn inval_kernel_cache(&self, names: Vec<OsString>, shared: Vec<u64>) {
+        let notifier = Arc::clone(&self.notifier);
+        if notifier.get().is_none() || names.is_empty() {
+            return;
+        }
+        let res = thread::Builder::new()
+            .name(String::from("envfs-notify"))
+            .spawn(move || {
+                let notifier = notifier.get().unwrap();
+                for ino in shared {
+                    if let Err(e) = notifier.inval_inode(ino, 0, 0) {
+                        debug!("cannot invalidate inode {} in the kernel: {}", ino, e);
+                    }
+                }
+                for name in names {
+                    match notifier.inval_entry(fuser::FUSE_ROOT_ID, &name) {
+                        // not cached by the kernel
+                        Err(e) if e.raw_os_error() == Some(ENOENT) => {}
+                        Err(e) => debug!(
+                            "cannot invalidate {} in the kernel: {}",
+                            name.to_string_lossy(),
+                            e
+                        ),
+                        Ok(()) => {}
+                    }
+                }
+            });
+        if let Err(e) = res {
+            warn!("cannot start thread to invalidate kernel entries: {}", e);
+        }
+    }
+
+    /// Resolves `name` like an execve of process `pid` would.
+    pub fn resolve(&self, pid: Pid, name: &OsStr, trace: &Trace) -> nix::Result<PathBuf> {
+        let creds = read_creds(pid).unwrap_or_else(|_| Creds::root());
+        self.resolve_name(pid, &creds, name, true, None, trace)
+            .map(|(path, _)| path)
+    }
+
+    /// Resolves `name` like `resolve` and records each decision in `trace`,
+    /// along with the caller and the system call it is in.
+    ///
+    /// Returns the result and whether the kernel would cache it for every caller.
+    pub fn explain(&self, pid: Pid, name: &OsStr, trace: &Trace) -> (nix::Result<PathBuf>, bool) {
+        let creds = read_creds(pid).unwrap_or_else(|_| Creds::root());
+        trace.add(|| {
+            format!(
+                "caller: pid {}, uid {}, gid {}, comm {}, mode {:?}",
+                pid,
+                creds.uid,
+                creds.gid,
+                read_comm(pid).unwrap_or_default(),
+                self.mode
+            )
+        });
+        trace.add(|| format!("current system call: {}", describe_syscall(pid)));
+        trace.add(|| String::from("resolving as if the process executed the name"));
+        let res = self.resolve_name(pid, &creds, name, true, None, trace);
+        let shared = res
+            .as_ref()
+            .is_ok_and(|(path, _)| self.is_shared(name, path));
+        (res.map(|(path, _)| path), shared)
+    }
+
+    /// Credentials of the process sending a request.
+    fn request_creds(&self, caller: &Caller) -> Creds {
+        let groups = if self.before_proc() {
+            vec![]
+        } else {
+            read_creds(caller.pid).map(|c| c.groups).unwrap_or_default()
         };
-        try_with!(
-            setrlimit(libc::RLIMIT_NOFILE, &limit),
-            "Cannot raise file descriptor limit"
-        );
+        Creds {
+            uid: caller.uid,
+            gid: caller.gid,
+            groups,
+        }
+    }
 
-        Ok(EnvFs {
-            inodes: Arc::new(ConcHashMap::<u64, Arc<Inode>>::new()),
-            inode_counter: Arc::new(RwLock::new(InodeCounter {
-                next_number: 3,
-                generation: 0,
-            })),
-            fallback_paths: Arc::new(fallback_paths.to_vec()),
-            mountpoints: vec![],
+    /// Whether lookups of `pid` are limited to the fallback paths because of the rate limit.
+    fn throttled(&self, pid: Pid) -> bool {
+        let limiter = match self.rate_limiter {
+            Some(ref limiter) => limiter,
+            None => return false,
+        };
+        match limiter.check(pid) {
+            Decision::Allow => false,
+            Decision::Trip => {
+                warn!(
+                    "process {} ({}) exceeded the rate limit, serving it from fallback paths for a while",
+                    pid,
+                    read_comm(pid).unwrap_or_default()
+                );
+                self.stats.record_trip();
+                self.stats.record_throttled();
+                true
+            }
+            Decision::Throttle => {
+                self.stats.record_throttled();
+                true
+            }
+        }
+    }
+
+    /// Returns the name of `pid` if it is excluded from per-process resolution.
+    fn ignored_comm(&self, pid: Pid) -> Option<String> {
+        if self.ignore_comms.is_empty() {
+            return None;
+        }
+        let comm = read_comm(pid).ok()?;
+        if self.ignore_comms.contains(&comm) {
+            Some(comm)
+        } else {
+            None
+        }
+    }
+
+    /// Executables in the fallback paths, listed so that `ls` and shell
+    /// completion show at least the names that resolve for every process.
+    fn fallback_names(&self) -> BTreeSet<OsString> {
+        let fallback_paths = self.fallback_paths.read().unwrap().clone();
+        let mut names = BTreeSet::new();
+        for dir in fallback_paths.before.iter().chain(&fallback_paths.after) {
+            // reading our own mount would list it recursively at best
+            if self.mountpoints().iter().any(|m| dir.starts_with(m)) {
+                continue;
+            }
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    debug!("cannot list {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let executable = fs::metadata(entry.path())
+                    .is_ok_and(|stat| stat.is_file() && stat.mode() & 0o111 != 0);
+                if executable {
+                    names.insert(entry.file_name());
+                }
+            }
+        }
+        names
+    }
+
+    /// Names listed in the root directory: static entries, the underlay and
+    /// the executables of the fallback paths.
+    fn root_names(&self) -> Vec<OsString> {
+        let mut fallback_names = self.fallback_names();
+        for name in self.listed_names.iter() {
+            fallback_names.remove(name);
+        }
+        self.listed_names
+            .iter()
+            .cloned()
+            .chain(fallback_names)
+            .collect()
+    }
+
+    /// Takes a snapshot of the names, so that all readdir calls on the handle
+    /// see the same entries at the same offsets.
+    fn open_root(&self, reply: ReplyOpen) {
+        self.open_names(self.root_names(), reply);
+    }
+
+    /// Lists the files of the directory the subdirectory was found in for
+    /// the caller of the lookup.
+    fn open_subdir(&self, caller: &Caller, inode: &Inode, reply: ReplyOpen) {
+        let _guard = switch_creds(&self.request_creds(caller)).unwrap_or_else(|e| {
+            warn!("cannot switch to credentials of {}: {}", caller.pid, e);
+            None
+        });
+        let names = match fs::read_dir(&inode.path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_ok_and(|t| !t.is_dir()))
+                .map(|entry| entry.file_name())
+                .collect(),
+            Err(e) => {
+                debug!("cannot list {}: {}", inode.path.display(), e);
+                vec![]
+            }
+        };
+        self.open_names(names, reply);
+    }
+
+    fn open_names(&self, names: Vec<OsString>, reply: ReplyOpen) {
+        let fh = self.next_dir_handle.fetch_add(1, Ordering::Relaxed);
+        self.dir_handles.lock().unwrap().insert(fh, Arc::new(names));
+        reply.opened(fh, 0);
+    }
+
+    fn list_names(&self, names: &[OsString], offset: i64, mut reply: ReplyDirectory) {
+        let mut entries = vec![
+            (1, FileType::Directory, OsStr::new(".")),
+            (1, FileType::Directory, OsStr::new("..")),
+        ];
+        // The inode numbers are only reported to userspace, lookups allocate their own.
+        for (i, name) in names.iter().enumerate() {
+            entries.push((u64::MAX - i as u64, FileType::Symlink, name.as_os_str()));
+        }
+
+        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            // i + 1 means the index of the next entry
+            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    /// Time budget for resolving a request of `caller`, if one is configured.
+    fn deadline(&self, caller: &Caller) -> Option<Deadline> {
+        self.lookup_deadline
+            .map(|budget| Deadline::new(caller.received, budget))
+    }
+
+    fn record_deadline(&self, deadline: Option<&Deadline>) {
+        if deadline.is_some_and(|d| d.was_reached()) {
+            self.stats.record_degraded();
+        }
+    }
+
+    /// Runs `f` for a request of `caller` on a worker thread if there are any.
+    fn dispatch<F: FnOnce(&EnvFs) + Send + 'static>(&self, caller: &Caller, f: F) {
+        match self.workers {
+            Some(ref workers) => {
+                let fs = self.clone();
+                workers.execute(caller.pid.as_raw(), move || f(&fs));
+            }
+            None => f(self),
+        }
+    }
+
+    fn resolve_name(
+        &self,
+        pid: Pid,
+        creds: &Creds,
+        name: &OsStr,
+        resolve_always: bool,
+        deadline: Option<&Deadline>,
+        trace: &Trace,
+    ) -> nix::Result<(PathBuf, Source)> {
+        let mut policy = Cow::Borrowed(&*self.policy);
+        match name.as_bytes().iter().position(|c| *c == b'/') {
+            None if self.subdirs || self.expose_dirs => policy.to_mut().allow_dirs = true,
+            None => {}
+            // one level of subdirectories, e.g. `core_perl/prove`
+            Some(pos)
+                if self.subdirs
+                    && is_plain_name(&name.as_bytes()[..pos])
+                    && is_plain_name(&name.as_bytes()[pos + 1..]) => {}
+            Some(_) => {
+                trace.add(|| String::from("name contains '/'"));
+                return Err(Errno::ENOENT);
+            }
+        }
+        let early = self.before_proc();
+        if policy.check_interp && !early {
+            policy.to_mut().root = Some(PathBuf::from(format!("/proc/{}/root", pid)));
+        }
+        if (policy.prefer_arch || policy.check_interp) && !early {
+            // read before switching credentials, the executable may not be readable by the caller
+            let arch = ElfArch::of_file(Path::new(&format!("/proc/{}/exe", pid)));
+            trace.add(|| match arch {
+                Some(arch) => format!("caller is {}", arch),
+                None => String::from("cannot read the architecture of the caller"),
+            });
+            policy.to_mut().arch = arch;
+        }
+        if rescache::is_enabled() {
+            policy.to_mut().uid = Some(creds.uid);
+        }
+        // Permission checks during the resolution are done as the caller.
+        let _guard = match switch_creds(creds) {
+            Ok(guard) => guard,
+            Err(e) => {
+                warn!("cannot switch to credentials of {}: {}", pid, e);
+                None
+            }
+        };
+        if let Some(ref policy_file) = self.policy_file {
+            let rules = policy_file.current();
+            let reloads = policy_file.reloads();
+            if self.policy_reloads.swap(reloads, Ordering::SeqCst) != reloads {
+                debug!("policy changed, invalidating all resolutions");
+                self.invalidate_all();
+            }
+            if let Some(rule) = rules.rule_for(creds.uid, creds.gid, &creds.groups) {
+                trace.add(|| format!("policy rule in line {} applies", rule.line()));
+                if !rule.allows_name(name) {
+                    debug!(
+                        "policy does not allow uid {} to resolve {}",
+                        creds.uid,
+                        name.to_string_lossy()
+                    );
+                    trace.add(|| String::from("name is not allowed by the policy"));
+                    return Err(Errno::ENOENT);
+                }
+                if !rule.trusted_prefixes.is_empty() {
+                    policy.to_mut().rule_prefixes = rule.trusted_prefixes.clone();
+                }
+            }
+        }
+        let ctx = RequestCtx {
+            pid,
+            uid: creds.uid,
+            mountpoints: self.mountpoints(),
+            policy: &policy,
+            resolve_always,
+            deadline,
+            trace,
+        };
+        let resolver = if early {
+            trace.add(|| String::from("/proc is not mounted yet, only use fallback paths"));
+            &self.fallback_resolver
+        } else if self.throttled(pid) {
+            trace.add(|| String::from("rate limit exceeded, only use fallback paths"));
+            &self.fallback_resolver
+        } else if let Some(comm) = self.ignored_comm(pid) {
+            trace.add(|| format!("{} is ignored, only use fallback paths", comm));
+            &self.fallback_resolver
+        } else if let Some(deadline) = deadline.filter(|d| d.is_expired()) {
+            // e.g. the request waited for a worker behind slow lookups
+            deadline.mark_reached();
+            trace.add(|| {
+                format!(
+                    "lookup deadline of {:?} reached, only use fallback paths",
+                    deadline.budget()
+                )
+            });
+            &self.fallback_resolver
+        } else {
+            &self.resolver
+        };
+        let (path, source) = match resolver.resolve_source(&ctx, name) {
+            Ok(resolved) => resolved,
+            Err(miss) => match self.stripped_name(name) {
+                Some(stem) => {
+                    trace.add(|| format!("try {}", stem.to_string_lossy()));
+                    resolver
+                        .resolve_source(&ctx, stem)
+                        .map_err(|e| worse_miss(miss, e))?
+                }
+                None => return Err(miss),
+            },
+        };
+        if self.resolve_symlinks {
+            Ok((resolve_symlinks(path, self.mountpoints(), trace), source))
+        } else {
+            Ok((path, source))
+        }
+    }
+
+    /// `name` without the first of `strip_suffixes` it ends with, compared
+    /// case-insensitively like on the systems these names come from.
+    fn stripped_name<'a>(&self, name: &'a OsStr) -> Option<&'a OsStr> {
+        let bytes = name.as_bytes();
+        self.strip_suffixes.iter().find_map(|suffix| {
+            let suffix = suffix.as_bytes();
+            let stem = bytes
+                .len()
+                .checked_sub(suffix.len())
+                .filter(|len| *len > 0)?;
+            if bytes[stem..].eq_ignore_ascii_case(suffix) {
+                Some(OsStr::from_bytes(&bytes[..stem]))
+            } else {
+                None
+            }
         })
     }
 
-    fn next_inode_number(&self) -> (u64, u64) {
-        let mut counter = self.inode_counter.write().unwrap();
-        let next_number = counter.next_number;
-        counter.next_number += 1;
-
-        if next_number == 0 {
-            counter.next_number = fuser::FUSE_ROOT_ID + 1;
-            counter.generation += 1;
+    fn lookup_name(&self, caller: &Caller, name: &OsStr, reply: ReplyEntry) {
+        let started = Instant::now();
+        let mut span = spans::span("lookup");
+        span.attr("envfs.name", name.to_string_lossy().into_owned());
+        span.attr("process.pid", i64::from(caller.pid.as_raw()));
+        let creds = self.request_creds(caller);
+        let deadline = self.deadline(caller);
+        let res = self.resolve_name(
+            caller.pid,
+            &creds,
+            name,
+            false,
+            deadline.as_ref(),
+            &Trace::disabled(),
+        );
+        self.record_deadline(deadline.as_ref());
+        self.report_resolution("lookup", caller, name, &res, started);
+        self.stats.record(name, res.is_ok());
+        match &res {
+            Ok((_, source)) => self.stats.record_source(*source),
+            Err(e) => span.fail(|| e.desc().to_string()),
+        }
+        match res {
+            Ok((path, _)) => {
+                self.audit(caller, name, &path);
+                let shared = self.is_shared(name, &path);
+                let dir = self.subdirs && path.is_dir();
+                let ttl = self.cache_ttl(&path);
+                let expires = ttl.map_or(u64::MAX, |ttl| {
+                    let ttl = ttl.as_millis().min(u128::from(u64::MAX)) as u64;
+                    now_millis().saturating_add(ttl)
+                });
+                let inserted = self.inodes.insert_with(|ino| Inode {
+                    name: intern::name(name),
+                    path: intern::path(&path),
+                    pid: caller.pid,
+                    kind: if dir {
+                        FileType::Directory
+                    } else {
+                        FileType::Symlink
+                    },
+                    uid: caller.uid,
+                    ino,
+                    epoch: self.cache_epoch.load(Ordering::SeqCst),
+                    nlookup: RwLock::new(1),
+                    last_used: AtomicU64::new(now_millis()),
+                    stale: AtomicBool::new(false),
+                    shared,
+                    expires,
+                });
+                let ino = match inserted {
+                    Some(ino) => ino,
+                    None => {
+                        warn!("inode table is full");
+                        reply.error(libc::ENFILE);
+                        return;
+                    }
+                };
+                let attr = if dir {
+                    dir_attr(ino)
+                } else {
+                    self.attr(ino, &path)
+                };
+                if self.inodes.len() > self.max_inodes {
+                    // drop a tenth at once so that not every lookup has to sort the table
+                    self.evict_inodes(self.max_inodes - self.max_inodes / 10, None);
+                }
+
+                let ttl = if shared {
+                    ttl.unwrap_or(SHARED_TTL)
+                } else {
+                    Duration::from_secs(0)
+                };
+                // the generation of the slot is already part of the inode number
+                reply.entry(&ttl, &attr, 0);
+            }
+            Err(e) => reply.error(e as i32),
+        }
+    }
+
+    /// Logs a resolution at debug level and sends it to `envfs trace` clients.
+    fn report_resolution(
+        &self,
+        event: &str,
+        caller: &Caller,
+        name: &OsStr,
+        res: &nix::Result<(PathBuf, Source)>,
+        started: Instant,
+    ) {
+        let log = log::log_enabled!(log::Level::Debug);
+        if !log && self.events.is_empty() && !self.recent.is_enabled() {
+            return;
+        }
+        let latency = started.elapsed().as_micros() as u64;
+        let pid = caller.pid;
+        let comm = self.comm(pid);
+        let result = res.as_ref().ok().map(|(p, _)| p.to_string_lossy());
+        let source = res.as_ref().ok().map(|(_, source)| source.as_str());
+        let error = res.as_ref().err().map(|e| format!("{:?}", e));
+        let fields = [
+            ("name", Field::Str(&name.to_string_lossy())),
+            ("pid", Field::Num(pid.as_raw() as u64)),
+            ("uid", Field::Num(caller.uid as u64)),
+            ("comm", Field::Str(&comm)),
+            ("result", result.as_deref().map_or(Field::Null, Field::Str)),
+            ("source", source.map_or(Field::Null, Field::Str)),
+            ("error", error.as_deref().map_or(Field::Null, Field::Str)),
+            ("latency_us", Field::Num(latency)),
+        ];
+        if !self.events.is_empty() || self.recent.is_enabled() {
+            let line = logger::event_line(event, &fields);
+            self.recent.push(&line);
+            if !self.events.is_empty() {
+                self.events.publish(&line);
+            }
+        }
+        if log && logger::comm_matches_filter(&comm) {
+            logger::log_event(event, &fields);
+        }
+    }
+
+    /// The last resolutions as lines like `1760000000.123 lookup name="ls" ...`, oldest first.
+    pub fn recent_events(&self) -> Vec<String> {
+        self.recent.lines()
+    }
+
+    /// Streams resolutions as lines like `lookup name="ls" ...` until the receiver is dropped.
+    pub fn subscribe_events(&self) -> Receiver<String> {
+        self.events.subscribe()
+    }
+
+    /// Whether `name` resolves to `path` for every caller.
+    fn is_shared(&self, name: &OsStr, path: &Path) -> bool {
+        // static entries come first for everybody unless a policy rule forbids them
+        if self.policy_file.is_none() && self.static_names.iter().any(|n| n == name) {
+            return true;
+        }
+        // candidates are checked with the credentials of the caller, so only
+        // executables that everybody may run are found the same for everyone
+        self.caller_independent && executable_by_everyone(path)
+    }
+
+    /// Resolves the name of `inode` again for a different caller.
+    fn readlink_again(&self, caller: &Caller, inode: &Inode, reply: ReplyData) {
+        let started = Instant::now();
+        let creds = self.request_creds(caller);
+        let name = &*inode.name;
+        let deadline = self.deadline(caller);
+        let res = self.resolve_name(
+            caller.pid,
+            &creds,
+            name,
+            false,
+            deadline.as_ref(),
+            &Trace::disabled(),
+        );
+        self.record_deadline(deadline.as_ref());
+        self.report_resolution("readlink", caller, name, &res, started);
+        match res {
+            Ok((target, _)) => {
+                self.audit(caller, name, &target);
+                reply.data(target.as_os_str().as_bytes());
+            }
+            Err(e) => reply.error(e as i32),
+        }
+    }
+
+    /// TTL of the cache rule for `target`, if any.
+    fn cache_ttl(&self, target: &Path) -> Option<Duration> {
+        self.cache_rules
+            .iter()
+            .filter(|rule| target.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.as_os_str().len())
+            .map(|rule| rule.ttl)
+    }
+
+    fn attr(&self, ino: u64, target: &Path) -> FileAttr {
+        if self.mirror_attr {
+            mirrored_attr(ino, target)
+        } else {
+            symlink_attr(ino)
+        }
+    }
+
+    /// Replaces the fallback paths of a running filesystem.
+    pub fn set_fallback_paths(&self, fallback_paths: FallbackPaths) {
+        debug!("set fallback paths to {:?}", fallback_paths);
+        *self.fallback_paths.write().unwrap() = fallback_paths;
+        self.invalidate_all();
+    }
+
+    fn inode(&self, ino: u64) -> nix::Result<Arc<Inode>> {
+        assert!(ino > 0);
+
+        match self.inodes.get(ino) {
+            Some(inode) => {
+                inode.last_used.store(now_millis(), Ordering::Relaxed);
+                Ok(inode)
+            }
+            // dropped by forget or evicted, the slot may already hold another inode
+            None => Err(Errno::ESTALE),
+        }
+    }
+
+    /// Drops inodes that no request is using, the least recently used first,
+    /// until at most `keep` remain, or with `idle` those unused for that long.
+    ///
+    /// Inodes the kernel forgot are already gone, so the victims are inodes it
+    /// still references. It gets ESTALE for them, which makes it look the name
+    /// up again, and the generation in the inode number keeps a reused slot
+    /// from answering for them.
+    fn evict_inodes(&self, keep: usize, idle: Option<Duration>) -> usize {
+        let _guard = match self.gc_lock.try_lock() {
+            Ok(guard) => guard,
+            // another thread is already at it
+            Err(_) => return 0,
+        };
+        let mut candidates: Vec<(u64, u64)> = vec![];
+        self.inodes.for_each(|ino, inode| {
+            if Arc::strong_count(inode) == 1 {
+                candidates.push((ino, inode.last_used.load(Ordering::Relaxed)));
+            }
+        });
+        let victims = match idle {
+            Some(idle) => {
+                let cutoff = now_millis().saturating_sub(idle.as_millis() as u64);
+                candidates.retain(|(_, last_used)| *last_used < cutoff);
+                candidates.len()
+            }
+            None => {
+                // the kernel may still be working with an inode it just looked up
+                let cutoff = now_millis().saturating_sub(MIN_INODE_AGE.as_millis() as u64);
+                candidates.retain(|(_, last_used)| *last_used < cutoff);
+                candidates.sort_by_key(|(_, last_used)| *last_used);
+                self.inode_count()
+                    .saturating_sub(keep)
+                    .min(candidates.len())
+            }
+        };
+        let mut evicted = 0;
+        for (ino, _) in &candidates[..victims] {
+            if self.inodes.remove(*ino).is_some() {
+                evicted += 1;
+            }
+        }
+        // also covers names of inodes the kernel forgot since the last run
+        let purged = intern::purge();
+        if purged > 0 {
+            debug!("dropped {} unused names and paths", purged);
+        }
+        if evicted > 0 {
+            debug!("evicted {} inodes", evicted);
+            self.stats.record_evicted(evicted as u64);
+        }
+        evicted
+    }
+
+    /// Resolves `names` in the background like lookups of processes without
+    /// a PATH of their own, so that the directories of the fallback paths and
+    /// the default PATH are open and the resolutions cached before the first
+    /// lookups arrive.
+    pub fn prefetch(&self, names: Vec<OsString>) {
+        let fs = self.clone();
+        let res = thread::Builder::new()
+            .name(String::from("envfs-prefetch"))
+            .spawn(move || {
+                let started = Instant::now();
+                let fallback_paths = fs.fallback_paths.read().unwrap().clone();
+                let default_path = fs.default_path.clone().unwrap_or_default();
+                let trace = Trace::disabled();
+                // searches run with the credentials of the daemon
+                let policy = CandidatePolicy {
+                    uid: Some(nix::unistd::geteuid().as_raw()),
+                    ..(*fs.policy).clone()
+                };
+                let found = names
+                    .iter()
+                    .filter(|name| {
+                        // each search is cached separately, like during lookups
+                        let searches = [
+                            (OsStr::new(""), &fallback_paths.before[..]),
+                            (default_path.as_os_str(), &[][..]),
+                            (OsStr::new(""), &fallback_paths.after[..]),
+                        ];
+                        searches
+                            .iter()
+                            .filter(|(path, fallback)| !path.is_empty() || !fallback.is_empty())
+                            .any(|(path, fallback)| {
+                                which(path, name, fallback, fs.mountpoints(), &policy, &trace)
+                                    .is_ok()
+                            })
+                    })
+                    .count();
+                info!(
+                    "prefetched {} of {} names in {:?}",
+                    found,
+                    names.len(),
+                    started.elapsed()
+                );
+            });
+        if let Err(e) = res {
+            warn!("cannot start prefetching: {}", e);
+        }
+    }
+
+    /// Writes changed resolutions to the cache file, if one is configured.
+    pub fn save_resolution_cache(&self) {
+        if let Err(e) = rescache::save() {
+            warn!("{}", e);
         }
+    }
 
-        (next_number, counter.generation)
+    /// Periodically drops inodes that were not used during the last `interval`.
+    pub fn spawn_inode_gc(&self, interval: Duration) {
+        let fs = self.clone();
+        let res = thread::Builder::new()
+            .name(String::from("envfs-gc"))
+            .spawn(move || loop {
+                thread::sleep(interval);
+                fs.evict_inodes(0, Some(interval));
+                fs.save_resolution_cache();
+            });
+        if let Err(e) = res {
+            warn!("cannot start inode garbage collection: {}", e);
+        }
     }
 
-    fn inode(&self, ino: u64) -> nix::Result<Arc<Inode>> {
-        assert!(ino > 0);
+    /// Bind mounts the mountpoints created by `mount` again that are no longer
+    /// served by envfs, e.g. because a script unmounted them.
+    fn repair_bind_mounts(&self) {
+        let primary = match self.mountpoints.first() {
+            Some(primary) => primary,
+            None => return,
+        };
+        // held so that the mounts are not repaired while `unmount` removes them
+        let bind_mounts = self.bind_mounts.lock().unwrap();
+        for mountpoint in bind_mounts.iter() {
+            if self.is_retired() {
+                return;
+            }
+            match is_envfs_mountpoint(mountpoint) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    debug!("cannot check bind mounts: {}", e);
+                    return;
+                }
+            }
+            // binding the directory below an unmounted primary would hide the bind mount target
+            if !is_envfs_mountpoint(primary).unwrap_or(false) {
+                warn!(
+                    "{} was unmounted, but {} is not served by envfs either, not mounting it again",
+                    mountpoint.display(),
+                    primary.display()
+                );
+                return;
+            }
+            let res = mount(
+                Some(primary),
+                mountpoint,
+                None::<&str>,
+                nix::mount::MsFlags::MS_BIND,
+                None::<&str>,
+            );
+            match res {
+                Ok(()) => {
+                    warn!("{} was unmounted, mounted it again", mountpoint.display());
+                    self.stats.record_repaired();
+                }
+                Err(e) => warn!(
+                    "{} was unmounted and cannot be mounted again: {}",
+                    mountpoint.display(),
+                    e
+                ),
+            }
+        }
+    }
 
-        match self.inodes.find(&ino) {
-            Some(inode) => Ok(Arc::clone(inode.get())),
-            None => Err(Errno::ESTALE),
+    /// Checks every `interval` that the bind mounts are still in place, see
+    /// `repair_bind_mounts`.
+    pub fn spawn_mount_repair(&self, interval: Duration) {
+        if self.bind_mounts.lock().unwrap().is_empty() {
+            return;
+        }
+        let fs = self.clone();
+        let res = thread::Builder::new()
+            .name(String::from("envfs-mounts"))
+            .spawn(move || loop {
+                thread::sleep(interval);
+                fs.repair_bind_mounts();
+            });
+        if let Err(e) = res {
+            warn!("cannot start the check of bind mounts: {}", e);
         }
     }
 
-    pub fn mount(self, mountpoints: &[PathBuf]) -> Result<fuser::BackgroundSession> {
-        assert!(mountpoints.len() > 1);
+    pub fn mount(&mut self, mountpoints: &[PathBuf]) -> Result<fuser::BackgroundSession> {
+        let session = self.mount_session(mountpoints)?;
+        Ok(try_with!(session.spawn(), "failed to start fuse session"))
+    }
 
-        let cntrfs = EnvFs {
-            inodes: Arc::clone(&self.inodes),
-            inode_counter: Arc::clone(&self.inode_counter),
-            fallback_paths: Arc::clone(&self.fallback_paths),
-            mountpoints: mountpoints.to_vec(),
-        };
+    /// Like `mount`, but leaves starting the session to the caller, which can
+    /// e.g. drop privileges before any thread is started.
+    pub fn mount_session(&mut self, mountpoints: &[PathBuf]) -> Result<fuser::Session<EnvFs>> {
+        assert!(!mountpoints.is_empty());
+
+        self.mountpoints = Arc::new(mountpoints.to_vec());
 
         let session = try_with!(
-            fuser::spawn_mount2(
-                cntrfs,
-                mountpoints[0].clone(),
+            fuser::Session::new(
+                self.clone(),
+                &mountpoints[0],
                 &[
                     fuser::MountOption::FSName(ENVFS_NAME.to_string()),
                     fuser::MountOption::AllowOther,
@@ -187,8 +1761,10 @@ impl EnvFs {
                     fuser::MountOption::RO
                 ]
             ),
-            "failed to spawn mount2"
+            "failed to mount {}",
+            mountpoints[0].display()
         );
+        let _ = self.notifier.set(session.notifier());
 
         for mountpoint in mountpoints.iter().skip(1) {
             try_with!(
@@ -197,6 +1773,7 @@ impl EnvFs {
                 mountpoint.display()
             );
             match is_envfs_mountpoint(mountpoint) {
+                Ok(true) if self.mount_over => {}
                 Ok(true) => {
                     debug!("{} is already a mountpoint", mountpoint.display());
                     continue;
@@ -211,19 +1788,87 @@ impl EnvFs {
                     continue;
                 }
             }
+            if let Err(e) = mount(
+                Some(&mountpoints[0]),
+                mountpoint,
+                None::<&str>,
+                nix::mount::MsFlags::MS_BIND,
+                None::<&str>,
+            ) {
+                self.unmount_bind_mounts();
+                bail!("failed to bind mount {}: {}", mountpoint.display(), e);
+            }
+            self.bind_mounts.lock().unwrap().push(mountpoint.clone());
+        }
+        Ok(session)
+    }
+
+    fn unmount_bind_mounts(&self) {
+        let mut bind_mounts = self.bind_mounts.lock().unwrap();
+        while let Some(mountpoint) = bind_mounts.pop() {
+            if let Err(e) = unmount_path(&mountpoint) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    /// Leaves the mountpoints alone on shutdown, they are served by another instance now.
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::SeqCst)
+    }
+
+    /// Mounts created by `mount` in the order they have to be unmounted.
+    pub fn active_mounts(&self) -> Vec<PathBuf> {
+        let mut mounts: Vec<PathBuf> = self
+            .bind_mounts
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect();
+        mounts.extend(self.mountpoints.first().cloned());
+        mounts
+    }
+
+    /// Removes the bind mounts created by `mount` in reverse order, then the
+    /// filesystem itself. Mountpoints that are still in use are detached.
+    pub fn unmount(&self) {
+        if self.is_retired() {
+            return;
+        }
+        self.unmount_bind_mounts();
+        if let Some(mountpoint) = self.mountpoints.first() {
+            if let Err(e) = unmount_path(mountpoint) {
+                warn!("{}", e);
+            }
+        }
+        if let Some(ref underlay) = self.underlay_mount {
+            if let Err(e) = unmount_path(underlay) {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+/// Unmounts `path`, lazily if it is busy. Succeeds if nothing is mounted there.
+fn unmount_path(path: &Path) -> Result<()> {
+    match umount2(path, MntFlags::empty()) {
+        Ok(()) | Err(Errno::EINVAL) => Ok(()),
+        Err(Errno::EBUSY) => {
+            debug!("{} is busy, detach it", path.display());
             try_with!(
-                mount(
-                    Some(&mountpoints[0]),
-                    mountpoint,
-                    None::<&str>,
-                    nix::mount::MsFlags::MS_BIND,
-                    None::<&str>
-                ),
-                "failed to bind mount {}",
-                mountpoint.display()
+                umount2(path, MntFlags::MNT_DETACH),
+                "cannot detach {}",
+                path.display()
             );
+            Ok(())
         }
-        Ok(session)
+        Err(e) => bail!("cannot unmount {}: {}", path.display(), e),
     }
 }
 
@@ -239,6 +1884,19 @@ macro_rules! tryfuse {
     };
 }
 
+fn dir_attr(ino: u64) -> FileAttr {
+    FileAttr {
+        ino,
+        nlink: 2,
+        ..ROOT_DIR_ATTR
+    }
+}
+
+/// Whether `name` is a single, non-empty path component.
+fn is_plain_name(name: &[u8]) -> bool {
+    !name.is_empty() && name != b"." && name != b".." && !name.contains(&b'/')
+}
+
 fn symlink_attr(ino: u64) -> FileAttr {
     FileAttr {
         ino,
@@ -250,7 +1908,7 @@ fn symlink_attr(ino: u64) -> FileAttr {
         crtime: UNIX_EPOCH,
         uid: 0,
         gid: 0,
-        perm: 777,
+        perm: 0o777,
         kind: FileType::Symlink,
         nlink: 1,
         rdev: 0,
@@ -260,288 +1918,130 @@ fn symlink_attr(ino: u64) -> FileAttr {
     }
 }
 
-fn _which<P1, P2>(path: &Path, exe_name: P1, mountpoints: &[P2]) -> Option<PathBuf>
-where
-    P1: AsRef<Path>,
-    P2: AsRef<Path>,
-{
-    if mountpoints.iter().any(|m| path.starts_with(m)) {
-        return None;
+fn unix_time(secs: i64, nsecs: i64) -> SystemTime {
+    let offset = Duration::new(secs.unsigned_abs(), nsecs as u32);
+    if secs >= 0 {
+        UNIX_EPOCH + offset
+    } else {
+        UNIX_EPOCH - offset
     }
+}
 
-    // Do we still need this check if we already check for mountpoints?
-    if let Ok(stat) = path.symlink_metadata() {
-        if stat.nlink() as u32 == ENVFS_MAGIC {
-            return None;
+/// Attributes of the symlink with the size, owner, mode and timestamps of its target.
+fn mirrored_attr(ino: u64, target: &Path) -> FileAttr {
+    let mut attr = symlink_attr(ino);
+    let meta = match fs::metadata(target) {
+        Ok(meta) => meta,
+        Err(e) => {
+            debug!("cannot stat {}: {}", target.display(), e);
+            return attr;
         }
-    }
-
-    let full_path = path.join(&exe_name);
-    let res = unistd::access(&full_path, unistd::AccessFlags::X_OK);
-    if res.is_ok() {
-        Some(full_path)
-    } else {
-        None
-    }
+    };
+    attr.size = meta.size();
+    attr.blocks = meta.blocks();
+    attr.atime = unix_time(meta.atime(), meta.atime_nsec());
+    attr.mtime = unix_time(meta.mtime(), meta.mtime_nsec());
+    attr.ctime = unix_time(meta.ctime(), meta.ctime_nsec());
+    attr.uid = meta.uid();
+    attr.gid = meta.gid();
+    attr.perm = (meta.mode() & 0o7777) as u16;
+    attr
 }
 
-fn which<P1, P2>(
-    path_env: &OsStr,
-    exe_name: P1,
-    fallback_paths: &[PathBuf],
-    mountpoints: &[P2],
-) -> Option<PathBuf>
-where
-    P1: AsRef<Path>,
-    P2: AsRef<Path>,
-{
-    let exe = env::split_paths(&path_env).find_map(|dir| _which(&dir, &exe_name, mountpoints));
+/// Extended attributes of the target that are visible through its symlink.
+///
+/// The kernel itself does not pass `user.*` requests on symlinks to FUSE.
+fn is_forwarded_xattr(name: &[u8]) -> bool {
+    name == b"security.capability" || name.starts_with(b"user.")
+}
 
-    exe.or_else(|| {
-        fallback_paths
-            .iter()
-            .find_map(|dir| _which(dir, &exe_name, mountpoints))
-    })
-}
-
-fn read_environment(pid: unistd::Pid) -> Result<HashMap<OsString, OsString>> {
-    let path = PathBuf::from("/proc").join(pid.to_string()).join("environ");
-    let f = try_with!(File::open(&path), "failed to open {}", path.display());
-    let reader = BufReader::new(f);
-    let res: HashMap<OsString, OsString> = reader
-        .split(b'\0')
-        .filter_map(|var| {
-            let var = match var {
-                Ok(var) => var,
-                Err(_) => return None,
-            };
+fn cstring(s: &OsStr) -> nix::Result<CString> {
+    CString::new(s.as_bytes()).map_err(|_| Errno::EINVAL)
+}
 
-            let tuple: Vec<&[u8]> = var.splitn(2, |b| *b == b'=').collect();
-            if tuple.len() != 2 {
-                return None;
-            }
-            Some((
-                OsString::from_vec(Vec::from(tuple[0])),
-                OsString::from_vec(Vec::from(tuple[1])),
-            ))
-        })
-        .collect();
-    Ok(res)
-}
-
-#[cfg(any(
-    target_arch = "x86_64",
-    target_arch = "arm",
-    target_arch = "powerpc",
-    target_arch = "powerpc64",
-    target_arch = "sparc64",
-    target_arch = "mips",
-    target_arch = "mips64",
-    target_arch = "s390x"
-))]
-fn is_open_syscall(num: usize) -> bool {
-    num == libc::SYS_open as usize || num == libc::SYS_openat as usize
-}
-
-#[cfg(not(any(
-    target_arch = "x86_64",
-    target_arch = "arm",
-    target_arch = "powerpc",
-    target_arch = "powerpc64",
-    target_arch = "sparc64",
-    target_arch = "mips",
-    target_arch = "mips64",
-    target_arch = "s390x"
-)))]
-fn is_open_syscall(num: usize) -> bool {
-    num == libc::SYS_openat as usize
-}
-
-fn is_execve_syscall(num: usize) -> bool {
-    num == libc::SYS_execve as usize || num == libc::SYS_execveat as usize
-}
-
-fn resolve_target<P1, P2>(
-    pid: Pid,
-    name: P1,
-    fallback_paths: &[PathBuf],
-    mountpoints: &[P2],
-) -> Option<PathBuf>
+/// Calls `f` with a buffer of the size it reports for a zero-length buffer.
+fn read_xattr_buf<F>(f: F) -> nix::Result<Vec<u8>>
 where
-    P1: AsRef<Path>,
-    P2: AsRef<Path>,
+    F: Fn(*mut libc::c_void, libc::size_t) -> libc::ssize_t,
 {
-    let env = match read_environment(pid) {
-        Ok(env) => env,
-        Err(_) => {
-            return None;
-        }
-    };
-    let args = match get_syscall_args(pid) {
-        Ok(args) => args,
-        Err(e) => {
-            debug!("Could not parse syscall arguments: {}", e);
-            return None;
-        }
-    };
-    if args.is_empty() {
-        debug!("no syscall arguments received from /proc/<pid>/syscall");
-        return None;
-    }
-
-    // execve is always allowed and handled differently
-    if is_execve_syscall(args[0]) {
-        // If we have an execve system call, fetch the latest environment variables from /proc/<pid>/mem
-        if args.len() < 4 {
-            debug!(
-                "expected at least 4 syscall arguments in execve syscall, got {}",
-                args.len() - 1
-            );
-            return None;
+    loop {
+        let len = f(ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(Errno::last());
         }
-        let envp = if args[0] == libc::SYS_execve as usize {
-            args[3]
-        } else {
-            args[4]
-        };
-        match get_path_from_mem(pid, envp) {
-            Ok(path) => {
-                if let Some(exe) = which(&path, &name, &[], mountpoints) {
-                    return Some(exe);
-                }
-            }
-            Err(e) => {
-                debug!(
-                    "Could not read environment variables from child from memory: {}",
-                    e
-                )
-                // fallback to the default path
+        let mut buf = vec![0u8; len as usize];
+        let len = f(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if len < 0 {
+            // the attribute grew in between
+            if Errno::last() == Errno::ERANGE {
+                continue;
             }
+            return Err(Errno::last());
         }
+        buf.truncate(len as usize);
+        return Ok(buf);
     }
-    let mut path = OsStr::new("");
-
-    // We need to allow open/openat because some programs want to open themself, i.e. bash
-    let allowed_syscall = is_open_syscall(args[0])
-        || is_execve_syscall(args[0])
-        || env.contains_key(OsStr::new("ENVFS_RESOLVE_ALWAYS"));
-
-    if allowed_syscall {
-        if let Some(v) = env.get(OsStr::new("PATH")) {
-            path = v;
-        };
-    }
-
-    // We return all paths in fallback path to be resolved always independently
-    // of the syscall.
-    which(path, &name, fallback_paths, mountpoints)
 }
 
-fn get_syscall_args(pid: Pid) -> Result<Vec<usize>> {
-    let line = loop {
-        let path = format!("/proc/{}/syscall", pid.as_raw());
-        let line = try_with!(fs::read_to_string(path), "cannot read syscall file");
-        // Sometimes system calls are still in progress when we are trying to read them.
-        if line != "running\n" {
-            break line;
-        }
-    };
-    let res = line
-        .trim_end()
-        .split(' ')
-        .enumerate()
-        .map(|(i, col)| {
-            if i == 0 {
-                col.parse::<usize>()
-            } else {
-                usize::from_str_radix(&col[2..], 16)
-            }
-        })
-        .collect::<std::result::Result<Vec<_>, _>>();
-    Ok(try_with!(
-        res,
-        "syscall arguments '{}' cannot be parsed as integer",
-        line
-    ))
-}
-
-fn get_path_from_mem(pid: Pid, envp: usize) -> Result<OsString> {
-    let path = format!("/proc/{}/mem", pid.as_raw());
-    let f = try_with!(File::open(&path), "failed to open {}", path);
-    let mut reader = BufReader::new(f);
-    try_with!(
-        reader.seek(SeekFrom::Start(envp as u64)),
-        "failed to see in {}",
-        &path
-    );
-    let mut pointer_buf = [0; 8];
-
-    // read pointers of envp and dereference it
-    let mut env_pointers: Vec<usize> = vec![];
-    loop {
-        let num = try_with!(reader.read(&mut pointer_buf), "error reading memory");
-        if num < size_of::<usize>() {
-            break;
-        }
-        let p = usize::from_ne_bytes(pointer_buf);
-        // envp is terminated by a NULL pointer
-        if p == 0 {
-            break;
-        }
-        env_pointers.push(p);
+fn reply_xattr(data: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
     }
+}
 
-    // dereference strings from envp
-    let mut buf = vec![];
-    assert!(size_of::<usize>() <= size_of::<u64>());
-    for p in env_pointers.iter() {
-        try_with!(
-            reader.seek(SeekFrom::Start(*p as u64)),
-            "failed to seek to string"
-        );
-        try_with!(reader.read_until(b'\0', &mut buf), "failed to read string");
-        for var in buf.split(|c| *c == b'\0') {
-            if var.starts_with(b"PATH=") {
-                return Ok(OsString::from_vec(var[5..].to_vec()));
+impl Filesystem for EnvFs {
+    fn init(
+        &mut self,
+        _req: &Request,
+        config: &mut KernelConfig,
+    ) -> std::result::Result<(), c_int> {
+        // symlinks would be cached for all callers, not just the one that resolved them,
+        // inodes of entries that are not shared are only used for a single lookup
+        if self.caller_independent {
+            if let Err(e) = config.add_capabilities(consts::FUSE_CACHE_SYMLINKS) {
+                debug!("kernel cannot cache symlinks: {:#x}", e);
             }
         }
-        buf.clear();
+        Ok(())
     }
-    Ok(OsString::new())
-}
 
-impl Filesystem for EnvFs {
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        // no subdirectories
-        if parent != fuser::FUSE_ROOT_ID {
-            reply.error(ENOENT);
+        if parent == fuser::FUSE_ROOT_ID && name == STATE_DIR {
+            reply.entry(&TTL, &dir_attr(STATE_DIR_INO), 0);
             return;
         }
-
-        let pid = Pid::from_raw(req.pid() as i32);
-
-        match resolve_target(pid, name, self.fallback_paths.as_slice(), &self.mountpoints) {
-            Some(path) => {
-                let (next_number, generation) = self.next_inode_number();
-
-                let attr = symlink_attr(next_number);
-
-                let inode = Arc::new(Inode {
-                    name: PathBuf::from(name),
-                    path,
-                    pid,
-                    kind: attr.kind,
-                    ino: attr.ino,
-                    nlookup: RwLock::new(1),
-                });
-                assert!(self.inodes.insert(next_number, inode).is_none());
-
-                reply.entry(&Duration::from_secs(0), &attr, generation);
-            }
-            None => {
+        if parent == STATE_DIR_INO {
+            if name == RECENT_FILE {
+                reply.entry(&TTL, &recent_attr(), 0);
+            } else {
                 reply.error(ENOENT);
             }
+            return;
         }
+        let name = if parent == fuser::FUSE_ROOT_ID {
+            name.to_os_string()
+        } else {
+            // resolved as DIR/NAME in every directory of the caller's PATH
+            match self.inode(parent) {
+                Ok(dir) if dir.kind == FileType::Directory => {
+                    let mut path = dir.name.to_os_string();
+                    path.push("/");
+                    path.push(name);
+                    path
+                }
+                _ => {
+                    reply.error(ENOENT);
+                    return;
+                }
+            }
+        };
+
+        let caller = Caller::new(req);
+        self.dispatch(&caller, move |fs| fs.lookup_name(&caller, &name, reply));
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
@@ -549,45 +2049,173 @@ impl Filesystem for EnvFs {
             reply.attr(&TTL, &ROOT_DIR_ATTR);
             return;
         }
-        tryfuse!(self.inode(ino), reply);
-        reply.attr(&TTL, &symlink_attr(ino));
+        if ino == STATE_DIR_INO {
+            reply.attr(&TTL, &dir_attr(STATE_DIR_INO));
+            return;
+        }
+        if ino == RECENT_INO {
+            reply.attr(&TTL, &recent_attr());
+            return;
+        }
+        let inode = tryfuse!(self.inode(ino), reply);
+        if inode.kind == FileType::Directory {
+            reply.attr(&TTL, &dir_attr(inode.ino));
+            return;
+        }
+        let ttl = self.cache_ttl(&inode.path).unwrap_or(TTL);
+        reply.attr(&ttl, &self.attr(inode.ino, &inode.path));
     }
 
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        reply.error(ENOENT);
+        // The kernel always reports FUSE_SUPER_MAGIC as f_type, envfs mounts are
+        // recognized by their device in mountinfo instead, see is_envfs_dir.
+        let files = self.inode_count() as u64;
+        reply.statfs(
+            0,
+            0,
+            0,
+            files,
+            0,
+            STATFS_BLOCK_SIZE,
+            NAME_MAX,
+            STATFS_BLOCK_SIZE,
+        );
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        if ino == STATE_DIR_INO {
+            reply.opened(0, 0);
+            return;
+        }
+        if ino != fuser::FUSE_ROOT_ID {
+            let inode = tryfuse!(self.inode(ino), reply);
+            if inode.kind != FileType::Directory {
+                reply.error(libc::ENOTDIR);
+                return;
+            }
+            let caller = Caller::new(req);
+            self.dispatch(&caller, move |fs| fs.open_subdir(&caller, &inode, reply));
+            return;
+        }
+        // the fallback paths may be on a slow file system
+        self.dispatch(&Caller::new(req), move |fs| fs.open_root(reply));
     }
 
     fn readdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        if ino == STATE_DIR_INO {
+            let entries = [
+                (STATE_DIR_INO, FileType::Directory, "."),
+                (fuser::FUSE_ROOT_ID, FileType::Directory, ".."),
+                (RECENT_INO, FileType::RegularFile, RECENT_FILE),
+            ];
+            for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+                if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
+                    break;
+                }
+            }
+            reply.ok();
+            return;
+        }
+        let names = self.dir_handles.lock().unwrap().get(&fh).cloned();
         if ino != fuser::FUSE_ROOT_ID {
-            reply.error(ENOENT);
+            match names {
+                Some(names) => self.list_names(&names, offset, reply),
+                None => reply.error(ENOENT),
+            }
             return;
         }
+        match names {
+            Some(names) => self.list_names(&names, offset, reply),
+            // opened by a previous instance before an upgrade
+            None => self.dispatch(&Caller::new(req), move |fs| {
+                fs.list_names(&fs.root_names(), offset, reply)
+            }),
+        }
+    }
 
-        let entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-        ];
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.dir_handles.lock().unwrap().remove(&fh);
+        reply.ok();
+    }
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                break;
-            }
+    fn fsyncdir(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        // nothing is ever written
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        if ino != RECENT_INO {
+            // symlinks and directories are never opened as files
+            reply.error(libc::EINVAL);
+            return;
         }
+        let mut content = self.recent_events().join("\n");
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        let fh = self.next_dir_handle.fetch_add(1, Ordering::Relaxed);
+        self.recent_handles
+            .lock()
+            .unwrap()
+            .insert(fh, Arc::new(content.into_bytes()));
+        // the size is only known once opened
+        reply.opened(fh, consts::FOPEN_DIRECT_IO);
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let content = match self.recent_handles.lock().unwrap().get(&fh) {
+            Some(content) => Arc::clone(content),
+            None => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        };
+        let start = (offset.max(0) as usize).min(content.len());
+        let end = start.saturating_add(size as usize).min(content.len());
+        reply.data(&content[start..end]);
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.recent_handles.lock().unwrap().remove(&fh);
         reply.ok();
     }
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
-        match self.inodes.find_mut(&ino) {
-            Some(ref mut inode_lock) => {
-                let inode = inode_lock.get();
+        match self.inodes.get(ino) {
+            Some(inode) => {
                 let mut old_nlookup = inode.nlookup.write().unwrap();
                 assert!(*old_nlookup >= nlookup);
 
@@ -600,40 +2228,159 @@ impl Filesystem for EnvFs {
             None => return,
         };
 
-        self.inodes.remove(&ino);
+        self.inodes.remove(ino);
     }
 
     fn destroy(&mut self) {
         self.inodes.clear();
+        self.save_resolution_cache();
     }
-    fn getxattr(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        _name: &OsStr,
-        _size: u32,
-        reply: ReplyXattr,
-    ) {
-        reply.error(ENODATA);
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        if ino == fuser::FUSE_ROOT_ID || is_state_ino(ino) || !is_forwarded_xattr(name.as_bytes()) {
+            reply.error(ENODATA);
+            return;
+        }
+        let inode = tryfuse!(self.inode(ino), reply);
+        let c_path = tryfuse!(cstring(inode.path.as_os_str()), reply);
+        let c_name = tryfuse!(cstring(name), reply);
+        let value = tryfuse!(
+            read_xattr_buf(|buf, len| unsafe {
+                libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), buf, len)
+            }),
+            reply
+        );
+        reply_xattr(&value, size, reply);
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        if ino == fuser::FUSE_ROOT_ID || is_state_ino(ino) {
+            reply_xattr(&[], size, reply);
+            return;
+        }
+        let inode = tryfuse!(self.inode(ino), reply);
+        let c_path = tryfuse!(cstring(inode.path.as_os_str()), reply);
+        let names = tryfuse!(
+            read_xattr_buf(|buf, len| unsafe {
+                libc::listxattr(c_path.as_ptr(), buf as *mut libc::c_char, len)
+            }),
+            reply
+        );
+        let mut forwarded = vec![];
+        for name in names.split(|c| *c == b'\0') {
+            if is_forwarded_xattr(name) {
+                forwarded.extend_from_slice(name);
+                forwarded.push(b'\0');
+            }
+        }
+        reply_xattr(&forwarded, size, reply);
     }
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        if is_state_ino(ino) {
+            reply.error(libc::EINVAL);
+            return;
+        }
         let inode = tryfuse!(self.inode(ino), reply);
-        let pid = Pid::from_raw(req.pid() as i32);
-        if inode.pid != pid {
+        if inode.kind == FileType::Directory {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let pid = request_pid(req);
+        // Results depend on the credentials of the caller, so another user
+        // must not see a resolution of the original process either.
+        if (!inode.shared && (inode.pid != pid || inode.uid != req.uid()))
+            || inode.epoch != self.cache_epoch.load(Ordering::SeqCst)
+            || inode.stale.load(Ordering::Relaxed)
+            || inode.expires <= now_millis()
+        {
             // unlikely
-            match resolve_target(pid, &inode.name, &self.fallback_paths, &self.mountpoints) {
-                Some(target) => {
-                    reply.data(target.as_os_str().as_bytes());
-                    return;
-                }
-                None => {
-                    reply.error(ENOENT);
-                    return;
-                }
-            }
+            let caller = Caller::new(req);
+            self.dispatch(&caller, move |fs| fs.readlink_again(&caller, &inode, reply));
+            return;
         }
         let data = inode.path.as_os_str().as_bytes();
         reply.data(data);
     }
+
+    fn ioctl(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        if ino != fuser::FUSE_ROOT_ID {
+            reply.error(libc::ENOTTY);
+            return;
+        }
+        let command = match ioctl::Command::parse(cmd, in_data) {
+            Ok(command) => command,
+            Err(e) => {
+                reply.error(e as i32);
+                return;
+            }
+        };
+        debug!("ioctl from pid {}: {:?}", req.pid(), command);
+        // Flushing lets any user slow down lookups of everybody, and log
+        // messages may contain the environment of other users. The control
+        // socket, D-Bus and varlink interfaces are limited to root as well.
+        if req.uid() != 0 {
+            reply.error(libc::EPERM);
+            return;
+        }
+        match command {
+            ioctl::Command::FlushCache => self.flush_caches(),
+            ioctl::Command::Invalidate(name) => {
+                self.invalidate_name(&name);
+            }
+            ioctl::Command::LogLevel(level) => logger::set_level(level),
+        }
+        reply.ioctl(0, &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envfs_mounts() {
+        let mountinfo = "\
+22 1 0:21 / /proc rw,nosuid - proc proc rw
+40 22 0:35 / /usr/bin rw,nosuid - fuse envfs rw,user_id=0,group_id=0,allow_other
+41 1 0:35 / /my\\040bin rw,nosuid - fuse envfs rw,user_id=0,group_id=0,allow_other
+42 1 0:36 / /bin rw - fuse sshfs rw
+43 1 0:37 / /opt - fuse.envfs envfs rw
+";
+        assert_eq!(
+            envfs_mounts(mountinfo),
+            [
+                (libc::makedev(0, 35), PathBuf::from("/usr/bin")),
+                (libc::makedev(0, 35), PathBuf::from("/my bin")),
+                (libc::makedev(0, 37), PathBuf::from("/opt"))
+            ]
+        );
+        assert_eq!(unescape_mountinfo("a\\134b\\0"), PathBuf::from("a\\b\\0"));
+    }
+
+    #[test]
+    fn test_envfs_mounts_malformed() {
+        let mountinfo = "\
+40 22 0:35 / /usr/bin rw,nosuid fuse envfs rw
+41 1 0:x / /bin rw - fuse envfs rw
+42 1 259:65536 / /sbin rw shared:1 - fuse envfs rw
+43 1 0:37
+- fuse envfs rw
+";
+        // lines without a separator or a valid device are skipped
+        assert_eq!(
+            envfs_mounts(mountinfo),
+            [(libc::makedev(259, 65536), PathBuf::from("/sbin"))]
+        );
+        assert!(is_envfs_dir(u64::MAX, u64::from(ENVFS_MAGIC)));
+    }
 }
@@ -0,0 +1,88 @@
+//! Shared copies of the names and symlink targets of inodes.
+//!
+//! A few names such as `sh` or `env` make up most lookups, so every inode and
+//! the lookup counters refer to one copy of each name and resolved path
+//! instead of allocating their own.
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub struct Interner<T: ?Sized + 'static> {
+    values: Mutex<BTreeSet<Arc<T>>>,
+}
+
+impl<T: ?Sized + Ord + 'static> Interner<T>
+where
+    for<'a> Arc<T>: From<&'a T>,
+{
+    pub const fn new() -> Interner<T> {
+        Interner {
+            values: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Returns the shared copy of `value`, adding it if there is none yet.
+    pub fn intern(&self, value: &T) -> Arc<T> {
+        let mut values = self.values.lock().unwrap();
+        if let Some(shared) = values.get(value) {
+            return Arc::clone(shared);
+        }
+        let shared = Arc::from(value);
+        values.insert(Arc::clone(&shared));
+        shared
+    }
+
+    /// Drops the values nothing refers to anymore, returns how many.
+    pub fn purge(&self) -> usize {
+        let mut values = self.values.lock().unwrap();
+        let before = values.len();
+        values.retain(|value| Arc::strong_count(value) > 1);
+        before - values.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.lock().unwrap().len()
+    }
+}
+
+static NAMES: Interner<OsStr> = Interner::new();
+static PATHS: Interner<Path> = Interner::new();
+
+pub fn name(name: &OsStr) -> Arc<OsStr> {
+    NAMES.intern(name)
+}
+
+pub fn path(path: &Path) -> Arc<Path> {
+    PATHS.intern(path)
+}
+
+/// Number of distinct names and paths currently shared.
+pub fn count() -> usize {
+    NAMES.len() + PATHS.len()
+}
+
+/// Drops names and paths that are no longer used, called after inodes were evicted.
+pub fn purge() -> usize {
+    NAMES.purge() + PATHS.purge()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let interner: Interner<OsStr> = Interner::new();
+        let a = interner.intern(OsStr::new("sh"));
+        let b = interner.intern(OsStr::new("sh"));
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(interner.len(), 1);
+        drop(a);
+        assert_eq!(interner.purge(), 0);
+        drop(b);
+        assert_eq!(interner.purge(), 1);
+        assert_eq!(interner.len(), 0);
+    }
+}
@@ -0,0 +1,110 @@
+//! Commands sent as ioctls on the root directory of a mount.
+//!
+//! Unlike the control socket they only need the mountpoint, so they also work
+//! from containers that bind-mount it. The kernel only forwards ioctls with
+//! a fixed input size to FUSE, so names are passed in a buffer of `NAME_LEN`
+//! bytes.
+
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::{request_code_none, request_code_write};
+use simple_error::{bail, try_with};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+use crate::result::Result;
+
+const MAGIC: u8 = b'E';
+
+/// Size of the buffer of `INVALIDATE`, the name is terminated by a NUL byte.
+pub const NAME_LEN: usize = 256;
+
+pub const FLUSH_CACHE: u32 = request_code_none!(MAGIC, 1) as u32;
+pub const INVALIDATE: u32 = request_code_write!(MAGIC, 2, NAME_LEN) as u32;
+/// Takes the level as `u32`, 0 for off up to 5 for trace.
+pub const LOG_LEVEL: u32 = request_code_write!(MAGIC, 3, std::mem::size_of::<u32>()) as u32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    FlushCache,
+    /// Resolve inodes of this name again on their next use
+    Invalidate(OsString),
+    LogLevel(log::LevelFilter),
+}
+
+const LEVELS: [log::LevelFilter; 6] = [
+    log::LevelFilter::Off,
+    log::LevelFilter::Error,
+    log::LevelFilter::Warn,
+    log::LevelFilter::Info,
+    log::LevelFilter::Debug,
+    log::LevelFilter::Trace,
+];
+
+impl Command {
+    /// Decodes ioctl `cmd` with its input `data`, `ENOTTY` for unknown commands.
+    pub fn parse(cmd: u32, data: &[u8]) -> nix::Result<Command> {
+        match cmd {
+            FLUSH_CACHE => Ok(Command::FlushCache),
+            INVALIDATE => {
+                let len = data.iter().position(|c| *c == 0).unwrap_or(data.len());
+                let name = &data[..len];
+                if name.is_empty() || name.contains(&b'/') {
+                    return Err(Errno::EINVAL);
+                }
+                Ok(Command::Invalidate(OsString::from_vec(name.to_vec())))
+            }
+            LOG_LEVEL => {
+                let level = match data.try_into() {
+                    Ok(bytes) => u32::from_ne_bytes(bytes) as usize,
+                    Err(_) => return Err(Errno::EINVAL),
+                };
+                match LEVELS.get(level) {
+                    Some(level) => Ok(Command::LogLevel(*level)),
+                    None => Err(Errno::EINVAL),
+                }
+            }
+            _ => Err(Errno::ENOTTY),
+        }
+    }
+
+    /// Sends the command to the instance mounted at `mountpoint`.
+    pub fn send(&self, mountpoint: &Path) -> Result<()> {
+        let fd = try_with!(
+            open(
+                mountpoint,
+                OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                Mode::empty()
+            ),
+            "cannot open {}",
+            mountpoint.display()
+        );
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let res = match self {
+            Command::FlushCache => unsafe { libc::ioctl(fd.as_raw_fd(), FLUSH_CACHE as _) },
+            Command::Invalidate(name) => {
+                let name = name.as_bytes();
+                if name.len() >= NAME_LEN {
+                    bail!("name is longer than {} bytes", NAME_LEN - 1);
+                }
+                let mut buf = [0u8; NAME_LEN];
+                buf[..name.len()].copy_from_slice(name);
+                unsafe { libc::ioctl(fd.as_raw_fd(), INVALIDATE as _, buf.as_ptr()) }
+            }
+            Command::LogLevel(level) => {
+                let level = *level as u32;
+                unsafe { libc::ioctl(fd.as_raw_fd(), LOG_LEVEL as _, &level as *const u32) }
+            }
+        };
+        try_with!(
+            Errno::result(res),
+            "ioctl on {} failed",
+            mountpoint.display()
+        );
+        Ok(())
+    }
+}
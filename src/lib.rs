@@ -0,0 +1,59 @@
+//! envfs resolves `/usr/bin/NAME` and friends to the executable `NAME` in the
+//! `PATH` of the process accessing it.
+//!
+//! Besides the `envfs` binary, the filesystem can be embedded in other programs:
+//!
+//! ```no_run
+//! use envfs::{EnvFs, Mode};
+//! use std::path::PathBuf;
+//!
+//! # fn main() -> envfs::result::Result<()> {
+//! let (fs, session) = EnvFs::builder()
+//!     .fallback_paths(["/run/current-system/sw/bin"])
+//!     .mode(Mode::Process)
+//!     .mount(&[PathBuf::from("/usr/bin")])?;
+//! // the filesystem is unmounted once `session` is dropped
+//! # drop((fs, session));
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod audit;
+mod caps;
+pub mod control;
+pub mod crash;
+mod creds;
+pub mod dbus;
+mod dircache;
+pub mod elf;
+pub mod events;
+pub mod fs;
+mod intern;
+pub mod ioctl;
+pub mod library;
+pub mod logger;
+mod num_cpus;
+pub mod options;
+pub mod policy;
+pub mod privileges;
+mod procdir;
+pub mod prochelper;
+pub mod ratelimit;
+mod rescache;
+pub mod resolve;
+pub mod resolver;
+pub mod result;
+mod rotate;
+pub mod sandbox;
+mod setrlimit;
+pub mod slab;
+pub mod spans;
+pub mod stats;
+pub mod syscalls;
+pub mod underlay;
+pub mod upgrade;
+mod uring;
+pub mod varlink;
+mod workers;
+
+pub use crate::fs::{EnvFs, EnvFsBuilder, Mode};
@@ -0,0 +1,423 @@
+//! Resolution of shared libraries for mounts over library directories.
+//!
+//! The dynamic linker looks up the libraries of a program by name in the
+//! directories of `LD_LIBRARY_PATH` and of its configuration. With
+//! `Mode::Library` envfs answers these lookups the way the linker of the
+//! requesting process would, so that `/usr/lib/libfoo.so.1` works on systems
+//! that keep their libraries elsewhere.
+
+use nix::errno::Errno;
+use std::convert::TryInto;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::creds::check_readable;
+use crate::fs::is_envfs_dir;
+use crate::procdir::{ProcReader, Procfs, RealProcfs};
+use crate::resolve::{parse_environ, worse_miss, CandidatePolicy, Trace};
+use crate::resolver::{FallbackPaths, Priority, RequestCtx, Resolver};
+use crate::syscalls::Abi;
+
+/// Configuration of the dynamic linker, read by `LibraryResolver::new`.
+pub const LD_SO_CONF: &str = "/etc/ld.so.conf";
+
+/// Searched by the dynamic linker after the configured directories.
+const TRUSTED_DIRS: &[&str] = &["/lib64", "/usr/lib64", "/lib", "/usr/lib"];
+
+/// Includes nested deeper than this are ignored, they can only be loops.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Set in the auxiliary vector of setuid programs, which ignore `LD_*` variables.
+const AT_SECURE: u64 = 23;
+
+/// Returns the library directories listed in the `ld.so.conf` at `path`,
+/// following its `include` lines.
+pub fn read_ld_so_conf(path: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    read_ld_so_conf_into(path, 0, &mut dirs);
+    dirs
+}
+
+fn read_ld_so_conf_into(path: &Path, depth: usize, dirs: &mut Vec<PathBuf>) {
+    if depth > MAX_INCLUDE_DEPTH {
+        return;
+    }
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(_) => return,
+    };
+    let base = path.parent().unwrap_or(Path::new("/"));
+    for line in parse_ld_so_conf(&content) {
+        match line {
+            ConfLine::Dir(dir) => {
+                if !dirs.contains(&dir) {
+                    dirs.push(dir)
+                }
+            }
+            ConfLine::Include(pattern) => {
+                for file in expand_include(&base.join(pattern)) {
+                    read_ld_so_conf_into(&file, depth + 1, dirs);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ConfLine {
+    Dir(PathBuf),
+    /// Glob of further files, relative to the including file
+    Include(PathBuf),
+}
+
+fn parse_ld_so_conf(content: &[u8]) -> Vec<ConfLine> {
+    let mut lines = vec![];
+    for line in content.split(|c| *c == b'\n') {
+        let line = match line.iter().position(|c| *c == b'#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+        let mut words = line
+            .split(|c| c.is_ascii_whitespace() || *c == b':' || *c == b',')
+            .filter(|w| !w.is_empty())
+            .peekable();
+        match words.peek() {
+            Some(&b"include") => {
+                words.next();
+                lines.extend(words.map(|w| ConfLine::Include(PathBuf::from(OsStr::from_bytes(w)))));
+            }
+            // capabilities of the libc5 era
+            Some(&b"hwcap") => {}
+            _ => {
+                for word in words {
+                    // `DIR=TYPE` of old libc versions
+                    let dir = word.split(|c| *c == b'=').next().unwrap_or(word);
+                    if dir.starts_with(b"/") {
+                        lines.push(ConfLine::Dir(PathBuf::from(OsStr::from_bytes(dir))));
+                    }
+                }
+            }
+        }
+    }
+    lines
+}
+
+/// Expands a `*` in the last component of `pattern`, the files are sorted by name.
+fn expand_include(pattern: &Path) -> Vec<PathBuf> {
+    let name = match pattern.file_name() {
+        Some(name) => name.as_bytes(),
+        None => return vec![],
+    };
+    let (prefix, suffix) = match name.iter().position(|c| *c == b'*') {
+        Some(pos) => (&name[..pos], &name[pos + 1..]),
+        None => return vec![pattern.to_path_buf()],
+    };
+    let dir = pattern.parent().unwrap_or(Path::new("/"));
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .filter(|file| {
+                let file = file.as_bytes();
+                file.len() >= prefix.len() + suffix.len()
+                    && file.starts_with(prefix)
+                    && file.ends_with(suffix)
+            })
+            .map(|file| dir.join(file))
+            .collect(),
+        Err(_) => vec![],
+    };
+    files.sort();
+    files
+}
+
+/// The variables of a process that change where its libraries are found.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct LibraryEnv {
+    /// Entries of `LD_PRELOAD` with a directory
+    pub preload: Vec<PathBuf>,
+    pub library_path: Vec<PathBuf>,
+}
+
+/// Whether the process was started in secure-execution mode, e.g. setuid.
+fn is_secure(proc: &dyn ProcReader) -> nix::Result<bool> {
+    let auxv = proc.read("auxv").map_err(|_| Errno::EIO)?;
+    let size = Abi::detect_in(proc).pointer_size();
+    let word = |bytes: &[u8]| -> u64 {
+        match size {
+            4 => u32::from_ne_bytes(bytes.try_into().unwrap()) as u64,
+            _ => u64::from_ne_bytes(bytes.try_into().unwrap()),
+        }
+    };
+    for entry in auxv.chunks_exact(2 * size) {
+        if word(&entry[..size]) == AT_SECURE {
+            return Ok(word(&entry[size..]) != 0);
+        }
+    }
+    Ok(false)
+}
+
+/// Reads `LD_PRELOAD` and `LD_LIBRARY_PATH` from the environment of the process.
+///
+/// `$ORIGIN` is replaced by the directory of its executable, entries with
+/// other substitutions and relative ones are skipped.
+pub fn read_library_env(proc: &dyn ProcReader, trace: &Trace) -> nix::Result<LibraryEnv> {
+    match is_secure(proc) {
+        Ok(false) => {}
+        Ok(true) => {
+            trace.add(|| String::from("secure execution, ignore LD_* variables"));
+            return Ok(LibraryEnv::default());
+        }
+        Err(e) => {
+            trace.add(|| String::from("cannot read auxiliary vector"));
+            return Err(e);
+        }
+    }
+    let environ = match proc.read("environ") {
+        Ok(environ) => environ,
+        Err(e) => {
+            trace.add(|| format!("cannot read environment: {}", e));
+            return Err(Errno::EIO);
+        }
+    };
+    let env = parse_environ(&environ);
+    let origin = || {
+        proc.read_link("exe")
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    };
+
+    let mut library_env = LibraryEnv::default();
+    if let Some(path) = env.get(OsStr::new("LD_LIBRARY_PATH")) {
+        trace.add(|| format!("LD_LIBRARY_PATH: {}", path.to_string_lossy()));
+        for entry in path.as_bytes().split(|c| *c == b':' || *c == b';') {
+            match expand_origin(entry, origin) {
+                Some(dir) if dir.is_absolute() => library_env.library_path.push(dir),
+                _ => trace.add(|| {
+                    format!(
+                        "skip '{}' in LD_LIBRARY_PATH",
+                        String::from_utf8_lossy(entry)
+                    )
+                }),
+            }
+        }
+    }
+    if let Some(preload) = env.get(OsStr::new("LD_PRELOAD")) {
+        trace.add(|| format!("LD_PRELOAD: {}", preload.to_string_lossy()));
+        for entry in preload.as_bytes().split(|c| *c == b':' || *c == b' ') {
+            // bare names are searched like any other library
+            if entry.starts_with(b"/") && !entry.contains(&b'$') {
+                library_env
+                    .preload
+                    .push(PathBuf::from(OsStr::from_bytes(entry)));
+            }
+        }
+    }
+    Ok(library_env)
+}
+
+fn expand_origin<F: FnOnce() -> Option<PathBuf>>(entry: &[u8], origin: F) -> Option<PathBuf> {
+    if !entry.contains(&b'$') {
+        return Some(PathBuf::from(OsStr::from_bytes(entry)));
+    }
+    let rest = entry
+        .strip_prefix(b"${ORIGIN}")
+        .or_else(|| entry.strip_prefix(b"$ORIGIN"))?;
+    if rest.contains(&b'$') || !(rest.is_empty() || rest.starts_with(b"/")) {
+        return None;
+    }
+    let mut dir = origin()?.into_os_string().into_vec();
+    dir.extend_from_slice(rest);
+    Some(PathBuf::from(OsString::from_vec(dir)))
+}
+
+/// Checks `dir/name` the way `which` checks executables, but for read
+/// permission and for regular files only.
+fn check_library<P: AsRef<Path>>(
+    dir: &Path,
+    name: &OsStr,
+    mountpoints: &[P],
+    policy: &CandidatePolicy,
+    trace: &Trace,
+) -> nix::Result<PathBuf> {
+    if dir.is_relative() {
+        return Err(Errno::ENOENT);
+    }
+    if mountpoints.iter().any(|m| dir.starts_with(m))
+        || dir
+            .metadata()
+            .is_ok_and(|stat| is_envfs_dir(stat.dev(), stat.nlink()))
+    {
+        trace.add(|| format!("skip {}: is an envfs mount", dir.display()));
+        return Err(Errno::ENOENT);
+    }
+    let path = dir.join(name);
+    match check_readable(&path) {
+        Ok(()) => {}
+        Err(e) => {
+            trace.add(|| format!("check {}: {}", path.display(), e.desc()));
+            return match e {
+                Errno::EACCES => Err(Errno::EACCES),
+                _ => Err(Errno::ENOENT),
+            };
+        }
+    }
+    if let Err(reason) = policy.check(&path) {
+        trace.add(|| format!("check {}: refused, {}", path.display(), reason));
+        return Err(Errno::ENOENT);
+    }
+    trace.add(|| format!("check {}: found", path.display()));
+    Ok(path)
+}
+
+/// Resolves library names like the dynamic linker of the requesting process.
+///
+/// Directories are searched in this order: the fallback paths with
+/// `Priority::Before`, `LD_LIBRARY_PATH`, the directories of `ld.so.conf`,
+/// the default library directories and the remaining fallback paths. An
+/// entry of `LD_PRELOAD` whose file name matches is served before all of them.
+pub struct LibraryResolver {
+    fallback_paths: Arc<RwLock<FallbackPaths>>,
+    config_dirs: Vec<PathBuf>,
+    /// Read `LD_PRELOAD` and `LD_LIBRARY_PATH` of the requesting process
+    use_environment: bool,
+}
+
+impl LibraryResolver {
+    /// Reads the configured directories from `LD_SO_CONF`, `paths` is shared
+    /// like the one of `FallbackResolver`.
+    pub fn new(paths: Arc<RwLock<FallbackPaths>>, use_environment: bool) -> LibraryResolver {
+        LibraryResolver {
+            fallback_paths: paths,
+            config_dirs: read_ld_so_conf(Path::new(LD_SO_CONF)),
+            use_environment,
+        }
+    }
+
+    fn resolve_in(
+        &self,
+        proc: Option<&dyn ProcReader>,
+        ctx: &RequestCtx,
+        name: &OsStr,
+    ) -> nix::Result<PathBuf> {
+        let mut miss = Errno::ENOENT;
+        let env = match proc {
+            Some(proc) => match read_library_env(proc, ctx.trace) {
+                Ok(env) => env,
+                Err(e) => {
+                    miss = e;
+                    LibraryEnv::default()
+                }
+            },
+            None => LibraryEnv::default(),
+        };
+        if let Some(path) = env.preload.iter().find(|p| p.file_name() == Some(name)) {
+            if let Some(dir) = path.parent() {
+                return check_library(dir, name, ctx.mountpoints, ctx.policy, ctx.trace);
+            }
+        }
+
+        let fallback_paths = self.fallback_paths.read().unwrap();
+        let dirs = fallback_paths
+            .get(Priority::Before)
+            .iter()
+            .chain(&env.library_path)
+            .chain(&self.config_dirs)
+            .cloned()
+            .chain(TRUSTED_DIRS.iter().map(PathBuf::from))
+            .chain(fallback_paths.get(Priority::After).iter().cloned());
+        for dir in dirs {
+            match check_library(&dir, name, ctx.mountpoints, ctx.policy, ctx.trace) {
+                Ok(path) => return Ok(path),
+                Err(e) => miss = worse_miss(miss, e),
+            }
+        }
+        Err(miss)
+    }
+}
+
+impl Resolver for LibraryResolver {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        if !self.use_environment {
+            return self.resolve_in(None, ctx, name);
+        }
+        match RealProcfs.open(ctx.pid) {
+            Ok(proc) => self.resolve_in(Some(&*proc), ctx, name),
+            Err(e) => {
+                ctx.trace.add(|| format!("cannot open process: {}", e));
+                self.resolve_in(None, ctx, name)
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        String::from("library directories")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procdir::fake::{FakeProcess, FakeProcfs};
+    use nix::unistd::Pid;
+
+    fn auxv(secure: u64) -> Vec<u8> {
+        let mut auxv = vec![];
+        for word in [6, 4096, AT_SECURE as usize, secure as usize, 0, 0] {
+            auxv.extend_from_slice(&word.to_ne_bytes());
+        }
+        auxv
+    }
+
+    #[test]
+    fn ld_so_conf_lines() {
+        let lines = parse_ld_so_conf(
+            b"# comment\ninclude ld.so.conf.d/*.conf\n/usr/local/lib:/opt/lib, /old=libc5\nhwcap 0 nosegneg\nrelative\n",
+        );
+        assert_eq!(
+            lines,
+            vec![
+                ConfLine::Include(PathBuf::from("ld.so.conf.d/*.conf")),
+                ConfLine::Dir(PathBuf::from("/usr/local/lib")),
+                ConfLine::Dir(PathBuf::from("/opt/lib")),
+                ConfLine::Dir(PathBuf::from("/old")),
+            ]
+        );
+    }
+
+    #[test]
+    fn library_env_expands_origin_and_ignores_secure_processes() {
+        let environ = b"LD_LIBRARY_PATH=/opt/lib:$ORIGIN/../lib:rel:$LIB/x\0LD_PRELOAD=/p/libhook.so libbare.so\0".to_vec();
+        let procfs = FakeProcfs::default()
+            .process(
+                1,
+                FakeProcess::default()
+                    .file("environ", environ.clone())
+                    .file("auxv", auxv(0))
+                    .link("exe", "/app/bin/prog"),
+            )
+            .process(
+                2,
+                FakeProcess::default()
+                    .file("environ", environ)
+                    .file("auxv", auxv(1)),
+            );
+        let trace = Trace::disabled();
+
+        let env = read_library_env(&*procfs.open(Pid::from_raw(1)).unwrap(), &trace).unwrap();
+        assert_eq!(
+            env,
+            LibraryEnv {
+                preload: vec![PathBuf::from("/p/libhook.so")],
+                library_path: vec![PathBuf::from("/opt/lib"), PathBuf::from("/app/bin/../lib")],
+            }
+        );
+
+        let env = read_library_env(&*procfs.open(Pid::from_raw(2)).unwrap(), &trace).unwrap();
+        assert_eq!(env, LibraryEnv::default());
+    }
+}
@@ -1,4 +1,125 @@
-struct Logger;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::result::Result as EnvfsResult;
+use crate::rotate::RotatingFile;
+
+/// Identical messages logged again within this time are only counted.
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(10);
+
+/// Distinct messages counted at once, further ones are always logged.
+const MAX_DEDUP_MESSAGES: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+struct Logger {
+    /// Write to `FILE`, takes precedence over syslog
+    file: AtomicBool,
+    syslog: AtomicBool,
+    json: AtomicBool,
+    /// `DEFAULT_DEDUP_WINDOW` in milliseconds, 0 logs every message
+    dedup_window: AtomicU64,
+}
+
+struct Repeated {
+    level: log::Level,
+    /// When the message was last logged
+    since: Instant,
+    /// Times it was suppressed since
+    count: u64,
+}
+
+/// Suppresses identical messages, e.g. the same failure to read `/proc` of a
+/// process that keeps executing commands.
+struct Dedup {
+    seen: BTreeMap<String, Repeated>,
+}
+
+impl Dedup {
+    /// Returns whether `message` should be logged, and the summaries of
+    /// suppressed messages whose window ended, to be logged before it.
+    fn check(
+        &mut self,
+        now: Instant,
+        window: Duration,
+        level: log::Level,
+        message: &str,
+    ) -> (bool, Vec<(log::Level, String)>) {
+        let mut summaries = vec![];
+        self.seen.retain(|message, repeated| {
+            if now.duration_since(repeated.since) < window {
+                return true;
+            }
+            if repeated.count > 0 {
+                summaries.push((
+                    repeated.level,
+                    format!("message repeated {} times: {}", repeated.count, message),
+                ));
+            }
+            false
+        });
+        if let Some(repeated) = self.seen.get_mut(message) {
+            repeated.count += 1;
+            return (false, summaries);
+        }
+        if self.seen.len() < MAX_DEDUP_MESSAGES {
+            self.seen.insert(
+                message.to_string(),
+                Repeated {
+                    level,
+                    since: now,
+                    count: 0,
+                },
+            );
+        }
+        (true, summaries)
+    }
+}
+
+static DEDUP: Mutex<Dedup> = Mutex::new(Dedup {
+    seen: BTreeMap::new(),
+});
+
+impl Logger {
+    fn write_message(&self, level: log::Level, message: &str) {
+        if self.json.load(Ordering::Relaxed) {
+            let mut line = json_prefix(level);
+            let _ = write!(line, ",\"message\":{}}}", json_string(message));
+            self.write(level, &line);
+        } else {
+            self.write(level, message);
+        }
+    }
+
+    fn write(&self, level: log::Level, line: &str) {
+        if self.file.load(Ordering::Relaxed) {
+            if let Some(file) = FILE.lock().unwrap().as_mut() {
+                // nowhere left to report a failure to
+                let _ = if self.json.load(Ordering::Relaxed) {
+                    file.write_line(line)
+                } else {
+                    file.write_line(&format!("{} {} - {}", timestamp(), level, line))
+                };
+            }
+        } else if self.syslog.load(Ordering::Relaxed) {
+            syslog(level, line);
+        } else if self.json.load(Ordering::Relaxed) {
+            eprintln!("{}", line);
+        } else {
+            eprintln!("{} - {}", level, line);
+        }
+    }
+}
 
 impl log::Log for Logger {
     fn enabled(&self, _metadata: &log::Metadata) -> bool {
@@ -6,17 +127,237 @@ impl log::Log for Logger {
     }
 
     fn log(&self, record: &log::Record) {
-        if self.enabled(record.metadata()) {
-            eprintln!("{} - {}", record.level(), record.args());
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        let window = self.dedup_window.load(Ordering::Relaxed);
+        if window > 0 {
+            let (log, summaries) = DEDUP.lock().unwrap().check(
+                Instant::now(),
+                Duration::from_millis(window),
+                record.level(),
+                &message,
+            );
+            for (level, summary) in summaries {
+                self.write_message(level, &summary);
+            }
+            if !log {
+                return;
+            }
         }
+        self.write_message(record.level(), &message);
     }
     fn flush(&self) {}
 }
 
-static LOGGER: Logger = Logger;
+static LOGGER: Logger = Logger {
+    file: AtomicBool::new(false),
+    syslog: AtomicBool::new(false),
+    json: AtomicBool::new(false),
+    dedup_window: AtomicU64::new(DEFAULT_DEDUP_WINDOW.as_millis() as u64),
+};
+
+static FILE: Mutex<Option<RotatingFile>> = Mutex::new(None);
+
+/// Programs whose lookups are logged, all programs if empty.
+static COMM_FILTER: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+pub fn set_comm_filter(comms: Vec<String>) {
+    *COMM_FILTER.write().unwrap() = comms;
+}
+
+/// Returns false if lookup events of `comm` are filtered out by `log-filter-comm`.
+pub fn comm_matches_filter(comm: &str) -> bool {
+    let filter = COMM_FILTER.read().unwrap();
+    filter.is_empty() || filter.iter().any(|c| c == comm)
+}
+
+fn syslog(level: log::Level, msg: &str) {
+    let priority = match level {
+        log::Level::Error => libc::LOG_ERR,
+        log::Level::Warn => libc::LOG_WARNING,
+        log::Level::Info => libc::LOG_INFO,
+        log::Level::Debug | log::Level::Trace => libc::LOG_DEBUG,
+    };
+    // Interior NUL bytes cannot be represented, drop them instead of the message.
+    let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+    unsafe {
+        libc::syslog(
+            priority,
+            b"%s\0".as_ptr() as *const libc::c_char,
+            msg.as_ptr(),
+        )
+    };
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Seconds since the epoch with milliseconds, e.g. `1760000000.123`.
+pub(crate) fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+}
+
+fn json_prefix(level: log::Level) -> String {
+    format!("{{\"time\":{},\"level\":\"{}\"", timestamp(), level)
+}
+
+/// Value of a field in a structured log event.
+pub enum Field<'a> {
+    Str(&'a str),
+    Num(u64),
+    Null,
+}
+
+/// Logs a structured event at debug level, as a JSON object or as key=value pairs.
+pub fn log_event(event: &str, fields: &[(&str, Field)]) {
+    if !log::log_enabled!(log::Level::Debug) {
+        return;
+    }
+    let mut line;
+    if LOGGER.json.load(Ordering::Relaxed) {
+        line = json_prefix(log::Level::Debug);
+        let _ = write!(line, ",\"event\":{}", json_string(event));
+        for (key, value) in fields {
+            let _ = match value {
+                Field::Str(s) => write!(line, ",{}:{}", json_string(key), json_string(s)),
+                Field::Num(n) => write!(line, ",{}:{}", json_string(key), n),
+                Field::Null => write!(line, ",{}:null", json_string(key)),
+            };
+        }
+        line.push('}');
+    } else {
+        line = event_line(event, fields);
+    }
+    LOGGER.write(log::Level::Debug, &line);
+}
+
+/// Formats an event as `event key=value ...`, the text form of `log_event`.
+pub fn event_line(event: &str, fields: &[(&str, Field)]) -> String {
+    let mut line = String::from(event);
+    for (key, value) in fields {
+        let _ = match value {
+            Field::Str(s) => write!(line, " {}={:?}", key, s),
+            Field::Num(n) => write!(line, " {}={}", key, n),
+            Field::Null => write!(line, " {}=-", key),
+        };
+    }
+    line
+}
+
+/// Writes `line` independent of the current log level, used for reports requested by the admin.
+pub fn report(line: &str) {
+    LOGGER.write_message(log::Level::Info, line);
+}
 
-pub fn enable_debug_log() -> Result<(), log::SetLoggerError> {
+pub fn init_logger(level: log::LevelFilter, format: LogFormat) -> Result<(), log::SetLoggerError> {
     log::set_logger(&LOGGER)?;
-    log::set_max_level(log::LevelFilter::Debug);
+    LOGGER
+        .json
+        .store(format == LogFormat::Json, Ordering::Relaxed);
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Sends log messages to syslog instead of stderr, used once stdio is detached.
+pub fn log_to_syslog() {
+    unsafe {
+        libc::openlog(
+            b"envfs\0".as_ptr() as *const libc::c_char,
+            libc::LOG_PID,
+            libc::LOG_DAEMON,
+        )
+    };
+    LOGGER.syslog.store(true, Ordering::Relaxed);
+}
+
+/// Appends log messages to `path` instead of stderr or syslog, rotated once
+/// it reaches `max_size` with `keep` old files kept.
+pub fn log_to_file(path: &Path, max_size: u64, keep: usize) -> EnvfsResult<()> {
+    *FILE.lock().unwrap() = Some(RotatingFile::open(path, max_size, keep)?);
+    LOGGER.file.store(true, Ordering::Relaxed);
     Ok(())
 }
+
+/// Opens the log file again after it was moved away, done on SIGHUP.
+pub fn reopen() -> EnvfsResult<()> {
+    match FILE.lock().unwrap().as_mut() {
+        Some(file) => file.reopen(),
+        None => Ok(()),
+    }
+}
+
+/// Sets how long identical messages are only counted after being logged,
+/// `Duration::ZERO` logs all of them.
+pub fn set_dedup_window(window: Duration) {
+    LOGGER
+        .dedup_window
+        .store(window.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Changes the log level, can be called at any time after `init_logger`.
+pub fn set_level(level: log::LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Switches to the next more verbose level, wrapping around from trace to off.
+pub fn cycle_level() -> log::LevelFilter {
+    let level = match log::max_level() {
+        log::LevelFilter::Off => log::LevelFilter::Error,
+        log::LevelFilter::Error => log::LevelFilter::Warn,
+        log::LevelFilter::Warn => log::LevelFilter::Info,
+        log::LevelFilter::Info => log::LevelFilter::Debug,
+        log::LevelFilter::Debug => log::LevelFilter::Trace,
+        log::LevelFilter::Trace => log::LevelFilter::Off,
+    };
+    set_level(level);
+    level
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_messages_are_summarized() {
+        let mut dedup = Dedup {
+            seen: BTreeMap::new(),
+        };
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+        let warn = log::Level::Warn;
+        assert_eq!(dedup.check(start, window, warn, "failed"), (true, vec![]));
+        for _ in 0..3 {
+            assert_eq!(dedup.check(start, window, warn, "failed"), (false, vec![]));
+        }
+        assert_eq!(dedup.check(start, window, warn, "other"), (true, vec![]));
+        let later = start + window;
+        let (log, summaries) = dedup.check(later, window, warn, "failed");
+        assert!(log);
+        assert_eq!(
+            summaries,
+            vec![(warn, String::from("message repeated 3 times: failed"))]
+        );
+    }
+}
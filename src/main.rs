@@ -1,99 +1,548 @@
-use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
+use nix::mount::{umount2, MntFlags};
 use nix::sys::signal;
-use nix::{mount, unistd};
-use simple_error::bail;
-use simple_error::try_with;
+use simple_error::{bail, try_with};
+use std::ffi::OsString;
+use std::fs;
 use std::path::PathBuf;
-use std::sync::{Condvar, Mutex};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::fs::EnvFs;
-use crate::logger::enable_debug_log;
-use crate::result::Result;
+use envfs::audit::AuditLog;
+use envfs::dbus::Bus;
+use envfs::logger::{self, init_logger};
+use envfs::options::{is_mount_helper, parse_command_options, parse_options, Conflict, Options};
+use envfs::policy::PolicyFile;
+use envfs::ratelimit::RateLimiter;
+use envfs::resolve::{CandidatePolicy, DEFAULT_SETUID_PREFIXES};
+use envfs::resolver::{InterpreterResolver, NixProfileResolver, StaticResolver, SysextResolver};
+use envfs::result::Result;
+use envfs::underlay::Underlay;
+use envfs::{control, crash, privileges, prochelper, sandbox, spans, upgrade, varlink, EnvFs};
 
-mod fs;
-mod logger;
-mod result;
-mod setrlimit;
+mod commands;
+mod daemon;
+mod doctor;
+mod systemd;
+mod watchdog;
+
+/// Exit codes understood by mount(8) when running as a mount helper.
+const MOUNT_EX_USAGE: i32 = 1;
+const MOUNT_EX_FAIL: i32 = 32;
 
 struct MountGuard<'a> {
-    mountpoints: &'a [PathBuf],
+    fs: &'a EnvFs,
+    /// Unset if the mountpoints are unmounted by a helper process
+    unmount: bool,
 }
 
-lazy_static! {
-    static ref SIGNAL_RECEIVED: Condvar = Condvar::new();
+/// Signals handled by `wait_signal`, they are blocked in all other threads.
+fn handled_signals() -> signal::SigSet {
+    let mut signals = signal::SigSet::empty();
+    signals.add(signal::SIGINT);
+    signals.add(signal::SIGTERM);
+    signals.add(signal::SIGUSR1);
+    signals.add(signal::SIGUSR2);
+    signals.add(signal::SIGHUP);
+    signals
 }
 
-extern "C" fn handle_sigint(_: i32) {
-    SIGNAL_RECEIVED.notify_all();
-}
+/// How long a replaced instance keeps serving processes that still use its detached mounts.
+const RETIRE_TIMEOUT: Duration = Duration::from_secs(10);
 
-struct Options {
-    mountpoints: Vec<PathBuf>,
-    debug: bool,
-    show_help: bool,
-    foreground: bool,
-    remount: bool,
-    fallback_paths: Vec<PathBuf>,
-    args: Vec<String>,
-}
+/// Number of names included in the statistics dumped on SIGUSR1.
+const STATS_DUMP_SIZE: usize = 20;
 
-fn wait_signal(mountpoints: &[PathBuf]) -> Result<()> {
-    let guard = MountGuard { mountpoints };
+fn wait_signal(fs: &EnvFs, unmount: bool) -> Result<()> {
+    let guard = MountGuard { fs, unmount };
 
-    let sig_action = signal::SigAction::new(
-        signal::SigHandler::Handler(handle_sigint),
-        signal::SaFlags::empty(),
-        signal::SigSet::empty(),
-    );
+    let signals = handled_signals();
+    loop {
+        match try_with!(signals.wait(), "failed to wait for signal") {
+            signal::SIGUSR1 => {
+                for line in fs.stats().report(STATS_DUMP_SIZE) {
+                    logger::report(&line);
+                }
+            }
+            signal::SIGUSR2 => {
+                let level = logger::cycle_level();
+                info!("log level set to {}", level);
+            }
+            signal::SIGHUP => {
+                if let Err(e) = logger::reopen() {
+                    warn!("{}", e);
+                }
+            }
+            _ => break,
+        }
+    }
+    info!("Stop fuse");
 
-    unsafe {
-        try_with!(
-            signal::sigaction(signal::SIGINT, &sig_action),
-            "Unable to register SIGINT handler"
-        );
-        try_with!(
-            signal::sigaction(signal::SIGTERM, &sig_action),
-            "Unable to register SIGTERM handler"
-        );
+    drop(guard);
+
+    Ok(())
+}
+
+fn mount_fs(opts: &Options) -> Result<(EnvFs, fuser::Session<EnvFs>)> {
+    let mut builder = EnvFs::builder()
+        .fallback_paths_before(&opts.fallback_paths.before)
+        .fallback_paths(&opts.fallback_paths.after)
+        .mode(opts.mode)
+        .empty_path(opts.empty_path)
+        .syscall_timeout(opts.syscall_timeout)
+        .allowed_syscalls(opts.allowed_syscalls.clone())
+        .resolve_symlinks(opts.resolve_symlinks)
+        .mirror_attr(opts.mirror_attr)
+        .subdirs(opts.subdirs)
+        .expose_dirs(opts.expose_dirs)
+        .cache_rules(opts.cache_rules.clone())
+        // conflicts with running instances are resolved by `served_mountpoints`
+        .mount_over(true)
+        .candidate_policy(candidate_policy(opts))
+        .ignore_comms(opts.ignore_comm.clone())
+        .strip_suffixes(opts.strip_suffixes.clone());
+    if let Some(rate) = opts.rate_limit {
+        let burst = opts.rate_limit_burst.unwrap_or(rate);
+        builder = builder.rate_limit(RateLimiter::new(rate, burst, opts.rate_limit_cooldown));
+    }
+    if let Some(max_inodes) = opts.max_inodes {
+        builder = builder.max_inodes(max_inodes);
+    }
+    if let Some(count) = opts.recent_events {
+        builder = builder.recent_events(count);
     }
+    if opts.io_uring {
+        builder = builder.io_uring(true);
+    }
+    if opts.no_mem_read {
+        builder = builder.read_mem(false);
+    }
+    if opts.early_boot {
+        builder = builder.early_boot(true);
+    }
+    if let Some(nofile) = opts.nofile {
+        builder = builder.nofile(nofile);
+    }
+    if let Some(memlock) = opts.memlock {
+        builder = builder.memlock(memlock);
+    }
+    if let Some(ref file) = opts.cache_file {
+        builder = builder.cache_file(file);
+    }
+    if let Some(deadline) = opts.lookup_deadline {
+        builder = builder.lookup_deadline(deadline);
+    }
+    if let Some(threads) = opts.threads {
+        builder = builder.threads(threads);
+    }
+    if let Some(ref path) = opts.default_path {
+        builder = builder.default_path(path);
+    }
+    if let Some(ref path) = opts.policy_file {
+        builder = builder.policy_file(PolicyFile::open(path)?);
+    }
+    if let Some(ref path) = opts.static_entries {
+        builder = builder.static_entries(StaticResolver::from_file(path)?);
+    }
+    if opts.nix_profiles {
+        builder = builder.resolver(NixProfileResolver);
+    }
+    if let Some(ref names) = opts.interpreters {
+        builder = builder.resolver(InterpreterResolver::new(names.clone()));
+    }
+    if let Some(ref hierarchies) = opts.sysext {
+        builder = builder.resolver(SysextResolver::new(hierarchies.clone()));
+    }
+    if let Some(ref program) = opts.resolve_hook {
+        builder = builder.resolve_hook(program);
+        if opts.sandbox {
+            if let Some(ruleset) = sandbox::Ruleset::new(&hook_sandbox_rules(opts))? {
+                builder = builder.hook_sandbox(ruleset);
+            }
+        }
+    }
+    if let Some(ref path) = opts.audit_log {
+        builder = builder.audit_log(AuditLog::open(
+            path,
+            opts.audit_log_max_size,
+            opts.audit_log_keep,
+        )?);
+    }
+    if opts.underlay {
+        builder = builder.underlay(Underlay::open(&opts.mountpoints[0])?);
+    }
+    let mut fs = try_with!(builder.build(), "cannot create filesystem");
 
-    let mutex = Mutex::new(());
-    let lock_result = try_with!(mutex.lock(), "cannot acquire lock");
-    let res = try_with!(
-        SIGNAL_RECEIVED.wait(lock_result),
-        "failed to wait for signal barrier"
+    let session = try_with!(
+        fs.mount_session(&opts.mountpoints),
+        "cannot start fuse sessions"
     );
-    info!("Stop fuse");
+    Ok((fs, session))
+}
 
-    drop(guard);
-    drop(res);
+fn candidate_policy(opts: &Options) -> CandidatePolicy {
+    let setuid_prefixes = if opts.setuid_prefixes.is_empty() {
+        DEFAULT_SETUID_PREFIXES.iter().map(PathBuf::from).collect()
+    } else {
+        opts.setuid_prefixes.clone()
+    };
+    CandidatePolicy {
+        trusted_prefixes: opts.trusted_prefixes.clone(),
+        rule_prefixes: vec![],
+        refuse_setuid: opts.refuse_setuid,
+        setuid_prefixes,
+        prefer_arch: opts.prefer_arch,
+        arch: None,
+        check_interp: opts.check_interp,
+        root: None,
+        uid: None,
+        allow_dirs: false,
+    }
+}
+
+/// Mountpoints already served by a running envfs, found in `/proc/self/mountinfo`.
+fn served_mountpoints(opts: &Options) -> Result<Vec<PathBuf>> {
+    if opts.upgrade {
+        return Ok(vec![]);
+    }
+    let mut served = vec![];
+    for mountpoint in &opts.mountpoints {
+        match envfs::fs::is_envfs_mountpoint(mountpoint) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                warn!("{}", e);
+                return Ok(vec![]);
+            }
+        }
+        // the mount of an instance that was killed is left behind without a server
+        if let Err(e) = fs::metadata(mountpoint) {
+            if e.raw_os_error() == Some(libc::ENOTCONN) {
+                // even mounting over it fails
+                info!("detaching the stale envfs on {}", mountpoint.display());
+                try_with!(
+                    umount2(mountpoint, MntFlags::MNT_DETACH),
+                    "cannot detach the stale envfs on {}",
+                    mountpoint.display()
+                );
+                continue;
+            }
+        }
+        served.push(mountpoint.clone());
+    }
+    if let Some(mountpoint) = served.first() {
+        match opts.conflict {
+            Conflict::Error => bail!(
+                "{} is already served by envfs, use `envfs upgrade` to replace it or -o conflict=takeover",
+                mountpoint.display()
+            ),
+            Conflict::Takeover => {
+                info!("taking over {} from the running instance", mountpoint.display())
+            }
+            Conflict::Ignore => {
+                info!("mounting over the envfs on {}", mountpoint.display())
+            }
+        }
+    }
+    Ok(served)
+}
 
+/// Lets the running instance exit without unmounting and detaches its mounts
+/// covered by ours, the other side of `envfs upgrade`.
+fn take_over(
+    opts: &Options,
+    fs: &EnvFs,
+    mountpoints: &[PathBuf],
+    roots: Vec<fs::File>,
+) -> Result<()> {
+    if let Err(e) = control::request(&control_socket(opts), "retire", "") {
+        fs.unmount();
+        bail!("cannot take over from the running instance: {}", e);
+    }
+    upgrade::detach(mountpoints, roots);
     Ok(())
 }
 
 fn serve_fs(opts: &Options) -> Result<()> {
-    if !opts.foreground {
-        try_with!(unistd::daemon(true, true), "cannot daemonize");
+    // before looking at the mountpoints, a mount of a passed FUSE device
+    // would block every access until it is served
+    for (fd, name) in systemd::listen_fds() {
+        if systemd::is_fuse_device(fd) {
+            // fuser can only serve a FUSE device it mounted itself
+            simple_error::bail!(
+                "received /dev/fuse as file descriptor {} ({}), serving a pre-opened FUSE device is not supported",
+                fd,
+                name
+            );
+        }
+        warn!("ignoring passed file descriptor {} ({})", fd, name);
+        // not inherited by the resolve hook or other children
+        let _ = nix::unistd::close(fd);
     }
+    let served = served_mountpoints(opts)?;
+    // opened before our mounts cover them, afterwards only these reach the old mounts
+    let roots = match opts.conflict {
+        Conflict::Takeover => served
+            .iter()
+            .map(|m| upgrade::open_root(m))
+            .collect::<Result<Vec<_>>>()?,
+        _ => vec![],
+    };
+    let ready = if opts.foreground {
+        None
+    } else {
+        match daemon::daemonize()? {
+            daemon::Forked::Parent => return Ok(()),
+            daemon::Forked::Child(ready) => Some(ready),
+        }
+    };
 
-    let fs = try_with!(
-        EnvFs::new(opts.fallback_paths.as_slice()),
-        "cannot create filesystem"
-    );
+    // Threads spawned from here on inherit the signal mask, only the main thread receives them.
+    if let Err(e) = handled_signals().thread_block() {
+        let e = format!("cannot block signals: {}", e);
+        match ready {
+            Some(ready) => ready.fail(&e, MOUNT_EX_FAIL),
+            None => simple_error::bail!("{}", e),
+        }
+    }
+
+    let started = mount_fs(opts).and_then(|(fs, session)| {
+        if !roots.is_empty() {
+            take_over(opts, &fs, &served, roots)?;
+        }
+        let pidfile = match opts.pidfile {
+            Some(ref path) => Some(daemon::write_pidfile(path)?),
+            None => None,
+        };
+        // created before dropping privileges, /run/envfs is only writable by root
+        let control = match control::bind(&control_socket(opts)) {
+            Ok(control) => Some(control),
+            Err(e) => {
+                warn!("cannot start control socket: {}", e);
+                None
+            }
+        };
+        let varlink = if opts.varlink {
+            match varlink::bind(&varlink_socket(opts)) {
+                Ok(varlink) => Some(varlink),
+                Err(e) => {
+                    warn!("cannot start varlink socket: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // the bus only lets root own the name
+        let dbus = opts.dbus.and_then(|kind| match Bus::connect(kind) {
+            Ok(bus) => Some(bus),
+            Err(e) => {
+                warn!("cannot register on the D-Bus: {}", e);
+                None
+            }
+        });
+        if helper_unmounts(opts) {
+            privileges::spawn_unmount_helper(fs.active_mounts())?;
+        }
+        if let Some(hidepid) = prochelper::hidepid() {
+            if opts.proc_helper {
+                info!("/proc is mounted with hidepid={}, reading it through the helper", hidepid);
+            } else if nix::unistd::geteuid().is_root() {
+                info!("/proc is mounted with hidepid={}, keeping CAP_SYS_PTRACE to see all processes", hidepid);
+            } else {
+                warn!("/proc is mounted with hidepid={}, processes of other users are invisible to envfs; run it as root, with -o proc-helper or in the gid= group of /proc", hidepid);
+            }
+        }
+        if opts.proc_helper {
+            prochelper::spawn()?;
+        }
+        if let Some(ref user) = opts.run_as {
+            privileges::run_as(user, !opts.proc_helper)?;
+        }
+        if opts.sandbox {
+            sandbox::restrict_syscalls()?;
+        }
+        let session = try_with!(session.spawn(), "cannot start fuse session");
+        Ok((fs, session, pidfile, control, varlink, dbus))
+    });
+    let (fs, session, pidfile, mut control, mut varlink, dbus) = match started {
+        Ok(res) => res,
+        Err(e) => match ready {
+            Some(ready) => ready.fail(&e, MOUNT_EX_FAIL),
+            None => return Err(e),
+        },
+    };
+    if let Err(e) = crash::install(&fs.active_mounts(), opts.abort_on_panic) {
+        warn!("cannot install crash handlers: {}", e);
+    }
+    if let Some(ready) = ready {
+        ready.succeed();
+    }
+
+    if let Some(ref mut control) = control {
+        control.serve(fs.clone());
+    }
+    if let Some(ref mut varlink) = varlink {
+        varlink.serve(fs.clone());
+    }
+    if let Some(bus) = dbus {
+        bus.serve(fs.clone());
+    }
+    if opts.otlp {
+        if let Err(e) = spans::enable(&otlp_endpoint(opts)) {
+            warn!("{}", e);
+        }
+    }
+
+    if let Some(interval) = opts.inode_gc {
+        fs.spawn_inode_gc(interval);
+    }
+    match opts.repair_mounts {
+        // mounting is neither allowed as another user nor in the sandbox
+        Some(_) if helper_unmounts(opts) => {
+            info!("bind mounts are not repaired without the privileges to mount")
+        }
+        Some(interval) => fs.spawn_mount_repair(interval),
+        None => {}
+    }
 
-    let session = try_with!(fs.mount(&opts.mountpoints), "cannot start fuse sessions");
+    let prefetch = prefetch_names(opts)?;
+    if !prefetch.is_empty() {
+        fs.prefetch(prefetch);
+    }
+
+    let state = if opts.upgrade {
+        // the previous instance exits once it has handed over
+        format!("MAINPID={}\nREADY=1", std::process::id())
+    } else {
+        String::from("READY=1")
+    };
+    if let Err(e) = systemd::notify(&state) {
+        warn!("cannot notify systemd: {}", e);
+    }
+    // Ping twice per interval as recommended by sd_watchdog_enabled(3).
+    let systemd_interval = systemd::watchdog_interval().map(|interval| interval / 2);
+    let interval = match (opts.watchdog, systemd_interval) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    if let Some(interval) = interval {
+        watchdog::spawn(
+            fs.clone(),
+            watchdog::Config {
+                interval,
+                // systemd should only consider envfs alive if the mount answers
+                stat_mountpoint: opts.watchdog_stat || systemd_interval.is_some(),
+                restart: opts.watchdog_restart,
+                notify_systemd: systemd_interval.is_some(),
+            },
+        );
+    }
 
-    wait_signal(&opts.mountpoints)?;
+    wait_signal(&fs, !helper_unmounts(opts))?;
+    if fs.is_retired() {
+        info!("replaced by a new instance");
+        fs.save_resolution_cache();
+        let started = Instant::now();
+        while !session.guard.is_finished() && started.elapsed() < RETIRE_TIMEOUT {
+            thread::sleep(Duration::from_millis(100));
+        }
+        // Dropping the session, control socket or pidfile would remove the
+        // ones of the new instance.
+        process::exit(0);
+    }
+    let _ = systemd::notify("STOPPING=1");
+    fs.save_resolution_cache();
+    drop(control);
     drop(session);
+    drop(pidfile);
 
     Ok(())
 }
 
+/// Names of `-o prefetch` and the ones listed in `-o prefetch-file`.
+///
+/// The file has one name per line, empty lines and lines starting with `#` are skipped.
+fn prefetch_names(opts: &Options) -> Result<Vec<OsString>> {
+    let mut names = opts.prefetch.clone();
+    if let Some(ref file) = opts.prefetch_file {
+        let content = try_with!(fs::read_to_string(file), "cannot read {}", file.display());
+        names.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#') && !l.contains('/'))
+                .map(OsString::from),
+        );
+    }
+    Ok(names)
+}
+
+/// Whether envfs loses the privileges to unmount and leaves it to a helper process.
+fn helper_unmounts(opts: &Options) -> bool {
+    opts.run_as.is_some() || opts.sandbox
+}
+
+/// Files the resolve hook can access with `-o sandbox`.
+fn hook_sandbox_rules(opts: &Options) -> sandbox::Rules {
+    let mut rules = sandbox::Rules::default();
+    rules.read.push(PathBuf::from("/proc"));
+    rules.read.push(PathBuf::from("/etc"));
+    rules
+        .read
+        .extend(opts.fallback_paths.before.iter().cloned());
+    rules.read.extend(opts.fallback_paths.after.iter().cloned());
+    if let Some(ref path) = opts.default_path {
+        rules.read.extend(
+            path.split(':')
+                .filter(|p| p.starts_with('/'))
+                .map(PathBuf::from),
+        );
+    }
+    rules.read.extend(opts.resolve_hook.iter().cloned());
+    rules.read.extend(opts.sandbox_paths.iter().cloned());
+    // stdin of the hook
+    rules.write.push(PathBuf::from("/dev/null"));
+    rules
+}
+
+fn control_socket(opts: &Options) -> PathBuf {
+    match opts.control_socket {
+        Some(ref path) => path.clone(),
+        None => control::socket_path(&opts.mountpoints[0]),
+    }
+}
+
+/// `-o otlp-endpoint=URL`, else the standard OpenTelemetry variable.
+fn otlp_endpoint(opts: &Options) -> String {
+    match opts.otlp_endpoint {
+        Some(ref url) => url.clone(),
+        None => std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .unwrap_or_else(|_| String::from(spans::DEFAULT_ENDPOINT)),
+    }
+}
+
+fn varlink_socket(opts: &Options) -> PathBuf {
+    match opts.varlink_socket {
+        Some(ref path) => path.clone(),
+        None => varlink::socket_path(&opts.mountpoints[0]),
+    }
+}
+
+fn remount(opts: &Options) -> Result<()> {
+    control::request(
+        &control_socket(opts),
+        "remount",
+        &opts.mount_options.join(","),
+    )?;
+    Ok(())
+}
+
 impl<'a> Drop for MountGuard<'a> {
     fn drop(&mut self) {
-        for mountpoint in self.mountpoints {
-            let _ = mount::umount(mountpoint);
+        if self.unmount {
+            self.fs.unmount();
         }
     }
 }
@@ -102,129 +551,246 @@ fn show_help(prog_name: &str) {
     eprintln!("USAGE: {} [options] mountpoint", prog_name);
     eprintln!("-h, --help             show help");
     eprintln!("-f, --foreground       do not daemonize");
-    eprintln!("-o debug               debug logging");
-    eprintln!("-o fallback-path=PATH  Fallback path if PATH is not set");
+    eprintln!("-o debug               debug logging, same as log-level=debug");
+    eprintln!("-o log-level=LEVEL     off, error, warn, info, debug or trace");
+    eprintln!("                       (SIGUSR2 cycles through the levels at runtime)");
+    eprintln!("-o log-format=FORMAT   text (default) or json");
+    eprintln!("-o log-target=TARGET   syslog or file:PATH (default: stderr in the foreground,");
+    eprintln!("                       syslog otherwise), SIGHUP reopens the file");
+    eprintln!("-o log-max-size=BYTES  Rotate the log file at this size (default: 10MiB)");
+    eprintln!("-o log-keep=N          Number of rotated log files to keep (default: 5)");
+    eprintln!("-o log-dedup=SECONDS   Only count identical messages within SECONDS and log");
+    eprintln!("                       how often they repeated (default: 10, 0 disables it)");
+    eprintln!("-o log-filter-comm=NAME");
+    eprintln!("                       Only log lookups of programs called NAME");
+    eprintln!("                       (can be passed multiple times)");
+    eprintln!("-o fallback-path=PATH[:priority=before|after]");
+    eprintln!("                       Fallback path if PATH is not set, with priority=before");
+    eprintln!("                       it is searched before the PATH of the process");
     eprintln!("                       (can be passed multiple times)");
+    eprintln!("-o mode=MODE           process (default): use the PATH of the calling process,");
+    eprintln!("                       system: only use fallback paths,");
+    eprintln!("                       library: find shared libraries like the dynamic linker");
+    eprintln!("-o empty-path=MODE     ignore (default): skip empty and relative PATH entries,");
+    eprintln!("                       cwd: search them in the working directory of the process");
+    eprintln!("-o ignore-comm=NAMES   Colon-separated process names that are only served");
+    eprintln!("                       from the fallback paths (can be passed multiple times)");
+    eprintln!("-o resolve-symlinks    Point to the final target of executables that are symlinks");
+    eprintln!("-o trusted-prefix=DIR  Only serve executables below DIR");
+    eprintln!("                       (can be passed multiple times)");
+    eprintln!("-o policy[=FILE]       Apply per-user rules from FILE, reloaded on changes");
+    eprintln!("                       (default: /etc/envfs.policy)");
+    eprintln!("-o refuse-setuid       Skip setuid and setgid executables outside of the");
+    eprintln!("                       setuid prefixes");
+    eprintln!("-o setuid-prefix=DIR   Serve setuid executables below DIR with refuse-setuid");
+    eprintln!("                       (default: /run/wrappers/bin, can be passed multiple times)");
+    eprintln!("-o mirror-attr         Report size, owner, mode and times of the target");
+    eprintln!("-o subdirs=on|off      Serve directories found in PATH as subdirectories whose");
+    eprintln!("                       entries are searched in all of PATH (default: off)");
+    eprintln!("-o expose-dirs         Serve directories found in PATH as symlinks to them");
+    eprintln!("-o cache-rule=PREFIX:TTL");
+    eprintln!("                       Cache resolutions to executables below PREFIX for TTL,");
+    eprintln!("                       e.g. /nix/store:infinite,/home:5s");
+    eprintln!("-o strip-suffixes=LIST Retry names ending with one of the comma-separated");
+    eprintln!("                       suffixes without it, e.g. .exe,.bat");
+    eprintln!("-o prefer-arch         Prefer executables of the ELF class and machine of the");
+    eprintln!("                       calling process over earlier matches in PATH");
+    eprintln!("-o check-interp        Skip executables whose ELF interpreter is missing in the");
+    eprintln!("                       root of the calling process, implies prefer-arch");
+    eprintln!("-o default-path=DIRS   Colon-separated PATH for requests from the kernel (pid 0)");
+    eprintln!("                       or processes whose environment cannot be read");
+    eprintln!("-o syscall-timeout=MS  Wait at most MS milliseconds (default: 100) for the");
+    eprintln!("                       caller to enter a system call, then use its PATH");
+    eprintln!("-o prefetch=NAME:...   Resolve these names at startup to warm up the caches");
+    eprintln!("-o prefetch-file=FILE  Also resolve the names listed in FILE at startup");
+    eprintln!("-o cache-file[=FILE]   Keep resolutions across restarts in FILE");
+    eprintln!("                       (default: /var/cache/envfs/resolutions.bin)");
+    eprintln!("-o lookup-deadline=MS  Answer lookups within about MS milliseconds, using");
+    eprintln!("                       cached environments and fallback paths if needed");
+    eprintln!("-o allow-syscalls=LIST Colon-separated open, exec, stat, access or syscall");
+    eprintln!("                       numbers during which PATH is used (default: open:exec)");
+    eprintln!("-o rate-limit=N        Serve processes that look up more than N names per");
+    eprintln!("                       second from the fallback paths only for a while");
+    eprintln!("-o rate-limit-burst=N  Lookups a process may do at once (default: rate-limit)");
+    eprintln!("-o rate-limit-cooldown=SECONDS");
+    eprintln!("                       How long a limited process is throttled (default: 10)");
+    eprintln!("-o max-inodes=N        Drop the least recently used inodes beyond N");
+    eprintln!("                       (default: 65536)");
+    eprintln!("-o recent-events=N     Keep the last N resolutions for 'envfs status --recent'");
+    eprintln!("                       (default: 256, 0 keeps none)");
+    eprintln!("-o inode-gc=SECONDS    Drop inodes unused for SECONDS, checked every SECONDS");
+    eprintln!("                       (default: 60, 0 disables it)");
+    eprintln!("-o repair-mounts=SECONDS");
+    eprintln!("                       Bind mount mountpoints again that were unmounted,");
+    eprintln!("                       checked every SECONDS (default: 10, 0 disables it)");
+    eprintln!("-o threads=N           Resolve up to N lookups in parallel");
+    eprintln!("                       (default: number of CPUs)");
+    eprintln!("-o no-mem-read         Never read the PATH passed to execve from the memory of");
+    eprintln!("                       the process, only use /proc/<pid>/environ");
+    eprintln!("-o early-boot          Serve only static entries and fallback paths until /proc");
+    eprintln!("                       is mounted, e.g. in an initramfs");
+    eprintln!("-o io-uring            Read the /proc files of each lookup in one batch");
+    eprintln!("                       with io_uring (not available with sandbox=on)");
+    eprintln!("-o memlock=BYTES|max   Raise the locked memory limit for io-uring, needed on");
+    eprintln!("                       kernels before 5.12");
+    eprintln!("-o nofile=N|max        Raise the file descriptor limit to N (default: 1048576),");
+    eprintln!("                       up to the hard limit when not privileged");
+    eprintln!("-o abort-on-panic=false");
+    eprintln!("                       Keep serving when a worker thread panics instead of");
+    eprintln!("                       unmounting and aborting");
     eprintln!("-o bind-mount=PATH     Bind mount PATH with envfs");
     eprintln!("                       (can be passed multiple times)");
+    eprintln!("-o static-entries=FILE Always serve the 'NAME PATH' pairs listed in FILE");
+    eprintln!("-o underlay            Serve the files hidden by the mount if PATH has no match");
+    eprintln!("-o nix-profiles        Also look in ~/.nix-profile/bin and");
+    eprintln!("                       /etc/profiles/per-user/<user>/bin of the calling user");
+    eprintln!("-o interpreters[=NAMES]");
+    eprintln!("                       Find script interpreters like python3 in the system and");
+    eprintln!("                       per-user profiles if PATH has no match (NAMES: a");
+    eprintln!("                       colon-separated list replacing the default ones)");
+    eprintln!("-o sysext[=DIRS]       Serve the executables of merged systemd-sysext extensions");
+    eprintln!("                       found in DIRS (default: the sysext search paths)");
+    eprintln!("-o resolve-hook=PROGRAM");
+    eprintln!("                       Run 'PROGRAM NAME PID UID' for names that cannot be");
+    eprintln!("                       resolved and use the path it prints");
+    eprintln!("-o control-socket=PATH Unix socket used to talk to the running instance");
+    eprintln!("                       (default: /run/envfs/<mountpoint>.sock)");
+    eprintln!("-o varlink[=PATH]      Serve the varlink interface io.systemd.envfs on PATH");
+    eprintln!("                       (default: /run/envfs/<mountpoint>.varlink)");
+    eprintln!("-o dbus[=system|session]");
+    eprintln!("                       Offer the control operations as org.envfs.Manager on");
+    eprintln!("                       the system or session bus (default: system)");
+    eprintln!("-o otlp-endpoint[=URL] Export the timing of lookups as OpenTelemetry spans to");
+    eprintln!(
+        "                       an OTLP/HTTP collector (default: $OTEL_EXPORTER_OTLP_ENDPOINT"
+    );
+    eprintln!("                       or http://localhost:4318)");
+    eprintln!("-o audit-log=PATH      Log every successful resolution to PATH");
+    eprintln!("-o audit-log-max-size=BYTES");
+    eprintln!("                       Rotate the audit log at this size (default: 10MiB)");
+    eprintln!("-o audit-log-keep=N    Number of rotated audit logs to keep (default: 5)");
+    eprintln!("-o watchdog=SECONDS    Check every SECONDS that lookups are answered and log");
+    eprintln!("                       diagnostics if they hang");
+    eprintln!("-o watchdog-stat       Let the check also stat a file in the mountpoint");
+    eprintln!("-o watchdog-restart    Replace envfs with a fresh instance when a check hangs");
+    eprintln!("-o run-as=USER         Switch to USER after mounting, keeping only the");
+    eprintln!("                       capabilities needed to inspect other processes");
+    eprintln!("-o proc-helper         Read /proc through a privileged helper process, so that");
+    eprintln!("                       envfs keeps no capabilities with run-as");
+    eprintln!("-o sandbox=on          Restrict system calls with seccomp and the files the");
+    eprintln!("                       resolve hook can access with Landlock");
+    eprintln!("-o sandbox-path=PATH   Let the sandboxed resolve hook read and execute PATH");
+    eprintln!("                       (can be passed multiple times)");
+    eprintln!("-o pidfile=PATH        Write the process id of the daemon to PATH");
+    eprintln!("-o upgrade             Mount over a running instance, which then exits");
+    eprintln!("                       (used by the upgrade command)");
+    eprintln!("-o conflict=MODE       What to do if the mountpoint is already served by envfs:");
+    eprintln!("                       error (default), takeover the running instance or");
+    eprintln!("                       ignore it and mount on top");
+    eprintln!("-o remount             Apply log-level and fallback-path options");
+    eprintln!("                       to the running instance");
+    eprintln!();
+    eprintln!("When invoked as mount.envfs, the mount(8) helper convention is used:");
+    eprintln!("  mount.envfs none MOUNTPOINT [-sfnv] [-o options] [-t type]");
+    eprintln!("-f is treated as fake mount and -s, -n, -v as well as -t are ignored.");
+    eprintln!();
+    eprintln!(
+        "Run '{} COMMAND --help' for commands to control a running instance.",
+        prog_name
+    );
 }
 
-fn parse_mount_options(mount_options: &str, opts: &mut Options) -> Result<()> {
-    for option in mount_options.split(',') {
-        let mount_opt: Vec<&str> = option.splitn(2, '=').collect();
-        match mount_opt[0] {
-            // ignore
-            "ro" | "rw" | "nofail" => {}
-            "remount" => {
-                opts.remount = true;
-            }
-            "debug" => {
-                opts.debug = true;
-            }
-            "bind-mount" => {
-                if mount_opt.len() != 2 {
-                    bail!("bind-mount needs an argument");
-                }
-                opts.mountpoints.push(PathBuf::from(mount_opt[1]));
-            }
-            "fallback-path" => {
-                if mount_opt.len() != 2 {
-                    bail!("fallback-path needs an argument");
-                }
-                opts.fallback_paths.push(PathBuf::from(mount_opt[1]));
-            }
-            _ => {
-                eprintln!("ignore invalid mount option: {}", mount_opt[0]);
-            }
+fn run_command(app_name: &str, command: &str, args: &[String]) -> i32 {
+    let opts = match parse_command_options(args) {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("{}: {}", app_name, err);
+            return 1;
         }
-    }
-    Ok(())
-}
-
-fn parse_options(args: &[String]) -> Result<Options> {
-    let mut i: usize = 0;
-    let mut opts = Options {
-        mountpoints: vec![],
-        debug: false,
-        show_help: false,
-        foreground: false,
-        remount: false,
-        fallback_paths: vec![],
-        args: vec![],
     };
-    loop {
-        if i >= args.len() {
-            return Ok(opts);
-        }
-        match args[i].as_ref() {
-            "-h" | "--help" => {
-                opts.show_help = true;
-                return Ok(opts);
-            }
-            "-f" | "--foreground" => {
-                opts.foreground = true;
-            }
-            "-o" => {
-                i += 1;
-                if i >= args.len() {
-                    bail!("'-o' requires an argument");
-                }
-                parse_mount_options(&args[i], &mut opts)?;
-            }
-            _ => {
-                if args[i].starts_with('-') && args[i] != "--" {
-                    bail!("unrecognized argument '{}'", args[i]);
-                }
-                if args[i] == "--" {
-                    opts.args.extend_from_slice(&args[i + 1..]);
-                    return Ok(opts);
-                }
-                opts.args.push(String::from(args[i].as_str()));
-            }
+    if opts.show_help {
+        commands::show_help(app_name);
+        return 0;
+    }
+    match commands::run_command(command, &opts) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", app_name, e);
+            1
         }
-        i += 1;
     }
 }
 
 fn run_app(args: &[String]) -> i32 {
     let default_name = String::from("envfs");
     let app_name = args.first().unwrap_or(&default_name);
-    let mut opts = match parse_options(&args[1..]) {
+    let mount_helper = is_mount_helper(app_name);
+    match args.get(1) {
+        Some(command) if !mount_helper && commands::is_command(command) => {
+            run_command(app_name, command, &args[2..])
+        }
+        Some(command) if !mount_helper && command == "mount" => run_mount(app_name, &args[2..]),
+        _ => run_mount(app_name, &args[1..]),
+    }
+}
+
+fn run_mount(app_name: &str, args: &[String]) -> i32 {
+    let mut opts = match parse_options(args, is_mount_helper(app_name)) {
         Ok(opts) => opts,
         Err(err) => {
             eprintln!("{}: {}", app_name, err);
-            return 1;
+            return MOUNT_EX_USAGE;
         }
     };
+    if opts.show_help {
+        show_help(app_name);
+        return 0;
+    }
     if opts.args.is_empty() {
-        eprintln!("Not enough arguments.");
+        eprintln!("{}: not enough arguments", app_name);
+        show_help(app_name);
+        return MOUNT_EX_USAGE;
+    }
+    if opts.args.len() > 2 {
+        eprintln!("{}: too many arguments", app_name);
         show_help(app_name);
-        return 1;
+        return MOUNT_EX_USAGE;
     }
     opts.mountpoints.insert(
         0,
         PathBuf::from(&opts.args[usize::from(opts.args.len() != 1)]),
     );
 
-    if opts.show_help {
-        show_help(app_name);
+    if opts.fake {
         return 0;
     }
     if opts.remount {
-        eprintln!("Ignoring remount request.");
+        if let Err(e) = remount(&opts) {
+            eprintln!("{}: remount failed: {}", app_name, e);
+            return MOUNT_EX_FAIL;
+        }
         return 0;
     }
-    if opts.debug {
-        if let Err(err) = enable_debug_log() {
-            eprintln!("{}: cannot set up logging: {}", app_name, err);
+    let log_level = opts.log_level.unwrap_or(log::LevelFilter::Off);
+    if let Err(err) = init_logger(log_level, opts.log_format) {
+        eprintln!("{}: cannot set up logging: {}", app_name, err);
+    }
+    logger::set_comm_filter(opts.log_filter_comm.clone());
+    logger::set_dedup_window(opts.log_dedup);
+    if let Some(ref path) = opts.log_file {
+        if let Err(e) = logger::log_to_file(path, opts.log_max_size, opts.log_keep) {
+            eprintln!("{}: {}", app_name, e);
+            return MOUNT_EX_FAIL;
         }
+    } else if opts.log_syslog {
+        logger::log_to_syslog();
     }
 
     match serve_fs(&opts) {
         Ok(()) => {}
         Err(e) => {
-            eprintln!("{}", e);
-            return 1;
+            eprintln!("{}: {}", app_name, e);
+            return MOUNT_EX_FAIL;
         }
     };
 
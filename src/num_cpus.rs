@@ -0,0 +1,119 @@
+//! Number of CPUs available to envfs, used to size thread pools.
+//!
+//! Besides the CPUs envfs may run on, this respects the CPU quota of its
+//! cgroup, which is how container runtimes usually limit CPUs.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Returns the number of CPUs the process may run on, at least 1.
+pub fn get() -> usize {
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    match cgroup_quota() {
+        Some(quota) => cpus.min(quota),
+        None => cpus,
+    }
+}
+
+/// Rounds a quota of `quota` per `period` microseconds up to whole CPUs.
+fn quota_cpus(quota: u64, period: u64) -> Option<usize> {
+    if period == 0 {
+        return None;
+    }
+    Some(quota.div_ceil(period).max(1) as usize)
+}
+
+/// Parses `cpu.max` of cgroup v2, `None` if it is unlimited.
+fn parse_cpu_max(content: &str) -> Option<usize> {
+    let mut fields = content.split_whitespace();
+    let quota = fields.next()?.parse::<u64>().ok()?;
+    let period = fields.next()?.parse::<u64>().ok()?;
+    quota_cpus(quota, period)
+}
+
+/// Parses `cpu.cfs_quota_us` and `cpu.cfs_period_us` of cgroup v1, the quota
+/// is -1 if it is unlimited.
+fn parse_cfs(quota: &str, period: &str) -> Option<usize> {
+    let quota = quota.trim().parse::<i64>().ok()?;
+    let period = period.trim().parse::<u64>().ok()?;
+    if quota <= 0 {
+        return None;
+    }
+    quota_cpus(quota as u64, period)
+}
+
+/// Directories of the cgroup `path` and its parents below `root`.
+///
+/// In a cgroup namespace `/proc/self/cgroup` is relative to the namespace
+/// while `root` may still show the whole hierarchy, so paths that do not exist
+/// are skipped.
+fn cgroup_dirs(root: &Path, path: &str) -> Vec<PathBuf> {
+    let mut dir = root.join(path.trim_start_matches('/'));
+    let mut dirs = vec![];
+    while dir.starts_with(root) {
+        if dir.is_dir() {
+            dirs.push(dir.clone());
+        }
+        if !dir.pop() {
+            break;
+        }
+    }
+    dirs
+}
+
+/// Smallest CPU quota of the cgroup of this process and its parents.
+fn cgroup_quota() -> Option<usize> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let root = Path::new(CGROUP_ROOT);
+    let mut quota: Option<usize> = None;
+    let mut limit = |cpus: Option<usize>| {
+        if let Some(cpus) = cpus {
+            quota = Some(quota.map_or(cpus, |q| q.min(cpus)));
+        }
+    };
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (_, controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(id), Some(controllers), Some(path)) => (id, controllers, path),
+            _ => continue,
+        };
+        if controllers.is_empty() {
+            for dir in cgroup_dirs(root, path) {
+                limit(
+                    fs::read_to_string(dir.join("cpu.max"))
+                        .ok()
+                        .and_then(|c| parse_cpu_max(&c)),
+                );
+            }
+        } else if controllers.split(',').any(|c| c == "cpu") {
+            for hierarchy in &["cpu", "cpu,cpuacct", "cpuacct,cpu"] {
+                for dir in cgroup_dirs(&root.join(hierarchy), path) {
+                    let quota = fs::read_to_string(dir.join("cpu.cfs_quota_us"));
+                    let period = fs::read_to_string(dir.join("cpu.cfs_period_us"));
+                    if let (Ok(quota), Ok(period)) = (quota, period) {
+                        limit(parse_cfs(&quota, &period));
+                    }
+                }
+            }
+        }
+    }
+    quota
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quota() {
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_cpu_max("200000 100000\n"), Some(2));
+        assert_eq!(parse_cpu_max("150000 100000\n"), Some(2));
+        assert_eq!(parse_cpu_max("1000 100000\n"), Some(1));
+        assert_eq!(parse_cfs("-1\n", "100000\n"), None);
+        assert_eq!(parse_cfs("400000\n", "100000\n"), Some(4));
+    }
+}
@@ -0,0 +1,856 @@
+use simple_error::bail;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::audit;
+use crate::dbus::BusKind;
+use crate::fs::{CacheRule, Mode};
+use crate::logger::{LogFormat, DEFAULT_DEDUP_WINDOW};
+use crate::policy::DEFAULT_POLICY_FILE;
+use crate::ratelimit;
+use crate::rescache::DEFAULT_CACHE_FILE;
+use crate::resolve::{EmptyPath, DEFAULT_SYSCALL_TIMEOUT};
+use crate::resolver::{FallbackPaths, Priority, DEFAULT_INTERPRETERS, DEFAULT_SYSEXT_HIERARCHIES};
+use crate::result::Result;
+use crate::syscalls::AllowedSyscalls;
+
+/// Default interval of the inode garbage collection.
+pub const DEFAULT_INODE_GC: Duration = Duration::from_secs(60);
+
+/// Default interval of the check for missing bind mounts.
+pub const DEFAULT_REPAIR_MOUNTS: Duration = Duration::from_secs(10);
+
+/// What to do if a mountpoint is already served by another envfs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conflict {
+    /// Refuse to mount
+    Error,
+    /// Mount on top and let the running instance exit, like `envfs upgrade`
+    Takeover,
+    /// Mount on top, the running instance serves again once this one is unmounted
+    Ignore,
+}
+
+pub struct Options {
+    pub mountpoints: Vec<PathBuf>,
+    pub log_level: Option<log::LevelFilter>,
+    pub log_format: LogFormat,
+    /// Log to this file instead of stderr or syslog
+    pub log_file: Option<PathBuf>,
+    pub log_syslog: bool,
+    pub log_max_size: u64,
+    pub log_keep: usize,
+    /// Identical log messages within this time are only counted
+    pub log_dedup: Duration,
+    pub log_filter_comm: Vec<String>,
+    /// Processes that are only served from the fallback paths
+    pub ignore_comm: Vec<String>,
+    pub show_help: bool,
+    pub foreground: bool,
+    pub remount: bool,
+    pub fake: bool,
+    pub mount_helper: bool,
+    pub fallback_paths: FallbackPaths,
+    pub mode: Mode,
+    pub empty_path: EmptyPath,
+    pub default_path: Option<String>,
+    pub syscall_timeout: Duration,
+    /// Names resolved at startup to warm up the caches
+    pub prefetch: Vec<OsString>,
+    /// File with more names to resolve at startup, one per line
+    pub prefetch_file: Option<PathBuf>,
+    /// Cache TTLs by the prefix of the target
+    pub cache_rules: Vec<CacheRule>,
+    /// Keeps resolutions across restarts in this file
+    pub cache_file: Option<PathBuf>,
+    /// Time budget of a lookup, `None` for no limit
+    pub lookup_deadline: Option<Duration>,
+    pub allowed_syscalls: AllowedSyscalls,
+    /// Worker threads for lookups, `None` for one per CPU within the cgroup quota
+    pub threads: Option<usize>,
+    /// Read /proc files with io_uring
+    pub io_uring: bool,
+    /// Never read the memory of processes, only their environ
+    pub no_mem_read: bool,
+    /// Only serve static entries and fallback paths until /proc is mounted
+    pub early_boot: bool,
+    /// File descriptor limit, `u64::MAX` for the highest allowed
+    pub nofile: Option<u64>,
+    /// Locked memory limit in bytes used with io_uring, `u64::MAX` for unlimited
+    pub memlock: Option<u64>,
+    pub abort_on_panic: bool,
+    /// Mount over a running instance, which shuts down afterwards
+    pub upgrade: bool,
+    pub conflict: Conflict,
+    /// Interval of the health check
+    pub watchdog: Option<Duration>,
+    pub watchdog_stat: bool,
+    pub watchdog_restart: bool,
+    /// User the daemon switches to after mounting
+    pub run_as: Option<String>,
+    /// Read `/proc` through a privileged helper process
+    pub proc_helper: bool,
+    /// Restrict the daemon with seccomp and Landlock after mounting
+    pub sandbox: bool,
+    /// Additional paths readable inside the sandbox
+    pub sandbox_paths: Vec<PathBuf>,
+    /// Directories outside of which nothing is served, unless empty
+    pub trusted_prefixes: Vec<PathBuf>,
+    pub max_inodes: Option<usize>,
+    /// Resolutions kept for `envfs status --recent`
+    pub recent_events: Option<usize>,
+    /// Interval of the inode garbage collection, `None` to disable it
+    pub inode_gc: Option<Duration>,
+    /// Interval of the check for missing bind mounts, `None` to disable it
+    pub repair_mounts: Option<Duration>,
+    /// Lookups per second and process before it is only served from fallback paths
+    pub rate_limit: Option<u32>,
+    pub rate_limit_burst: Option<u32>,
+    pub rate_limit_cooldown: Duration,
+    /// Per-user rules, see `policy`
+    pub policy_file: Option<PathBuf>,
+    pub refuse_setuid: bool,
+    /// Directories setuid programs may still be served from
+    pub setuid_prefixes: Vec<PathBuf>,
+    pub resolve_symlinks: bool,
+    pub mirror_attr: bool,
+    pub subdirs: bool,
+    pub expose_dirs: bool,
+    pub prefer_arch: bool,
+    /// Skip executables whose ELF interpreter does not exist for the caller
+    pub check_interp: bool,
+    /// Suffixes like `.exe` that are retried without, see `EnvFsBuilder::strip_suffixes`
+    pub strip_suffixes: Vec<OsString>,
+    pub resolve_hook: Option<PathBuf>,
+    pub nix_profiles: bool,
+    /// Hierarchies of systemd-sysext extensions to serve while merged, see `SysextResolver`
+    pub sysext: Option<Vec<PathBuf>>,
+    /// Names served from installed profiles, see `InterpreterResolver`
+    pub interpreters: Option<Vec<OsString>>,
+    pub static_entries: Option<PathBuf>,
+    /// Serve the files below the mountpoint, see `underlay`
+    pub underlay: bool,
+    pub control_socket: Option<PathBuf>,
+    /// Serve the varlink interface `io.systemd.envfs`
+    pub varlink: bool,
+    /// Socket of the varlink interface, `None` for the default
+    pub varlink_socket: Option<PathBuf>,
+    /// Offer the control operations as a D-Bus service on this bus
+    pub dbus: Option<BusKind>,
+    /// Export spans of lookups with OTLP
+    pub otlp: bool,
+    /// OTLP/HTTP collector, `None` for the default
+    pub otlp_endpoint: Option<String>,
+    pub pidfile: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_log_max_size: u64,
+    pub audit_log_keep: usize,
+    /// Raw `-o` arguments as passed on the command line, forwarded on remount
+    pub mount_options: Vec<String>,
+    pub args: Vec<String>,
+}
+
+impl Options {
+    pub fn new(mount_helper: bool) -> Options {
+        Options {
+            mountpoints: vec![],
+            log_level: None,
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_syslog: false,
+            log_max_size: audit::DEFAULT_MAX_SIZE,
+            log_keep: audit::DEFAULT_KEEP,
+            log_dedup: DEFAULT_DEDUP_WINDOW,
+            log_filter_comm: vec![],
+            ignore_comm: vec![],
+            show_help: false,
+            foreground: false,
+            remount: false,
+            fake: false,
+            mount_helper,
+            fallback_paths: FallbackPaths::default(),
+            mode: Mode::Process,
+            empty_path: EmptyPath::Ignore,
+            default_path: None,
+            syscall_timeout: DEFAULT_SYSCALL_TIMEOUT,
+            prefetch: vec![],
+            prefetch_file: None,
+            cache_rules: vec![],
+            cache_file: None,
+            lookup_deadline: None,
+            allowed_syscalls: AllowedSyscalls::default(),
+            threads: None,
+            io_uring: false,
+            no_mem_read: false,
+            early_boot: false,
+            nofile: None,
+            memlock: None,
+            abort_on_panic: true,
+            upgrade: false,
+            conflict: Conflict::Error,
+            watchdog: None,
+            watchdog_stat: false,
+            watchdog_restart: false,
+            run_as: None,
+            proc_helper: false,
+            sandbox: false,
+            sandbox_paths: vec![],
+            trusted_prefixes: vec![],
+            max_inodes: None,
+            recent_events: None,
+            inode_gc: Some(DEFAULT_INODE_GC),
+            repair_mounts: Some(DEFAULT_REPAIR_MOUNTS),
+            rate_limit: None,
+            rate_limit_burst: None,
+            rate_limit_cooldown: ratelimit::DEFAULT_COOLDOWN,
+            policy_file: None,
+            refuse_setuid: false,
+            setuid_prefixes: vec![],
+            resolve_symlinks: false,
+            mirror_attr: false,
+            subdirs: false,
+            expose_dirs: false,
+            prefer_arch: false,
+            check_interp: false,
+            strip_suffixes: vec![],
+            resolve_hook: None,
+            nix_profiles: false,
+            interpreters: None,
+            sysext: None,
+            static_entries: None,
+            underlay: false,
+            control_socket: None,
+            varlink: false,
+            varlink_socket: None,
+            otlp: false,
+            otlp_endpoint: None,
+            dbus: None,
+            pidfile: None,
+            audit_log: None,
+            audit_log_max_size: audit::DEFAULT_MAX_SIZE,
+            audit_log_keep: audit::DEFAULT_KEEP,
+            mount_options: vec![],
+            args: vec![],
+        }
+    }
+}
+
+/// Mount options that mount(8) and systemd pass along but that have no meaning for envfs.
+fn is_ignored_mount_option(name: &str) -> bool {
+    matches!(
+        name,
+        "ro" | "rw"
+            | "nofail"
+            | "auto"
+            | "noauto"
+            | "defaults"
+            | "nosuid"
+            | "suid"
+            | "nodev"
+            | "dev"
+            | "noexec"
+            | "exec"
+            | "user"
+            | "nouser"
+            | "users"
+            | "owner"
+            | "_netdev"
+            | "async"
+            | "sync"
+            | "atime"
+            | "noatime"
+            | "relatime"
+            | "strictatime"
+            | "nodiratime"
+            | "comment"
+    ) || name.starts_with("x-")
+}
+
+pub fn is_mount_helper(prog_name: &str) -> bool {
+    Path::new(prog_name)
+        .file_name()
+        .map(|name| name.to_string_lossy().starts_with("mount."))
+        .unwrap_or(false)
+}
+
+pub fn parse_log_level(level: &str) -> Result<log::LevelFilter> {
+    match level.parse::<log::LevelFilter>() {
+        Ok(level) => Ok(level),
+        Err(_) => bail!(
+            "invalid log level '{}', expected off, error, warn, info, debug or trace",
+            level
+        ),
+    }
+}
+
+/// Parses `PATH[:priority=before|after]`.
+pub fn parse_fallback_path(value: &str) -> Result<(PathBuf, Priority)> {
+    let (path, priority) = match value.rsplit_once(":priority=") {
+        Some((path, "before")) => (path, Priority::Before),
+        Some((path, "after")) => (path, Priority::After),
+        Some((_, priority)) => bail!(
+            "invalid fallback-path priority '{}', expected before or after",
+            priority
+        ),
+        None => (value, Priority::After),
+    };
+    if path.is_empty() {
+        bail!("fallback-path needs an argument");
+    }
+    Ok((PathBuf::from(path), priority))
+}
+
+/// Parses the value of a resource limit, `max` is the highest one allowed.
+fn parse_limit(value: &str) -> Option<u64> {
+    match value {
+        "max" => Some(u64::MAX),
+        _ => value.parse::<u64>().ok().filter(|n| *n > 0),
+    }
+}
+
+/// Parses a TTL like `500ms`, `5s`, `10m`, `1h` or `infinite`, plain numbers are seconds.
+fn parse_ttl(value: &str) -> Option<Duration> {
+    if value == "infinite" {
+        return Some(Duration::MAX);
+    }
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let n = value[..split].parse::<u64>().ok()?;
+    match &value[split..] {
+        "ms" => Some(Duration::from_millis(n)),
+        "" | "s" => Some(Duration::from_secs(n)),
+        "m" => n.checked_mul(60).map(Duration::from_secs),
+        "h" => n.checked_mul(3600).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+/// Parses `PREFIX:TTL`.
+fn parse_cache_rule(value: &str) -> Result<CacheRule> {
+    match value.rsplit_once(':') {
+        Some((prefix, ttl)) if prefix.starts_with('/') => match parse_ttl(ttl) {
+            Some(ttl) => Ok(CacheRule {
+                prefix: PathBuf::from(prefix),
+                ttl,
+            }),
+            None => bail!(
+                "invalid cache-rule TTL '{}', expected e.g. 5s or infinite",
+                ttl
+            ),
+        },
+        _ => bail!("cache-rule needs an absolute prefix and a TTL like /nix/store:infinite"),
+    }
+}
+
+pub fn parse_mount_options(mount_options: &str, opts: &mut Options) -> Result<()> {
+    let mut previous = "";
+    for option in mount_options.split(',') {
+        let mount_opt: Vec<&str> = option.splitn(2, '=').collect();
+        // `cache-rule=/nix/store:infinite,/home:5s` continues after the comma
+        let continues_cache_rule = option.starts_with('/') && previous == "cache-rule";
+        if !option.starts_with('/') {
+            previous = mount_opt[0];
+        }
+        match mount_opt[0] {
+            _ if continues_cache_rule => opts.cache_rules.push(parse_cache_rule(option)?),
+            "" => {}
+            name if is_ignored_mount_option(name) => {}
+            "remount" => {
+                opts.remount = true;
+            }
+            "debug" => {
+                opts.log_level = Some(log::LevelFilter::Debug);
+            }
+            "nodebug" => {
+                opts.log_level = Some(log::LevelFilter::Off);
+            }
+            "log-level" => {
+                if mount_opt.len() != 2 {
+                    bail!("log-level needs an argument");
+                }
+                opts.log_level = Some(parse_log_level(mount_opt[1])?);
+            }
+            "log-format" => {
+                opts.log_format = match mount_opt.get(1) {
+                    Some(&"text") => LogFormat::Text,
+                    Some(&"json") => LogFormat::Json,
+                    _ => bail!("log-format needs to be either text or json"),
+                };
+            }
+            "log-target" => match mount_opt.get(1) {
+                Some(&"syslog") => opts.log_syslog = true,
+                Some(target) if target.starts_with("file:/") => {
+                    opts.log_file = Some(PathBuf::from(&target["file:".len()..]))
+                }
+                _ => bail!("log-target needs to be syslog or file:PATH with an absolute PATH"),
+            },
+            "log-max-size" => match mount_opt.get(1).and_then(|v| v.parse::<u64>().ok()) {
+                Some(size) => opts.log_max_size = size,
+                None => bail!("log-max-size needs a size in bytes"),
+            },
+            "log-keep" => match mount_opt.get(1).and_then(|v| v.parse::<usize>().ok()) {
+                Some(keep) => opts.log_keep = keep,
+                None => bail!("log-keep needs a number"),
+            },
+            "log-dedup" => match mount_opt.get(1).and_then(|v| v.parse::<u64>().ok()) {
+                Some(secs) => opts.log_dedup = Duration::from_secs(secs),
+                None => bail!("log-dedup needs a number of seconds"),
+            },
+            "ignore-comm" => match mount_opt.get(1) {
+                Some(names) if !names.is_empty() => opts
+                    .ignore_comm
+                    .extend(names.split(':').filter(|n| !n.is_empty()).map(String::from)),
+                _ => bail!("ignore-comm needs an argument"),
+            },
+            "log-filter-comm" => {
+                if mount_opt.len() != 2 {
+                    bail!("log-filter-comm needs an argument");
+                }
+                opts.log_filter_comm.push(mount_opt[1].to_string());
+            }
+            "bind-mount" => {
+                if mount_opt.len() != 2 {
+                    bail!("bind-mount needs an argument");
+                }
+                opts.mountpoints.push(PathBuf::from(mount_opt[1]));
+            }
+            "fallback-path" => {
+                if mount_opt.len() != 2 {
+                    bail!("fallback-path needs an argument");
+                }
+                let (path, priority) = parse_fallback_path(mount_opt[1])?;
+                opts.fallback_paths.push(path, priority);
+            }
+            "mode" => {
+                opts.mode = match mount_opt.get(1) {
+                    Some(&"process") => Mode::Process,
+                    Some(&"system") => Mode::System,
+                    Some(&"library") => Mode::Library,
+                    _ => bail!("mode needs to be either process, system or library"),
+                };
+            }
+            "nix-profiles" => opts.nix_profiles = true,
+            "interpreters" => {
+                opts.interpreters = Some(match mount_opt.get(1) {
+                    None => DEFAULT_INTERPRETERS.iter().map(OsString::from).collect(),
+                    Some(names) => {
+                        let names: Vec<&str> = names.split(':').filter(|n| !n.is_empty()).collect();
+                        if names.is_empty() || names.iter().any(|n| n.contains('/')) {
+                            bail!("interpreters needs a colon-separated list of names");
+                        }
+                        names.into_iter().map(OsString::from).collect()
+                    }
+                })
+            }
+            "sysext" => {
+                opts.sysext = Some(match mount_opt.get(1) {
+                    None => DEFAULT_SYSEXT_HIERARCHIES
+                        .iter()
+                        .map(PathBuf::from)
+                        .collect(),
+                    Some(dirs) => {
+                        let dirs: Vec<&str> = dirs.split(':').filter(|d| !d.is_empty()).collect();
+                        if dirs.is_empty() || dirs.iter().any(|d| !d.starts_with('/')) {
+                            bail!("sysext needs a colon-separated list of absolute paths");
+                        }
+                        dirs.into_iter().map(PathBuf::from).collect()
+                    }
+                })
+            }
+            "resolve-symlinks" => opts.resolve_symlinks = true,
+            "trusted-prefix" => match mount_opt.get(1) {
+                Some(path) if path.starts_with('/') => {
+                    opts.trusted_prefixes.push(PathBuf::from(path))
+                }
+                _ => bail!("trusted-prefix needs an absolute path"),
+            },
+            "policy" => match mount_opt.get(1) {
+                None => opts.policy_file = Some(PathBuf::from(DEFAULT_POLICY_FILE)),
+                Some(path) if path.starts_with('/') => opts.policy_file = Some(PathBuf::from(path)),
+                _ => bail!("policy needs an absolute path"),
+            },
+            "refuse-setuid" => opts.refuse_setuid = true,
+            "setuid-prefix" => match mount_opt.get(1) {
+                Some(path) if path.starts_with('/') => {
+                    opts.setuid_prefixes.push(PathBuf::from(path))
+                }
+                _ => bail!("setuid-prefix needs an absolute path"),
+            },
+            "mirror-attr" => opts.mirror_attr = true,
+            "cache-rule" => match mount_opt.get(1) {
+                Some(rule) => opts.cache_rules.push(parse_cache_rule(rule)?),
+                None => bail!("cache-rule needs PREFIX:TTL"),
+            },
+            "expose-dirs" => opts.expose_dirs = true,
+            "subdirs" => match mount_opt.get(1) {
+                None | Some(&"on") => opts.subdirs = true,
+                Some(&"off") => opts.subdirs = false,
+                Some(v) => bail!("subdirs must be on or off, not {}", v),
+            },
+            "prefer-arch" => opts.prefer_arch = true,
+            "check-interp" => opts.check_interp = true,
+            "strip-suffixes" => match mount_opt.get(1) {
+                Some(list) => {
+                    let suffixes: Vec<&str> = list.split(',').filter(|s| !s.is_empty()).collect();
+                    if suffixes.is_empty() || suffixes.iter().any(|s| s.contains('/')) {
+                        bail!("strip-suffixes needs a comma-separated list like .exe,.bat");
+                    }
+                    opts.strip_suffixes = suffixes.into_iter().map(OsString::from).collect();
+                }
+                None => bail!("strip-suffixes needs an argument"),
+            },
+            "underlay" => opts.underlay = true,
+            "static-entries" => {
+                if mount_opt.len() != 2 {
+                    bail!("static-entries needs an argument");
+                }
+                opts.static_entries = Some(PathBuf::from(mount_opt[1]));
+            }
+            "empty-path" => {
+                opts.empty_path = match mount_opt.get(1) {
+                    Some(&"cwd") => EmptyPath::Cwd,
+                    Some(&"ignore") => EmptyPath::Ignore,
+                    _ => bail!("empty-path needs to be either cwd or ignore"),
+                };
+            }
+            "default-path" => {
+                if mount_opt.len() != 2 {
+                    bail!("default-path needs an argument");
+                }
+                opts.default_path = Some(mount_opt[1].to_string());
+            }
+            "syscall-timeout" => match mount_opt.get(1).and_then(|v| v.parse::<u64>().ok()) {
+                Some(ms) => opts.syscall_timeout = Duration::from_millis(ms),
+                None => bail!("syscall-timeout needs a time in milliseconds"),
+            },
+            "prefetch" => {
+                let names: Vec<&str> = mount_opt.get(1).map_or(vec![], |names| {
+                    names.split(':').filter(|n| !n.is_empty()).collect()
+                });
+                if names.is_empty() || names.iter().any(|n| n.contains('/')) {
+                    bail!("prefetch needs a colon-separated list of names");
+                }
+                opts.prefetch.extend(names.into_iter().map(OsString::from));
+            }
+            "prefetch-file" => match mount_opt.get(1) {
+                Some(path) => opts.prefetch_file = Some(PathBuf::from(path)),
+                None => bail!("prefetch-file needs a path"),
+            },
+            "cache-file" => {
+                opts.cache_file = Some(PathBuf::from(
+                    mount_opt.get(1).copied().unwrap_or(DEFAULT_CACHE_FILE),
+                ))
+            }
+            "lookup-deadline" => match mount_opt.get(1).and_then(|v| v.parse::<u64>().ok()) {
+                Some(0) => opts.lookup_deadline = None,
+                Some(ms) => opts.lookup_deadline = Some(Duration::from_millis(ms)),
+                None => bail!("lookup-deadline needs a time in milliseconds"),
+            },
+            "threads" => match mount_opt.get(1).and_then(|v| v.parse::<usize>().ok()) {
+                Some(n) if n > 0 => opts.threads = Some(n),
+                _ => bail!("threads needs a positive number"),
+            },
+            "io-uring" => opts.io_uring = true,
+            "no-mem-read" => opts.no_mem_read = true,
+            "early-boot" => opts.early_boot = true,
+            "nofile" => match mount_opt.get(1).and_then(|v| parse_limit(v)) {
+                Some(n) => opts.nofile = Some(n),
+                None => bail!("nofile needs a positive number or 'max'"),
+            },
+            "memlock" => match mount_opt.get(1).and_then(|v| parse_limit(v)) {
+                Some(n) => opts.memlock = Some(n),
+                None => bail!("memlock needs a positive number of bytes or 'max'"),
+            },
+            "max-inodes" => match mount_opt.get(1).and_then(|v| v.parse::<usize>().ok()) {
+                Some(n) if n > 0 => opts.max_inodes = Some(n),
+                _ => bail!("max-inodes needs a positive number"),
+            },
+            "recent-events" => match mount_opt.get(1).and_then(|v| v.parse::<usize>().ok()) {
+                Some(n) => opts.recent_events = Some(n),
+                None => bail!("recent-events needs a number"),
+            },
+            "inode-gc" => match mount_opt.get(1).and_then(|v| v.parse::<u64>().ok()) {
+                Some(0) => opts.inode_gc = None,
+                Some(secs) => opts.inode_gc = Some(Duration::from_secs(secs)),
+                None => bail!("inode-gc needs an interval in seconds"),
+            },
+            "repair-mounts" => match mount_opt.get(1).and_then(|v| v.parse::<u64>().ok()) {
+                Some(0) => opts.repair_mounts = None,
+                Some(secs) => opts.repair_mounts = Some(Duration::from_secs(secs)),
+                None => bail!("repair-mounts needs an interval in seconds"),
+            },
+            "rate-limit" => match mount_opt.get(1).and_then(|v| v.parse::<u32>().ok()) {
+                Some(n) if n > 0 => opts.rate_limit = Some(n),
+                _ => bail!("rate-limit needs a positive number of lookups per second"),
+            },
+            "rate-limit-burst" => match mount_opt.get(1).and_then(|v| v.parse::<u32>().ok()) {
+                Some(n) if n > 0 => opts.rate_limit_burst = Some(n),
+                _ => bail!("rate-limit-burst needs a positive number"),
+            },
+            "rate-limit-cooldown" => match mount_opt.get(1).and_then(|v| v.parse::<u64>().ok()) {
+                Some(secs) => opts.rate_limit_cooldown = Duration::from_secs(secs),
+                None => bail!("rate-limit-cooldown needs a time in seconds"),
+            },
+            "upgrade" => opts.upgrade = true,
+            "conflict" => {
+                opts.conflict = match mount_opt.get(1) {
+                    Some(&"error") => Conflict::Error,
+                    Some(&"takeover") => Conflict::Takeover,
+                    Some(&"ignore") => Conflict::Ignore,
+                    _ => bail!("conflict needs to be either error, takeover or ignore"),
+                };
+            }
+            "run-as" => match mount_opt.get(1) {
+                Some(user) if !user.is_empty() => opts.run_as = Some(user.to_string()),
+                _ => bail!("run-as needs a user"),
+            },
+            "proc-helper" => opts.proc_helper = true,
+            "sandbox" => match mount_opt.get(1) {
+                None | Some(&"on") => opts.sandbox = true,
+                Some(&"off") => opts.sandbox = false,
+                Some(v) => bail!("sandbox must be on or off, not {}", v),
+            },
+            "sandbox-path" => match mount_opt.get(1) {
+                Some(path) if path.starts_with('/') => opts.sandbox_paths.push(PathBuf::from(path)),
+                _ => bail!("sandbox-path needs an absolute path"),
+            },
+            "watchdog" => match mount_opt.get(1).and_then(|v| v.parse::<u64>().ok()) {
+                Some(secs) if secs > 0 => opts.watchdog = Some(Duration::from_secs(secs)),
+                _ => bail!("watchdog needs an interval in seconds"),
+            },
+            "watchdog-stat" => opts.watchdog_stat = true,
+            "watchdog-restart" => opts.watchdog_restart = true,
+            "abort-on-panic" => match mount_opt.get(1) {
+                None | Some(&"true") => opts.abort_on_panic = true,
+                Some(&"false") => opts.abort_on_panic = false,
+                Some(v) => bail!("abort-on-panic must be true or false, not {}", v),
+            },
+            "allow-syscalls" => match mount_opt.get(1) {
+                Some(list) => opts.allowed_syscalls = AllowedSyscalls::parse(list)?,
+                None => bail!("allow-syscalls needs an argument"),
+            },
+            "resolve-hook" => {
+                if mount_opt.len() != 2 {
+                    bail!("resolve-hook needs an argument");
+                }
+                opts.resolve_hook = Some(PathBuf::from(mount_opt[1]));
+            }
+            "control-socket" => {
+                if mount_opt.len() != 2 {
+                    bail!("control-socket needs an argument");
+                }
+                opts.control_socket = Some(PathBuf::from(mount_opt[1]));
+            }
+            "varlink" => {
+                opts.varlink = true;
+                match mount_opt.get(1) {
+                    Some(path) if path.starts_with('/') => {
+                        opts.varlink_socket = Some(PathBuf::from(path))
+                    }
+                    Some(_) => bail!("varlink needs an absolute path"),
+                    None => {}
+                }
+            }
+            "dbus" => match mount_opt.get(1) {
+                None | Some(&"system") => opts.dbus = Some(BusKind::System),
+                Some(&"session") => opts.dbus = Some(BusKind::Session),
+                Some(v) => bail!("dbus must be system or session, not {}", v),
+            },
+            "otlp-endpoint" => {
+                opts.otlp = true;
+                opts.otlp_endpoint = mount_opt.get(1).map(|url| url.to_string());
+            }
+            "pidfile" => {
+                if mount_opt.len() != 2 {
+                    bail!("pidfile needs an argument");
+                }
+                opts.pidfile = Some(PathBuf::from(mount_opt[1]));
+            }
+            "audit-log" => {
+                if mount_opt.len() != 2 {
+                    bail!("audit-log needs an argument");
+                }
+                opts.audit_log = Some(PathBuf::from(mount_opt[1]));
+            }
+            "audit-log-max-size" => match mount_opt.get(1).and_then(|v| v.parse::<u64>().ok()) {
+                Some(size) => opts.audit_log_max_size = size,
+                None => bail!("audit-log-max-size needs a size in bytes"),
+            },
+            "audit-log-keep" => match mount_opt.get(1).and_then(|v| v.parse::<usize>().ok()) {
+                Some(keep) => opts.audit_log_keep = keep,
+                None => bail!("audit-log-keep needs a number"),
+            },
+            _ => {
+                eprintln!("ignore invalid mount option: {}", mount_opt[0]);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn parse_options(args: &[String], mount_helper: bool) -> Result<Options> {
+    let mut i: usize = 0;
+    let mut opts = Options::new(mount_helper);
+    loop {
+        if i >= args.len() {
+            return Ok(opts);
+        }
+        match args[i].as_ref() {
+            "-h" | "--help" => {
+                opts.show_help = true;
+                return Ok(opts);
+            }
+            "-f" if opts.mount_helper => {
+                opts.fake = true;
+            }
+            "-f" | "--foreground" => {
+                opts.foreground = true;
+            }
+            "-s" | "-n" | "-v" if opts.mount_helper => {}
+            "-t" if opts.mount_helper => {
+                i += 1;
+                if i >= args.len() {
+                    bail!("'-t' requires an argument");
+                }
+            }
+            "-o" => {
+                i += 1;
+                if i >= args.len() {
+                    bail!("'-o' requires an argument");
+                }
+                parse_mount_options(&args[i], &mut opts)?;
+                opts.mount_options.push(args[i].clone());
+            }
+            _ => {
+                if args[i].starts_with('-') && args[i] != "--" {
+                    bail!("unrecognized argument '{}'", args[i]);
+                }
+                if args[i] == "--" {
+                    opts.args.extend_from_slice(&args[i + 1..]);
+                    return Ok(opts);
+                }
+                opts.args.push(String::from(args[i].as_str()));
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Options of the subcommands talking to a running instance.
+pub struct CommandOptions {
+    pub socket: Option<PathBuf>,
+    pub mountpoint: Option<PathBuf>,
+    pub pid: Option<i32>,
+    /// PATH to resolve against instead of the one of a process
+    pub path: Option<String>,
+    pub fallback_paths: FallbackPaths,
+    pub local: bool,
+    /// `status` also shows the last resolutions
+    pub recent: bool,
+    pub top: usize,
+    pub show_help: bool,
+    pub args: Vec<String>,
+}
+
+pub fn parse_command_options(args: &[String]) -> Result<CommandOptions> {
+    let mut opts = CommandOptions {
+        socket: None,
+        mountpoint: None,
+        pid: None,
+        path: None,
+        fallback_paths: FallbackPaths::default(),
+        local: false,
+        recent: false,
+        top: 10,
+        show_help: false,
+        args: vec![],
+    };
+    let mut i: usize = 0;
+    while i < args.len() {
+        match args[i].as_ref() {
+            "-h" | "--help" => {
+                opts.show_help = true;
+                return Ok(opts);
+            }
+            "--local" => {
+                opts.local = true;
+            }
+            "--recent" => {
+                opts.recent = true;
+            }
+            "--socket" | "--mountpoint" | "--pid" | "--path" | "--fallback-path" | "--top" => {
+                if i + 1 >= args.len() {
+                    bail!("'{}' requires an argument", args[i]);
+                }
+                let value = &args[i + 1];
+                match args[i].as_ref() {
+                    "--socket" => opts.socket = Some(PathBuf::from(value)),
+                    "--mountpoint" => opts.mountpoint = Some(PathBuf::from(value)),
+                    "--path" => opts.path = Some(value.clone()),
+                    "--fallback-path" => {
+                        let (path, priority) = parse_fallback_path(value)?;
+                        opts.fallback_paths.push(path, priority);
+                    }
+                    "--top" => match value.parse::<usize>() {
+                        Ok(n) => opts.top = n,
+                        Err(_) => bail!("invalid number '{}'", value),
+                    },
+                    _ => match value.parse::<i32>() {
+                        Ok(pid) => opts.pid = Some(pid),
+                        Err(_) => bail!("invalid pid '{}'", value),
+                    },
+                }
+                i += 1;
+            }
+            arg => {
+                if arg.starts_with('-') {
+                    bail!("unrecognized argument '{}'", arg);
+                }
+                opts.args.push(arg.to_string());
+            }
+        }
+        i += 1;
+    }
+    Ok(opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_rules() {
+        let mut opts = Options::new(false);
+        parse_mount_options("cache-rule=/nix/store:infinite,/home:5s,ro", &mut opts).unwrap();
+        let rules: Vec<_> = opts
+            .cache_rules
+            .iter()
+            .map(|r| (r.prefix.to_str().unwrap(), r.ttl))
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                ("/nix/store", Duration::MAX),
+                ("/home", Duration::from_secs(5))
+            ]
+        );
+        assert_eq!(parse_ttl("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_ttl("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_ttl("5x"), None);
+        assert!(parse_cache_rule("home:5s").is_err());
+    }
+
+    #[test]
+    fn test_repair_mounts() {
+        let mut opts = Options::new(false);
+        assert_eq!(opts.repair_mounts, Some(DEFAULT_REPAIR_MOUNTS));
+        parse_mount_options("repair-mounts=3", &mut opts).unwrap();
+        assert_eq!(opts.repair_mounts, Some(Duration::from_secs(3)));
+        parse_mount_options("repair-mounts=0", &mut opts).unwrap();
+        assert_eq!(opts.repair_mounts, None);
+        assert!(parse_mount_options("repair-mounts", &mut opts).is_err());
+        assert!(parse_mount_options("repair-mounts=-1", &mut opts).is_err());
+    }
+}
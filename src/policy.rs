@@ -0,0 +1,311 @@
+//! Per-user restrictions on which names are resolved and where they may point.
+//!
+//! Each line of the policy file is a rule of space separated `key=value` pairs:
+//!
+//! ```text
+//! # untrusted users only get a few programs, and only from the system profile
+//! uid=1000-59999 names=bash,sh,python3* prefix=/run/current-system prefix=/nix/store
+//! uid=60000-65535 names-regex=python3\.[0-9]+ deny=*-config
+//! gid=100 names=*
+//! ```
+//!
+//! `uid` and `gid` take a number or an inclusive range, `gid` matches the
+//! primary and supplementary groups of the caller. `names` is a comma
+//! separated list of patterns where `*` matches any number and `?` a single
+//! character, `names-regex` a regular expression that has to match the whole
+//! name. Names matching `deny` or `deny-regex` are refused even if they match
+//! `names`. The first rule whose `uid` and `gid` match is applied, callers
+//! without a matching rule are not restricted.
+
+use log::{info, warn};
+use regex_lite::Regex;
+use simple_error::{bail, try_with};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::result::Result;
+
+pub const DEFAULT_POLICY_FILE: &str = "/etc/envfs.policy";
+
+/// How often the policy file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Rule {
+    /// Line in the policy file, for traces
+    line: usize,
+    uids: Option<(u32, u32)>,
+    gids: Option<(u32, u32)>,
+    /// `None` allows all names
+    names: Option<Vec<Pattern>>,
+    denied_names: Vec<Pattern>,
+    pub trusted_prefixes: Vec<PathBuf>,
+}
+
+impl Rule {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    fn matches(&self, uid: u32, gid: u32, groups: &[u32]) -> bool {
+        let in_range = |(start, end): (u32, u32), id: u32| start <= id && id <= end;
+        if let Some(uids) = self.uids {
+            if !in_range(uids, uid) {
+                return false;
+            }
+        }
+        match self.gids {
+            Some(gids) => std::iter::once(&gid)
+                .chain(groups)
+                .any(|gid| in_range(gids, *gid)),
+            None => true,
+        }
+    }
+
+    pub fn allows_name(&self, name: &OsStr) -> bool {
+        if self
+            .denied_names
+            .iter()
+            .any(|pattern| pattern.matches(name))
+        {
+            return false;
+        }
+        match self.names {
+            Some(ref patterns) => patterns.iter().any(|pattern| pattern.matches(name)),
+            None => true,
+        }
+    }
+}
+
+enum Pattern {
+    Glob(Vec<u8>),
+    /// Anchored at both ends
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn regex(value: &str) -> std::result::Result<Pattern, regex_lite::Error> {
+        Regex::new(&format!("^(?:{})$", value)).map(Pattern::Regex)
+    }
+
+    fn matches(&self, name: &OsStr) -> bool {
+        match self {
+            Pattern::Glob(pattern) => glob_match(pattern, name.as_bytes()),
+            // names that are not UTF-8 never match
+            Pattern::Regex(regex) => name.to_str().is_some_and(|name| regex.is_match(name)),
+        }
+    }
+}
+
+fn parse_globs(value: &str) -> impl Iterator<Item = Pattern> + '_ {
+    value
+        .split(',')
+        .filter(|n| !n.is_empty())
+        .map(|n| Pattern::Glob(n.as_bytes().to_vec()))
+}
+
+/// Matches `name` against a pattern with `*` and `?` wildcards.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+fn parse_range(value: &str) -> Option<(u32, u32)> {
+    let (start, end) = match value.split_once('-') {
+        Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+        None => {
+            let id = value.parse().ok()?;
+            (id, id)
+        }
+    };
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+#[derive(Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn parse(content: &str, path: &Path) -> Result<Policy> {
+        let mut rules = vec![];
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut rule = Rule {
+                line: i + 1,
+                uids: None,
+                gids: None,
+                names: None,
+                denied_names: vec![],
+                trusted_prefixes: vec![],
+            };
+            for field in line.split_whitespace() {
+                let (key, value) = match field.split_once('=') {
+                    Some(pair) => pair,
+                    None => bail!("{}:{}: expected KEY=VALUE", path.display(), i + 1),
+                };
+                match key {
+                    "uid" | "gid" => {
+                        let range = match parse_range(value) {
+                            Some(range) => range,
+                            None => bail!("{}:{}: invalid {} range", path.display(), i + 1, key),
+                        };
+                        if key == "uid" {
+                            rule.uids = Some(range);
+                        } else {
+                            rule.gids = Some(range);
+                        }
+                    }
+                    "names" => rule
+                        .names
+                        .get_or_insert_with(Vec::new)
+                        .extend(parse_globs(value)),
+                    "deny" => rule.denied_names.extend(parse_globs(value)),
+                    "names-regex" | "deny-regex" => {
+                        let pattern = match Pattern::regex(value) {
+                            Ok(pattern) => pattern,
+                            Err(e) => bail!("{}:{}: invalid {}: {}", path.display(), i + 1, key, e),
+                        };
+                        if key == "names-regex" {
+                            rule.names.get_or_insert_with(Vec::new).push(pattern);
+                        } else {
+                            rule.denied_names.push(pattern);
+                        }
+                    }
+                    "prefix" if value.starts_with('/') => {
+                        rule.trusted_prefixes.push(PathBuf::from(value));
+                    }
+                    "prefix" => bail!("{}:{}: prefix must be absolute", path.display(), i + 1),
+                    _ => bail!("{}:{}: unknown key {}", path.display(), i + 1, key),
+                }
+            }
+            rules.push(rule);
+        }
+        Ok(Policy { rules })
+    }
+
+    /// The rule applied to a caller, `None` if it is not restricted.
+    pub fn rule_for(&self, uid: u32, gid: u32, groups: &[u32]) -> Option<&Rule> {
+        self.rules.iter().find(|r| r.matches(uid, gid, groups))
+    }
+}
+
+struct State {
+    policy: Arc<Policy>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+/// A policy that is reloaded when its file changes.
+pub struct PolicyFile {
+    path: PathBuf,
+    state: Mutex<State>,
+    /// Number of times a changed file was loaded
+    reloads: AtomicU64,
+}
+
+/// Reads `path`, a missing file is an empty policy so that it can be created later.
+fn load(path: &Path) -> Result<(Policy, Option<SystemTime>)> {
+    let modified = match fs::metadata(path) {
+        Ok(stat) => stat.modified().ok(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Policy::default(), None)),
+        Err(e) => bail!("cannot stat {}: {}", path.display(), e),
+    };
+    let content = try_with!(fs::read_to_string(path), "cannot read {}", path.display());
+    Ok((Policy::parse(&content, path)?, modified))
+}
+
+impl PolicyFile {
+    pub fn open(path: &Path) -> Result<PolicyFile> {
+        let (policy, modified) = load(path)?;
+        Ok(PolicyFile {
+            path: path.to_path_buf(),
+            state: Mutex::new(State {
+                policy: Arc::new(policy),
+                modified,
+                checked: Instant::now(),
+            }),
+            reloads: AtomicU64::new(0),
+        })
+    }
+
+    /// Number of times the policy changed since the file was opened.
+    pub fn reloads(&self) -> u64 {
+        self.reloads.load(Ordering::SeqCst)
+    }
+
+    /// Returns the current policy, reloading the file if it changed.
+    ///
+    /// If the new content is invalid, the previous policy stays in effect.
+    pub fn current(&self) -> Arc<Policy> {
+        let mut state = self.state.lock().unwrap();
+        if state.checked.elapsed() < RELOAD_INTERVAL {
+            return Arc::clone(&state.policy);
+        }
+        state.checked = Instant::now();
+        let modified = fs::metadata(&self.path)
+            .ok()
+            .and_then(|s| s.modified().ok());
+        if modified != state.modified {
+            match load(&self.path) {
+                Ok((policy, modified)) => {
+                    info!("reloaded policy from {}", self.path.display());
+                    state.policy = Arc::new(policy);
+                    state.modified = modified;
+                    self.reloads.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(e) => {
+                    warn!("keeping previous policy: {}", e);
+                    // do not retry until the file changes again
+                    state.modified = modified;
+                }
+            }
+        }
+        Arc::clone(&state.policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_patterns() {
+        let policy = Policy::parse(
+            "uid=1000 names=sh,python3* names-regex=perl5\\.[0-9]+ deny=python3-config\n\
+             uid=2000 deny-regex=.*-config\n",
+            Path::new("policy"),
+        )
+        .unwrap();
+        let allows = |uid, name| {
+            policy
+                .rule_for(uid, uid, &[])
+                .unwrap()
+                .allows_name(OsStr::new(name))
+        };
+        assert!(allows(1000, "sh"));
+        assert!(allows(1000, "python3.12"));
+        assert!(!allows(1000, "python3-config"));
+        assert!(allows(1000, "perl5.36"));
+        assert!(!allows(1000, "xperl5.36"));
+        assert!(!allows(1000, "bash"));
+        assert!(allows(2000, "bash"));
+        assert!(!allows(2000, "pkg-config"));
+        assert!(Policy::parse("names-regex=(", Path::new("policy")).is_err());
+    }
+}
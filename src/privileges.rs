@@ -0,0 +1,120 @@
+//! Running the daemon as an unprivileged user once the filesystem is mounted.
+
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::mount::{umount2, MntFlags};
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::unistd::{self, ForkResult, User};
+use simple_error::{bail, try_with};
+use std::fs;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::process;
+
+use crate::caps::{self, CAP_DAC_READ_SEARCH, CAP_SYS_PTRACE};
+use crate::result::Result;
+
+/// Capabilities kept after switching users, needed to read the environment,
+/// system call and memory of processes of other users.
+const RETAINED_CAPS: [u32; 2] = [CAP_SYS_PTRACE, CAP_DAC_READ_SEARCH];
+
+/// Capabilities kept by `run_as`.
+fn retained_caps(retain_caps: bool) -> &'static [u32] {
+    if retain_caps {
+        &RETAINED_CAPS
+    } else {
+        &[]
+    }
+}
+
+/// Switches all ids of the process to `name` and drops all capabilities not in
+/// `RETAINED_CAPS`, or all of them unless `retain_caps`, when a helper reads `/proc`.
+///
+/// Capabilities are per thread, so this has to be called before any thread is started.
+pub fn run_as(name: &str, retain_caps: bool) -> Result<()> {
+    let retained = retained_caps(retain_caps);
+    let user = match try_with!(User::from_name(name), "cannot look up user {}", name) {
+        Some(user) => user,
+        None => bail!("no such user: {}", name),
+    };
+    caps::drop_bounding_set(retained)?;
+
+    // keep the permitted capabilities across the change of uid
+    let res = unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) };
+    try_with!(Errno::result(res), "cannot set keep-caps");
+    try_with!(unistd::setgroups(&[user.gid]), "cannot set groups");
+    try_with!(
+        unistd::setresgid(user.gid, user.gid, user.gid),
+        "cannot switch to group {}",
+        user.gid
+    );
+    try_with!(
+        unistd::setresuid(user.uid, user.uid, user.uid),
+        "cannot switch to user {}",
+        name
+    );
+    let res = unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) };
+    try_with!(Errno::result(res), "cannot clear keep-caps");
+
+    try_with!(caps::set(retained), "cannot set capabilities");
+    info!("running as {} ({})", name, user.uid);
+    Ok(())
+}
+
+/// Closes all file descriptors but `keep`, in particular /dev/fuse, whose
+/// connection would otherwise outlive the daemon.
+pub(crate) fn close_fds_except(keep: RawFd) {
+    let fds: Vec<RawFd> = match fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|e| e.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(_) => return,
+    };
+    for fd in fds.into_iter().filter(|fd| *fd > 2 && *fd != keep) {
+        let _ = unistd::close(fd);
+    }
+}
+
+/// Forks a process that keeps the privileges of the caller and unmounts
+/// `mountpoints` in order once the calling process exits, which can no
+/// longer do it itself after `run_as`.
+pub fn spawn_unmount_helper(mountpoints: Vec<PathBuf>) -> Result<()> {
+    let (read_end, write_end): (OwnedFd, OwnedFd) = try_with!(unistd::pipe(), "cannot create pipe");
+    match try_with!(unsafe { unistd::fork() }, "cannot fork unmount helper") {
+        ForkResult::Parent { .. } => {
+            // closed when the daemon exits, including crashes
+            std::mem::forget(write_end);
+            Ok(())
+        }
+        ForkResult::Child => {
+            drop(write_end);
+            close_fds_except(read_end.as_raw_fd());
+            // a service manager stopping envfs signals all of its processes
+            for sig in [Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP] {
+                let _ = unsafe { signal::signal(sig, SigHandler::SigIgn) };
+            }
+            let mut buf = [0u8; 1];
+            while let Err(Errno::EINTR) = unistd::read(read_end.as_raw_fd(), &mut buf) {}
+            for mountpoint in &mountpoints {
+                match umount2(mountpoint, MntFlags::MNT_DETACH) {
+                    Ok(()) | Err(Errno::EINVAL) => {}
+                    Err(e) => warn!("cannot unmount {}: {}", mountpoint.display(), e),
+                }
+            }
+            process::exit(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retained_caps() {
+        assert_eq!(retained_caps(true), [CAP_SYS_PTRACE, CAP_DAC_READ_SEARCH]);
+        // with the /proc helper envfs keeps no capabilities at all
+        assert!(retained_caps(false).is_empty());
+    }
+}
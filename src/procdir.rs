@@ -0,0 +1,452 @@
+//! Access to the files and memory of processes.
+//!
+//! Resolution reads `/proc` through the `Procfs` and `ProcReader` traits, so
+//! that tests can replace it with the in-memory `fake::FakeProcfs`.
+//!
+//! The real implementation opens `/proc/<pid>` once, which saves the path
+//! lookup of `/proc/<pid>` for every file read during a request, and makes
+//! sure all of them belong to the same process even if its pid is reused in
+//! between.
+
+use log::warn;
+use nix::errno::Errno;
+use nix::fcntl::{self, OFlag};
+use nix::sys::stat::Mode;
+use nix::sys::statfs::{statfs, PROC_SUPER_MAGIC};
+use nix::sys::uio::{process_vm_readv, RemoteIoVec};
+use nix::unistd::{self, Pid};
+use simple_error::{bail, try_with};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{IoSliceMut, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Once;
+
+use crate::prochelper;
+use crate::result::Result;
+use crate::spans::{self, Span};
+use crate::uring;
+
+static IO_URING_WARNING: Once = Once::new();
+
+/// Opens processes, `RealProcfs` for the actual `/proc`.
+pub trait Procfs {
+    fn open(&self, pid: Pid) -> Result<Box<dyn ProcReader>>;
+}
+
+/// Files below `/proc/<pid>` and the memory of one process.
+pub trait ProcReader {
+    fn pid(&self) -> Pid;
+
+    /// Reads `file`, a path relative to `/proc/<pid>`.
+    fn read(&self, file: &str) -> Result<Vec<u8>>;
+
+    /// Reads at most `len` bytes from the start of `file`.
+    fn read_head(&self, file: &str, len: usize) -> Result<Vec<u8>>;
+
+    fn read_link(&self, file: &str) -> Result<PathBuf>;
+
+    /// Reads the given ranges of the memory of the process in as few system calls as possible.
+    ///
+    /// Unreadable ranges are returned empty and ranges that become unreadable
+    /// halfway are truncated.
+    fn read_mem(&self, ranges: &[RemoteIoVec]) -> Result<Vec<Vec<u8>>>;
+
+    /// Hints that `files` are read next, so that they can be read in one batch.
+    fn prefetch(&self, _files: &[&str]) {}
+
+    fn read_to_string(&self, file: &str) -> Result<String> {
+        let content = self.read(file)?;
+        Ok(try_with!(
+            String::from_utf8(content),
+            "/proc/{}/{} is not valid utf-8",
+            self.pid(),
+            file
+        ))
+    }
+}
+
+/// Whether procfs is mounted on `/proc` and shows the processes of our pid namespace.
+pub(crate) fn is_mounted() -> bool {
+    statfs("/proc").is_ok_and(|stat| stat.filesystem_type() == PROC_SUPER_MAGIC)
+        && std::path::Path::new("/proc/self/stat").exists()
+}
+
+/// How the pid namespace of `/proc` relates to the one envfs mounted in, whose
+/// pids the kernel passes with requests.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum PidNamespace {
+    /// `/proc` shows the pids of requests
+    Same,
+    /// `/proc` belongs to another pid namespace, pids are translated through a pidfd
+    Translated,
+    /// `/proc` belongs to another pid namespace and the kernel cannot translate pids
+    Foreign,
+}
+
+/// `PidNamespace` as detected by `detect_pid_namespace`.
+static PID_NAMESPACE: AtomicU8 = AtomicU8::new(PidNamespace::Same as u8);
+
+/// Pid of the process `pid` of our pid namespace in the one of `/proc`, read
+/// from the fdinfo of a pidfd. Fails with `ESRCH` if it is not visible there.
+fn pidfd_pid(pid: Pid) -> nix::Result<Pid> {
+    if pid.as_raw() <= 0 {
+        return Err(Errno::ESRCH);
+    }
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if fd < 0 {
+        return Err(Errno::last());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd.as_raw_fd()))
+        .map_err(|e| Errno::from_raw(e.raw_os_error().unwrap_or(libc::EIO)))?;
+    // 0 if the process is not visible in the namespace of /proc, -1 once it exited
+    match fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Pid:"))
+        .and_then(|pid| pid.trim().parse::<i32>().ok())
+    {
+        Some(pid) if pid > 0 => Ok(Pid::from_raw(pid)),
+        Some(_) => Err(Errno::ESRCH),
+        None => Err(Errno::ENOSYS),
+    }
+}
+
+/// Compares the pid namespace of `/proc` with ours, e.g. when the `/proc` of
+/// the host is mounted into the container envfs runs in.
+pub(crate) fn detect_pid_namespace() -> PidNamespace {
+    let ours = unistd::getpid();
+    let shown = std::fs::read_link("/proc/self")
+        .ok()
+        .and_then(|link| link.to_str()?.parse::<i32>().ok());
+    let ns = match shown {
+        Some(shown) if shown != ours.as_raw() => {
+            // older kernels show the pid in the namespace of the reader instead
+            if pidfd_pid(ours) == Ok(Pid::from_raw(shown)) {
+                PidNamespace::Translated
+            } else {
+                PidNamespace::Foreign
+            }
+        }
+        _ => PidNamespace::Same,
+    };
+    PID_NAMESPACE.store(ns as u8, Ordering::Relaxed);
+    ns
+}
+
+/// What `detect_pid_namespace` found last.
+pub(crate) fn pid_namespace() -> PidNamespace {
+    match PID_NAMESPACE.load(Ordering::Relaxed) {
+        ns if ns == PidNamespace::Translated as u8 => PidNamespace::Translated,
+        ns if ns == PidNamespace::Foreign as u8 => PidNamespace::Foreign,
+        _ => PidNamespace::Same,
+    }
+}
+
+/// Pid under `/proc` of `pid` from a request, `None` if the process cannot
+/// be found there. Reading `/proc/<pid>` directly would show another process
+/// if the pid namespaces differ.
+pub(crate) fn translate_pid(pid: Pid) -> Option<Pid> {
+    match pid_namespace() {
+        PidNamespace::Same => Some(pid),
+        PidNamespace::Translated => pidfd_pid(pid).ok(),
+        PidNamespace::Foreign => None,
+    }
+}
+
+pub struct RealProcfs;
+
+impl Procfs for RealProcfs {
+    fn open(&self, pid: Pid) -> Result<Box<dyn ProcReader>> {
+        if prochelper::is_enabled() {
+            return prochelper::open(pid);
+        }
+        Ok(Box::new(ProcDir::open(pid)?))
+    }
+}
+
+pub struct ProcDir {
+    pid: Pid,
+    fd: OwnedFd,
+    /// Contents read ahead by `prefetch`, each is handed out once
+    prefetched: RefCell<Vec<(String, Vec<u8>)>>,
+}
+
+/// Files read ahead by `prefetch` are at most this large, longer ones are read again.
+const PREFETCH_LIMIT: usize = 4096;
+
+impl ProcDir {
+    pub fn open(pid: Pid) -> Result<ProcDir> {
+        let path = format!("/proc/{}", pid.as_raw());
+        let fd = try_with!(
+            fcntl::open(
+                path.as_str(),
+                OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            ),
+            "failed to open {}",
+            path
+        );
+        Ok(ProcDir {
+            pid,
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            prefetched: RefCell::new(vec![]),
+        })
+    }
+
+    /// Opens `file`, a path relative to `/proc/<pid>`.
+    pub fn open_file(&self, file: &str) -> nix::Result<File> {
+        let fd = fcntl::openat(
+            Some(self.fd.as_raw_fd()),
+            file,
+            OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+}
+
+fn file_span(name: &'static str, file: &str) -> Span {
+    let mut span = spans::span(name);
+    span.attr("proc.file", file);
+    span
+}
+
+fn fail_on_error<T, E: std::fmt::Display>(span: &mut Span, res: &std::result::Result<T, E>) {
+    if let Err(e) = res {
+        span.fail(|| e.to_string());
+    }
+}
+
+impl ProcReader for ProcDir {
+    fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn read(&self, file: &str) -> Result<Vec<u8>> {
+        {
+            let mut prefetched = self.prefetched.borrow_mut();
+            if let Some(pos) = prefetched.iter().position(|(f, _)| f == file) {
+                return Ok(prefetched.swap_remove(pos).1);
+            }
+        }
+        let mut span = file_span("proc.read", file);
+        let mut content = vec![];
+        let res = self
+            .open_file(file)
+            .map_err(std::io::Error::from)
+            .and_then(|mut f| f.read_to_end(&mut content));
+        fail_on_error(&mut span, &res);
+        try_with!(res, "failed to read /proc/{}/{}", self.pid, file);
+        Ok(content)
+    }
+
+    fn read_head(&self, file: &str, len: usize) -> Result<Vec<u8>> {
+        let mut span = file_span("proc.read", file);
+        let mut content = vec![];
+        let res = self
+            .open_file(file)
+            .map_err(std::io::Error::from)
+            .and_then(|f| f.take(len as u64).read_to_end(&mut content));
+        fail_on_error(&mut span, &res);
+        try_with!(res, "failed to read /proc/{}/{}", self.pid, file);
+        Ok(content)
+    }
+
+    fn read_link(&self, file: &str) -> Result<PathBuf> {
+        let mut span = file_span("proc.readlink", file);
+        let res = fcntl::readlinkat(Some(self.fd.as_raw_fd()), file);
+        fail_on_error(&mut span, &res);
+        let target = try_with!(res, "failed to read /proc/{}/{}", self.pid, file);
+        Ok(PathBuf::from(target))
+    }
+
+    fn read_mem(&self, ranges: &[RemoteIoVec]) -> Result<Vec<Vec<u8>>> {
+        let mut span = spans::span("proc.read_mem");
+        span.attr("proc.ranges", ranges.len() as i64);
+        let pid = self.pid;
+        let mut bufs: Vec<Vec<u8>> = ranges.iter().map(|r| vec![0; r.len]).collect();
+        let mut lens = vec![0; ranges.len()];
+        let mut start = 0;
+        while start < ranges.len() {
+            let end = (start + libc::UIO_MAXIOV as usize).min(ranges.len());
+            let mut local: Vec<IoSliceMut> = bufs[start..end]
+                .iter_mut()
+                .map(|b| IoSliceMut::new(b))
+                .collect();
+            let mut read = match process_vm_readv(pid, &mut local, &ranges[start..end]) {
+                Ok(read) => read,
+                // the first range is not mapped
+                Err(Errno::EFAULT) => 0,
+                Err(e) => {
+                    span.fail(|| e.desc().to_string());
+                    bail!("cannot read memory of process {}: {}", pid, e)
+                }
+            };
+            // Ranges are filled in order, the first one that is not complete
+            // failed and the kernel stopped there.
+            let mut i = start;
+            while i < end && read >= ranges[i].len {
+                lens[i] = ranges[i].len;
+                read -= ranges[i].len;
+                i += 1;
+            }
+            if i < end {
+                lens[i] = read;
+                i += 1;
+            }
+            start = i;
+        }
+        for (buf, len) in bufs.iter_mut().zip(lens) {
+            buf.truncate(len);
+        }
+        Ok(bufs)
+    }
+
+    /// Reads `files` with the io_uring ring of the calling thread, so that
+    /// the next `read` of each of them needs no further system calls.
+    ///
+    /// Files that cannot be read this way are left to `read`.
+    fn prefetch(&self, files: &[&str]) {
+        let res =
+            uring::with_ring(|ring| ring.read_files(self.fd.as_raw_fd(), files, PREFETCH_LIMIT));
+        let contents = match res {
+            Ok(contents) => contents,
+            Err(e) => {
+                IO_URING_WARNING.call_once(|| {
+                    warn!(
+                        "io_uring is not available, reading /proc files one by one: {}",
+                        e
+                    )
+                });
+                return;
+            }
+        };
+        let mut prefetched = self.prefetched.borrow_mut();
+        for (file, content) in files.iter().zip(contents) {
+            match content {
+                Ok(content) if content.len() < PREFETCH_LIMIT => {
+                    prefetched.push((file.to_string(), content))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    //! In-memory processes for tests of the resolution logic.
+
+    use nix::sys::uio::RemoteIoVec;
+    use nix::unistd::Pid;
+    use simple_error::bail;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    use super::{ProcReader, Procfs};
+    use crate::result::Result;
+
+    #[derive(Default)]
+    pub struct FakeProcess {
+        files: HashMap<String, Vec<u8>>,
+        links: HashMap<String, PathBuf>,
+        /// Readable memory as start address and content
+        mem: Vec<(usize, Vec<u8>)>,
+    }
+
+    impl FakeProcess {
+        pub fn file<C: Into<Vec<u8>>>(mut self, name: &str, content: C) -> Self {
+            self.files.insert(name.to_string(), content.into());
+            self
+        }
+
+        pub fn link<P: Into<PathBuf>>(mut self, name: &str, target: P) -> Self {
+            self.links.insert(name.to_string(), target.into());
+            self
+        }
+
+        pub fn mem<C: Into<Vec<u8>>>(mut self, addr: usize, content: C) -> Self {
+            self.mem.push((addr, content.into()));
+            self
+        }
+    }
+
+    #[derive(Default)]
+    pub struct FakeProcfs {
+        processes: HashMap<i32, Rc<FakeProcess>>,
+    }
+
+    impl FakeProcfs {
+        pub fn process(mut self, pid: i32, process: FakeProcess) -> Self {
+            self.processes.insert(pid, Rc::new(process));
+            self
+        }
+    }
+
+    impl Procfs for FakeProcfs {
+        fn open(&self, pid: Pid) -> Result<Box<dyn ProcReader>> {
+            match self.processes.get(&pid.as_raw()) {
+                Some(process) => Ok(Box::new(FakeReader {
+                    pid,
+                    process: Rc::clone(process),
+                })),
+                None => bail!("no process {}", pid),
+            }
+        }
+    }
+
+    struct FakeReader {
+        pid: Pid,
+        process: Rc<FakeProcess>,
+    }
+
+    impl ProcReader for FakeReader {
+        fn pid(&self) -> Pid {
+            self.pid
+        }
+
+        fn read(&self, file: &str) -> Result<Vec<u8>> {
+            match self.process.files.get(file) {
+                Some(content) => Ok(content.clone()),
+                None => bail!("no {} in process {}", file, self.pid),
+            }
+        }
+
+        fn read_head(&self, file: &str, len: usize) -> Result<Vec<u8>> {
+            let mut content = self.read(file)?;
+            content.truncate(len);
+            Ok(content)
+        }
+
+        fn read_link(&self, file: &str) -> Result<PathBuf> {
+            match self.process.links.get(file) {
+                Some(target) => Ok(target.clone()),
+                None => bail!("no {} in process {}", file, self.pid),
+            }
+        }
+
+        fn read_mem(&self, ranges: &[RemoteIoVec]) -> Result<Vec<Vec<u8>>> {
+            Ok(ranges
+                .iter()
+                .map(|range| {
+                    self.process
+                        .mem
+                        .iter()
+                        .find(|(start, content)| {
+                            *start <= range.base && range.base < start + content.len()
+                        })
+                        .map(|(start, content)| {
+                            let offset = range.base - start;
+                            let end = (offset + range.len).min(content.len());
+                            content[offset..end].to_vec()
+                        })
+                        .unwrap_or_default()
+                })
+                .collect())
+        }
+    }
+}
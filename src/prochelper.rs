@@ -0,0 +1,433 @@
+//! A privileged helper process that reads `/proc` for the daemon.
+//!
+//! When `/proc` is mounted with `hidepid`, the entries of other users'
+//! processes are only visible with `CAP_SYS_PTRACE` or in the `gid=` group of
+//! the mount. With `-o proc-helper` a helper is forked before envfs drops its
+//! privileges and keeps just the capabilities needed to read `/proc`, so that
+//! the daemon itself can run without any.
+//!
+//! The daemon sends requests over a socket pair, each naming a process it
+//! opened before by handle, like `ProcDir` keeps `/proc/<pid>` open. Requests
+//! are answered one at a time. The helper exits once the daemon closes its
+//! end of the socket.
+
+use log::{debug, warn};
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::sys::uio::RemoteIoVec;
+use nix::unistd::{self, ForkResult, Pid};
+use simple_error::{bail, try_with, SimpleError};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+use crate::caps::{self, CAP_DAC_READ_SEARCH, CAP_SYS_PTRACE};
+use crate::privileges;
+use crate::procdir::{ProcDir, ProcReader};
+use crate::result::Result;
+
+/// Capabilities of the helper, enough to read `/proc` of every process.
+const HELPER_CAPS: [u32; 2] = [CAP_SYS_PTRACE, CAP_DAC_READ_SEARCH];
+
+/// Processes the helper keeps open at the same time.
+const MAX_HANDLES: usize = 4096;
+
+/// Bounds requests, which only carry a file name or memory ranges.
+const MAX_REQUEST: usize = 1 << 20;
+
+/// Bounds answers, `environ` alone may be as large as `ARG_MAX`.
+const MAX_RESPONSE: usize = 64 << 20;
+
+const OPEN: u8 = 0;
+const CLOSE: u8 = 1;
+const READ: u8 = 2;
+const READ_HEAD: u8 = 3;
+const READ_LINK: u8 = 4;
+const READ_MEM: u8 = 5;
+
+/// The daemon's end of the socket, `None` unless the helper runs.
+static HELPER: Mutex<Option<UnixStream>> = Mutex::new(None);
+
+/// Returns the `hidepid` option of the mount on `/proc`, unless it shows all processes.
+pub fn hidepid() -> Option<String> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        let (fields, rest) = line.split_once(" - ")?;
+        if fields.split(' ').nth(4)? != "/proc" {
+            return None;
+        }
+        let options = rest.split(' ').nth(2)?;
+        let value = options
+            .split(',')
+            .find_map(|o| o.strip_prefix("hidepid="))?;
+        if value == "0" || value == "off" {
+            None
+        } else {
+            Some(value.to_string())
+        }
+    })
+}
+
+pub fn is_enabled() -> bool {
+    HELPER.lock().unwrap().is_some()
+}
+
+/// Forks the helper, from then on `/proc` is read through it.
+///
+/// Has to be called while envfs still has the privileges the helper should keep.
+pub fn spawn() -> Result<()> {
+    let (daemon, helper) = try_with!(UnixStream::pair(), "cannot create socket pair");
+    match try_with!(unsafe { unistd::fork() }, "cannot fork proc helper") {
+        ForkResult::Parent { .. } => {
+            drop(helper);
+            *HELPER.lock().unwrap() = Some(daemon);
+            Ok(())
+        }
+        ForkResult::Child => {
+            drop(daemon);
+            privileges::close_fds_except(helper.as_raw_fd());
+            // a service manager stopping envfs signals all of its processes
+            for sig in [Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP] {
+                let _ = unsafe { signal::signal(sig, SigHandler::SigIgn) };
+            }
+            if let Err(e) = caps::drop_bounding_set(&HELPER_CAPS) {
+                warn!("proc helper: {}", e);
+            }
+            if let Err(e) = caps::set(&HELPER_CAPS) {
+                warn!("proc helper: cannot set capabilities: {}", e);
+            }
+            serve(helper);
+            process::exit(0);
+        }
+    }
+}
+
+fn read_frame(stream: &mut UnixStream, max: usize) -> std::io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > max {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn write_frame(stream: &mut UnixStream, frame: &[u8]) -> std::io::Result<()> {
+    let mut buf = (frame.len() as u32).to_le_bytes().to_vec();
+    buf.extend_from_slice(frame);
+    stream.write_all(&buf)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn rest(&mut self) -> &[u8] {
+        std::mem::take(&mut self.0)
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, b: &[u8]) {
+    out.extend_from_slice(&(b.len() as u32).to_le_bytes());
+    out.extend_from_slice(b);
+}
+
+/// Whether the daemon may ask for `file` below `/proc/<pid>`: a relative path
+/// that does not leave the directory, and for reads does not go through the
+/// links to the process's root, cwd, executable or open files.
+fn is_safe(file: &str, link: bool) -> bool {
+    let components: Vec<Component> = Path::new(file).components().collect();
+    !components.is_empty()
+        && components.iter().enumerate().all(|(i, c)| match c {
+            Component::Normal(name) => {
+                let magic = matches!(
+                    name.as_bytes(),
+                    b"cwd" | b"root" | b"exe" | b"fd" | b"map_files" | b"ns"
+                );
+                !magic || (link && i == components.len() - 1)
+            }
+            _ => false,
+        })
+}
+
+/// Answers one request, `None` for requests without an answer.
+fn answer(
+    handles: &mut BTreeMap<u32, ProcDir>,
+    next: &mut u32,
+    request: &[u8],
+) -> Option<Result<Vec<u8>>> {
+    let mut r = Reader(request);
+    let op = r.u8()?;
+    if op == OPEN {
+        let pid = r.u32()? as i32;
+        if handles.len() >= MAX_HANDLES {
+            return Some(Err("too many open processes".into()));
+        }
+        return Some(ProcDir::open(Pid::from_raw(pid)).map(|dir| {
+            *next = next.wrapping_add(1);
+            handles.insert(*next, dir);
+            next.to_le_bytes().to_vec()
+        }));
+    }
+    let handle = r.u32()?;
+    if op == CLOSE {
+        handles.remove(&handle);
+        return None;
+    }
+    let dir = match handles.get(&handle) {
+        Some(dir) => dir,
+        None => {
+            return Some(Err(SimpleError::new(format!(
+                "no process with handle {}",
+                handle
+            ))))
+        }
+    };
+    let res = match op {
+        READ_MEM => {
+            let mut ranges = vec![];
+            for _ in 0..r.u32()? {
+                let base = r.u64()? as usize;
+                let len = r.u64()? as usize;
+                ranges.push(RemoteIoVec { base, len });
+            }
+            let total = ranges
+                .iter()
+                .fold(0usize, |total, range| total.saturating_add(range.len));
+            if total > MAX_RESPONSE {
+                return Some(Err("memory ranges too large".into()));
+            }
+            dir.read_mem(&ranges).map(|bufs| {
+                let mut out = (bufs.len() as u32).to_le_bytes().to_vec();
+                for buf in bufs {
+                    put_bytes(&mut out, &buf);
+                }
+                out
+            })
+        }
+        READ | READ_HEAD | READ_LINK => {
+            let len = if op == READ_HEAD {
+                r.u64()? as usize
+            } else {
+                0
+            };
+            let file = std::str::from_utf8(r.rest()).ok()?;
+            if !is_safe(file, op == READ_LINK) {
+                return Some(Err(SimpleError::new(format!("refusing to read {}", file))));
+            }
+            match op {
+                READ => dir.read(file),
+                READ_HEAD => dir.read_head(file, len),
+                _ => dir
+                    .read_link(file)
+                    .map(|target| target.as_os_str().as_bytes().to_vec()),
+            }
+        }
+        _ => return Some(Err(SimpleError::new(format!("unknown request {}", op)))),
+    };
+    Some(res)
+}
+
+fn serve(mut stream: UnixStream) {
+    let mut handles = BTreeMap::new();
+    let mut next = 0;
+    // the daemon closed its end or sent garbage
+    while let Ok(request) = read_frame(&mut stream, MAX_REQUEST) {
+        let res = match answer(&mut handles, &mut next, &request) {
+            Some(res) => res,
+            None if request.first() == Some(&CLOSE) => continue,
+            None => Err("malformed request".into()),
+        };
+        let frame = match res {
+            Ok(mut data) => {
+                data.insert(0, 0);
+                data
+            }
+            Err(e) => {
+                let mut data = vec![1];
+                data.extend_from_slice(e.to_string().as_bytes());
+                data
+            }
+        };
+        if write_frame(&mut stream, &frame).is_err() {
+            break;
+        }
+    }
+    debug!("proc helper exits");
+}
+
+/// Sends `request` to the helper and returns its answer.
+fn call(request: &[u8]) -> Result<Vec<u8>> {
+    let mut helper = HELPER.lock().unwrap();
+    let stream = match helper.as_mut() {
+        Some(stream) => stream,
+        None => bail!("proc helper is not running"),
+    };
+    try_with!(write_frame(stream, request), "cannot reach proc helper");
+    let mut response = try_with!(
+        read_frame(stream, MAX_RESPONSE),
+        "no answer from proc helper"
+    );
+    match response.first() {
+        Some(0) => {
+            response.remove(0);
+            Ok(response)
+        }
+        _ => bail!(
+            "{}",
+            String::from_utf8_lossy(response.get(1..).unwrap_or(&[]))
+        ),
+    }
+}
+
+/// Opens process `pid` in the helper.
+pub(crate) fn open(pid: Pid) -> Result<Box<dyn ProcReader>> {
+    let mut request = vec![OPEN];
+    request.extend_from_slice(&(pid.as_raw() as u32).to_le_bytes());
+    let handle = call(&request)?;
+    let handle = match handle.as_slice().try_into() {
+        Ok(handle) => u32::from_le_bytes(handle),
+        Err(_) => bail!("invalid answer from proc helper"),
+    };
+    Ok(Box::new(HelperReader { pid, handle }))
+}
+
+/// A process opened by the helper.
+struct HelperReader {
+    pid: Pid,
+    handle: u32,
+}
+
+impl HelperReader {
+    fn request(&self, op: u8) -> Vec<u8> {
+        let mut request = vec![op];
+        request.extend_from_slice(&self.handle.to_le_bytes());
+        request
+    }
+
+    fn call_file(&self, op: u8, arg: Option<u64>, file: &str) -> Result<Vec<u8>> {
+        let mut request = self.request(op);
+        if let Some(arg) = arg {
+            request.extend_from_slice(&arg.to_le_bytes());
+        }
+        request.extend_from_slice(file.as_bytes());
+        call(&request)
+    }
+}
+
+impl Drop for HelperReader {
+    fn drop(&mut self) {
+        let request = self.request(CLOSE);
+        if let Some(stream) = HELPER.lock().unwrap().as_mut() {
+            let _ = write_frame(stream, &request);
+        }
+    }
+}
+
+impl ProcReader for HelperReader {
+    fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn read(&self, file: &str) -> Result<Vec<u8>> {
+        self.call_file(READ, None, file)
+    }
+
+    fn read_head(&self, file: &str, len: usize) -> Result<Vec<u8>> {
+        self.call_file(READ_HEAD, Some(len as u64), file)
+    }
+
+    fn read_link(&self, file: &str) -> Result<PathBuf> {
+        let target = self.call_file(READ_LINK, None, file)?;
+        Ok(PathBuf::from(OsString::from_vec(target)))
+    }
+
+    fn read_mem(&self, ranges: &[RemoteIoVec]) -> Result<Vec<Vec<u8>>> {
+        let mut request = self.request(READ_MEM);
+        request.extend_from_slice(&(ranges.len() as u32).to_le_bytes());
+        for range in ranges {
+            request.extend_from_slice(&(range.base as u64).to_le_bytes());
+            request.extend_from_slice(&(range.len as u64).to_le_bytes());
+        }
+        let response = call(&request)?;
+        let mut r = Reader(&response);
+        let parsed: Option<Vec<Vec<u8>>> = (|| {
+            let mut bufs = vec![];
+            for _ in 0..r.u32()? {
+                let len = r.u32()? as usize;
+                bufs.push(r.take(len)?.to_vec());
+            }
+            Some(bufs)
+        })();
+        match parsed {
+            Some(bufs) if bufs.len() == ranges.len() => Ok(bufs),
+            _ => bail!("invalid answer from proc helper"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_safe() {
+        assert!(is_safe("environ", false));
+        assert!(is_safe("task/100/syscall", false));
+        assert!(is_safe("cwd", true));
+        assert!(!is_safe("cwd", false));
+        assert!(!is_safe("cwd/etc/shadow", true));
+        assert!(!is_safe("../1/environ", false));
+        assert!(!is_safe("/etc/shadow", false));
+        assert!(!is_safe("", false));
+
+        let mut handles = BTreeMap::new();
+        let mut next = 0;
+        let mut open = vec![OPEN];
+        open.extend_from_slice(&(process::id()).to_le_bytes());
+        let handle = answer(&mut handles, &mut next, &open).unwrap().unwrap();
+        let mut read = vec![READ];
+        read.extend_from_slice(&handle);
+        read.extend_from_slice(b"comm");
+        assert!(!answer(&mut handles, &mut next, &read)
+            .unwrap()
+            .unwrap()
+            .is_empty());
+        let mut close = vec![CLOSE];
+        close.extend_from_slice(&handle);
+        assert!(answer(&mut handles, &mut next, &close).is_none());
+        assert!(answer(&mut handles, &mut next, &read).unwrap().is_err());
+    }
+}
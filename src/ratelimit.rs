@@ -0,0 +1,127 @@
+//! Per-process limit on lookups that inspect the calling process.
+//!
+//! Every process gets a token bucket refilled at `rate` tokens per second.
+//! Once it runs empty the circuit breaker trips and the process is only
+//! served from static entries and fallback paths until `cooldown` passed.
+
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Bounds memory use, buckets of processes that stayed within their limit are dropped first.
+const MAX_BUCKETS: usize = 4096;
+
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(10);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    tripped_until: Option<Instant>,
+}
+
+/// Result of `RateLimiter::check`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Decision {
+    Allow,
+    /// The process ran out of tokens with this lookup
+    Trip,
+    /// The breaker of the process is still open
+    Throttle,
+}
+
+pub struct RateLimiter {
+    /// Tokens per second
+    rate: f64,
+    burst: f64,
+    cooldown: Duration,
+    buckets: Mutex<HashMap<Pid, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Allows `rate` lookups per second with bursts of up to `burst` lookups.
+    pub fn new(rate: u32, burst: u32, cooldown: Duration) -> RateLimiter {
+        RateLimiter {
+            rate: f64::from(rate),
+            burst: f64::from(burst.max(1)),
+            cooldown,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, pid: Pid) -> Decision {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&pid) {
+            self.prune(&mut buckets, now);
+        }
+        let bucket = buckets.entry(pid).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+            tripped_until: None,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        match bucket.tripped_until {
+            Some(until) if now < until => return Decision::Throttle,
+            Some(_) => bucket.tripped_until = None,
+            None => {}
+        }
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Decision::Allow
+        } else {
+            bucket.tripped_until = Some(now + self.cooldown);
+            Decision::Trip
+        }
+    }
+
+    /// Drops buckets that are full again, then the least recently used ones
+    /// until a quarter of `MAX_BUCKETS` is free. Tripped breakers are kept, a
+    /// process must not reset its own by forking many children.
+    fn prune(&self, buckets: &mut HashMap<Pid, Bucket>, now: Instant) {
+        let rate = self.rate;
+        let burst = self.burst;
+        let tripped = |b: &Bucket| b.tripped_until.is_some_and(|until| now < until);
+        buckets.retain(|_, b| {
+            let tokens = b.tokens + now.duration_since(b.updated).as_secs_f64() * rate;
+            tripped(b) || tokens < burst
+        });
+        let target = MAX_BUCKETS - MAX_BUCKETS / 4;
+        if buckets.len() <= target {
+            return;
+        }
+        let mut untripped: Vec<(Instant, Pid)> = buckets
+            .iter()
+            .filter(|(_, b)| !tripped(b))
+            .map(|(pid, b)| (b.updated, *pid))
+            .collect();
+        untripped.sort_unstable();
+        let excess = buckets.len() - target;
+        for (_, pid) in untripped.into_iter().take(excess) {
+            buckets.remove(&pid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_keeps_tripped_breakers() {
+        let limiter = RateLimiter::new(1, 1, Duration::from_secs(60));
+        let tripped = Pid::from_raw(1);
+        assert_eq!(limiter.check(tripped), Decision::Allow);
+        assert_eq!(limiter.check(tripped), Decision::Trip);
+        // children that used their only token are not full again and must be evicted by age
+        for pid in 2..(3 * MAX_BUCKETS as i32) {
+            limiter.check(Pid::from_raw(pid));
+        }
+        assert!(limiter.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+        assert_eq!(limiter.check(tripped), Decision::Throttle);
+    }
+}
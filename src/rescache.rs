@@ -0,0 +1,295 @@
+//! Resolutions of names in a list of directories, kept across restarts in a
+//! cache file.
+//!
+//! An entry remembers the directories that were searched until the name was
+//! found, together with their mtimes. It is used as long as none of them
+//! changed and the executable still passes the checks for the caller, so that
+//! the first lookups after a boot or restart do not search every directory
+//! of PATH again. Only searches that found a name without skipping a
+//! candidate the caller could not execute are cached, and entries are kept
+//! per uid because directories that are searchable differ between users.
+
+use log::{debug, warn};
+use simple_error::{bail, try_with};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::result::Result;
+
+pub const DEFAULT_CACHE_FILE: &str = "/var/cache/envfs/resolutions.bin";
+
+const MAGIC: &[u8; 8] = b"ENVFSRC2";
+
+/// Bounds the size of the cache file.
+const MAX_ENTRIES: usize = 4096;
+
+type Mtime = (i64, i64);
+
+struct Entry {
+    /// Directories searched in order, the name was found in the last one
+    dirs: Vec<(PathBuf, Mtime)>,
+}
+
+struct Cache {
+    file: PathBuf,
+    /// Keyed by `search_key` of the caller and all directories that would be searched, and the name
+    entries: BTreeMap<(u64, OsString), Entry>,
+    /// Changed since it was last written
+    dirty: bool,
+}
+
+/// `None` unless a cache file is configured.
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+/// FNV-1a hash of the uid of the caller and the directories, stable across
+/// builds unlike `DefaultHasher`.
+pub fn search_key(uid: libc::uid_t, dirs: &[PathBuf]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let dirs = dirs.iter().map(|dir| dir.as_os_str().as_bytes());
+    for bytes in std::iter::once(&uid.to_le_bytes()[..]).chain(dirs) {
+        for b in bytes.iter().chain(&[0]) {
+            hash ^= u64::from(*b);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+fn mtime(dir: &Path) -> Option<Mtime> {
+    fs::metadata(dir)
+        .ok()
+        .map(|stat| (stat.mtime(), stat.mtime_nsec()))
+}
+
+pub fn is_enabled() -> bool {
+    CACHE.lock().unwrap().is_some()
+}
+
+/// Returns where `name` was found when `dirs` were searched before, if none
+/// of the directories changed since.
+pub fn lookup(key: u64, dirs: &[PathBuf], name: &OsStr) -> Option<PathBuf> {
+    let searched: Vec<(PathBuf, Mtime)> = {
+        let cache = CACHE.lock().unwrap();
+        let entry = cache.as_ref()?.entries.get(&(key, name.to_os_string()))?;
+        entry.dirs.clone()
+    };
+    if searched.len() > dirs.len() || searched.iter().zip(dirs).any(|((a, _), b)| a != b) {
+        return None;
+    }
+    if searched.iter().any(|(dir, time)| mtime(dir) != Some(*time)) {
+        return None;
+    }
+    Some(searched.last()?.0.join(name))
+}
+
+/// Remembers that `name` was found in the last of the searched directories `dirs`.
+pub fn store(key: u64, dirs: &[PathBuf], name: &OsStr) {
+    let dirs: Option<Vec<(PathBuf, Mtime)>> = dirs
+        .iter()
+        .map(|dir| Some((dir.clone(), mtime(dir)?)))
+        .collect();
+    let dirs = match dirs {
+        Some(dirs) => dirs,
+        None => return,
+    };
+    let mut cache = CACHE.lock().unwrap();
+    let cache = match cache.as_mut() {
+        Some(cache) => cache,
+        None => return,
+    };
+    let key = (key, name.to_os_string());
+    if cache.entries.len() >= MAX_ENTRIES && !cache.entries.contains_key(&key) {
+        let first = cache.entries.keys().next().cloned();
+        if let Some(first) = first {
+            cache.entries.remove(&first);
+        }
+    }
+    cache.entries.insert(key, Entry { dirs });
+    cache.dirty = true;
+}
+
+/// Drops all entries, e.g. after the cache was flushed.
+pub fn clear() {
+    if let Some(cache) = CACHE.lock().unwrap().as_mut() {
+        cache.entries.clear();
+        cache.dirty = true;
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<OsString> {
+        let len = self.u32()? as usize;
+        Some(OsString::from_vec(self.take(len)?.to_vec()))
+    }
+}
+
+fn parse(data: &[u8]) -> Option<BTreeMap<(u64, OsString), Entry>> {
+    let mut r = Reader(data);
+    if r.take(MAGIC.len())? != MAGIC {
+        return None;
+    }
+    let mut entries = BTreeMap::new();
+    for _ in 0..r.u32()?.min(MAX_ENTRIES as u32) {
+        let key = r.u64()?;
+        let name = r.bytes()?;
+        let mut dirs = vec![];
+        for _ in 0..r.u32()? {
+            let dir = PathBuf::from(r.bytes()?);
+            let time = (r.u64()? as i64, r.u64()? as i64);
+            dirs.push((dir, time));
+        }
+        if dirs.is_empty() || name.as_bytes().contains(&b'/') {
+            return None;
+        }
+        entries.insert((key, name), Entry { dirs });
+    }
+    Some(entries)
+}
+
+fn serialize(entries: &BTreeMap<(u64, OsString), Entry>) -> Vec<u8> {
+    fn bytes(out: &mut Vec<u8>, b: &[u8]) {
+        out.extend_from_slice(&(b.len() as u32).to_le_bytes());
+        out.extend_from_slice(b);
+    }
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for ((key, name), entry) in entries {
+        out.extend_from_slice(&key.to_le_bytes());
+        bytes(&mut out, name.as_bytes());
+        out.extend_from_slice(&(entry.dirs.len() as u32).to_le_bytes());
+        for (dir, (sec, nsec)) in &entry.dirs {
+            bytes(&mut out, dir.as_os_str().as_bytes());
+            out.extend_from_slice(&(*sec as u64).to_le_bytes());
+            out.extend_from_slice(&(*nsec as u64).to_le_bytes());
+        }
+    }
+    out
+}
+
+/// Reads `file` if it is owned by us and not writable by others.
+fn load(file: &Path) -> Result<BTreeMap<(u64, OsString), Entry>> {
+    let stat = match fs::metadata(file) {
+        Ok(stat) => stat,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => bail!("cannot stat {}: {}", file.display(), e),
+    };
+    if stat.uid() != nix::unistd::geteuid().as_raw() || stat.mode() & 0o022 != 0 {
+        bail!(
+            "{} is writable by other users, not using it",
+            file.display()
+        );
+    }
+    let data = try_with!(fs::read(file), "cannot read {}", file.display());
+    match parse(&data) {
+        Some(entries) => Ok(entries),
+        None => bail!(
+            "{} is corrupt, starting with an empty cache",
+            file.display()
+        ),
+    }
+}
+
+/// Enables the cache and loads the entries of a previous run from `file`.
+pub fn enable(file: &Path) {
+    let entries = match load(file) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("{}", e);
+            BTreeMap::new()
+        }
+    };
+    debug!(
+        "loaded {} resolutions from {}",
+        entries.len(),
+        file.display()
+    );
+    *CACHE.lock().unwrap() = Some(Cache {
+        file: file.to_path_buf(),
+        entries,
+        dirty: false,
+    });
+}
+
+/// Writes the entries to the cache file if they changed.
+pub fn save() -> Result<()> {
+    let (file, data) = {
+        let mut cache = CACHE.lock().unwrap();
+        match cache.as_mut() {
+            Some(cache) if cache.dirty => {
+                cache.dirty = false;
+                (cache.file.clone(), serialize(&cache.entries))
+            }
+            _ => return Ok(()),
+        }
+    };
+    if let Some(dir) = file.parent() {
+        try_with!(fs::create_dir_all(dir), "cannot create {}", dir.display());
+    }
+    let tmp = file.with_extension("tmp");
+    let res = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut f| f.write_all(&data));
+    try_with!(res, "cannot write {}", tmp.display());
+    try_with!(fs::rename(&tmp, &file), "cannot replace {}", file.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut entries = BTreeMap::new();
+        entries.insert(
+            (42, OsString::from("sh")),
+            Entry {
+                dirs: vec![
+                    (PathBuf::from("/usr/local/bin"), (1, 2)),
+                    (PathBuf::from("/bin"), (-3, 4)),
+                ],
+            },
+        );
+        let parsed = parse(&serialize(&entries)).unwrap();
+        let entry = &parsed[&(42, OsString::from("sh"))];
+        assert_eq!(entry.dirs, entries[&(42, OsString::from("sh"))].dirs);
+        assert!(parse(b"ENVFSRC2\x01\x00\x00\x00").is_none());
+        assert_ne!(
+            search_key(0, &[PathBuf::from("/a"), PathBuf::from("/b")]),
+            search_key(0, &[PathBuf::from("/ab")])
+        );
+        // another user may not be able to search the same directories
+        let dirs = [PathBuf::from("/home/alice/bin"), PathBuf::from("/bin")];
+        assert_ne!(search_key(1000, &dirs), search_key(1001, &dirs));
+    }
+}
@@ -0,0 +1,2041 @@
+//! Resolution of executable names against the environment of the requesting process.
+
+use log::{debug, warn};
+use nix::errno::Errno;
+use nix::fcntl::{self, AtFlags, OFlag, OpenHow, ResolveFlag};
+use nix::sys::signal::{self, Signal};
+use nix::sys::stat::{fstatat, Mode};
+use nix::sys::uio::RemoteIoVec;
+use nix::sys::wait::waitpid;
+use nix::unistd::{self, ForkResult, Pid};
+use simple_error::{bail, try_with, SimpleError};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::creds::check_executable;
+use crate::dircache;
+use crate::elf::{self, ElfArch};
+use crate::fs::is_envfs_dir;
+use crate::procdir::{self, ProcReader, Procfs, RealProcfs};
+use crate::rescache;
+use crate::result::Result;
+use crate::spans;
+use crate::syscalls::{Abi, AllowedSyscalls, Syscall};
+
+/// Collects a human readable account of the decisions taken during a resolution.
+pub struct Trace {
+    lines: Option<RefCell<Vec<String>>>,
+    /// Lines are indented by two spaces per level, see `nested`
+    depth: Cell<usize>,
+}
+
+impl Default for Trace {
+    fn default() -> Trace {
+        Trace::new()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace {
+            lines: Some(RefCell::new(vec![])),
+            depth: Cell::new(0),
+        }
+    }
+
+    /// A trace that does not record anything, used on the hot path.
+    pub fn disabled() -> Trace {
+        Trace {
+            lines: None,
+            depth: Cell::new(0),
+        }
+    }
+
+    pub fn add<F: FnOnce() -> String>(&self, line: F) {
+        if let Some(ref lines) = self.lines {
+            let indent = "  ".repeat(self.depth.get());
+            lines.borrow_mut().push(indent + &line());
+        }
+    }
+
+    /// Indents the lines added until the returned guard is dropped.
+    pub fn nested(&self) -> Nested<'_> {
+        self.depth.set(self.depth.get() + 1);
+        Nested(self)
+    }
+
+    pub fn into_lines(self) -> Vec<String> {
+        self.lines.map(RefCell::into_inner).unwrap_or_default()
+    }
+}
+
+pub struct Nested<'a>(&'a Trace);
+
+impl Drop for Nested<'_> {
+    fn drop(&mut self) {
+        self.0.depth.set(self.0.depth.get() - 1);
+    }
+}
+
+/// Time budget of a lookup, once it is used up envfs stops waiting for the
+/// requesting process and answers from what it has at hand.
+pub struct Deadline {
+    at: Instant,
+    budget: Duration,
+    /// Set when an answer was degraded because of the deadline
+    reached: Cell<bool>,
+}
+
+impl Deadline {
+    /// A deadline of `budget` for a request received at `received`.
+    pub fn new(received: Instant, budget: Duration) -> Deadline {
+        Deadline {
+            at: received + budget,
+            budget,
+            reached: Cell::new(false),
+        }
+    }
+
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Duration::ZERO
+    }
+
+    /// Records that the answer is degraded because of the deadline.
+    pub fn mark_reached(&self) {
+        self.reached.set(true);
+    }
+
+    pub fn was_reached(&self) -> bool {
+        self.reached.get()
+    }
+}
+
+/// How long a resolution waits for the process to enter a system call.
+#[derive(Clone, Copy)]
+pub enum Wait<'a> {
+    /// Not at all, use the PATH of its environment right away
+    Never,
+    /// Up to the syscall timeout of the configuration
+    Timeout,
+    /// Up to the syscall timeout, but not past the deadline
+    Until(&'a Deadline),
+}
+
+/// Combines the reasons why two attempts found nothing into the one to report.
+///
+/// Like `execvp`, a candidate that exists but cannot be executed by the
+/// caller (`EACCES`) wins over an unreadable process (`EIO`), which wins over
+/// nothing being found at all (`ENOENT`).
+pub fn worse_miss(a: Errno, b: Errno) -> Errno {
+    fn rank(e: Errno) -> u8 {
+        match e {
+            Errno::EACCES => 2,
+            Errno::EIO => 1,
+            _ => 0,
+        }
+    }
+    if rank(b) > rank(a) {
+        b
+    } else {
+        a
+    }
+}
+
+/// Setuid programs may be served from these directories with `refuse_setuid`.
+pub const DEFAULT_SETUID_PREFIXES: &[&str] = &["/run/wrappers/bin"];
+
+/// Which executables found while searching a PATH may be served.
+#[derive(Clone, Debug, Default)]
+pub struct CandidatePolicy {
+    /// Only serve executables below these directories, unless empty
+    pub trusted_prefixes: Vec<PathBuf>,
+    /// Also required by the policy file rule of the caller, unless empty
+    pub rule_prefixes: Vec<PathBuf>,
+    /// Skip setuid and setgid executables that are not below `setuid_prefixes`
+    pub refuse_setuid: bool,
+    pub setuid_prefixes: Vec<PathBuf>,
+    /// Prefer executables that match the architecture of the caller
+    pub prefer_arch: bool,
+    /// Architecture of the caller, filled in for each request with `prefer_arch`
+    pub arch: Option<ElfArch>,
+    /// Skip executables whose ELF interpreter is missing in the root of the caller
+    pub check_interp: bool,
+    /// `/proc/<pid>/root` of the caller, filled in for each request with `check_interp`
+    pub root: Option<PathBuf>,
+    /// Uid of the caller, filled in for each request if resolutions are kept in
+    /// a cache file
+    pub uid: Option<libc::uid_t>,
+    /// Serve a directory of the same name if no executable matches, for
+    /// virtual subdirectories
+    pub allow_dirs: bool,
+}
+
+/// Whether `path` is not below any of `prefixes`, an empty list allows everything.
+fn outside_prefixes(path: &Path, prefixes: &[PathBuf]) -> bool {
+    if prefixes.is_empty() {
+        return false;
+    }
+    // `..` would make the prefix check meaningless
+    path.components().any(|c| c == Component::ParentDir)
+        || !prefixes.iter().any(|p| path.starts_with(p))
+}
+
+impl CandidatePolicy {
+    /// Returns why the executable `path` must not be served.
+    pub(crate) fn check(&self, path: &Path) -> std::result::Result<(), String> {
+        if outside_prefixes(path, &self.trusted_prefixes) {
+            return Err(String::from("outside of the trusted prefixes"));
+        }
+        if outside_prefixes(path, &self.rule_prefixes) {
+            return Err(String::from(
+                "outside of the prefixes allowed by the policy",
+            ));
+        }
+        if !self.refuse_setuid || self.setuid_prefixes.iter().any(|p| path.starts_with(p)) {
+            return Ok(());
+        }
+        let mode = match fs::metadata(path) {
+            Ok(stat) => stat.mode(),
+            Err(e) => return Err(format!("cannot stat: {}", e)),
+        };
+        if mode & libc::S_ISUID != 0 {
+            return Err(String::from("setuid"));
+        }
+        // setgid without group execute permission marks mandatory locking
+        if mode & (libc::S_ISGID | libc::S_IXGRP) == libc::S_ISGID | libc::S_IXGRP {
+            return Err(String::from("setgid"));
+        }
+        Ok(())
+    }
+}
+
+/// Symlinks followed in one lookup before giving up with ELOOP, like the kernel.
+const MAX_SYMLINKS: usize = 40;
+
+/// Looks up `path` below the directory `root` like `openat2` with
+/// `RESOLVE_IN_ROOT`: absolute symlinks and `..` cannot leave `root`.
+fn lookup_in_root(root: OwnedFd, path: &Path) -> nix::Result<()> {
+    fn components(path: &Path) -> impl Iterator<Item = OsString> + '_ {
+        path.components().filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+    }
+    // directories walked so far, `..` goes back to the previous one
+    let mut dirs = vec![root];
+    let mut pending: Vec<OsString> = components(path).collect();
+    pending.reverse();
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        let dir = dirs[dirs.len() - 1].as_raw_fd();
+        if name == ".." {
+            if dirs.len() > 1 {
+                dirs.pop();
+            }
+            continue;
+        }
+        let stat = fstatat(Some(dir), name.as_os_str(), AtFlags::AT_SYMLINK_NOFOLLOW)?;
+        match stat.st_mode & libc::S_IFMT {
+            libc::S_IFLNK => {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(Errno::ELOOP);
+                }
+                let target = PathBuf::from(fcntl::readlinkat(Some(dir), name.as_os_str())?);
+                if target.is_absolute() {
+                    dirs.truncate(1);
+                }
+                let mut target: Vec<OsString> = components(&target).collect();
+                target.reverse();
+                pending.extend(target);
+            }
+            libc::S_IFDIR if !pending.is_empty() => {
+                let fd = fcntl::openat(
+                    Some(dir),
+                    name.as_os_str(),
+                    OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+                    Mode::empty(),
+                )?;
+                dirs.push(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+            _ if !pending.is_empty() => return Err(Errno::ENOTDIR),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns the interpreter of the ELF executable `exe` if it does not exist
+/// below `root`, resolving symlinks in there like the kernel would for the caller.
+///
+/// Interpreters that cannot be checked are assumed to exist.
+fn missing_interpreter(root: &Path, exe: &Path) -> Option<PathBuf> {
+    let interp = elf::interpreter_of_file(exe)?;
+    let root_fd = fcntl::open(
+        root,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )
+    .ok()?;
+    let root_fd = unsafe { OwnedFd::from_raw_fd(root_fd) };
+    let how = OpenHow::new()
+        .flags(OFlag::O_PATH | OFlag::O_CLOEXEC)
+        .resolve(ResolveFlag::RESOLVE_IN_ROOT);
+    let res = match fcntl::openat2(root_fd.as_raw_fd(), &interp, how) {
+        // before Linux 5.6, or refused by a seccomp filter that predates openat2
+        Err(Errno::ENOSYS) | Err(Errno::EPERM) => lookup_in_root(root_fd, &interp),
+        res => res.map(|fd| drop(unsafe { OwnedFd::from_raw_fd(fd) })),
+    };
+    match res {
+        Err(Errno::ENOENT) | Err(Errno::ENOTDIR) => Some(interp),
+        _ => None,
+    }
+}
+
+fn _which<P1, P2>(
+    path: &Path,
+    exe_name: P1,
+    mountpoints: &[P2],
+    policy: &CandidatePolicy,
+    trace: &Trace,
+) -> nix::Result<PathBuf>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    if path.is_relative() {
+        trace.add(|| format!("skip '{}': relative PATH entry", path.display()));
+        return Err(Errno::ENOENT);
+    }
+
+    if mountpoints.iter().any(|m| path.starts_with(m)) {
+        trace.add(|| format!("skip {}: below an envfs mountpoint", path.display()));
+        return Err(Errno::ENOENT);
+    }
+
+    let dir = dircache::lookup(path);
+    let is_envfs = match dir {
+        Ok(ref dir) => is_envfs_dir(dir.dev, dir.nlink),
+        // Do we still need this check if we already check for mountpoints?
+        Err(_) => path
+            .symlink_metadata()
+            .is_ok_and(|stat| is_envfs_dir(stat.dev(), stat.nlink())),
+    };
+    if is_envfs {
+        trace.add(|| format!("skip {}: is an envfs mount", path.display()));
+        return Err(Errno::ENOENT);
+    }
+
+    let full_path = path.join(&exe_name);
+    let res = match dir {
+        Ok(dir) => dir.check_executable(exe_name.as_ref(), &full_path),
+        Err(Errno::ENOENT) => Err(Errno::ENOENT),
+        Err(_) => check_executable(&full_path),
+    };
+    match res {
+        Ok(()) => {
+            if let Err(reason) = policy.check(&full_path) {
+                debug!("refusing {}: {}", full_path.display(), reason);
+                trace.add(|| format!("check {}: refused, {}", full_path.display(), reason));
+                // the caller could execute it, envfs just does not serve it
+                return Err(Errno::ENOENT);
+            }
+            trace.add(|| format!("check {}: found", full_path.display()));
+            Ok(full_path)
+        }
+        Err(e) => {
+            trace.add(|| format!("check {}: {}", full_path.display(), e.desc()));
+            match e {
+                // e.g. no execute permission or a noexec mount
+                Errno::EACCES => Err(Errno::EACCES),
+                _ => Err(Errno::ENOENT),
+            }
+        }
+    }
+}
+
+pub fn which<P1, P2>(
+    path_env: &OsStr,
+    exe_name: P1,
+    fallback_paths: &[PathBuf],
+    mountpoints: &[P2],
+    policy: &CandidatePolicy,
+    trace: &Trace,
+) -> nix::Result<PathBuf>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let mut miss = Errno::ENOENT;
+    // first match of another architecture, served if none matches
+    let mut other_arch = None;
+    // first directory of that name with `allow_dirs`, served if no executable matches
+    let mut first_dir = None;
+    // split_paths yields a single empty component for an empty PATH
+    let dirs = if path_env.is_empty() {
+        None
+    } else {
+        Some(env::split_paths(&path_env))
+    };
+    let dirs: Vec<PathBuf> = dirs
+        .into_iter()
+        .flatten()
+        .chain(fallback_paths.iter().cloned())
+        .collect();
+    let name = exe_name.as_ref().as_os_str();
+    // rules of the policy file and the architecture differ between callers,
+    // names in subdirectories are not kept in the cache file
+    let cache_key = match policy.uid {
+        Some(uid)
+            if policy.rule_prefixes.is_empty()
+                && policy.arch.is_none()
+                && policy.root.is_none()
+                && !name.as_bytes().contains(&b'/')
+                && rescache::is_enabled() =>
+        {
+            Some(rescache::search_key(uid, &dirs))
+        }
+        _ => None,
+    };
+    if let Some(key) = cache_key {
+        if let Some(exe) = rescache::lookup(key, &dirs, name) {
+            match check_executable(&exe).map_err(|e| e.desc().to_string()) {
+                Ok(()) => match policy.check(&exe) {
+                    Ok(()) => {
+                        trace.add(|| format!("{}: found in the resolution cache", exe.display()));
+                        return Ok(exe);
+                    }
+                    Err(reason) => trace.add(|| format!("cached {}: {}", exe.display(), reason)),
+                },
+                Err(reason) => trace.add(|| format!("cached {}: {}", exe.display(), reason)),
+            }
+        }
+    }
+    // a later match may differ for callers that can execute the skipped candidate
+    let mut denied = false;
+    for (i, dir) in dirs.iter().enumerate() {
+        match _which(dir, &exe_name, mountpoints, policy, trace) {
+            Ok(exe) if exe.is_dir() => {
+                if policy.allow_dirs {
+                    trace.add(|| format!("{}: directory", exe.display()));
+                    first_dir.get_or_insert(exe);
+                } else {
+                    trace.add(|| format!("skip {}: is a directory", exe.display()));
+                }
+            }
+            Ok(exe) => {
+                if let Some(ref root) = policy.root {
+                    if let Some(interp) = missing_interpreter(root, &exe) {
+                        trace.add(|| {
+                            format!(
+                                "skip {}: interpreter {} does not exist for the caller",
+                                exe.display(),
+                                interp.display()
+                            )
+                        });
+                        continue;
+                    }
+                }
+                let arch = match policy.arch {
+                    Some(arch) => arch,
+                    None => {
+                        if let (Some(key), false) = (cache_key, denied) {
+                            rescache::store(key, &dirs[..=i], name);
+                        }
+                        return Ok(exe);
+                    }
+                };
+                match ElfArch::of_file(&exe) {
+                    Some(exe_arch) if exe_arch != arch => {
+                        trace.add(|| {
+                            format!("skip {}: {}, caller is {}", exe.display(), exe_arch, arch)
+                        });
+                        other_arch.get_or_insert(exe);
+                    }
+                    // scripts run anywhere
+                    _ => return Ok(exe),
+                }
+            }
+            Err(e) => {
+                denied |= e == Errno::EACCES;
+                miss = worse_miss(miss, e);
+            }
+        }
+    }
+    if let Some(exe) = other_arch {
+        trace.add(|| format!("no match for the architecture, use {}", exe.display()));
+        return Ok(exe);
+    }
+    if let Some(dir) = first_dir {
+        return Ok(dir);
+    }
+    Err(miss)
+}
+
+/// Maximum number of symlinks followed by `resolve_symlinks`, same as the kernel's limit.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Follows the symlink chain of `path` to the final file.
+///
+/// Links pointing below `mountpoints` are not followed, resolving them would
+/// make envfs look up its own files. On errors the last good path is returned.
+pub fn resolve_symlinks<P: AsRef<Path>>(
+    path: PathBuf,
+    mountpoints: &[P],
+    trace: &Trace,
+) -> PathBuf {
+    let mut path = path;
+    for _ in 0..MAX_SYMLINK_HOPS {
+        let target = match fs::read_link(&path) {
+            Ok(target) => target,
+            // not a symlink (anymore)
+            Err(_) => break,
+        };
+        let target = match path.parent() {
+            Some(parent) => parent.join(target),
+            None => target,
+        };
+        if mountpoints.iter().any(|m| target.starts_with(m)) {
+            trace.add(|| {
+                format!(
+                    "do not follow {}: below an envfs mountpoint",
+                    target.display()
+                )
+            });
+            return path;
+        }
+        trace.add(|| format!("follow {} -> {}", path.display(), target.display()));
+        path = target;
+    }
+    // Only canonicalize the directory, the file itself might be a link into a mountpoint.
+    let (parent, file_name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) => (parent, file_name),
+        _ => return path,
+    };
+    match fs::canonicalize(parent) {
+        Ok(parent) => parent.join(file_name),
+        Err(_) => path,
+    }
+}
+
+/// Returns the effective uid of a process, which owns its `/proc/<pid>` directory.
+pub fn read_uid(pid: Pid) -> Result<u32> {
+    let path = format!("/proc/{}", pid.as_raw());
+    let meta = try_with!(fs::metadata(&path), "failed to stat {}", path);
+    Ok(meta.uid())
+}
+
+/// Returns the command name of a process as shown in `/proc/<pid>/comm`.
+pub fn read_comm(pid: Pid) -> Result<String> {
+    let path = format!("/proc/{}/comm", pid.as_raw());
+    let comm = try_with!(fs::read_to_string(&path), "failed to read {}", path);
+    Ok(comm.trim_end_matches('\n').to_string())
+}
+
+/// Identifies the program a process runs, execve keeps the pid and start
+/// time but places the new environment at a different address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct ProcessImage {
+    starttime: u64,
+    env_start: u64,
+    env_end: u64,
+}
+
+fn read_process_image(proc: &dyn ProcReader) -> Result<ProcessImage> {
+    let stat = proc.read_to_string("stat")?;
+    let path = format!("/proc/{}/stat", proc.pid());
+    // comm may contain spaces and parentheses, the remaining fields follow the last ')'
+    let fields: Vec<&str> = match stat.rfind(')') {
+        Some(pos) => stat[pos + 1..].split_whitespace().collect(),
+        None => bail!("cannot parse {}", path),
+    };
+    // field numbers as in proc(5), the first one after comm is 3
+    let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+    match (field(22), field(48), field(49)) {
+        (Some(starttime), Some(env_start), Some(env_end)) => Ok(ProcessImage {
+            starttime,
+            env_start,
+            env_end,
+        }),
+        _ => bail!("cannot parse {}", path),
+    }
+}
+
+/// Number of processes whose environment is cached.
+const ENV_CACHE_SIZE: usize = 256;
+
+type Environment = Arc<LookupEnv>;
+
+static ENV_CACHE: Mutex<BTreeMap<i32, (ProcessImage, Environment)>> = Mutex::new(BTreeMap::new());
+
+/// Like `read_environment`, but reuses the result of earlier lookups of the same program.
+fn cached_environment(proc: &dyn ProcReader) -> Result<Environment> {
+    let pid = proc.pid();
+    let image = match read_process_image(proc) {
+        Ok(image) => image,
+        Err(_) => return read_lookup_env(proc).map(Arc::new),
+    };
+    if let Some((cached_image, env)) = ENV_CACHE.lock().unwrap().get(&pid.as_raw()) {
+        if *cached_image == image {
+            return Ok(Arc::clone(env));
+        }
+    }
+    let env = Arc::new(read_lookup_env(proc)?);
+    let mut cache = ENV_CACHE.lock().unwrap();
+    if cache.len() >= ENV_CACHE_SIZE && !cache.contains_key(&pid.as_raw()) {
+        // pids are mostly allocated in increasing order, drop the oldest one
+        let oldest = *cache.keys().next().unwrap();
+        cache.remove(&oldest);
+    }
+    cache.insert(pid.as_raw(), (image, Arc::clone(&env)));
+    Ok(env)
+}
+
+/// How many parents are tried if the environment of a process cannot be read.
+const MAX_ANCESTOR_DEPTH: usize = 4;
+
+/// Reads a pid valued field such as `PPid` from `/proc/<pid>/status`.
+fn read_status_pid(proc: &dyn ProcReader, key: &str) -> Result<Pid> {
+    let status = proc.read_to_string("status")?;
+    let value = status
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.trim().parse::<i32>().ok());
+    match value {
+        Some(value) => Ok(Pid::from_raw(value)),
+        None => bail!("no {} in /proc/{}/status", key, proc.pid()),
+    }
+}
+
+fn read_ppid(proc: &dyn ProcReader) -> Result<Pid> {
+    read_status_pid(proc, "PPid")
+}
+
+/// Returns the thread group id, i.e. the process id, of thread `tid`.
+pub fn read_tgid(tid: Pid) -> Result<Pid> {
+    read_status_pid(&*RealProcfs.open(tid)?, "Tgid")
+}
+
+/// A thread of a process. FUSE reports the thread that made a request, which
+/// is not listed in `/proc` for multithreaded programs and whose
+/// `/proc/<tid>/syscall` can be confused with the one of the process.
+struct Task<'a> {
+    tgid: Pid,
+    tid: Pid,
+    /// `/proc/<tgid>`
+    proc: Box<dyn ProcReader>,
+    /// Used to open the parents of the process
+    procfs: &'a dyn Procfs,
+}
+
+impl<'a> Task<'a> {
+    /// Looks up the process of `tid`, treats it as single-threaded if that fails.
+    fn open(procfs: &'a dyn Procfs, tid: Pid) -> Result<Task<'a>> {
+        let tgid = procfs
+            .open(tid)
+            .and_then(|thread| read_status_pid(&*thread, "Tgid"))
+            .unwrap_or(tid);
+        let proc = procfs.open(tgid)?;
+        Ok(Task {
+            tgid,
+            tid,
+            proc,
+            procfs,
+        })
+    }
+
+    /// Path of a per-thread file such as `syscall`, relative to `/proc/<tgid>`.
+    fn file(&self, file: &str) -> String {
+        format!("task/{}/{}", self.tid, file)
+    }
+}
+
+/// Environment of the nearest ancestor of `pid` with a readable, non-empty one.
+///
+/// Short-lived processes may be gone or not have their environment set up
+/// yet when envfs looks at them.
+fn ancestor_environment(task: &Task, trace: &Trace) -> Option<Environment> {
+    let mut pid = read_ppid(&*task.proc).ok()?;
+    for _ in 0..MAX_ANCESTOR_DEPTH {
+        // 0 is the parent of init and kernel threads
+        if pid.as_raw() <= 1 {
+            return None;
+        }
+        let parent = task.procfs.open(pid).ok()?;
+        match cached_environment(&*parent) {
+            Ok(env) if !env.is_empty() => {
+                trace.add(|| format!("use environment of ancestor {}", pid));
+                return Some(env);
+            }
+            _ => pid = read_ppid(&*parent).ok()?,
+        }
+    }
+    None
+}
+
+/// Drops all cached environments, open PATH directories and resolutions.
+pub fn clear_env_cache() {
+    ENV_CACHE.lock().unwrap().clear();
+    dircache::clear_dir_cache();
+    rescache::clear();
+}
+
+pub fn read_environment(pid: unistd::Pid) -> Result<HashMap<OsString, OsString>> {
+    read_environment_in(&*RealProcfs.open(pid)?)
+}
+
+fn read_environment_in(proc: &dyn ProcReader) -> Result<HashMap<OsString, OsString>> {
+    Ok(parse_environ(&proc.read("environ")?))
+}
+
+/// Calls `f` with the name and value of every variable in the content of
+/// `/proc/<pid>/environ`, without copying them.
+///
+/// Entries without `=` are skipped.
+pub fn for_each_var<'a, F>(environ: &'a [u8], mut f: F)
+where
+    F: FnMut(&'a [u8], &'a [u8]),
+{
+    for var in environ.split(|c| *c == b'\0') {
+        if let Some(eq) = var.iter().position(|c| *c == b'=') {
+            f(&var[..eq], &var[eq + 1..]);
+        }
+    }
+}
+
+/// Splits the content of `/proc/<pid>/environ` into variables.
+///
+/// The last one of duplicated names wins.
+pub fn parse_environ(environ: &[u8]) -> HashMap<OsString, OsString> {
+    let mut env = HashMap::new();
+    for_each_var(environ, |name, value| {
+        env.insert(
+            OsString::from_vec(name.to_vec()),
+            OsString::from_vec(value.to_vec()),
+        );
+    });
+    env
+}
+
+/// Value of the variable `name` in the content of `/proc/<pid>/environ`.
+pub fn environ_var<'a>(environ: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let mut found = None;
+    for_each_var(environ, |n, value| {
+        if n == name.as_bytes() {
+            found = Some(value);
+        }
+    });
+    found
+}
+
+/// The variables of an environment that lookups look at, extracted without
+/// copying the rest of it.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct LookupEnv {
+    /// Number of variables, kernel threads have none
+    pub variables: usize,
+    pub path: Option<OsString>,
+    /// `ENVFS_RESOLVE_ALWAYS` is set
+    pub resolve_always: bool,
+    /// Value of `ENVFS_RESOLVE_PATHS`
+    pub resolve_paths: Option<OsString>,
+    /// W3C trace context of the process, `TRACEPARENT`
+    pub traceparent: Option<OsString>,
+}
+
+impl LookupEnv {
+    /// Scans the content of `/proc/<pid>/environ`, the last one of duplicated names wins.
+    pub fn parse(environ: &[u8]) -> LookupEnv {
+        let mut env = LookupEnv::default();
+        for_each_var(environ, |name, value| {
+            env.variables += 1;
+            match name {
+                b"PATH" => env.path = Some(OsString::from_vec(value.to_vec())),
+                b"ENVFS_RESOLVE_ALWAYS" => env.resolve_always = true,
+                b"ENVFS_RESOLVE_PATHS" => {
+                    env.resolve_paths = Some(OsString::from_vec(value.to_vec()))
+                }
+                b"TRACEPARENT" => env.traceparent = Some(OsString::from_vec(value.to_vec())),
+                _ => {}
+            }
+        });
+        env
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.variables == 0
+    }
+
+    /// PATH of the environment, empty if it is not set.
+    pub fn path(&self) -> &OsStr {
+        self.path.as_deref().unwrap_or_default()
+    }
+
+    /// Whether `name` is listed in `ENVFS_RESOLVE_PATHS`, which opts single
+    /// names into resolution during any system call.
+    fn resolve_paths_contains(&self, name: &Path) -> bool {
+        match self.resolve_paths {
+            Some(ref names) => names
+                .as_bytes()
+                .split(|c| *c == b':')
+                .any(|n| n == name.as_os_str().as_bytes()),
+            None => false,
+        }
+    }
+}
+
+fn read_lookup_env(proc: &dyn ProcReader) -> Result<LookupEnv> {
+    Ok(LookupEnv::parse(&proc.read("environ")?))
+}
+
+/// How empty and other relative entries in PATH are treated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum EmptyPath {
+    /// Skip them, the working directory of envfs has no meaning for the caller.
+    #[default]
+    Ignore,
+    /// Resolve them relative to the working directory of the process, like a shell does.
+    Cwd,
+}
+
+/// Searches `path_env` of `task`, relative entries are handled according to `empty_path`.
+fn which_in_process<P1, P2>(
+    task: &Task,
+    path_env: &OsStr,
+    exe_name: P1,
+    mountpoints: &[P2],
+    policy: &CandidatePolicy,
+    empty_path: EmptyPath,
+    trace: &Trace,
+) -> nix::Result<PathBuf>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let has_relative =
+        !path_env.is_empty() && env::split_paths(path_env).any(|dir| dir.is_relative());
+    if empty_path == EmptyPath::Ignore || !has_relative {
+        return which(path_env, exe_name, &[], mountpoints, policy, trace);
+    }
+    // threads created with CLONE_FS unshared have their own working directory
+    let cwd = match task.proc.read_link(&task.file("cwd")) {
+        Ok(cwd) => cwd,
+        Err(e) => {
+            trace.add(|| format!("cannot read working directory: {}", e));
+            return which(path_env, exe_name, &[], mountpoints, policy, trace);
+        }
+    };
+    trace.add(|| format!("relative PATH entries are below {}", cwd.display()));
+    let dirs = env::split_paths(path_env).map(|dir| cwd.join(dir));
+    match env::join_paths(dirs) {
+        Ok(path) => which(&path, exe_name, &[], mountpoints, policy, trace),
+        // the working directory contains ':'
+        Err(_) => which(path_env, exe_name, &[], mountpoints, policy, trace),
+    }
+}
+
+/// How long `resolve_target` waits for a process to enter a system call by default.
+pub const DEFAULT_SYSCALL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Settings for resolving against the environment of a process.
+#[derive(Clone, Debug)]
+pub struct EnvConfig {
+    pub empty_path: EmptyPath,
+    /// Used if the environment of the process cannot be read, e.g. for requests with pid 0.
+    pub default_path: Option<OsString>,
+    /// After this time without a readable system call, the PATH from environ is used.
+    pub syscall_timeout: Duration,
+    pub allowed_syscalls: AllowedSyscalls,
+    /// Read the files needed by every lookup with io_uring
+    pub io_uring: bool,
+    /// Read the PATH passed to execve from the memory of the process
+    pub read_mem: bool,
+    /// What can be read of processes at all, lookups skip the rest
+    pub proc_access: ProcAccess,
+}
+
+impl Default for EnvConfig {
+    fn default() -> EnvConfig {
+        EnvConfig {
+            empty_path: EmptyPath::default(),
+            default_path: None,
+            syscall_timeout: DEFAULT_SYSCALL_TIMEOUT,
+            allowed_syscalls: AllowedSyscalls::default(),
+            io_uring: false,
+            read_mem: true,
+            proc_access: ProcAccess::default(),
+        }
+    }
+}
+
+/// What envfs can read of other processes, see `ProcAccess::probe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProcAccess {
+    /// `/proc/<pid>/environ`
+    pub environ: bool,
+    /// `/proc/<pid>/syscall`, missing without `CONFIG_HAVE_ARCH_TRACEHOOK`
+    pub syscall: bool,
+    /// The memory of the process, e.g. forbidden by Yama's `ptrace_scope=3` or an LSM
+    pub mem: bool,
+}
+
+impl Default for ProcAccess {
+    fn default() -> ProcAccess {
+        ProcAccess {
+            environ: true,
+            syscall: true,
+            mem: true,
+        }
+    }
+}
+
+impl ProcAccess {
+    /// Tries to read the environment, system call and stack of a child that
+    /// waits to be killed, as an ordinary process envfs would inspect.
+    pub fn probe() -> ProcAccess {
+        let none = ProcAccess {
+            environ: false,
+            syscall: false,
+            mem: false,
+        };
+        let child = match unsafe { unistd::fork() } {
+            Ok(ForkResult::Parent { child }) => child,
+            Ok(ForkResult::Child) => loop {
+                unsafe { libc::pause() };
+            },
+            Err(e) => {
+                warn!("cannot fork to probe /proc: {}", e);
+                return ProcAccess::default();
+            }
+        };
+        let opened = match procdir::translate_pid(child) {
+            Some(pid) => RealProcfs.open(pid),
+            None => Err(SimpleError::new("not visible in /proc")),
+        };
+        let access = match opened {
+            Ok(proc) => ProcAccess::probe_in(&*proc),
+            Err(e) => {
+                debug!("cannot probe process {}: {}", child, e);
+                none
+            }
+        };
+        let _ = signal::kill(child, Signal::SIGKILL);
+        let _ = waitpid(child, None);
+        access
+    }
+
+    fn probe_in(proc: &dyn ProcReader) -> ProcAccess {
+        let stack = proc.read_to_string("maps").ok().and_then(|maps| {
+            let line = maps.lines().find(|line| line.ends_with("[stack]"))?;
+            let start = line.split('-').next()?;
+            usize::from_str_radix(start, 16).ok()
+        });
+        let mem = stack.is_some_and(|base| {
+            proc.read_mem(&[RemoteIoVec { base, len: 1 }])
+                .is_ok_and(|bufs| bufs.first().is_some_and(|buf| !buf.is_empty()))
+        });
+        ProcAccess {
+            environ: proc.read_head("environ", 1).is_ok(),
+            syscall: proc.read_head("syscall", 1).is_ok(),
+            mem,
+        }
+    }
+
+    /// How lookups are resolved with this access, for the log.
+    pub fn strategy(&self) -> &'static str {
+        match (self.environ, self.syscall, self.mem) {
+            (false, true, true) => {
+                "only the PATH passed to execve, the default PATH and fallback paths are used"
+            }
+            (false, _, _) => "only the default PATH and fallback paths are used",
+            (true, false, _) => {
+                "the PATH from environ is used for every access, system calls cannot be checked"
+            }
+            (true, true, false) => {
+                "the PATH passed to execve cannot be read, environ is used instead"
+            }
+            (true, true, true) => "environ, system calls and memory of processes are readable",
+        }
+    }
+}
+
+/// Environment of the process, or of its nearest ancestor with a readable one.
+///
+/// Fails with `EIO` if the environment of the process could not be read and
+/// with `ENOENT` if it is empty, e.g. for kernel threads.
+fn process_environment(task: &Task, config: &EnvConfig, trace: &Trace) -> nix::Result<Environment> {
+    if !config.proc_access.environ {
+        trace.add(|| String::from("environments of processes cannot be read"));
+        return Err(Errno::EIO);
+    }
+    match cached_environment(&*task.proc) {
+        Ok(env) if !env.is_empty() => {
+            if let Some(traceparent) = &env.traceparent {
+                spans::set_remote_parent(traceparent.as_bytes());
+            }
+            Ok(env)
+        }
+        res => {
+            let miss = match res {
+                Ok(_) => {
+                    trace.add(|| String::from("environment is empty"));
+                    Errno::ENOENT
+                }
+                Err(e) => {
+                    trace.add(|| format!("cannot read environment: {}", e));
+                    Errno::EIO
+                }
+            };
+            ancestor_environment(task, trace).ok_or(miss)
+        }
+    }
+}
+
+/// Searches the configured default PATH, used if no environment can be read
+/// for the reason `miss`.
+fn which_default<P1, P2>(
+    name: P1,
+    mountpoints: &[P2],
+    policy: &CandidatePolicy,
+    config: &EnvConfig,
+    miss: Errno,
+    trace: &Trace,
+) -> nix::Result<PathBuf>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    // e.g. lookups by kernel threads or usermode helpers
+    let path = config.default_path.as_deref().ok_or(miss)?;
+    trace.add(|| format!("default PATH: {}", path.to_string_lossy()));
+    which(path, name, &[], mountpoints, policy, trace)
+}
+
+/// Resolves `name` in the PATH of process `pid`.
+///
+/// All files are read through one open `/proc/<pid>` directory, the
+/// environment is only read once the system call does not decide alone.
+///
+/// Fails with `EACCES` if a candidate exists but cannot be executed by the
+/// caller, `EIO` if the files of the process could not be read and `ENOENT`
+/// otherwise.
+pub fn resolve_target<P1, P2>(
+    pid: Pid,
+    name: P1,
+    mountpoints: &[P2],
+    policy: &CandidatePolicy,
+    wait: Wait,
+    config: &EnvConfig,
+    trace: &Trace,
+) -> nix::Result<PathBuf>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let mut span = spans::span("resolve_target");
+    span.attr("process.pid", i64::from(pid.as_raw()));
+    let res = match Task::open(&RealProcfs, pid) {
+        Ok(task) => resolve_task(&task, name, mountpoints, policy, wait, config, trace),
+        Err(e) => {
+            trace.add(|| format!("cannot open process: {}", e));
+            // the process is gone, nobody is left to see the error
+            which_default(&name, mountpoints, policy, config, Errno::ENOENT, trace)
+        }
+    };
+    if let Err(e) = &res {
+        span.fail(|| e.desc().to_string());
+    }
+    res
+}
+
+fn resolve_task<P1, P2>(
+    task: &Task,
+    name: P1,
+    mountpoints: &[P2],
+    policy: &CandidatePolicy,
+    wait: Wait,
+    config: &EnvConfig,
+    trace: &Trace,
+) -> nix::Result<PathBuf>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let empty_path = config.empty_path;
+    if task.tgid != task.tid {
+        trace.add(|| format!("thread {} of process {}", task.tid, task.tgid));
+    }
+    let wait = if config.proc_access.syscall {
+        wait
+    } else {
+        trace.add(|| String::from("system calls cannot be read, use the PATH for every access"));
+        Wait::Never
+    };
+    // everything but the current system call and working directory is shared by all threads
+    let pid = task.tgid;
+    let proc = &*task.proc;
+    let deadline = match wait {
+        Wait::Until(deadline) => Some(deadline),
+        _ => None,
+    };
+    if let Wait::Never = wait {
+        let env = match process_environment(task, config, trace) {
+            Ok(env) => env,
+            Err(e) => return which_default(&name, mountpoints, policy, config, e, trace),
+        };
+        let path = env.path();
+        trace.add(|| {
+            format!(
+                "PATH from /proc/{}/environ: {}",
+                pid,
+                path.to_string_lossy()
+            )
+        });
+        return which_in_process(task, path, &name, mountpoints, policy, empty_path, trace);
+    }
+    if config.io_uring {
+        // the environment cache is checked against stat for every lookup that reads it
+        task.proc.prefetch(&[&task.file("syscall"), "stat"]);
+    }
+    let timeout = match deadline {
+        Some(deadline) => config.syscall_timeout.min(deadline.remaining()),
+        None => config.syscall_timeout,
+    };
+    let args = match get_syscall_args(task, timeout) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            debug!("process {} did not enter a syscall in time", pid);
+            if timeout < config.syscall_timeout {
+                if let Some(deadline) = deadline {
+                    deadline.mark_reached();
+                    trace.add(|| format!("lookup deadline of {:?} reached", deadline.budget()));
+                }
+            }
+            let env = match process_environment(task, config, trace) {
+                Ok(env) => env,
+                Err(e) => return which_default(&name, mountpoints, policy, config, e, trace),
+            };
+            let path = env.path();
+            trace.add(|| {
+                format!(
+                    "still running after {:?}, PATH from /proc/{}/environ: {}",
+                    timeout,
+                    pid,
+                    path.to_string_lossy()
+                )
+            });
+            return which_in_process(task, path, &name, mountpoints, policy, empty_path, trace);
+        }
+        Err(e) => {
+            debug!("Could not parse syscall arguments: {}", e);
+            trace.add(|| format!("cannot parse syscall arguments: {}", e));
+            return Err(Errno::EIO);
+        }
+    };
+    if args.is_empty() {
+        debug!("no syscall arguments received from /proc/<pid>/syscall");
+        trace.add(|| String::from("no syscall arguments in /proc/<pid>/syscall"));
+        return Err(Errno::EIO);
+    }
+    let abi = Abi::detect_in(proc);
+    let syscall = abi.classify(args[0]);
+    trace.add(|| format!("syscall number: {} ({:?}, {:?})", args[0], abi, syscall));
+
+    // execve is always allowed and handled differently
+    if syscall.is_exec() && !config.read_mem {
+        trace.add(|| String::from("reading the execve envp is disabled"));
+    } else if syscall.is_exec() && !config.proc_access.mem {
+        trace.add(|| String::from("memory of processes cannot be read, skip the execve envp"));
+    } else if syscall.is_exec() {
+        // If we have an execve system call, fetch the latest environment variables from /proc/<pid>/mem
+        if args.len() < 4 {
+            debug!(
+                "expected at least 4 syscall arguments in execve syscall, got {}",
+                args.len() - 1
+            );
+            return Err(Errno::EIO);
+        }
+        let envp = if syscall == Syscall::Execve {
+            args[3]
+        } else {
+            args[4]
+        };
+        match get_path_from_mem(proc, envp, abi.pointer_size()) {
+            Ok(path) => {
+                trace.add(|| format!("PATH from execve envp: {}", path.to_string_lossy()));
+                if let Ok(exe) =
+                    which_in_process(task, &path, &name, mountpoints, policy, empty_path, trace)
+                {
+                    return Ok(exe);
+                }
+            }
+            Err(e) => {
+                debug!(
+                    "Could not read environment variables from child from memory: {}",
+                    e
+                );
+                trace.add(|| format!("cannot read execve envp: {}", e));
+                // fallback to the default path
+            }
+        }
+    }
+    let env = match process_environment(task, config, trace) {
+        Ok(env) => env,
+        Err(e) => return which_default(&name, mountpoints, policy, config, e, trace),
+    };
+    let mut path = OsStr::new("");
+
+    // We need to allow open/openat because some programs want to open themself, i.e. bash
+    let allowed_syscall = config.allowed_syscalls.contains(abi, args[0])
+        || env.resolve_always
+        || env.resolve_paths_contains(name.as_ref());
+
+    if allowed_syscall {
+        path = env.path();
+        trace.add(|| {
+            format!(
+                "PATH from /proc/{}/environ: {}",
+                pid,
+                path.to_string_lossy()
+            )
+        });
+    } else {
+        trace.add(|| String::from("syscall does not execute or open, ignore PATH"));
+    }
+
+    which_in_process(task, path, &name, mountpoints, policy, empty_path, trace)
+}
+
+/// Returns `None` if the process is still running in userspace after `timeout`.
+fn get_syscall_args(task: &Task, timeout: Duration) -> Result<Option<Vec<usize>>> {
+    let file = task.file("syscall");
+    let started = Instant::now();
+    let mut backoff = Duration::from_micros(10);
+    let line = loop {
+        let line = task.proc.read_to_string(&file)?;
+        // Sometimes system calls are still in progress when we are trying to read them.
+        if line != "running\n" {
+            break line;
+        }
+        let elapsed = started.elapsed();
+        if elapsed >= timeout {
+            return Ok(None);
+        }
+        thread::sleep(backoff.min(timeout - elapsed));
+        backoff = (backoff * 2).min(Duration::from_millis(10));
+    };
+    parse_syscall_line(&line).map(Some)
+}
+
+/// Describes the system call process `pid` is currently in, for explanations.
+pub fn describe_syscall(pid: Pid) -> String {
+    let line = match fs::read_to_string(format!("/proc/{}/syscall", pid)) {
+        Ok(line) => line,
+        Err(e) => return format!("unknown ({})", e),
+    };
+    match parse_syscall_line(&line) {
+        Ok(args) if !args.is_empty() => {
+            let abi = Abi::detect(pid);
+            format!("{} ({:?}, {:?})", args[0], abi, abi.classify(args[0]))
+        }
+        // `running` or `-1 ...` while in userspace
+        _ => format!("none ({})", line.split(' ').next().unwrap_or("").trim()),
+    }
+}
+
+/// Parses a line of `/proc/<pid>/syscall` into the system call number and its arguments.
+pub fn parse_syscall_line(line: &str) -> Result<Vec<usize>> {
+    let res = line
+        .trim_end()
+        .split(' ')
+        .enumerate()
+        .map(|(i, col)| {
+            if i == 0 {
+                col.parse::<usize>()
+            } else {
+                usize::from_str_radix(col.strip_prefix("0x").unwrap_or(col), 16)
+            }
+        })
+        .collect::<std::result::Result<Vec<_>, _>>();
+    Ok(try_with!(
+        res,
+        "syscall arguments '{}' cannot be parsed as integer",
+        line
+    ))
+}
+
+/// Number of envp entries fetched per `process_vm_readv` call.
+const POINTER_BATCH: usize = 64;
+/// Bytes read from the start of each environment string in the first pass.
+const STRING_CHUNK: usize = 256;
+/// Smallest page size on Linux, reads that do not cross it cannot fault halfway.
+const PAGE_SIZE: usize = 4096;
+/// Upper bound of envp entries, the kernel limits argv and envp to ARG_MAX bytes together.
+const MAX_ENV_POINTERS: usize = 0x10000;
+/// Longest environment string execve accepts (MAX_ARG_STRLEN).
+const MAX_STRING_BYTES: usize = 32 * PAGE_SIZE;
+/// Bytes read from a tracee for a single lookup.
+const MAX_TOTAL_BYTES: usize = 4 * 1024 * 1024;
+
+/// Readable address ranges of a process from `/proc/<pid>/maps`.
+struct Mappings(Vec<(usize, usize)>);
+
+impl Mappings {
+    fn read(proc: &dyn ProcReader) -> Result<Mappings> {
+        let maps = proc.read_to_string("maps")?;
+        let mut ranges = vec![];
+        for line in maps.lines() {
+            let mut cols = line.split_whitespace();
+            let (range, perms) = match (cols.next(), cols.next()) {
+                (Some(range), Some(perms)) => (range, perms),
+                _ => continue,
+            };
+            if !perms.starts_with('r') {
+                continue;
+            }
+            if let Some((start, end)) = range.split_once('-') {
+                if let (Ok(start), Ok(end)) = (
+                    usize::from_str_radix(start, 16),
+                    usize::from_str_radix(end, 16),
+                ) {
+                    ranges.push((start, end));
+                }
+            }
+        }
+        Ok(Mappings(ranges))
+    }
+
+    fn check(&self, addr: usize, what: &str) -> Result<()> {
+        if self
+            .0
+            .iter()
+            .any(|(start, end)| *start <= addr && addr < *end)
+        {
+            Ok(())
+        } else {
+            bail!("{} at {:#x} is not in a readable mapping", what, addr)
+        }
+    }
+}
+
+/// Tracks the bytes read from a tracee against `MAX_TOTAL_BYTES`.
+struct Budget(usize);
+
+impl Budget {
+    fn take(&mut self, bytes: usize) -> Result<()> {
+        self.0 += bytes;
+        if self.0 > MAX_TOTAL_BYTES {
+            bail!("read more than {} bytes of environment", MAX_TOTAL_BYTES);
+        }
+        Ok(())
+    }
+}
+
+/// Length of a read at `addr` of at most `len` bytes that stays within one page.
+fn within_page(addr: usize, len: usize) -> usize {
+    len.min(PAGE_SIZE - addr % PAGE_SIZE)
+}
+
+/// Reads the pointers of the NULL-terminated array at `addr`.
+fn read_pointers(
+    proc: &dyn ProcReader,
+    mut addr: usize,
+    ptr_size: usize,
+    budget: &mut Budget,
+) -> Result<Vec<usize>> {
+    let mut pointers = vec![];
+    loop {
+        if pointers.len() > MAX_ENV_POINTERS {
+            bail!("envp has more than {} entries", MAX_ENV_POINTERS);
+        }
+        let len = within_page(addr, POINTER_BATCH * ptr_size);
+        budget.take(len)?;
+        let buf = proc.read_mem(&[RemoteIoVec { base: addr, len }])?.remove(0);
+        if buf.len() < ptr_size {
+            bail!("cannot read envp at {:#x}", addr);
+        }
+        for chunk in buf.chunks_exact(ptr_size) {
+            let p = match ptr_size {
+                4 => u32::from_ne_bytes(chunk.try_into().unwrap()) as usize,
+                _ => usize::from_ne_bytes(chunk.try_into().unwrap()),
+            };
+            // envp is terminated by a NULL pointer
+            if p == 0 {
+                return Ok(pointers);
+            }
+            pointers.push(p);
+        }
+        addr += buf.len() - buf.len() % ptr_size;
+    }
+}
+
+fn get_path_from_mem(proc: &dyn ProcReader, envp: usize, ptr_size: usize) -> Result<OsString> {
+    let maps = Mappings::read(proc)?;
+    maps.check(envp, "envp")?;
+    let mut budget = Budget(0);
+    let pointers = read_pointers(proc, envp, ptr_size, &mut budget)?;
+    for p in &pointers {
+        maps.check(*p, "environment string")?;
+    }
+    let ranges: Vec<RemoteIoVec> = pointers
+        .iter()
+        .map(|&base| RemoteIoVec {
+            base,
+            len: within_page(base, STRING_CHUNK),
+        })
+        .collect();
+    budget.take(ranges.iter().map(|r| r.len).sum())?;
+    let heads = proc.read_mem(&ranges)?;
+    for (range, head) in ranges.iter().zip(heads) {
+        if !head.starts_with(b"PATH=") {
+            continue;
+        }
+        let mut var = head;
+        let mut addr = range.base + var.len();
+        while !var.contains(&0) {
+            if var.len() > MAX_STRING_BYTES {
+                bail!("PATH is longer than {} bytes", MAX_STRING_BYTES);
+            }
+            let len = within_page(addr, PAGE_SIZE);
+            budget.take(len)?;
+            let chunk = proc.read_mem(&[RemoteIoVec { base: addr, len }])?.remove(0);
+            if chunk.is_empty() {
+                bail!("cannot read environment string at {:#x}", addr);
+            }
+            addr += chunk.len();
+            var.extend_from_slice(&chunk);
+        }
+        let end = var.iter().position(|c| *c == 0).unwrap_or(var.len());
+        return Ok(OsString::from_vec(var[5..end].to_vec()));
+    }
+    Ok(OsString::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::procdir::fake::{FakeProcess, FakeProcfs};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    /// A directory with an executable `prog`, removed when dropped.
+    struct BinDir(PathBuf);
+
+    impl std::ops::Deref for BinDir {
+        type Target = PathBuf;
+
+        fn deref(&self) -> &PathBuf {
+            &self.0
+        }
+    }
+
+    impl Drop for BinDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn bin_dir(test: &str) -> BinDir {
+        let dir = env::temp_dir().join(format!("envfs-resolve-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        let prog = dir.join("prog");
+        fs::write(&prog, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&prog, fs::Permissions::from_mode(0o755)).unwrap();
+        BinDir(dir)
+    }
+
+    /// A line of `/proc/<pid>/syscall` for system call `nr` with `args`.
+    fn syscall_line(nr: libc::c_long, args: &[usize]) -> String {
+        let mut line = nr.to_string();
+        for i in 0..6 {
+            line.push_str(&format!(" {:#x}", args.get(i).copied().unwrap_or(0)));
+        }
+        // stack and instruction pointer
+        line.push_str(" 0x7ffc0000 0x7f000000\n");
+        line
+    }
+
+    fn environ(path: &Path) -> Vec<u8> {
+        format!("HOME=/root\0PATH={}\0", path.display()).into_bytes()
+    }
+
+    fn resolve(procfs: &FakeProcfs, pid: i32, config: &EnvConfig) -> nix::Result<PathBuf> {
+        let task = Task::open(procfs, Pid::from_raw(pid)).unwrap();
+        resolve_task(
+            &task,
+            "prog",
+            &[] as &[PathBuf],
+            &CandidatePolicy::default(),
+            Wait::Timeout,
+            config,
+            &Trace::disabled(),
+        )
+    }
+
+    #[test]
+    fn open_uses_path_from_environ() {
+        let dir = bin_dir("open");
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file("task/100/syscall", syscall_line(libc::SYS_openat, &[]))
+                .file("environ", environ(&dir)),
+        );
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Ok(dir.join("prog"))
+        );
+    }
+
+    #[test]
+    fn other_syscalls_ignore_path() {
+        let dir = bin_dir("other");
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file("task/100/syscall", syscall_line(libc::SYS_getpid, &[]))
+                .file("environ", environ(&dir)),
+        );
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Err(Errno::ENOENT)
+        );
+    }
+
+    #[test]
+    fn resolve_always_opts_into_other_syscalls() {
+        let dir = bin_dir("always");
+        let mut env = environ(&dir);
+        env.extend_from_slice(b"ENVFS_RESOLVE_ALWAYS=1\0");
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file("task/100/syscall", syscall_line(libc::SYS_getpid, &[]))
+                .file("environ", env),
+        );
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Ok(dir.join("prog"))
+        );
+    }
+
+    #[test]
+    fn execve_uses_path_from_envp() {
+        let dir = bin_dir("execve");
+        let envp = 0x10000;
+        let string: usize = 0x20000;
+        let mut pointers = string.to_ne_bytes().to_vec();
+        pointers.extend_from_slice(&0usize.to_ne_bytes());
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file(
+                    "task/100/syscall",
+                    syscall_line(libc::SYS_execve, &[0x1000, 0x2000, envp]),
+                )
+                // the environment the process had before execve does not matter
+                .file("environ", environ(Path::new("/nonexistent")))
+                .file(
+                    "maps",
+                    "10000-11000 r--p 00000000 00:00 0 [stack]\n20000-21000 r--p 00000000 00:00 0\n",
+                )
+                .mem(envp, pointers)
+                .mem(string, format!("PATH={}\0", dir.display())),
+        );
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Ok(dir.join("prog"))
+        );
+    }
+
+    #[test]
+    fn execve_envp_is_ignored_without_mem_read() {
+        let envp_dir = bin_dir("execve-envp");
+        let environ_dir = bin_dir("execve-environ");
+        let envp = 0x10000;
+        let string: usize = 0x20000;
+        let mut pointers = string.to_ne_bytes().to_vec();
+        pointers.extend_from_slice(&0usize.to_ne_bytes());
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file(
+                    "task/100/syscall",
+                    syscall_line(libc::SYS_execve, &[0x1000, 0x2000, envp]),
+                )
+                .file("environ", environ(&environ_dir))
+                .file(
+                    "maps",
+                    "10000-11000 r--p 00000000 00:00 0 [stack]\n20000-21000 r--p 00000000 00:00 0\n",
+                )
+                .mem(envp, pointers)
+                .mem(string, format!("PATH={}\0", envp_dir.display())),
+        );
+        let config = EnvConfig {
+            read_mem: false,
+            ..EnvConfig::default()
+        };
+        assert_eq!(resolve(&procfs, 100, &config), Ok(environ_dir.join("prog")));
+    }
+
+    #[test]
+    fn unavailable_facilities_are_probed_and_skipped() {
+        let dir = bin_dir("probe");
+        let procfs = FakeProcfs::default().process(
+            1,
+            FakeProcess::default()
+                .file("environ", environ(&dir))
+                .file("maps", "10000-11000 rw-p 00000000 00:00 0 [stack]\n")
+                .mem(0x10000, vec![0; 16]),
+        );
+        let proc = procfs.open(Pid::from_raw(1)).unwrap();
+        let access = ProcAccess::probe_in(&*proc);
+        assert_eq!(
+            access,
+            ProcAccess {
+                environ: true,
+                syscall: false,
+                mem: true,
+            }
+        );
+
+        // without the syscall file every access uses the PATH
+        let procfs = FakeProcfs::default()
+            .process(100, FakeProcess::default().file("environ", environ(&dir)));
+        let config = EnvConfig {
+            proc_access: access,
+            ..EnvConfig::default()
+        };
+        assert_eq!(resolve(&procfs, 100, &config), Ok(dir.join("prog")));
+
+        let config = EnvConfig {
+            proc_access: ProcAccess {
+                environ: false,
+                ..ProcAccess::default()
+            },
+            ..EnvConfig::default()
+        };
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file("task/100/syscall", syscall_line(libc::SYS_openat, &[]))
+                .file("environ", environ(&dir)),
+        );
+        assert_eq!(resolve(&procfs, 100, &config), Err(Errno::EIO));
+    }
+
+    #[test]
+    fn execve_with_unmapped_envp_falls_back_to_environ() {
+        let dir = bin_dir("execve-unmapped");
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file(
+                    "task/100/syscall",
+                    syscall_line(libc::SYS_execve, &[0x1000, 0x2000, 0x10000]),
+                )
+                .file("environ", environ(&dir))
+                .file("maps", ""),
+        );
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Ok(dir.join("prog"))
+        );
+    }
+
+    #[test]
+    fn missing_environ_uses_parent() {
+        let dir = bin_dir("parent");
+        let procfs = FakeProcfs::default()
+            .process(
+                100,
+                FakeProcess::default()
+                    .file("task/100/syscall", syscall_line(libc::SYS_openat, &[]))
+                    .file("status", "Tgid:\t100\nPPid:\t50\n"),
+            )
+            .process(50, FakeProcess::default().file("environ", environ(&dir)));
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Ok(dir.join("prog"))
+        );
+    }
+
+    #[test]
+    fn missing_environ_uses_default_path() {
+        let dir = bin_dir("default");
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file("task/100/syscall", syscall_line(libc::SYS_openat, &[]))
+                .file("status", "Tgid:\t100\nPPid:\t0\n"),
+        );
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Err(Errno::EIO)
+        );
+        let config = EnvConfig {
+            default_path: Some(dir.as_os_str().to_os_string()),
+            ..EnvConfig::default()
+        };
+        assert_eq!(resolve(&procfs, 100, &config), Ok(dir.join("prog")));
+    }
+
+    #[test]
+    fn thread_reads_its_own_syscall() {
+        let dir = bin_dir("thread");
+        let procfs = FakeProcfs::default()
+            .process(101, FakeProcess::default().file("status", "Tgid:\t100\n"))
+            .process(
+                100,
+                FakeProcess::default()
+                    .file("task/100/syscall", syscall_line(libc::SYS_getpid, &[]))
+                    .file("task/101/syscall", syscall_line(libc::SYS_openat, &[]))
+                    .file("environ", environ(&dir)),
+            );
+        assert_eq!(
+            resolve(&procfs, 101, &EnvConfig::default()),
+            Ok(dir.join("prog"))
+        );
+    }
+
+    #[test]
+    fn running_process_uses_environ_after_timeout() {
+        let dir = bin_dir("running");
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file("task/100/syscall", "running\n")
+                .file("environ", environ(&dir)),
+        );
+        let config = EnvConfig {
+            syscall_timeout: Duration::from_millis(1),
+            ..EnvConfig::default()
+        };
+        assert_eq!(resolve(&procfs, 100, &config), Ok(dir.join("prog")));
+    }
+
+    #[test]
+    fn deadline_cuts_waiting_for_a_running_process_short() {
+        let dir = bin_dir("deadline");
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file("task/100/syscall", "running\n")
+                .file("environ", environ(&dir)),
+        );
+        // would wait a minute without the deadline
+        let config = EnvConfig {
+            syscall_timeout: Duration::from_secs(60),
+            ..EnvConfig::default()
+        };
+        let deadline = Deadline::new(Instant::now(), Duration::from_millis(5));
+        let task = Task::open(&procfs, Pid::from_raw(100)).unwrap();
+        let res = resolve_task(
+            &task,
+            "prog",
+            &[] as &[PathBuf],
+            &CandidatePolicy::default(),
+            Wait::Until(&deadline),
+            &config,
+            &Trace::disabled(),
+        );
+        assert_eq!(res, Ok(dir.join("prog")));
+        assert!(deadline.was_reached());
+    }
+
+    #[test]
+    fn garbage_syscall_lines_resolve_nothing() {
+        let dir = bin_dir("garbage");
+        for line in &[
+            "",
+            "\n",
+            "garbage\n",
+            "257 x\n",
+            "257 0xzz\n",
+            "-1 0x0 0x0\n",
+        ] {
+            let procfs = FakeProcfs::default().process(
+                100,
+                FakeProcess::default()
+                    .file("task/100/syscall", *line)
+                    .file("environ", environ(&dir)),
+            );
+            assert_eq!(
+                resolve(&procfs, 100, &EnvConfig::default()),
+                Err(Errno::EIO),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn relative_path_entries_use_cwd_of_thread() {
+        let dir = bin_dir("cwd");
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file("task/100/syscall", syscall_line(libc::SYS_openat, &[]))
+                .file("environ", environ(Path::new(".")))
+                .link("task/100/cwd", dir.as_path()),
+        );
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Err(Errno::ENOENT)
+        );
+        let config = EnvConfig {
+            empty_path: EmptyPath::Cwd,
+            ..EnvConfig::default()
+        };
+        assert_eq!(resolve(&procfs, 100, &config), Ok(dir.join("./prog")));
+    }
+
+    #[test]
+    fn missing_syscall_file_resolves_nothing() {
+        let dir = bin_dir("no-syscall");
+        let procfs = FakeProcfs::default()
+            .process(100, FakeProcess::default().file("environ", environ(&dir)));
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Err(Errno::EIO)
+        );
+    }
+
+    #[test]
+    fn non_executable_candidate_is_reported_as_eacces() {
+        let dir = bin_dir("noexec");
+        fs::set_permissions(dir.join("prog"), fs::Permissions::from_mode(0o644)).unwrap();
+        let empty = bin_dir("noexec-empty");
+        fs::remove_file(empty.join("prog")).unwrap();
+        let path = format!("{}:{}", dir.display(), empty.display());
+        let res = which(
+            OsStr::new(&path),
+            "prog",
+            &[],
+            &[] as &[PathBuf],
+            &CandidatePolicy::default(),
+            &Trace::disabled(),
+        );
+        assert_eq!(res, Err(Errno::EACCES));
+
+        let procfs = FakeProcfs::default().process(
+            100,
+            FakeProcess::default()
+                .file("task/100/syscall", syscall_line(libc::SYS_openat, &[]))
+                .file("environ", format!("PATH={}\0", empty.display())),
+        );
+        assert_eq!(
+            resolve(&procfs, 100, &EnvConfig::default()),
+            Err(Errno::ENOENT)
+        );
+    }
+
+    #[test]
+    fn candidates_of_the_caller_architecture_are_preferred() {
+        let elf = |class: u8| {
+            let mut header = b"\x7fELF".to_vec();
+            header.extend_from_slice(&[class, 1]);
+            header.resize(18, 0);
+            header.extend_from_slice(&62u16.to_le_bytes());
+            header
+        };
+        let i386 = bin_dir("arch-32");
+        fs::write(i386.join("prog"), elf(1)).unwrap();
+        let amd64 = bin_dir("arch-64");
+        fs::write(amd64.join("prog"), elf(2)).unwrap();
+        let path = format!("{}:{}", i386.display(), amd64.display());
+        let which_for = |class| {
+            let policy = CandidatePolicy {
+                prefer_arch: true,
+                arch: Some(ElfArch { class, machine: 62 }),
+                ..CandidatePolicy::default()
+            };
+            which(
+                OsStr::new(&path),
+                "prog",
+                &[],
+                &[] as &[PathBuf],
+                &policy,
+                &Trace::disabled(),
+            )
+        };
+        assert_eq!(which_for(2), Ok(amd64.join("prog")));
+        assert_eq!(which_for(1), Ok(i386.join("prog")));
+        // falls back to the first match
+        assert_eq!(which_for(3), Ok(i386.join("prog")));
+    }
+
+    #[test]
+    fn candidates_with_missing_interpreter_are_skipped() {
+        let elf = |interp: &str| {
+            let mut data = b"\x7fELF\x02\x01".to_vec();
+            data.resize(64, 0);
+            data[18..20].copy_from_slice(&62u16.to_le_bytes());
+            // e_phoff, e_phentsize and e_phnum
+            data[32..40].copy_from_slice(&64u64.to_le_bytes());
+            data[54..56].copy_from_slice(&56u16.to_le_bytes());
+            data[56..58].copy_from_slice(&1u16.to_le_bytes());
+            let mut header = vec![0; 56];
+            header[..4].copy_from_slice(&3u32.to_le_bytes());
+            header[8..16].copy_from_slice(&120u64.to_le_bytes());
+            header[32..40].copy_from_slice(&(interp.len() as u64 + 1).to_le_bytes());
+            data.extend_from_slice(&header);
+            data.extend_from_slice(interp.as_bytes());
+            data.push(0);
+            data
+        };
+        let broken = bin_dir("interp-missing");
+        fs::write(broken.join("prog"), elf("/nonexistent/ld.so")).unwrap();
+        assert_eq!(
+            elf::interpreter(&elf("/nonexistent/ld.so")),
+            Some(PathBuf::from("/nonexistent/ld.so"))
+        );
+        let working = bin_dir("interp-present");
+        fs::write(working.join("prog"), elf("/bin/sh")).unwrap();
+        let path = format!("{}:{}", broken.display(), working.display());
+        let policy = CandidatePolicy {
+            check_interp: true,
+            root: Some(PathBuf::from("/proc/self/root")),
+            ..CandidatePolicy::default()
+        };
+        let res = which(
+            OsStr::new(&path),
+            "prog",
+            &[],
+            &[] as &[PathBuf],
+            &policy,
+            &Trace::disabled(),
+        );
+        assert_eq!(res, Ok(working.join("prog")));
+    }
+
+    #[test]
+    fn candidates_outside_trusted_prefixes_are_refused() {
+        let prefixes = [PathBuf::from("/nix/store"), PathBuf::from("/usr/bin")];
+        assert!(!outside_prefixes(Path::new("/etc/passwd"), &[]));
+        assert!(!outside_prefixes(Path::new("/usr/bin/env"), &prefixes));
+        assert!(outside_prefixes(Path::new("/tmp/env"), &prefixes));
+        // whole components only
+        assert!(outside_prefixes(Path::new("/usr/binx/env"), &prefixes));
+        assert!(outside_prefixes(
+            Path::new("/usr/bin/../../tmp/env"),
+            &prefixes
+        ));
+
+        let policy = CandidatePolicy {
+            trusted_prefixes: prefixes.to_vec(),
+            ..CandidatePolicy::default()
+        };
+        assert_eq!(
+            policy.check(Path::new("/tmp/env")),
+            Err(String::from("outside of the trusted prefixes"))
+        );
+    }
+
+    #[test]
+    fn setuid_candidates_are_refused_outside_setuid_prefixes() {
+        let dir = bin_dir("setuid");
+        let prog = dir.join("prog");
+        let mut policy = CandidatePolicy::default();
+        fs::set_permissions(&prog, fs::Permissions::from_mode(0o4755)).unwrap();
+        assert_eq!(policy.check(&prog), Ok(()));
+        policy.refuse_setuid = true;
+        assert_eq!(policy.check(&prog), Err(String::from("setuid")));
+        fs::set_permissions(&prog, fs::Permissions::from_mode(0o2755)).unwrap();
+        assert_eq!(policy.check(&prog), Err(String::from("setgid")));
+        // mandatory locking, not setgid
+        fs::set_permissions(&prog, fs::Permissions::from_mode(0o2745)).unwrap();
+        assert_eq!(policy.check(&prog), Ok(()));
+        fs::set_permissions(&prog, fs::Permissions::from_mode(0o4755)).unwrap();
+        policy.setuid_prefixes = vec![dir.to_path_buf()];
+        assert_eq!(policy.check(&prog), Ok(()));
+    }
+
+    #[test]
+    fn interpreter_fallback_stays_in_root() {
+        let root = bin_dir("interp-root");
+        fs::create_dir_all(root.join("lib/real")).unwrap();
+        fs::write(root.join("lib/real/ld.so"), "").unwrap();
+        symlink("/lib/real/ld.so", root.join("lib/ld.so")).unwrap();
+        symlink("../../../lib/real", root.join("lib/up")).unwrap();
+        // present in the root of envfs, but not in the one of the caller
+        symlink("/bin/sh", root.join("lib/host")).unwrap();
+        symlink("loop", root.join("lib/loop")).unwrap();
+        let lookup = |path: &str| {
+            let fd = fcntl::open(
+                root.as_path(),
+                OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )
+            .unwrap();
+            lookup_in_root(unsafe { OwnedFd::from_raw_fd(fd) }, Path::new(path))
+        };
+        assert_eq!(lookup("/lib/ld.so"), Ok(()));
+        assert_eq!(lookup("/lib/up/ld.so"), Ok(()));
+        assert_eq!(lookup("/../../lib/real/ld.so"), Ok(()));
+        assert_eq!(lookup("/lib/host"), Err(Errno::ENOENT));
+        assert_eq!(lookup("/lib/loop"), Err(Errno::ELOOP));
+        assert_eq!(lookup("/prog/ld.so"), Err(Errno::ENOTDIR));
+    }
+
+    #[test]
+    fn directories_are_only_served_when_allowed() {
+        let first = bin_dir("subdirs-1");
+        fs::create_dir(first.join("core_perl")).unwrap();
+        let second = bin_dir("subdirs-2");
+        fs::create_dir(second.join("core_perl")).unwrap();
+        let tool = second.join("core_perl").join("tool");
+        fs::write(&tool, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let path = format!("{}:{}", first.display(), second.display());
+        let which_with = |name: &str, allow_dirs| {
+            let policy = CandidatePolicy {
+                allow_dirs,
+                ..CandidatePolicy::default()
+            };
+            which(
+                OsStr::new(&path),
+                name,
+                &[],
+                &[] as &[PathBuf],
+                &policy,
+                &Trace::disabled(),
+            )
+        };
+        assert_eq!(which_with("core_perl", false), Err(Errno::ENOENT));
+        assert_eq!(which_with("core_perl", true), Ok(first.join("core_perl")));
+        assert_eq!(which_with("core_perl/tool", false), Ok(tool));
+    }
+
+    #[test]
+    fn lookup_env_keeps_only_used_variables() {
+        let env = LookupEnv::parse(
+            b"HOME=/root\0PATH=/bin\0NOEQ\0ENVFS_RESOLVE_ALWAYS=\0PATH=/usr/bin\0",
+        );
+        assert_eq!(env.variables, 4);
+        assert_eq!(env.path(), OsStr::new("/usr/bin"));
+        assert!(env.resolve_always);
+        assert_eq!(env.resolve_paths, None);
+        assert!(LookupEnv::parse(b"").is_empty());
+        assert_eq!(environ_var(b"A=1\0B=2\0", "B"), Some(&b"2"[..]));
+    }
+}
@@ -0,0 +1,728 @@
+//! Pluggable strategies to map a name to an executable, tried in order by the filesystem.
+
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::unistd::{Pid, Uid, User};
+use simple_error::{bail, try_with};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::Read;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::creds::check_executable;
+use crate::resolve::{
+    environ_var, read_tgid, resolve_target, which, worse_miss, CandidatePolicy, Deadline,
+    EnvConfig, Trace, Wait,
+};
+use crate::result::Result;
+use crate::sandbox::Ruleset;
+use crate::underlay::Underlay;
+
+/// The process on whose behalf a name is resolved.
+pub struct RequestCtx<'a> {
+    pub pid: Pid,
+    pub uid: u32,
+    /// envfs mountpoints, directories below them are never considered.
+    pub mountpoints: &'a [PathBuf],
+    pub policy: &'a CandidatePolicy,
+    /// Use the PATH of the process even if it is not currently executing or opening a file.
+    pub resolve_always: bool,
+    /// Time budget of the request, resolvers stop waiting for the process once it is used up
+    pub deadline: Option<&'a Deadline>,
+    pub trace: &'a Trace,
+}
+
+/// Where a resolution came from, counted separately in the statistics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The PATH of the requesting process
+    Path,
+    /// The fallback paths
+    Fallback,
+    /// Static entries and the underlay, independent of the process
+    Static,
+    /// Profiles, interpreters, the resolve hook and custom resolvers
+    Other,
+}
+
+impl Source {
+    pub const ALL: [Source; 4] = [
+        Source::Path,
+        Source::Fallback,
+        Source::Static,
+        Source::Other,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Source::Path => "path",
+            Source::Fallback => "fallback",
+            Source::Static => "static",
+            Source::Other => "other",
+        }
+    }
+}
+
+/// A strategy to map a name to an executable.
+///
+/// Misses are reported with the errno for the caller, see `worse_miss`.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf>;
+
+    /// Names the resolver in traces.
+    fn describe(&self) -> String {
+        String::from("custom resolver")
+    }
+
+    /// Counted for the resolutions of this resolver.
+    fn source(&self) -> Source {
+        Source::Other
+    }
+}
+
+/// Resolves against the PATH of the requesting process.
+#[derive(Default)]
+pub struct EnvResolver {
+    pub config: EnvConfig,
+}
+
+impl Resolver for EnvResolver {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        let wait = match ctx.deadline {
+            _ if ctx.resolve_always => Wait::Never,
+            Some(deadline) => Wait::Until(deadline),
+            None => Wait::Timeout,
+        };
+        resolve_target(
+            ctx.pid,
+            name,
+            ctx.mountpoints,
+            ctx.policy,
+            wait,
+            &self.config,
+            ctx.trace,
+        )
+    }
+
+    fn describe(&self) -> String {
+        String::from("PATH of the process")
+    }
+
+    fn source(&self) -> Source {
+        Source::Path
+    }
+}
+
+/// Whether a fallback path is tried before or after the PATH of the requesting process.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority {
+    Before,
+    After,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct FallbackPaths {
+    pub before: Vec<PathBuf>,
+    pub after: Vec<PathBuf>,
+}
+
+impl FallbackPaths {
+    pub fn push(&mut self, path: PathBuf, priority: Priority) {
+        match priority {
+            Priority::Before => self.before.push(path),
+            Priority::After => self.after.push(path),
+        }
+    }
+
+    pub fn get(&self, priority: Priority) -> &[PathBuf] {
+        match priority {
+            Priority::Before => &self.before,
+            Priority::After => &self.after,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.before.is_empty() && self.after.is_empty()
+    }
+}
+
+/// Resolves against a list of directories independently of the requesting process.
+pub struct FallbackResolver {
+    paths: Arc<RwLock<FallbackPaths>>,
+    priority: Priority,
+}
+
+impl FallbackResolver {
+    /// `paths` is shared so that it can be changed while mounted, only the
+    /// paths with `priority` are used.
+    pub fn new(paths: Arc<RwLock<FallbackPaths>>, priority: Priority) -> FallbackResolver {
+        FallbackResolver { paths, priority }
+    }
+}
+
+impl Resolver for FallbackResolver {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        let paths = self.paths.read().unwrap();
+        let paths = paths.get(self.priority);
+        if paths.is_empty() {
+            return Err(Errno::ENOENT);
+        }
+        match self.priority {
+            Priority::Before => ctx
+                .trace
+                .add(|| String::from("try fallback paths before PATH")),
+            Priority::After => ctx.trace.add(|| String::from("try fallback paths")),
+        }
+        which(
+            OsStr::new(""),
+            name,
+            paths,
+            ctx.mountpoints,
+            ctx.policy,
+            ctx.trace,
+        )
+    }
+
+    fn describe(&self) -> String {
+        match self.priority {
+            Priority::Before => String::from("fallback paths before PATH"),
+            Priority::After => String::from("fallback paths"),
+        }
+    }
+
+    fn source(&self) -> Source {
+        Source::Fallback
+    }
+}
+
+/// Fixed name to path mappings that are served without looking at the requesting process.
+pub struct StaticResolver {
+    entries: BTreeMap<OsString, PathBuf>,
+}
+
+impl StaticResolver {
+    pub fn new(entries: BTreeMap<OsString, PathBuf>) -> StaticResolver {
+        StaticResolver { entries }
+    }
+
+    /// Reads a file with one `NAME PATH` pair per line, lines starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> Result<StaticResolver> {
+        let content = try_with!(fs::read(path), "cannot read {}", path.display());
+        let mut entries = BTreeMap::new();
+        for (i, line) in content.split(|c| *c == b'\n').enumerate() {
+            let line = line.trim_ascii();
+            if line.is_empty() || line.starts_with(b"#") {
+                continue;
+            }
+            let (name, target) = match line.iter().position(u8::is_ascii_whitespace) {
+                Some(pos) => (&line[..pos], line[pos..].trim_ascii_start()),
+                None => bail!("{}:{}: expected NAME PATH", path.display(), i + 1),
+            };
+            if name.contains(&b'/') || !target.starts_with(b"/") {
+                bail!(
+                    "{}:{}: NAME must not contain '/' and PATH must be absolute",
+                    path.display(),
+                    i + 1
+                );
+            }
+            entries.insert(
+                OsString::from_vec(name.to_vec()),
+                PathBuf::from(OsString::from_vec(target.to_vec())),
+            );
+        }
+        Ok(StaticResolver { entries })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &OsStr> {
+        self.entries.keys().map(OsString::as_os_str)
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        let path = self.entries.get(name).ok_or(Errno::ENOENT)?;
+        ctx.trace
+            .add(|| format!("static entry: {}", path.display()));
+        Ok(path.clone())
+    }
+
+    fn describe(&self) -> String {
+        String::from("static entries")
+    }
+
+    fn source(&self) -> Source {
+        Source::Static
+    }
+}
+
+impl Resolver for Underlay {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        let path = self.lookup(name)?;
+        ctx.trace
+            .add(|| format!("underlay entry: {}", path.display()));
+        // symlinks are followed by the kernel with the permissions of the caller anyway
+        if self.mount_path().is_some_and(|m| path.starts_with(m)) {
+            if let Err(e) = check_executable(&path) {
+                ctx.trace
+                    .add(|| format!("underlay: {}: {}", path.display(), e.desc()));
+                return Err(e);
+            }
+        }
+        Ok(path)
+    }
+
+    fn describe(&self) -> String {
+        String::from("underlay")
+    }
+
+    fn source(&self) -> Source {
+        Source::Static
+    }
+}
+
+/// Resolves against the nix profiles of the requesting user, even if they are not in its PATH.
+pub struct NixProfileResolver;
+
+impl Resolver for NixProfileResolver {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        let user = match User::from_uid(Uid::from_raw(ctx.uid)) {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                ctx.trace
+                    .add(|| format!("no passwd entry for uid {}", ctx.uid));
+                return Err(Errno::ENOENT);
+            }
+            Err(e) => {
+                ctx.trace
+                    .add(|| format!("cannot look up uid {}: {}", ctx.uid, e));
+                return Err(Errno::ENOENT);
+            }
+        };
+        let profiles = [
+            user.dir.join(".nix-profile/bin"),
+            Path::new("/etc/profiles/per-user")
+                .join(&user.name)
+                .join("bin"),
+        ];
+        ctx.trace
+            .add(|| format!("try nix profiles of {}", user.name));
+        which(
+            OsStr::new(""),
+            name,
+            &profiles,
+            ctx.mountpoints,
+            ctx.policy,
+            ctx.trace,
+        )
+    }
+
+    fn describe(&self) -> String {
+        String::from("nix profiles of the user")
+    }
+}
+
+/// Interpreters commonly named in `#!` lines, served by `InterpreterResolver` by default.
+pub const DEFAULT_INTERPRETERS: &[&str] = &[
+    "bash", "sh", "zsh", "fish", "ksh", "dash", "python", "python2", "python3", "perl", "ruby",
+    "node", "php", "lua", "tclsh", "awk", "gawk", "Rscript", "guile", "runghc",
+];
+
+/// Profiles searched by `InterpreterResolver` before the per-user profiles.
+const SYSTEM_PROFILES: &[&str] = &[
+    "/run/current-system/sw/bin",
+    "/nix/var/nix/profiles/default/bin",
+];
+const USER_PROFILES: &str = "/etc/profiles/per-user";
+
+/// Finds the interpreters of scripts in the installed profiles, so that
+/// `#!/usr/bin/python3` works for callers that do not have it in their PATH.
+///
+/// Other names are left to the remaining resolvers, a script interpreter
+/// installed by any user is better than none but a program is not.
+pub struct InterpreterResolver {
+    names: Vec<OsString>,
+}
+
+impl InterpreterResolver {
+    pub fn new(names: Vec<OsString>) -> InterpreterResolver {
+        InterpreterResolver { names }
+    }
+
+    /// The system profiles followed by those in `/etc/profiles/per-user` in
+    /// the order of their names.
+    fn profiles() -> Vec<PathBuf> {
+        let mut profiles: Vec<PathBuf> = SYSTEM_PROFILES.iter().map(PathBuf::from).collect();
+        let mut users: Vec<PathBuf> = match fs::read_dir(USER_PROFILES) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path().join("bin"))
+                .collect(),
+            Err(_) => vec![],
+        };
+        users.sort();
+        profiles.extend(users);
+        profiles
+    }
+}
+
+impl Resolver for InterpreterResolver {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        if !self.names.iter().any(|n| n == name) {
+            return Err(Errno::ENOENT);
+        }
+        ctx.trace
+            .add(|| String::from("try installed profiles for interpreter"));
+        which(
+            OsStr::new(""),
+            name,
+            &InterpreterResolver::profiles(),
+            ctx.mountpoints,
+            ctx.policy,
+            ctx.trace,
+        )
+    }
+
+    fn describe(&self) -> String {
+        String::from("interpreters in installed profiles")
+    }
+}
+
+/// Where systemd-sysext looks for extensions, in order of precedence.
+pub const DEFAULT_SYSEXT_HIERARCHIES: &[&str] = &[
+    "/etc/extensions",
+    "/run/extensions",
+    "/var/lib/extensions",
+    "/usr/local/lib/extensions",
+    "/usr/lib/extensions",
+];
+
+/// Lists the extensions merged into `/usr`, one release file each.
+const SYSEXT_RELEASE_DIR: &str = "/usr/lib/extension-release.d";
+
+/// How often `SysextResolver` looks for newly merged or unmerged extensions.
+const SYSEXT_RESCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+struct SysextState {
+    /// `usr/bin` and `usr/sbin` of the merged extensions
+    dirs: Vec<PathBuf>,
+    checked: Option<Instant>,
+}
+
+/// Serves the executables of systemd-sysext extensions while they are merged.
+///
+/// The extensions overlay `/usr`, so their programs are hidden below an envfs
+/// mountpoint on `/usr/bin`. Only extensions that are directories are
+/// searched, raw images are only mounted inside the overlay.
+pub struct SysextResolver {
+    hierarchies: Vec<PathBuf>,
+    state: Mutex<SysextState>,
+}
+
+impl SysextResolver {
+    pub fn new(hierarchies: Vec<PathBuf>) -> SysextResolver {
+        SysextResolver {
+            hierarchies,
+            state: Mutex::new(SysextState {
+                dirs: vec![],
+                checked: None,
+            }),
+        }
+    }
+
+    /// The bin directories of the extensions in `hierarchies` that have a
+    /// release file in `release_dir`. An extension found in an earlier
+    /// hierarchy hides those of the same name in later ones.
+    fn scan(hierarchies: &[PathBuf], release_dir: &Path) -> Vec<PathBuf> {
+        let mut seen = BTreeSet::new();
+        let mut dirs = vec![];
+        for hierarchy in hierarchies {
+            let mut extensions: Vec<PathBuf> = match fs::read_dir(hierarchy) {
+                Ok(entries) => entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_dir())
+                    .collect(),
+                Err(_) => continue,
+            };
+            extensions.sort();
+            for extension in extensions {
+                let name = match extension.file_name() {
+                    Some(name) => name.to_os_string(),
+                    None => continue,
+                };
+                if !seen.insert(name.clone()) {
+                    continue;
+                }
+                let mut release = OsString::from("extension-release.");
+                release.push(&name);
+                if !release_dir.join(release).exists() {
+                    continue;
+                }
+                for bin in ["usr/bin", "usr/sbin"] {
+                    let dir = extension.join(bin);
+                    if dir.is_dir() {
+                        dirs.push(dir);
+                    }
+                }
+            }
+        }
+        dirs
+    }
+
+    fn dirs(&self) -> Vec<PathBuf> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .checked
+            .is_some_and(|checked| checked.elapsed() < SYSEXT_RESCAN_INTERVAL)
+        {
+            return state.dirs.clone();
+        }
+        state.checked = Some(Instant::now());
+        let dirs = SysextResolver::scan(&self.hierarchies, Path::new(SYSEXT_RELEASE_DIR));
+        if dirs != state.dirs {
+            info!("serving executables of merged extensions from {:?}", dirs);
+            state.dirs = dirs;
+        }
+        state.dirs.clone()
+    }
+}
+
+impl Resolver for SysextResolver {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        let dirs = self.dirs();
+        if dirs.is_empty() {
+            return Err(Errno::ENOENT);
+        }
+        ctx.trace
+            .add(|| String::from("try merged system extensions"));
+        which(
+            OsStr::new(""),
+            name,
+            &dirs,
+            ctx.mountpoints,
+            ctx.policy,
+            ctx.trace,
+        )
+    }
+
+    fn describe(&self) -> String {
+        String::from("merged system extensions")
+    }
+
+    fn source(&self) -> Source {
+        Source::Fallback
+    }
+}
+
+/// Set in the environment of the resolve hook so that its own misses do not run it again.
+const HOOK_ENV: &str = "ENVFS_RESOLVE_HOOK";
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asks an external program for the location of a name, used as a last resort.
+///
+/// The program is called as `PROGRAM NAME PID UID` and prints the absolute
+/// path of an executable on stdout.
+pub struct HookResolver {
+    program: PathBuf,
+    sandbox: Option<Arc<Ruleset>>,
+}
+
+impl HookResolver {
+    pub fn new(program: PathBuf) -> HookResolver {
+        HookResolver {
+            program,
+            sandbox: None,
+        }
+    }
+
+    /// Runs the program restricted to `ruleset`.
+    pub fn sandbox(mut self, ruleset: Ruleset) -> HookResolver {
+        self.sandbox = Some(Arc::new(ruleset));
+        self
+    }
+
+    fn run(&self, ctx: &RequestCtx, name: &OsStr) -> Result<OsString> {
+        let mut command = Command::new(&self.program);
+        command
+            .arg(name)
+            .arg(ctx.pid.to_string())
+            .arg(ctx.uid.to_string())
+            .env(HOOK_ENV, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+        if let Some(ref ruleset) = self.sandbox {
+            let ruleset = Arc::clone(ruleset);
+            unsafe {
+                command.pre_exec(move || ruleset.restrict_self());
+            }
+        }
+        let mut child = try_with!(command.spawn(), "cannot execute {}", self.program.display());
+
+        let started = Instant::now();
+        let status = loop {
+            match try_with!(child.try_wait(), "cannot wait for hook") {
+                Some(status) => break status,
+                None if started.elapsed() < HOOK_TIMEOUT => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                None => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    bail!("timed out after {:?}", HOOK_TIMEOUT);
+                }
+            }
+        };
+        if !status.success() {
+            bail!("exited with {}", status);
+        }
+
+        let mut out = vec![];
+        if let Some(mut stdout) = child.stdout.take() {
+            try_with!(stdout.read_to_end(&mut out), "cannot read output");
+        }
+        let line = out.split(|c| *c == b'\n').next().unwrap_or_default();
+        Ok(OsString::from_vec(line.to_vec()))
+    }
+}
+
+impl Resolver for HookResolver {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        let pid = read_tgid(ctx.pid).unwrap_or(ctx.pid);
+        match fs::read(format!("/proc/{}/environ", pid)) {
+            Ok(environ) if environ_var(&environ, HOOK_ENV).is_some() => {
+                ctx.trace
+                    .add(|| String::from("request from the resolve hook, skip it"));
+                return Err(Errno::ENOENT);
+            }
+            _ => {}
+        }
+
+        let out = match self.run(ctx, name) {
+            Ok(out) => out,
+            Err(e) => {
+                warn!("resolve hook for {}: {}", name.to_string_lossy(), e);
+                ctx.trace.add(|| format!("resolve hook: {}", e));
+                return Err(Errno::ENOENT);
+            }
+        };
+        let path = Path::new(&out);
+        if !path.is_absolute() || ctx.mountpoints.iter().any(|m| path.starts_with(m)) {
+            ctx.trace
+                .add(|| format!("resolve hook: ignore '{}'", path.display()));
+            return Err(Errno::ENOENT);
+        }
+        if let Err(reason) = ctx.policy.check(path) {
+            ctx.trace
+                .add(|| format!("resolve hook: refused {}, {}", path.display(), reason));
+            return Err(Errno::ENOENT);
+        }
+        match check_executable(path) {
+            Ok(()) => {
+                ctx.trace
+                    .add(|| format!("resolve hook: found {}", path.display()));
+                Ok(path.to_path_buf())
+            }
+            Err(e) => {
+                ctx.trace
+                    .add(|| format!("resolve hook: {}: {}", path.display(), e.desc()));
+                Err(e)
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("resolve hook {}", self.program.display())
+    }
+}
+
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        (**self).resolve(ctx, name)
+    }
+
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+
+    fn source(&self) -> Source {
+        (**self).source()
+    }
+}
+
+/// Tries each resolver in order and returns the first match, or the most
+/// specific miss if none matches.
+#[derive(Default)]
+pub struct Stack {
+    resolvers: Vec<Box<dyn Resolver>>,
+}
+
+impl Stack {
+    pub fn push<R: Resolver + 'static>(&mut self, resolver: R) {
+        self.resolvers.push(Box::new(resolver));
+    }
+
+    pub fn push_boxed(&mut self, resolver: Box<dyn Resolver>) {
+        self.resolvers.push(resolver);
+    }
+
+    /// Like `resolve`, but also tells which of the resolvers matched.
+    pub fn resolve_source(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<(PathBuf, Source)> {
+        let mut miss = Errno::ENOENT;
+        for resolver in &self.resolvers {
+            ctx.trace.add(|| format!("{}:", resolver.describe()));
+            let _nested = ctx.trace.nested();
+            match resolver.resolve(ctx, name) {
+                Ok(path) => return Ok((path, resolver.source())),
+                Err(e) => {
+                    ctx.trace.add(|| format!("miss: {}", e.desc()));
+                    miss = worse_miss(miss, e)
+                }
+            }
+        }
+        Err(miss)
+    }
+}
+
+impl Resolver for Stack {
+    fn resolve(&self, ctx: &RequestCtx, name: &OsStr) -> nix::Result<PathBuf> {
+        self.resolve_source(ctx, name).map(|(path, _)| path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_merged_extensions_are_searched() {
+        let root = std::env::temp_dir().join(format!("envfs-sysext-{}", std::process::id()));
+        let etc = root.join("etc");
+        let run = root.join("run");
+        let release_dir = root.join("extension-release.d");
+        for dir in [
+            etc.join("tools/usr/bin"),
+            run.join("tools/usr/bin"),
+            run.join("debug/usr/sbin"),
+            run.join("unmerged/usr/bin"),
+            release_dir.clone(),
+        ] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        for name in ["tools", "debug"] {
+            fs::write(release_dir.join(format!("extension-release.{}", name)), "").unwrap();
+        }
+        let dirs = SysextResolver::scan(&[etc.clone(), run.clone()], &release_dir);
+        assert_eq!(
+            dirs,
+            vec![etc.join("tools/usr/bin"), run.join("debug/usr/sbin")]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
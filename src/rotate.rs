@@ -0,0 +1,98 @@
+//! Append-only log file with size based rotation.
+
+use simple_error::try_with;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::result::Result;
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// Rotate once the file would grow beyond this size
+    max_size: u64,
+    /// Number of rotated files kept as `path.1` ... `path.N`
+    keep: usize,
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    let file = try_with!(
+        OpenOptions::new().create(true).append(true).open(path),
+        "cannot open {}",
+        path.display()
+    );
+    Ok(file)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> Result<RotatingFile> {
+        let file = open_append(path)?;
+        let size = try_with!(file.metadata(), "cannot stat {}", path.display()).len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.keep == 0 {
+            try_with!(
+                self.file.set_len(0),
+                "cannot truncate {}",
+                self.path.display()
+            );
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    try_with!(
+                        fs::rename(&from, rotated_path(&self.path, n + 1)),
+                        "cannot rotate {}",
+                        from.display()
+                    );
+                }
+            }
+            try_with!(
+                fs::rename(&self.path, rotated_path(&self.path, 1)),
+                "cannot rotate {}",
+                self.path.display()
+            );
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Opens `path` again, e.g. after logrotate moved the file away.
+    pub fn reopen(&mut self) -> Result<()> {
+        let file = open_append(&self.path)?;
+        self.size = try_with!(file.metadata(), "cannot stat {}", self.path.display()).len();
+        self.file = file;
+        Ok(())
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        try_with!(
+            writeln!(self.file, "{}", line),
+            "cannot write to {}",
+            self.path.display()
+        );
+        self.size += len;
+        Ok(())
+    }
+}
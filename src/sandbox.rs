@@ -0,0 +1,536 @@
+//! Restricts the daemon with seccomp and the resolve hook with Landlock once the filesystem is mounted.
+//!
+//! Looking up names does not need much: reading /proc, checking files with
+//! `faccessat2`, `stat` and `readlink` and talking to the kernel over the
+//! already open /dev/fuse.
+
+use log::{info, warn};
+use nix::errno::Errno;
+use simple_error::try_with;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use crate::result::Result;
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_uint = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+/// Rights known to the first Landlock ABI
+const ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+/// Rights that can be granted on a file rather than a directory
+const ACCESS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+const READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+const WRITE: u64 = READ
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_REFER
+    | ACCESS_FS_TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Offsets into `struct seccomp_data`
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SECCOMP_DATA_NR: u32 = 0;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// System calls available to the daemon, its threads and the resolve hook.
+///
+/// Mounting and unmounting is left out on purpose, the mountpoints are
+/// released by the helper process forked before the filter is applied.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // memory and threads
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_membarrier,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_get_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prctl,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_restart_syscall,
+    // signals
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    libc::SYS_tkill,
+    // time
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    // ids and credentials
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getgroups,
+    libc::SYS_setgroups,
+    libc::SYS_setfsuid,
+    libc::SYS_setfsgid,
+    libc::SYS_capget,
+    // files
+    libc::SYS_openat,
+    libc::SYS_openat2,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_fstatfs,
+    libc::SYS_statfs,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    libc::SYS_getxattr,
+    libc::SYS_lgetxattr,
+    libc::SYS_fgetxattr,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_ftruncate,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat2,
+    libc::SYS_process_vm_readv,
+    // sockets: control socket, syslog and NSS
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    // resolve hook and upgrades
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_pidfd_open,
+    libc::SYS_landlock_restrict_self,
+    libc::SYS_uname,
+    libc::SYS_prlimit64,
+    libc::SYS_getrandom,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_vfork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_fork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_renameat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_getrlimit,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_time,
+];
+
+/// Paths a sandboxed process keeps access to.
+#[derive(Default)]
+pub struct Rules {
+    /// Directories and files that can be read and executed
+    pub read: Vec<PathBuf>,
+    /// Directories and files that can also be written
+    pub write: Vec<PathBuf>,
+}
+
+/// A Landlock ruleset that processes spawned by envfs restrict themselves to.
+///
+/// envfs itself cannot be confined with Landlock: a Landlock domain may not
+/// access /proc/PID/syscall or environ of processes outside of it.
+pub struct Ruleset {
+    fd: OwnedFd,
+}
+
+impl Ruleset {
+    /// Returns `None` if the kernel does not support Landlock.
+    pub fn new(rules: &Rules) -> Result<Option<Ruleset>> {
+        let abi = match landlock_abi() {
+            Ok(abi) => abi,
+            Err(e) => {
+                warn!(
+                    "Landlock is not available ({}), file access of the resolve hook is not restricted",
+                    e
+                );
+                return Ok(None);
+            }
+        };
+        let ruleset = try_with!(create_ruleset(abi, rules), "cannot create Landlock ruleset");
+        Ok(Some(ruleset))
+    }
+
+    /// Confines the calling thread, meant to be called between fork and exec.
+    pub fn restrict_self(&self) -> io::Result<()> {
+        let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let res =
+            unsafe { libc::syscall(libc::SYS_landlock_restrict_self, self.fd.as_raw_fd(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Restricts envfs and every thread and process it starts to `ALLOWED_SYSCALLS`.
+///
+/// Other system calls fail with EPERM.
+pub fn restrict_syscalls() -> Result<()> {
+    // required for seccomp without CAP_SYS_ADMIN, e.g. after run-as
+    let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    try_with!(Errno::result(res), "cannot set no-new-privs");
+    apply_seccomp()
+}
+
+fn landlock_abi() -> nix::Result<i64> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    Errno::result(res)
+}
+
+/// Rights the kernel with Landlock ABI version `abi` can restrict.
+fn handled_access(abi: i64) -> u64 {
+    let mut handled = ACCESS_FS_ABI_1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+    handled
+}
+
+fn create_ruleset(abi: i64, rules: &Rules) -> Result<Ruleset> {
+    let handled = handled_access(abi);
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    let fd = try_with!(Errno::result(res), "cannot create ruleset");
+    let ruleset = Ruleset {
+        fd: unsafe { OwnedFd::from_raw_fd(fd as RawFd) },
+    };
+
+    for path in &rules.read {
+        add_rule(&ruleset.fd, path, (READ | ACCESS_FS_EXECUTE) & handled)?;
+    }
+    for path in &rules.write {
+        add_rule(&ruleset.fd, path, WRITE & handled)?;
+    }
+    Ok(ruleset)
+}
+
+fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<()> {
+    let c_path = try_with!(
+        CString::new(path.as_os_str().as_bytes()),
+        "invalid path {}",
+        path.display()
+    );
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    let fd = match Errno::result(fd) {
+        Ok(fd) => unsafe { File::from_raw_fd(fd) },
+        Err(Errno::ENOENT) => {
+            // e.g. a fallback path that only appears later
+            warn!("not granting access to missing {}", path.display());
+            return Ok(());
+        }
+        Err(e) => simple_error::bail!("cannot open {}: {}", path.display(), e),
+    };
+    let is_dir = try_with!(fd.metadata(), "cannot stat {}", path.display()).is_dir();
+    let attr = PathBeneathAttr {
+        allowed_access: rule_access(access, is_dir),
+        parent_fd: fd.as_raw_fd(),
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr,
+            0,
+        )
+    };
+    try_with!(
+        Errno::result(res),
+        "cannot grant access to {}",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Landlock rejects rules for files that grant directory rights.
+fn rule_access(access: u64, is_dir: bool) -> u64 {
+    if is_dir {
+        access
+    } else {
+        access & ACCESS_FILE
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// A program killing processes of another architecture and failing
+/// system calls outside of `ALLOWED_SYSCALLS` with EPERM.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    let mut filter = vec![
+        bpf_stmt(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            SECCOMP_DATA_ARCH,
+        ),
+        bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            AUDIT_ARCH,
+            1,
+            0,
+        ),
+        bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+    ];
+    for nr in ALLOWED_SYSCALLS {
+        filter.push(bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            *nr as u32,
+            0,
+            1,
+        ));
+        filter.push(bpf_stmt(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ALLOW,
+        ));
+    }
+    filter.push(bpf_stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    ));
+    filter
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn apply_seccomp() -> Result<()> {
+    let mut filter = seccomp_filter();
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog,
+        )
+    };
+    let res = try_with!(Errno::result(res), "cannot install seccomp filter");
+    if res > 0 {
+        simple_error::bail!("cannot install seccomp filter in thread {}", res);
+    }
+    info!(
+        "restricted system calls to {} allowed ones",
+        ALLOWED_SYSCALLS.len()
+    );
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn apply_seccomp() -> Result<()> {
+    warn!("no seccomp filter for this architecture, system calls are not restricted");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handled_access() {
+        assert_eq!(handled_access(1), ACCESS_FS_ABI_1);
+        assert_eq!(handled_access(2) & ACCESS_FS_REFER, ACCESS_FS_REFER);
+        assert_eq!(handled_access(2) & ACCESS_FS_TRUNCATE, 0);
+        assert_eq!(handled_access(3) & WRITE, WRITE);
+    }
+
+    #[test]
+    fn test_rule_access() {
+        let read = READ | ACCESS_FS_EXECUTE;
+        assert_eq!(rule_access(read, true), read);
+        assert_eq!(
+            rule_access(read, false),
+            ACCESS_FS_READ_FILE | ACCESS_FS_EXECUTE
+        );
+        assert_eq!(rule_access(WRITE, false) & ACCESS_FS_MAKE_REG, 0);
+    }
+
+    /// Runs the subset of classic BPF used by `seccomp_filter`.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn run_filter(filter: &[libc::sock_filter], arch: u32, nr: libc::c_long) -> u32 {
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let insn = &filter[pc];
+            let code = insn.code as u32;
+            pc += 1;
+            if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS {
+                acc = match insn.k {
+                    SECCOMP_DATA_NR => nr as u32,
+                    SECCOMP_DATA_ARCH => arch,
+                    k => panic!("unexpected offset {}", k),
+                };
+            } else if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K {
+                pc += if acc == insn.k { insn.jt } else { insn.jf } as usize;
+            } else if code == libc::BPF_RET | libc::BPF_K {
+                return insn.k;
+            } else {
+                panic!("unexpected instruction {:#x}", code);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn test_seccomp_filter() {
+        let filter = seccomp_filter();
+        let eperm = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        for nr in ALLOWED_SYSCALLS {
+            assert_eq!(
+                run_filter(&filter, AUDIT_ARCH, *nr),
+                libc::SECCOMP_RET_ALLOW
+            );
+        }
+        assert_eq!(run_filter(&filter, AUDIT_ARCH, libc::SYS_mount), eperm);
+        assert_eq!(run_filter(&filter, AUDIT_ARCH, libc::SYS_umount2), eperm);
+        assert_eq!(run_filter(&filter, AUDIT_ARCH, libc::SYS_ptrace), eperm);
+        // i386 system call numbers differ and must not slip through
+        assert_eq!(
+            run_filter(&filter, 0x4000_0003, libc::SYS_openat),
+            libc::SECCOMP_RET_KILL_PROCESS
+        );
+    }
+}
@@ -1,15 +1,57 @@
 pub use libc::rlimit64 as Rlimit;
 
 use nix::errno::Errno;
+use std::fs;
 
 #[cfg(target_env = "gnu")]
-pub fn setrlimit(resource: libc::c_uint, rlimit: &Rlimit) -> nix::Result<()> {
-    let res = unsafe { libc::setrlimit64(resource, rlimit as *const Rlimit) };
-    Errno::result(res).map(drop)
-}
+pub type Resource = libc::c_uint;
 
 #[cfg(not(target_env = "gnu"))]
-pub fn setrlimit(resource: libc::c_int, rlimit: &Rlimit) -> nix::Result<()> {
+pub type Resource = libc::c_int;
+
+pub fn setrlimit(resource: Resource, rlimit: &Rlimit) -> nix::Result<()> {
     let res = unsafe { libc::setrlimit64(resource, rlimit as *const Rlimit) };
     Errno::result(res).map(drop)
 }
+
+pub fn getrlimit(resource: Resource) -> nix::Result<Rlimit> {
+    let mut rlimit = Rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let res = unsafe { libc::getrlimit64(resource, &mut rlimit as *mut Rlimit) };
+    Errno::result(res).map(|_| rlimit)
+}
+
+/// Highest file descriptor limit the kernel allows, `fs.nr_open`.
+pub fn nr_open() -> u64 {
+    fs::read_to_string("/proc/sys/fs/nr_open")
+        .ok()
+        .and_then(|n| n.trim().parse().ok())
+        .unwrap_or(1_048_576)
+}
+
+/// Raises the soft and hard limit of `resource` to `wanted`, limits that are
+/// already higher are kept.
+///
+/// Without the privileges to raise the hard limit, the soft limit is raised
+/// to the hard limit instead. Returns the resulting soft limit.
+pub fn raise(resource: Resource, wanted: u64) -> nix::Result<u64> {
+    let mut limit = getrlimit(resource)?;
+    if limit.rlim_cur >= wanted {
+        return Ok(limit.rlim_cur);
+    }
+    let raised = Rlimit {
+        rlim_cur: wanted,
+        rlim_max: limit.rlim_max.max(wanted),
+    };
+    match setrlimit(resource, &raised) {
+        Ok(()) => Ok(wanted),
+        Err(Errno::EPERM) => {
+            limit.rlim_cur = limit.rlim_max;
+            setrlimit(resource, &limit)?;
+            Ok(limit.rlim_cur)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,215 @@
+//! Sharded slab that stores inodes under numbers encoding their slot.
+//!
+//! The low 32 bits of a handle select the shard and the slot within it, the
+//! high bits hold the generation of the slot. Freeing a slot bumps its
+//! generation, so a handle the kernel still uses after the inode was dropped
+//! no longer matches and `get` returns `None` instead of another inode.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+const SHARD_BITS: u32 = 4;
+const SHARDS: usize = 1 << SHARD_BITS;
+const SLOT_BITS: u32 = 32 - SHARD_BITS;
+
+/// Generations stay below this so handles never collide with the numbers
+/// readdir reports from the top of the range, and never become 0 or the root inode.
+const MAX_GENERATION: u32 = 1 << 31;
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<Arc<T>>,
+}
+
+struct Shard<T> {
+    slots: Vec<Slot<T>>,
+    /// Indexes of unused slots
+    free: Vec<u32>,
+}
+
+pub struct Slab<T> {
+    shards: Vec<RwLock<Shard<T>>>,
+    /// Shard the next insert goes to
+    next_shard: AtomicUsize,
+    len: AtomicUsize,
+}
+
+fn handle(shard: usize, index: u32, generation: u32) -> u64 {
+    (u64::from(generation) << 32) | (u64::from(index) << SHARD_BITS) | shard as u64
+}
+
+fn split(handle: u64) -> (usize, usize, u32) {
+    let shard = (handle as usize) & (SHARDS - 1);
+    let index = ((handle as u32) >> SHARD_BITS) as usize;
+    (shard, index, (handle >> 32) as u32)
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Slab<T> {
+        Slab {
+            shards: (0..SHARDS)
+                .map(|_| {
+                    RwLock::new(Shard {
+                        slots: vec![],
+                        free: vec![],
+                    })
+                })
+                .collect(),
+            next_shard: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Stores the value returned by `make`, which gets the handle of its slot.
+    ///
+    /// Returns `None` if all slots are used.
+    pub fn insert_with<F>(&self, make: F) -> Option<u64>
+    where
+        F: FnOnce(u64) -> T,
+    {
+        let shard_index = self.next_shard.fetch_add(1, Ordering::Relaxed) & (SHARDS - 1);
+        let mut shard = self.shards[shard_index].write().unwrap();
+        let index = match shard.free.pop() {
+            Some(index) => index,
+            None => {
+                let index = shard.slots.len() as u32;
+                if index >= 1 << SLOT_BITS {
+                    return None;
+                }
+                shard.slots.push(Slot {
+                    generation: 1,
+                    value: None,
+                });
+                index
+            }
+        };
+        let slot = &mut shard.slots[index as usize];
+        let handle = handle(shard_index, index, slot.generation);
+        slot.value = Some(Arc::new(make(handle)));
+        self.len.fetch_add(1, Ordering::Relaxed);
+        Some(handle)
+    }
+
+    /// Returns the value stored under `handle`, `None` if its slot was freed since.
+    pub fn get(&self, handle: u64) -> Option<Arc<T>> {
+        let (shard, index, generation) = split(handle);
+        let shard = self.shards[shard].read().unwrap();
+        match shard.slots.get(index) {
+            Some(slot) if slot.generation == generation => slot.value.clone(),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&self, handle: u64) -> Option<Arc<T>> {
+        let (shard_index, index, generation) = split(handle);
+        let mut shard = self.shards[shard_index].write().unwrap();
+        let slot = match shard.slots.get_mut(index) {
+            Some(slot) if slot.generation == generation => slot,
+            _ => return None,
+        };
+        let value = slot.value.take()?;
+        slot.generation = slot.generation % (MAX_GENERATION - 1) + 1;
+        shard.free.push(index as u32);
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// Calls `f` with the handle and value of every stored value.
+    ///
+    /// Values are not cloned, so `Arc::strong_count` tells whether anybody
+    /// else holds on to them. Each shard is locked while it is visited.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(u64, &Arc<T>),
+    {
+        for (shard_index, shard) in self.shards.iter().enumerate() {
+            let shard = shard.read().unwrap();
+            for (index, slot) in shard.slots.iter().enumerate() {
+                if let Some(ref value) = slot.value {
+                    f(handle(shard_index, index as u32, slot.generation), value);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.write().unwrap();
+            let Shard { slots, free } = &mut *shard;
+            for (index, slot) in slots.iter_mut().enumerate() {
+                if slot.value.take().is_some() {
+                    slot.generation = slot.generation % (MAX_GENERATION - 1) + 1;
+                    free.push(index as u32);
+                }
+            }
+        }
+        self.len.store(0, Ordering::Relaxed);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Slab<T> {
+        Slab::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_handles() {
+        let slab = Slab::new();
+        let a = slab.insert_with(|h| h).unwrap();
+        assert_eq!(slab.get(a).as_deref(), Some(&a));
+        assert_eq!(slab.remove(a).as_deref(), Some(&a));
+        assert!(slab.get(a).is_none());
+        assert!(slab.remove(a).is_none());
+
+        // the freed slot is reused under a new handle
+        let mut reused = None;
+        for _ in 0..SHARDS {
+            let h = slab.insert_with(|h| h).unwrap();
+            if split(h).0 == split(a).0 {
+                reused = Some(h);
+            }
+        }
+        let reused = reused.unwrap();
+        assert_eq!(split(reused).1, split(a).1);
+        assert_ne!(reused, a);
+        assert!(slab.get(a).is_none());
+
+        // handles from before `clear` are invalid afterwards
+        let before = slab.insert_with(|h| h).unwrap();
+        slab.clear();
+        assert!(slab.is_empty());
+        assert!(slab.get(before).is_none());
+        assert!(slab.get(reused).is_none());
+    }
+
+    #[test]
+    fn test_generation_wraparound() {
+        let slab = Slab::new();
+        let first = slab.insert_with(|h| h).unwrap();
+        let (shard, index, _) = split(first);
+        slab.shards[shard].write().unwrap().slots[index].generation = MAX_GENERATION - 1;
+        assert!(slab.get(first).is_none());
+        let oldest = handle(shard, index as u32, MAX_GENERATION - 1);
+        assert!(slab.remove(oldest).is_some());
+
+        let generation = slab.shards[shard].read().unwrap().slots[index].generation;
+        assert_eq!(generation, 1);
+        let wrapped = handle(shard, index as u32, generation);
+        assert_ne!(wrapped, 0);
+        assert_ne!(wrapped, fuser::FUSE_ROOT_ID);
+        assert!(slab.get(oldest).is_none());
+    }
+}
@@ -0,0 +1,421 @@
+//! Timing of lookups as OpenTelemetry spans, exported with OTLP over HTTP.
+//!
+//! A span is only recorded while an exporter is enabled, otherwise creating
+//! one costs a single atomic load. The spans of a lookup are collected on the
+//! thread that answers it and handed to the exporter together once the
+//! outermost span ends. If the calling process has `TRACEPARENT` in its
+//! environment, as set by build tools that propagate W3C trace context to
+//! child processes, the lookup becomes part of that trace.
+
+use log::{debug, info, warn};
+use simple_error::bail;
+use std::cell::{Cell, RefCell};
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::logger::json_string;
+use crate::result::Result;
+
+pub const DEFAULT_ENDPOINT: &str = "http://localhost:4318";
+
+/// How often finished spans are sent.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Spans kept while the collector is unreachable, newer ones are dropped.
+const MAX_QUEUED: usize = 8192;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static QUEUE: Mutex<Vec<Finished>> = Mutex::new(Vec::new());
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+pub enum Value {
+    Str(String),
+    Int(i64),
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Str(s)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Int(n)
+    }
+}
+
+struct Finished {
+    trace_id: u128,
+    id: u64,
+    parent: Option<u64>,
+    name: &'static str,
+    start: u64,
+    end: u64,
+    attrs: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+/// Spans of the lookup in progress on this thread.
+#[derive(Default)]
+struct Local {
+    /// Open spans, innermost last
+    stack: Vec<u64>,
+    finished: Vec<Finished>,
+    /// Trace and span id from the `TRACEPARENT` of the caller
+    remote_parent: Option<(u128, u64)>,
+}
+
+thread_local! {
+    static LOCAL: RefCell<Local> = RefCell::new(Local::default());
+    static RNG: Cell<u64> = Cell::new(seed());
+}
+
+fn seed() -> u64 {
+    let mut seed = [0u8; 8];
+    let res = unsafe { libc::getrandom(seed.as_mut_ptr() as *mut libc::c_void, seed.len(), 0) };
+    if res != seed.len() as isize {
+        // ids only need to be unique, not unpredictable
+        return unix_nanos() ^ u64::from(std::process::id());
+    }
+    u64::from_ne_bytes(seed) | 1
+}
+
+/// Random non-zero id, xorshift64*.
+fn random_id() -> u64 {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        rng.set(x);
+        x.wrapping_mul(0x2545f4914f6cdd1d).max(1)
+    })
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+struct Active {
+    id: u64,
+    name: &'static str,
+    start: u64,
+    attrs: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+/// A span that ends when dropped.
+pub struct Span(Option<Active>);
+
+/// Starts a span as a child of the innermost open span on this thread.
+pub fn span(name: &'static str) -> Span {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Span(None);
+    }
+    let id = random_id();
+    LOCAL.with(|local| local.borrow_mut().stack.push(id));
+    Span(Some(Active {
+        id,
+        name,
+        start: unix_nanos(),
+        attrs: vec![],
+        error: None,
+    }))
+}
+
+impl Span {
+    pub fn attr<V: Into<Value>>(&mut self, key: &'static str, value: V) {
+        if let Some(ref mut active) = self.0 {
+            active.attrs.push((key, value.into()));
+        }
+    }
+
+    /// Marks the span as failed.
+    pub fn fail<F: FnOnce() -> String>(&mut self, message: F) {
+        if let Some(ref mut active) = self.0 {
+            active.error = Some(message());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let active = match self.0.take() {
+            Some(active) => active,
+            None => return,
+        };
+        let end = unix_nanos();
+        let trace = LOCAL.with(|local| {
+            let mut local = local.borrow_mut();
+            // spans end in reverse order of their start
+            local.stack.retain(|id| *id != active.id);
+            let parent = local.stack.last().copied();
+            local.finished.push(Finished {
+                trace_id: 0,
+                id: active.id,
+                parent,
+                name: active.name,
+                start: active.start,
+                end,
+                attrs: active.attrs,
+                error: active.error,
+            });
+            if !local.stack.is_empty() {
+                return None;
+            }
+            let remote_parent = local.remote_parent.take();
+            Some((std::mem::take(&mut local.finished), remote_parent))
+        });
+        if let Some((mut spans, remote_parent)) = trace {
+            let (trace_id, root_parent) = match remote_parent {
+                Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+                None => (
+                    (u128::from(random_id()) << 64) | u128::from(random_id()),
+                    None,
+                ),
+            };
+            for span in spans.iter_mut() {
+                span.trace_id = trace_id;
+                if span.parent.is_none() {
+                    span.parent = root_parent;
+                }
+            }
+            let mut queue = QUEUE.lock().unwrap();
+            if queue.len() + spans.len() > MAX_QUEUED {
+                DROPPED.fetch_add(spans.len() as u64, Ordering::Relaxed);
+            } else {
+                queue.extend(spans);
+            }
+        }
+    }
+}
+
+/// Parses a W3C `traceparent` like `00-<32 hex trace id>-<16 hex span id>-01`.
+fn parse_traceparent(value: &[u8]) -> Option<(u128, u64)> {
+    let value = std::str::from_utf8(value).ok()?;
+    let mut fields = value.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let span_id = fields.next()?;
+    if version.len() != 2 || version == "ff" || trace_id.len() != 32 || span_id.len() != 16 {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(trace_id, 16)
+        .ok()
+        .filter(|id| *id != 0)?;
+    let span_id = u64::from_str_radix(span_id, 16)
+        .ok()
+        .filter(|id| *id != 0)?;
+    Some((trace_id, span_id))
+}
+
+/// Makes the lookup in progress on this thread part of the trace of the
+/// calling process, given the value of its `TRACEPARENT`.
+pub fn set_remote_parent(traceparent: &[u8]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(parent) = parse_traceparent(traceparent) {
+        LOCAL.with(|local| {
+            let mut local = local.borrow_mut();
+            if !local.stack.is_empty() {
+                local.remote_parent = Some(parent);
+            }
+        });
+    }
+}
+
+/// OTLP/JSON request body for `spans`.
+fn encode(spans: &[Finished]) -> String {
+    let mut out = String::from(
+        r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"envfs"}}]},"scopeSpans":[{"scope":{"name":"envfs"},"spans":["#,
+    );
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            r#"{{"traceId":"{:032x}","spanId":"{:016x}","#,
+            span.trace_id, span.id
+        );
+        if let Some(parent) = span.parent {
+            let _ = write!(out, r#""parentSpanId":"{:016x}","#, parent);
+        }
+        let _ = write!(
+            out,
+            r#""name":{},"kind":1,"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":["#,
+            json_string(span.name),
+            span.start,
+            span.end
+        );
+        for (j, (key, value)) in span.attrs.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            let value = match value {
+                Value::Str(s) => format!(r#"{{"stringValue":{}}}"#, json_string(s)),
+                Value::Int(n) => format!(r#"{{"intValue":"{}"}}"#, n),
+            };
+            let _ = write!(out, r#"{{"key":{},"value":{}}}"#, json_string(key), value);
+        }
+        out.push(']');
+        if let Some(ref error) = span.error {
+            let _ = write!(
+                out,
+                r#","status":{{"code":2,"message":{}}}"#,
+                json_string(error)
+            );
+        }
+        out.push('}');
+    }
+    out.push_str("]}]}]}");
+    out
+}
+
+struct Endpoint {
+    /// `host:port` to connect to
+    addr: String,
+    host: String,
+    path: String,
+}
+
+/// Parses `http://host[:port][/path]`, the path defaults to `/v1/traces`.
+fn parse_endpoint(url: &str) -> Result<Endpoint> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => bail!("OTLP endpoint {} must start with http://", url),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    if host.is_empty() {
+        bail!("OTLP endpoint {} has no host", url);
+    }
+    let path = match path.trim_end_matches('/') {
+        "" => String::from("/v1/traces"),
+        path if path.ends_with("/v1/traces") => path.to_string(),
+        path => format!("{}/v1/traces", path),
+    };
+    let has_port = match host.rfind(':') {
+        Some(i) => !host[i..].contains(']'),
+        None => false,
+    };
+    let addr = if has_port {
+        host.to_string()
+    } else {
+        format!("{}:4318", host)
+    };
+    Ok(Endpoint {
+        addr,
+        host: host.to_string(),
+        path,
+    })
+}
+
+fn post(endpoint: &Endpoint, body: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(&endpoint.addr)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint.path,
+        endpoint.host,
+        body.len(),
+        body
+    )?;
+    let mut status = [0u8; 12];
+    stream.read_exact(&mut status)?;
+    // "HTTP/1.1 200"
+    if status[9] != b'2' {
+        let code = String::from_utf8_lossy(&status[9..]).into_owned();
+        return Err(std::io::Error::other(format!(
+            "collector answered {}",
+            code
+        )));
+    }
+    Ok(())
+}
+
+fn export(endpoint: Endpoint) {
+    let mut failing = false;
+    loop {
+        thread::sleep(EXPORT_INTERVAL);
+        let spans = std::mem::take(&mut *QUEUE.lock().unwrap());
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            debug!("dropped {} spans, the OTLP collector is too slow", dropped);
+        }
+        if spans.is_empty() {
+            continue;
+        }
+        match post(&endpoint, &encode(&spans)) {
+            Ok(()) => failing = false,
+            Err(e) if !failing => {
+                warn!("cannot export spans to {}: {}", endpoint.addr, e);
+                failing = true;
+            }
+            Err(e) => debug!("cannot export spans: {}", e),
+        }
+    }
+}
+
+/// Starts recording spans and sending them to the OTLP/HTTP collector at `url`.
+pub fn enable(url: &str) -> Result<()> {
+    let endpoint = parse_endpoint(url)?;
+    info!(
+        "exporting spans to http://{}{}",
+        endpoint.addr, endpoint.path
+    );
+    let res = thread::Builder::new()
+        .name(String::from("envfs-otlp"))
+        .spawn(move || export(endpoint));
+    if let Err(e) = res {
+        bail!("cannot start OTLP exporter: {}", e);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_context() {
+        assert_eq!(
+            parse_traceparent(b"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\n"),
+            Some((0x4bf92f3577b34da6a3ce929d0e0e4736, 0x00f067aa0ba902b7))
+        );
+        assert_eq!(
+            parse_traceparent(b"00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+        let endpoint = parse_endpoint("http://collector/otlp/").unwrap();
+        assert_eq!(endpoint.addr, "collector:4318");
+        assert_eq!(endpoint.path, "/otlp/v1/traces");
+        assert_eq!(
+            parse_endpoint("http://[::1]:4000").unwrap().addr,
+            "[::1]:4000"
+        );
+        assert!(parse_endpoint("https://collector").is_err());
+    }
+}
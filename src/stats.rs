@@ -0,0 +1,200 @@
+//! Per-name lookup counters to see which binaries dominate lookups.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::intern;
+use crate::resolver::Source;
+
+/// Bounds memory use if a process looks up lots of random names.
+const MAX_NAMES: usize = 4096;
+
+#[derive(Default, Clone, Copy)]
+pub struct NameStats {
+    pub resolved: u64,
+    pub missed: u64,
+}
+
+impl NameStats {
+    pub fn total(&self) -> u64 {
+        self.resolved + self.missed
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    names: HashMap<Arc<OsStr>, NameStats>,
+    /// Lookups of names that did not fit into `names` anymore
+    untracked: u64,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    counters: Mutex<Counters>,
+    /// Health checks that did not finish in time
+    hangs: AtomicU64,
+    /// Times a process exceeded the rate limit
+    trips: AtomicU64,
+    /// Lookups served from fallback paths only because of the rate limit
+    throttled: AtomicU64,
+    /// Lookups answered without waiting for the process because of the lookup deadline
+    degraded: AtomicU64,
+    /// Inodes dropped by the size cap or garbage collection
+    evicted: AtomicU64,
+    /// Bind mounts that disappeared and were mounted again
+    repaired: AtomicU64,
+    /// Resolved lookups by where they were answered from, indexed like `Source::ALL`
+    sources: [AtomicU64; 4],
+}
+
+impl Stats {
+    pub fn record(&self, name: &OsStr, resolved: bool) {
+        let mut counters = self.counters.lock().unwrap();
+        if !counters.names.contains_key(name) && counters.names.len() >= MAX_NAMES {
+            counters.untracked += 1;
+            return;
+        }
+        let entry = match counters.names.get_mut(name) {
+            Some(entry) => entry,
+            None => counters.names.entry(intern::name(name)).or_default(),
+        };
+        if resolved {
+            entry.resolved += 1;
+        } else {
+            entry.missed += 1;
+        }
+    }
+
+    /// Returns the `n` most looked up names, most frequent first.
+    pub fn top(&self, n: usize) -> Vec<(OsString, NameStats)> {
+        let counters = self.counters.lock().unwrap();
+        let mut names: Vec<(OsString, NameStats)> = counters
+            .names
+            .iter()
+            .map(|(name, stats)| (name.to_os_string(), *stats))
+            .collect();
+        names.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then_with(|| a.0.cmp(&b.0)));
+        names.truncate(n);
+        names
+    }
+
+    pub fn record_hang(&self) {
+        self.hangs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hangs(&self) -> u64 {
+        self.hangs.load(Ordering::Relaxed)
+    }
+
+    pub fn record_trip(&self) {
+        self.trips.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn trips(&self) -> u64 {
+        self.trips.load(Ordering::Relaxed)
+    }
+
+    pub fn record_throttled(&self) {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    pub fn record_degraded(&self) {
+        self.degraded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn degraded(&self) -> u64 {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    pub fn record_evicted(&self, n: u64) {
+        self.evicted.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    pub fn record_repaired(&self) {
+        self.repaired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn repaired(&self) -> u64 {
+        self.repaired.load(Ordering::Relaxed)
+    }
+
+    pub fn record_source(&self, source: Source) {
+        self.sources[source as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Resolved lookups answered from `source`.
+    pub fn from_source(&self, source: Source) -> u64 {
+        self.sources[source as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn untracked(&self) -> u64 {
+        self.counters.lock().unwrap().untracked
+    }
+
+    /// Formats the `n` most looked up names as a table.
+    pub fn report(&self, n: usize) -> Vec<String> {
+        let mut lines = vec![format!("{:>10} {:>10} name", "resolved", "missed")];
+        for (name, stats) in self.top(n) {
+            lines.push(format!(
+                "{:>10} {:>10} {}",
+                stats.resolved,
+                stats.missed,
+                name.to_string_lossy()
+            ));
+        }
+        if Source::ALL
+            .iter()
+            .any(|source| self.from_source(*source) > 0)
+        {
+            lines.push(format!(
+                "answered from PATH: {}, fallback paths: {}, static entries: {}, other resolvers: {}",
+                self.from_source(Source::Path),
+                self.from_source(Source::Fallback),
+                self.from_source(Source::Static),
+                self.from_source(Source::Other)
+            ));
+        }
+        let untracked = self.untracked();
+        if untracked > 0 {
+            lines.push(format!("{} lookups of untracked names", untracked));
+        }
+        let hangs = self.hangs();
+        if hangs > 0 {
+            lines.push(format!("{} health checks timed out", hangs));
+        }
+        let evicted = self.evicted();
+        if evicted > 0 {
+            lines.push(format!("{} inodes evicted", evicted));
+        }
+        let repaired = self.repaired();
+        if repaired > 0 {
+            lines.push(format!("{} bind mounts repaired", repaired));
+        }
+        let degraded = self.degraded();
+        if degraded > 0 {
+            lines.push(format!(
+                "{} lookups answered early because of the deadline",
+                degraded
+            ));
+        }
+        let throttled = self.throttled();
+        if throttled > 0 {
+            lines.push(format!(
+                "{} lookups throttled after {} rate limit trips",
+                throttled,
+                self.trips()
+            ));
+        }
+        lines
+    }
+}
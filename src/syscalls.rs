@@ -0,0 +1,300 @@
+//! Classification of the system call a process is blocked in.
+//!
+//! 32-bit processes on a 64-bit kernel use the system call numbers of their
+//! own ABI, which is detected from the ELF header of `/proc/<pid>/exe`.
+
+use nix::unistd::Pid;
+use simple_error::bail;
+use std::mem::size_of;
+
+use crate::procdir::{ProcReader, Procfs, RealProcfs};
+
+use crate::result::Result;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Syscall {
+    Open,
+    Execve,
+    Execveat,
+    /// stat family and readlink, which `ls -l` needs as well
+    Stat,
+    Access,
+    Other,
+}
+
+impl Syscall {
+    pub fn is_exec(self) -> bool {
+        self == Syscall::Execve || self == Syscall::Execveat
+    }
+}
+
+/// Calls during which the PATH of the process is used for resolution.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AllowedSyscalls {
+    kinds: Vec<Syscall>,
+    /// Raw system call numbers of the native ABI, for calls that are not classified
+    numbers: Vec<usize>,
+}
+
+impl Default for AllowedSyscalls {
+    /// The calls that execute or open a program, not stat or access checks:
+    /// listing or probing the directory should not find anything.
+    fn default() -> AllowedSyscalls {
+        AllowedSyscalls {
+            kinds: vec![Syscall::Open, Syscall::Execve, Syscall::Execveat],
+            numbers: vec![],
+        }
+    }
+}
+
+impl AllowedSyscalls {
+    /// Parses a `:`-separated list of `open`, `exec`, `stat`, `access` or system call numbers.
+    pub fn parse(list: &str) -> Result<AllowedSyscalls> {
+        let mut allowed = AllowedSyscalls {
+            kinds: vec![],
+            numbers: vec![],
+        };
+        for name in list.split(':') {
+            match name {
+                "open" => allowed.kinds.push(Syscall::Open),
+                "exec" => allowed.kinds.extend(&[Syscall::Execve, Syscall::Execveat]),
+                "stat" => allowed.kinds.push(Syscall::Stat),
+                "access" => allowed.kinds.push(Syscall::Access),
+                _ => match name.parse::<usize>() {
+                    Ok(num) => allowed.numbers.push(num),
+                    Err(_) => bail!(
+                        "unknown syscall '{}', expected open, exec, stat, access or a number",
+                        name
+                    ),
+                },
+            }
+        }
+        Ok(allowed)
+    }
+
+    pub fn contains(&self, abi: Abi, num: usize) -> bool {
+        self.kinds.contains(&abi.classify(num))
+            || (abi == Abi::Native && self.numbers.contains(&num))
+    }
+}
+
+/// System call ABI of a process.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Abi {
+    Native,
+    /// x86_64 with 32-bit pointers
+    X32,
+    /// i386 binaries on x86_64
+    I386,
+    /// arm32 EABI binaries on aarch64
+    Arm,
+    /// mips o32 binaries on mips64
+    MipsO32,
+}
+
+const EM_386: u16 = 3;
+const EM_MIPS: u16 = 8;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const ELFCLASS32: u8 = 1;
+
+/// Set in the system call number of x32 processes.
+const X32_SYSCALL_BIT: usize = 0x4000_0000;
+
+impl Abi {
+    /// Detects the ABI of process `pid`, falls back to `Native` if its executable cannot be read.
+    pub fn detect(pid: Pid) -> Abi {
+        match RealProcfs.open(pid) {
+            Ok(proc) => Abi::detect_in(&*proc),
+            Err(_) => Abi::Native,
+        }
+    }
+
+    /// Like `detect`, for a process whose `/proc/<pid>` directory is already open.
+    pub(crate) fn detect_in(proc: &dyn ProcReader) -> Abi {
+        // Only 64-bit kernels run processes of another ABI.
+        if size_of::<usize>() != 8 {
+            return Abi::Native;
+        }
+        let header = match proc.read_head("exe", 20) {
+            Ok(header) if header.len() == 20 => header,
+            _ => return Abi::Native,
+        };
+        if &header[..4] != b"\x7fELF" || header[4] != ELFCLASS32 {
+            return Abi::Native;
+        }
+        // e_ident[EI_DATA]: 1 little endian, 2 big endian
+        let machine = if header[5] == 2 {
+            u16::from_be_bytes([header[18], header[19]])
+        } else {
+            u16::from_le_bytes([header[18], header[19]])
+        };
+        match machine {
+            EM_X86_64 if cfg!(target_arch = "x86_64") => Abi::X32,
+            EM_386 if cfg!(target_arch = "x86_64") => Abi::I386,
+            EM_ARM if cfg!(target_arch = "aarch64") => Abi::Arm,
+            EM_MIPS if cfg!(target_arch = "mips64") => Abi::MipsO32,
+            _ => Abi::Native,
+        }
+    }
+
+    /// Size of pointers in the memory of a process with this ABI.
+    pub fn pointer_size(self) -> usize {
+        match self {
+            Abi::Native => size_of::<usize>(),
+            _ => 4,
+        }
+    }
+
+    fn table(self) -> &'static Table {
+        match self {
+            Abi::Native => &NATIVE,
+            Abi::X32 => &X32,
+            Abi::I386 => &I386,
+            Abi::Arm => &ARM,
+            Abi::MipsO32 => &MIPS_O32,
+        }
+    }
+
+    pub fn classify(self, num: usize) -> Syscall {
+        let num = if self == Abi::X32 {
+            if num & X32_SYSCALL_BIT == 0 {
+                return Syscall::Other;
+            }
+            num & !X32_SYSCALL_BIT
+        } else {
+            num
+        };
+        let table = self.table();
+        if table.open.contains(&num) {
+            Syscall::Open
+        } else if num == table.execve {
+            Syscall::Execve
+        } else if num == table.execveat {
+            Syscall::Execveat
+        } else if table.stat.contains(&num) {
+            Syscall::Stat
+        } else if table.access.contains(&num) {
+            Syscall::Access
+        } else {
+            Syscall::Other
+        }
+    }
+}
+
+struct Table {
+    open: &'static [usize],
+    stat: &'static [usize],
+    access: &'static [usize],
+    execve: usize,
+    execveat: usize,
+}
+
+#[cfg(target_arch = "x86_64")]
+const NATIVE: Table = Table {
+    open: &[
+        libc::SYS_open as usize,
+        libc::SYS_openat as usize,
+        libc::SYS_openat2 as usize,
+    ],
+    stat: &[
+        libc::SYS_stat as usize,
+        libc::SYS_lstat as usize,
+        libc::SYS_newfstatat as usize,
+        libc::SYS_statx as usize,
+        libc::SYS_readlink as usize,
+        libc::SYS_readlinkat as usize,
+    ],
+    access: &[
+        libc::SYS_access as usize,
+        libc::SYS_faccessat as usize,
+        libc::SYS_faccessat2 as usize,
+    ],
+    execve: libc::SYS_execve as usize,
+    execveat: libc::SYS_execveat as usize,
+};
+
+#[cfg(any(
+    target_arch = "arm",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "s390x"
+))]
+const NATIVE: Table = Table {
+    open: &[
+        libc::SYS_open as usize,
+        libc::SYS_openat as usize,
+        libc::SYS_openat2 as usize,
+    ],
+    stat: &[
+        libc::SYS_statx as usize,
+        libc::SYS_readlink as usize,
+        libc::SYS_readlinkat as usize,
+    ],
+    access: &[
+        libc::SYS_access as usize,
+        libc::SYS_faccessat as usize,
+        libc::SYS_faccessat2 as usize,
+    ],
+    execve: libc::SYS_execve as usize,
+    execveat: libc::SYS_execveat as usize,
+};
+
+// Architectures with the generic system call table only have the *at variants.
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "s390x"
+)))]
+const NATIVE: Table = Table {
+    open: &[libc::SYS_openat as usize, libc::SYS_openat2 as usize],
+    stat: &[
+        libc::SYS_newfstatat as usize,
+        libc::SYS_statx as usize,
+        libc::SYS_readlinkat as usize,
+    ],
+    access: &[libc::SYS_faccessat as usize, libc::SYS_faccessat2 as usize],
+    execve: libc::SYS_execve as usize,
+    execveat: libc::SYS_execveat as usize,
+};
+
+// Numbers without X32_SYSCALL_BIT
+const X32: Table = Table {
+    open: &[2, 257, 437],
+    stat: &[4, 6, 262, 332, 89, 267],
+    access: &[21, 269, 439],
+    execve: 520,
+    execveat: 545,
+};
+
+const I386: Table = Table {
+    open: &[5, 295, 437],
+    stat: &[195, 196, 300, 383, 85, 305],
+    access: &[33, 307, 439],
+    execve: 11,
+    execveat: 358,
+};
+
+const ARM: Table = Table {
+    open: &[5, 322, 437],
+    stat: &[195, 196, 327, 397, 85, 332],
+    access: &[33, 334, 439],
+    execve: 11,
+    execveat: 387,
+};
+
+const MIPS_O32: Table = Table {
+    open: &[4005, 4288, 4437],
+    stat: &[4213, 4214, 4293, 4366, 4085, 4298],
+    access: &[4033, 4300, 4439],
+    execve: 4011,
+    execveat: 4356,
+};
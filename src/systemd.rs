@@ -0,0 +1,168 @@
+//! Minimal sd_notify(3) implementation for readiness and watchdog notifications.
+
+use nix::sys::stat::{self, SFlag};
+use nix::unistd;
+use simple_error::try_with;
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+use envfs::result::Result;
+
+/// Device number of /dev/fuse.
+const FUSE_MAJOR: u64 = 10;
+const FUSE_MINOR: u64 = 229;
+
+/// Sends `state` to the service manager. Does nothing if not started by systemd.
+pub fn notify(state: &str) -> Result<()> {
+    let socket_path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket_path = socket_path.to_string_lossy();
+    let addr = match socket_path.strip_prefix('@') {
+        Some(name) => try_with!(
+            SocketAddr::from_abstract_name(name.as_bytes()),
+            "invalid abstract socket name {}",
+            socket_path
+        ),
+        None => try_with!(
+            SocketAddr::from_pathname(socket_path.as_ref()),
+            "invalid socket path {}",
+            socket_path
+        ),
+    };
+    let socket = try_with!(UnixDatagram::unbound(), "cannot create notify socket");
+    try_with!(
+        socket.send_to_addr(state.as_bytes(), &addr),
+        "cannot send notification to {}",
+        socket_path
+    );
+    Ok(())
+}
+
+/// First file descriptor passed by the service manager, see sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the file descriptors passed by the service manager and their
+/// names, empty if there are none for this process.
+pub fn listen_fds() -> Vec<(RawFd, String)> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<i32>().ok());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok());
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    // not meant for processes started by envfs
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    parse_listen_fds(pid, count, &names, unistd::getpid().as_raw())
+}
+
+/// The file descriptors of `LISTEN_FDS` for process `own_pid`.
+fn parse_listen_fds(
+    pid: Option<i32>,
+    count: Option<i32>,
+    names: &str,
+    own_pid: i32,
+) -> Vec<(RawFd, String)> {
+    let count = match (pid, count) {
+        (Some(pid), Some(count)) if pid == own_pid && count > 0 => count,
+        _ => return vec![],
+    };
+    let mut names = names.split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| (fd, names.next().unwrap_or("unknown").to_string()))
+        .collect()
+}
+
+/// Whether `fd` refers to /dev/fuse.
+pub fn is_fuse_device(fd: RawFd) -> bool {
+    match stat::fstat(fd) {
+        Ok(st) => {
+            SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFCHR
+                && st.st_rdev == stat::makedev(FUSE_MAJOR, FUSE_MINOR)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Returns the interval in which systemd expects watchdog pings, if enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog_interval(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        unistd::getpid().as_raw(),
+    )
+}
+
+/// The interval of `WATCHDOG_USEC` if `WATCHDOG_PID` is unset or `own_pid`.
+fn parse_watchdog_interval(
+    usec: Option<&str>,
+    pid: Option<&str>,
+    own_pid: i32,
+) -> Option<Duration> {
+    let usec = usec?.parse::<u64>().ok()?;
+    if let Some(pid) = pid {
+        if pid.parse::<i32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(
+            parse_listen_fds(Some(42), Some(2), "fuse", 42),
+            [(3, String::from("fuse")), (4, String::from("unknown"))]
+        );
+        // meant for another process
+        assert!(parse_listen_fds(Some(41), Some(2), "", 42).is_empty());
+        assert!(parse_listen_fds(None, Some(2), "", 42).is_empty());
+        assert!(parse_listen_fds(Some(42), Some(-1), "", 42).is_empty());
+    }
+
+    #[test]
+    fn test_parse_watchdog_interval() {
+        let interval = Some(Duration::from_secs(30));
+        assert_eq!(
+            parse_watchdog_interval(Some("30000000"), None, 42),
+            interval
+        );
+        assert_eq!(
+            parse_watchdog_interval(Some("30000000"), Some("42"), 42),
+            interval
+        );
+        // meant for another process of the service
+        assert_eq!(
+            parse_watchdog_interval(Some("30000000"), Some("41"), 42),
+            None
+        );
+        assert_eq!(parse_watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog_interval(Some("soon"), None, 42), None);
+        assert_eq!(parse_watchdog_interval(None, Some("42"), 42), None);
+    }
+
+    #[test]
+    fn test_is_fuse_device() {
+        let file = File::open("/dev/null").unwrap();
+        assert!(!is_fuse_device(file.as_raw_fd()));
+        if let Ok(fuse) = File::open("/dev/fuse") {
+            assert!(is_fuse_device(fuse.as_raw_fd()));
+        }
+    }
+}
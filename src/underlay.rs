@@ -0,0 +1,175 @@
+//! Files that were in the mountpoint before envfs was mounted over it.
+//!
+//! The directory is opened before mounting and bind mounted below
+//! `/run/envfs/underlay`, so that its files stay reachable for the processes
+//! the symlinks of envfs are served to.
+
+use log::warn;
+use nix::errno::Errno;
+use nix::fcntl::{open, readlinkat, AtFlags, OFlag};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sys::stat::{fstatat, Mode};
+use simple_error::{bail, try_with};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use crate::control::escape_mountpoint;
+use crate::fs::is_envfs_dir;
+use crate::result::Result;
+
+const UNDERLAY_DIR: &str = "/run/envfs/underlay";
+
+pub struct Underlay {
+    dir: OwnedFd,
+    /// Bind mount of the directory, `None` if it could not be created
+    path: Option<PathBuf>,
+}
+
+fn open_dir(path: &Path) -> Result<OwnedFd> {
+    let fd = try_with!(
+        open(
+            path,
+            OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty()
+        ),
+        "cannot open {}",
+        path.display()
+    );
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Mounts the directory `dir` at `path`, replacing what a crashed instance left there.
+fn bind(dir: &OwnedFd, path: &Path) -> Result<()> {
+    try_with!(fs::create_dir_all(path), "cannot create {}", path.display());
+    let _ = umount2(path, MntFlags::MNT_DETACH);
+    let source = format!("/proc/self/fd/{}", dir.as_raw_fd());
+    try_with!(
+        mount(
+            Some(source.as_str()),
+            path,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        ),
+        "cannot bind mount to {}",
+        path.display()
+    );
+    Ok(())
+}
+
+impl Underlay {
+    /// Opens the directory at `mountpoint`, which envfs is about to cover.
+    ///
+    /// When upgrading, `mountpoint` is already served by envfs and the bind
+    /// mount of the previous instance is taken over instead.
+    pub fn open(mountpoint: &Path) -> Result<Underlay> {
+        let path = Path::new(UNDERLAY_DIR).join(escape_mountpoint(mountpoint));
+        let stat = try_with!(
+            fs::metadata(mountpoint),
+            "cannot stat {}",
+            mountpoint.display()
+        );
+        if is_envfs_dir(stat.dev(), stat.nlink()) {
+            if !path.exists() {
+                bail!(
+                    "{} is already served by envfs without an underlay",
+                    mountpoint.display()
+                );
+            }
+            return Ok(Underlay {
+                dir: open_dir(&path)?,
+                path: Some(path),
+            });
+        }
+
+        let dir = open_dir(mountpoint)?;
+        let path = match bind(&dir, &path) {
+            Ok(()) => Some(path),
+            Err(e) => {
+                warn!("only symlinks of the underlay are served: {}", e);
+                None
+            }
+        };
+        Ok(Underlay { dir, path })
+    }
+
+    /// Bind mount that has to be removed when envfs is unmounted.
+    pub fn mount_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Names of the files and symlinks in the directory.
+    pub fn names(&self) -> Vec<OsString> {
+        let entries = match fs::read_dir(format!("/proc/self/fd/{}", self.dir.as_raw_fd())) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("cannot list underlay: {}", e);
+                return vec![];
+            }
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| !t.is_dir()))
+            .map(|entry| entry.file_name())
+            .collect()
+    }
+
+    /// Returns what `name` is served as: the target of a symlink, or the path
+    /// of a regular file in the bind mount.
+    pub fn lookup(&self, name: &OsStr) -> nix::Result<PathBuf> {
+        let fd = self.dir.as_raw_fd();
+        let stat = fstatat(Some(fd), name, AtFlags::AT_SYMLINK_NOFOLLOW)?;
+        match stat.st_mode & libc::S_IFMT {
+            // relative targets are resolved below the mountpoint, like before
+            libc::S_IFLNK => Ok(PathBuf::from(readlinkat(Some(fd), name)?)),
+            libc::S_IFREG => match self.path {
+                Some(ref path) => Ok(path.join(name)),
+                None => Err(Errno::ENOENT),
+            },
+            _ => Err(Errno::ENOENT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    #[test]
+    fn test_lookup() {
+        let dir = env::temp_dir().join(format!("envfs-underlay-{}", process::id()));
+        fs::create_dir_all(dir.join("subdir")).unwrap();
+        fs::write(dir.join("script"), "#!/bin/sh\n").unwrap();
+        symlink("../lib/prog", dir.join("prog")).unwrap();
+        let bound = Path::new(UNDERLAY_DIR).join("usr-bin");
+        let mut underlay = Underlay {
+            dir: open_dir(&dir).unwrap(),
+            path: Some(bound.clone()),
+        };
+
+        let mut names = underlay.names();
+        names.sort();
+        assert_eq!(names, [OsString::from("prog"), OsString::from("script")]);
+        assert_eq!(
+            underlay.lookup(OsStr::new("prog")),
+            Ok(PathBuf::from("../lib/prog"))
+        );
+        assert_eq!(
+            underlay.lookup(OsStr::new("script")),
+            Ok(bound.join("script"))
+        );
+        assert_eq!(underlay.lookup(OsStr::new("subdir")), Err(Errno::ENOENT));
+        assert_eq!(underlay.lookup(OsStr::new("missing")), Err(Errno::ENOENT));
+        // without the bind mount regular files are unreachable
+        underlay.path = None;
+        assert_eq!(underlay.lookup(OsStr::new("script")), Err(Errno::ENOENT));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+//! Replaces a running instance with a new envfs binary without leaving its
+//! mountpoints unserved.
+//!
+//! The new instance is mounted on top of the mounts of the old one, which are
+//! detached afterwards. The old instance keeps answering requests that still
+//! reach its detached mounts, e.g. from processes in the middle of an execve,
+//! for a few seconds before it exits.
+
+use log::{info, warn};
+use nix::mount::{umount2, MntFlags};
+use nix::sys::signal;
+use nix::unistd;
+use simple_error::{bail, try_with};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::crash;
+use crate::fs::EnvFs;
+use crate::options::is_mount_helper;
+use crate::result::Result;
+
+/// `argv[0]` and arguments of the command line `args` with the `upgrade` option added.
+fn upgrade_args(mut args: impl Iterator<Item = OsString>) -> (OsString, Vec<OsString>) {
+    let arg0 = args.next().unwrap_or_else(|| OsString::from("envfs"));
+    let mount_helper = is_mount_helper(&arg0.to_string_lossy());
+    // the new instance reports success once it is mounted instead of running in foreground
+    let mut args: Vec<OsString> = args
+        .filter(|arg| mount_helper || (arg != "-f" && arg != "--foreground"))
+        .collect();
+    let pos = usize::from(!mount_helper && args.first().is_some_and(|arg| arg == "mount"));
+    args.splice(pos..pos, [OsString::from("-o"), OsString::from("upgrade")]);
+    (arg0, args)
+}
+
+/// Command line of this instance with `exe` as program and the `upgrade` option added.
+fn new_instance(exe: &Path) -> Command {
+    let (arg0, args) = upgrade_args(env::args_os());
+    let mut cmd = Command::new(exe);
+    // mount.envfs parses its arguments differently
+    cmd.arg0(arg0).args(args);
+    cmd
+}
+
+/// Opens the root of the mount on `mountpoint`, it stays reachable through the
+/// file descriptor once another mount covers it.
+pub fn open_root(mountpoint: &Path) -> Result<File> {
+    let root = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+        .open(mountpoint);
+    Ok(try_with!(root, "cannot open {}", mountpoint.display()))
+}
+
+/// Starts `exe` with the options of this instance and shuts this one down once it is mounted.
+pub fn upgrade(fs: &EnvFs, exe: &Path) -> Result<()> {
+    let mounts = fs.active_mounts();
+    // opened before the new instance covers them, afterwards only these reach the old mounts
+    let roots = mounts
+        .iter()
+        .map(|m| open_root(m))
+        .collect::<Result<Vec<_>>>()?;
+
+    info!("starting {}", exe.display());
+    let status = try_with!(
+        new_instance(exe).status(),
+        "cannot execute {}",
+        exe.display()
+    );
+    if !status.success() {
+        bail!("{} exited with {}", exe.display(), status);
+    }
+
+    retire(fs)?;
+    detach(&mounts, roots);
+    Ok(())
+}
+
+/// Detaches the mounts on `mountpoints` through their roots opened before
+/// they were covered.
+pub fn detach(mountpoints: &[PathBuf], roots: Vec<File>) {
+    for (mountpoint, root) in mountpoints.iter().zip(&roots) {
+        let path = format!("/proc/self/fd/{}", root.as_raw_fd());
+        if let Err(e) = umount2(path.as_str(), MntFlags::MNT_DETACH) {
+            warn!("cannot detach old mount of {}: {}", mountpoint.display(), e);
+        }
+    }
+}
+
+/// Hands the mountpoints over to a new instance and shuts this one down
+/// without unmounting them.
+pub fn retire(fs: &EnvFs) -> Result<()> {
+    // from now on the mountpoints belong to the new instance
+    crash::disarm();
+    fs.retire();
+    try_with!(
+        signal::kill(unistd::getpid(), signal::SIGTERM),
+        "cannot signal main thread"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = OsString> {
+        args.iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_upgrade_args() {
+        let (arg0, rest) = upgrade_args(args(&["envfs", "-f", "-o", "debug", "/usr/bin"]));
+        assert_eq!(arg0, "envfs");
+        assert_eq!(rest, ["-o", "upgrade", "-o", "debug", "/usr/bin"]);
+
+        let (_, rest) = upgrade_args(args(&["envfs", "mount", "--foreground", "/usr/bin"]));
+        assert_eq!(rest, ["mount", "-o", "upgrade", "/usr/bin"]);
+
+        // mount(8) passes -f to mean something else
+        let (arg0, rest) = upgrade_args(args(&["/sbin/mount.envfs", "none", "/usr/bin", "-f"]));
+        assert_eq!(arg0, "/sbin/mount.envfs");
+        assert_eq!(rest, ["-o", "upgrade", "none", "/usr/bin", "-f"]);
+    }
+}
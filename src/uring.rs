@@ -0,0 +1,383 @@
+//! Minimal io_uring ring to read several `/proc` files with a few system calls.
+//!
+//! Only what `ProcDir::prefetch` needs is implemented: all files are opened
+//! in one submission, read in a second one and closed in a third one,
+//! instead of an `openat`, `read` and `close` per file.
+
+use nix::errno::Errno;
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const IORING_OP_OPENAT: u8 = 18;
+const IORING_OP_CLOSE: u8 = 19;
+const IORING_OP_READ: u8 = 22;
+
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+/// Submission queue entries of a ring, also the most files read at once.
+pub const RING_ENTRIES: u32 = 16;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    file_index: u32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> nix::Result<Mmap> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Errno::last());
+        }
+        Ok(Mmap { ptr, len })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { (self.ptr as *mut u8).add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+pub struct Ring {
+    fd: RawFd,
+    sq: Mmap,
+    /// `None` if the completion queue shares the mapping of the submission queue
+    cq: Option<Mmap>,
+    sqes: Mmap,
+    params: Params,
+}
+
+impl Ring {
+    pub fn new() -> nix::Result<Ring> {
+        let mut params = Params::default();
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                RING_ENTRIES,
+                &mut params as *mut Params,
+            )
+        };
+        let fd = Errno::result(res)? as RawFd;
+        let close_on_err = |e| {
+            unsafe { libc::close(fd) };
+            e
+        };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let single = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+        let sq = Mmap::new(
+            fd,
+            if single { sq_len.max(cq_len) } else { sq_len },
+            IORING_OFF_SQ_RING,
+        )
+        .map_err(close_on_err)?;
+        let cq = if single {
+            None
+        } else {
+            Some(Mmap::new(fd, cq_len, IORING_OFF_CQ_RING).map_err(close_on_err)?)
+        };
+        let sqes = Mmap::new(
+            fd,
+            params.sq_entries as usize * std::mem::size_of::<Sqe>(),
+            IORING_OFF_SQES,
+        )
+        .map_err(close_on_err)?;
+        Ok(Ring {
+            fd,
+            sq,
+            cq,
+            sqes,
+            params,
+        })
+    }
+
+    fn cq(&self) -> &Mmap {
+        self.cq.as_ref().unwrap_or(&self.sq)
+    }
+
+    /// Submits `sqes` and waits until all of them completed, returns their results in order.
+    fn submit(&mut self, sqes: Vec<Sqe>) -> nix::Result<Vec<i32>> {
+        assert!(sqes.len() <= self.params.sq_entries as usize);
+        let off = &self.params.sq_off;
+        let mask = unsafe { *self.sq.at::<u32>(off.ring_mask) };
+        let tail = unsafe { &*self.sq.at::<AtomicU32>(off.tail) };
+        let array = self.sq.at::<u32>(off.array);
+        let mut index = tail.load(Ordering::Relaxed);
+        let count = sqes.len();
+        for (i, mut sqe) in sqes.into_iter().enumerate() {
+            sqe.user_data = i as u64;
+            let slot = index & mask;
+            unsafe {
+                ptr::write(self.sqes.at::<Sqe>(0).add(slot as usize), sqe);
+                *array.add(slot as usize) = slot;
+            }
+            index = index.wrapping_add(1);
+        }
+        tail.store(index, Ordering::Release);
+
+        let mut results = vec![0; count];
+        let mut done = 0;
+        while done < count {
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd,
+                    if done == 0 { count } else { 0 },
+                    count - done,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<libc::c_void>(),
+                    0,
+                )
+            };
+            match Errno::result(res) {
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+            let off = &self.params.cq_off;
+            let cq = self.cq();
+            let mask = unsafe { *cq.at::<u32>(off.ring_mask) };
+            let head = unsafe { &*cq.at::<AtomicU32>(off.head) };
+            let tail = unsafe { &*cq.at::<AtomicU32>(off.tail) };
+            let cqes = cq.at::<Cqe>(off.cqes);
+            let mut current = head.load(Ordering::Relaxed);
+            while current != tail.load(Ordering::Acquire) {
+                let cqe = unsafe { &*cqes.add((current & mask) as usize) };
+                if let Some(result) = results.get_mut(cqe.user_data as usize) {
+                    *result = cqe.res;
+                }
+                current = current.wrapping_add(1);
+                done += 1;
+            }
+            head.store(current, Ordering::Release);
+        }
+        Ok(results)
+    }
+
+    /// Reads up to `limit` bytes of each of `files`, relative to `dirfd`.
+    pub fn read_files(
+        &mut self,
+        dirfd: RawFd,
+        files: &[&str],
+        limit: usize,
+    ) -> nix::Result<Vec<nix::Result<Vec<u8>>>> {
+        let paths: Vec<CString> = files
+            .iter()
+            .map(|f| CString::new(*f).map_err(|_| Errno::EINVAL))
+            .collect::<nix::Result<_>>()?;
+        let opens = paths
+            .iter()
+            .map(|path| Sqe {
+                opcode: IORING_OP_OPENAT,
+                fd: dirfd,
+                addr: path.as_ptr() as u64,
+                op_flags: (libc::O_RDONLY | libc::O_CLOEXEC) as u32,
+                ..Sqe::default()
+            })
+            .collect();
+        let fds = self.submit(opens)?;
+
+        let mut buffers: Vec<Vec<u8>> = fds.iter().map(|_| vec![0; limit]).collect();
+        let reads = fds
+            .iter()
+            .zip(buffers.iter_mut())
+            .filter(|(fd, _)| **fd >= 0)
+            .map(|(fd, buf)| Sqe {
+                opcode: IORING_OP_READ,
+                fd: *fd,
+                addr: buf.as_mut_ptr() as u64,
+                len: buf.len() as u32,
+                ..Sqe::default()
+            })
+            .collect();
+        let lens = self.submit(reads);
+
+        let closes: Vec<Sqe> = fds
+            .iter()
+            .filter(|fd| **fd >= 0)
+            .map(|fd| Sqe {
+                opcode: IORING_OP_CLOSE,
+                fd: *fd,
+                ..Sqe::default()
+            })
+            .collect();
+        if self.submit(closes).is_err() {
+            for fd in fds.iter().filter(|fd| **fd >= 0) {
+                unsafe { libc::close(*fd) };
+            }
+        }
+        let mut lens = lens?.into_iter();
+
+        Ok(fds
+            .iter()
+            .zip(buffers)
+            .map(|(fd, mut buf)| {
+                if *fd < 0 {
+                    return Err(Errno::from_raw(-fd));
+                }
+                match lens.next() {
+                    Some(len) if len >= 0 => {
+                        buf.truncate(len as usize);
+                        Ok(buf)
+                    }
+                    Some(len) => Err(Errno::from_raw(-len)),
+                    None => Err(Errno::EIO),
+                }
+            })
+            .collect())
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+thread_local! {
+    /// Created on first use, `Err` if io_uring is not available to this thread
+    static RING: RefCell<Option<nix::Result<Ring>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with the ring of the calling thread.
+pub fn with_ring<F, T>(f: F) -> nix::Result<T>
+where
+    F: FnOnce(&mut Ring) -> nix::Result<T>,
+{
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        match ring.get_or_insert_with(Ring::new) {
+            Ok(ring) => f(ring),
+            Err(e) => Err(*e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::io::AsRawFd;
+    use std::process;
+
+    #[test]
+    fn test_layout() {
+        // sizes of the kernel ABI in linux/io_uring.h
+        assert_eq!(std::mem::size_of::<Sqe>(), 64);
+        assert_eq!(std::mem::size_of::<Cqe>(), 16);
+        assert_eq!(std::mem::size_of::<Params>(), 120);
+    }
+
+    #[test]
+    fn test_read_files() {
+        let dir = env::temp_dir().join(format!("envfs-uring-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("short"), "PATH=/bin").unwrap();
+        fs::write(dir.join("long"), "x".repeat(100)).unwrap();
+        let dirfd = fs::File::open(&dir).unwrap();
+        let res =
+            with_ring(|ring| ring.read_files(dirfd.as_raw_fd(), &["short", "missing", "long"], 32));
+        fs::remove_dir_all(&dir).unwrap();
+        let files = match res {
+            Ok(files) => files,
+            // e.g. disabled with kernel.io_uring_disabled or by a container
+            Err(Errno::ENOSYS) | Err(Errno::EPERM) => return,
+            Err(e) => panic!("cannot read files: {}", e),
+        };
+        assert_eq!(files[0], Ok(b"PATH=/bin".to_vec()));
+        assert_eq!(files[1], Err(Errno::ENOENT));
+        assert_eq!(files[2], Ok(vec![b'x'; 32]));
+    }
+}
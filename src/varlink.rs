@@ -0,0 +1,506 @@
+//! Varlink service `io.systemd.envfs`, an alternative transport for the
+//! commands of the control socket.
+//!
+//! Each call is a JSON object terminated by a NUL byte, e.g.
+//! `{"method":"io.systemd.envfs.Resolve","parameters":{"pid":1,"name":"sh"}}`,
+//! and is answered by the same handlers as the control socket. Like that
+//! socket, the varlink socket is only accessible to root.
+
+use log::{debug, warn};
+use simple_error::try_with;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::control::{self, escape_mountpoint, TRACE_PREFIX};
+use crate::fs::EnvFs;
+use crate::logger::json_string;
+use crate::result::Result;
+
+const INTERFACE: &str = "io.systemd.envfs";
+
+const DESCRIPTION: &str = "interface io.systemd.envfs
+
+# Lines as printed by `envfs status`
+method Status() -> (lines: []string)
+
+# Resolves name like an execve of process pid would
+method Resolve(pid: int, name: string) -> (path: ?string, trace: []string)
+
+# Every decision of a lookup of name by process pid
+method Explain(pid: int, name: string) -> (lines: []string)
+
+method FlushCache() -> ()
+
+# Forces symlinks named name to be resolved again
+method Invalidate(name: string) -> (count: int)
+
+# The most frequently looked up names
+method Stats(count: int) -> (lines: []string)
+
+method SetLogLevel(level: string) -> ()
+
+error Failed (message: string)
+";
+
+/// Longest call accepted from a client.
+const MAX_CALL: usize = 64 * 1024;
+
+/// Returns the default varlink socket for a mountpoint, i.e. `/run/envfs/usr-bin.varlink` for `/usr/bin`.
+pub fn socket_path(mountpoint: &Path) -> PathBuf {
+    control::socket_path(mountpoint)
+        .with_file_name(format!("{}.varlink", escape_mountpoint(mountpoint)))
+}
+
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Json::Num(n) if n.fract() == 0.0 => Some(*n as i64),
+            _ => None,
+        }
+    }
+}
+
+/// Deepest nesting of arrays and objects accepted.
+const MAX_DEPTH: usize = 32;
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_ws(&mut self) {
+        while self.s.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> Option<()> {
+        self.skip_ws();
+        if self.s.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Option<Json> {
+        if self.s[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = std::str::from_utf8(self.s.get(self.pos..self.pos + 4)?).ok()?;
+        self.pos += 4;
+        u32::from_str_radix(digits, 16).ok()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.eat(b'"')?;
+        let mut out = vec![];
+        loop {
+            let c = *self.s.get(self.pos)?;
+            self.pos += 1;
+            match c {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    let escaped = *self.s.get(self.pos)?;
+                    self.pos += 1;
+                    let c = match escaped {
+                        b'"' | b'\\' | b'/' => escaped as char,
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&code) {
+                                // surrogate pair
+                                self.literal("\\u", Json::Null)?;
+                                let low = self.hex4()?;
+                                code = 0x10000
+                                    + ((code - 0xd800) << 10)
+                                    + (low.checked_sub(0xdc00)?);
+                            }
+                            char::from_u32(code)?
+                        }
+                        _ => return None,
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return None;
+        }
+        let value = self.scalar_or_nested();
+        self.depth -= 1;
+        value
+    }
+
+    fn scalar_or_nested(&mut self) -> Option<Json> {
+        self.skip_ws();
+        match *self.s.get(self.pos)? {
+            b'{' => {
+                self.pos += 1;
+                let mut fields = vec![];
+                if self.eat(b'}').is_some() {
+                    return Some(Json::Object(fields));
+                }
+                loop {
+                    let key = self.string()?;
+                    self.eat(b':')?;
+                    fields.push((key, self.value()?));
+                    if self.eat(b'}').is_some() {
+                        return Some(Json::Object(fields));
+                    }
+                    self.eat(b',')?;
+                }
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut items = vec![];
+                if self.eat(b']').is_some() {
+                    return Some(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.eat(b']').is_some() {
+                        return Some(Json::Array(items));
+                    }
+                    self.eat(b',')?;
+                }
+            }
+            b'"' => self.string().map(Json::Str),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'n' => self.literal("null", Json::Null),
+            _ => {
+                let start = self.pos;
+                while self
+                    .s
+                    .get(self.pos)
+                    .is_some_and(|c| c.is_ascii_digit() || b"+-.eE".contains(c))
+                {
+                    self.pos += 1;
+                }
+                let number = std::str::from_utf8(&self.s[start..self.pos]).ok()?;
+                number.parse().ok().map(Json::Num)
+            }
+        }
+    }
+}
+
+fn parse_json(s: &[u8]) -> Option<Json> {
+    let mut parser = Parser {
+        s,
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_ws();
+    if parser.pos == s.len() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn string_array(lines: &[String]) -> String {
+    let items: Vec<String> = lines.iter().map(|l| json_string(l)).collect();
+    format!("[{}]", items.join(","))
+}
+
+/// A reply is either the JSON object of the output parameters or an error
+/// name with its parameters.
+type Reply = std::result::Result<String, (String, String)>;
+
+fn parameter_error(name: &str) -> (String, String) {
+    (
+        String::from("org.varlink.service.InvalidParameter"),
+        format!("{{\"parameter\":{}}}", json_string(name)),
+    )
+}
+
+fn invalid_parameter(name: &str) -> Reply {
+    Err(parameter_error(name))
+}
+
+/// Runs `command` through the control socket handlers.
+fn execute(
+    fs: &EnvFs,
+    command: &str,
+    arg: &str,
+) -> std::result::Result<Vec<String>, (String, String)> {
+    control::execute(fs, command, arg).map_err(|e| {
+        (
+            format!("{}.Failed", INTERFACE),
+            format!("{{\"message\":{}}}", json_string(&e.to_string())),
+        )
+    })
+}
+
+/// The `PID NAME` argument of the `resolve` and `explain` commands.
+fn pid_name(params: &Json) -> std::result::Result<String, (String, String)> {
+    let pid = params
+        .get("pid")
+        .and_then(Json::as_int)
+        .ok_or_else(|| parameter_error("pid"))?;
+    let name = params
+        .get("name")
+        .and_then(Json::as_str)
+        .ok_or_else(|| parameter_error("name"))?;
+    Ok(format!("{} {}", pid, name))
+}
+
+fn call(fs: &EnvFs, method: &str, params: &Json) -> Reply {
+    let lines = |lines: Vec<String>| format!("{{\"lines\":{}}}", string_array(&lines));
+    match method {
+        "org.varlink.service.GetInfo" => Ok(format!(
+            "{{\"vendor\":\"envfs\",\"product\":\"envfs\",\"version\":{},\"url\":\"https://github.com/Mic92/envfs\",\"interfaces\":[\"org.varlink.service\",{}]}}",
+            json_string(env!("CARGO_PKG_VERSION")),
+            json_string(INTERFACE)
+        )),
+        "org.varlink.service.GetInterfaceDescription" => {
+            match params.get("interface").and_then(Json::as_str) {
+                Some(INTERFACE) => Ok(format!(
+                    "{{\"description\":{}}}",
+                    json_string(DESCRIPTION)
+                )),
+                Some(interface) => Err((
+                    String::from("org.varlink.service.InterfaceNotFound"),
+                    format!("{{\"interface\":{}}}", json_string(interface)),
+                )),
+                None => invalid_parameter("interface"),
+            }
+        }
+        "io.systemd.envfs.Status" => execute(fs, "status", "").map(lines),
+        "io.systemd.envfs.Resolve" => {
+            let output = execute(fs, "resolve", &pid_name(params)?)?;
+            let (trace, path): (Vec<String>, Vec<String>) =
+                output.into_iter().partition(|l| l.starts_with(TRACE_PREFIX));
+            let trace: Vec<String> = trace
+                .into_iter()
+                .map(|l| l[TRACE_PREFIX.len()..].to_string())
+                .collect();
+            let path = path.last().map_or(String::from("null"), |p| json_string(p));
+            Ok(format!(
+                "{{\"path\":{},\"trace\":{}}}",
+                path,
+                string_array(&trace)
+            ))
+        }
+        "io.systemd.envfs.Explain" => execute(fs, "explain", &pid_name(params)?).map(lines),
+        "io.systemd.envfs.FlushCache" => execute(fs, "flush-cache", "").map(|_| String::from("{}")),
+        "io.systemd.envfs.Invalidate" => {
+            let name = match params.get("name").and_then(Json::as_str) {
+                Some(name) if !name.is_empty() => name,
+                _ => return invalid_parameter("name"),
+            };
+            let output = execute(fs, "invalidate", name)?;
+            let count = output
+                .iter()
+                .find_map(|l| l.strip_prefix("invalidated: "))
+                .unwrap_or("0");
+            Ok(format!("{{\"count\":{}}}", count))
+        }
+        "io.systemd.envfs.Stats" => match params.get("count").and_then(Json::as_int) {
+            Some(count) if count >= 0 => execute(fs, "stats", &count.to_string()).map(lines),
+            _ => invalid_parameter("count"),
+        },
+        "io.systemd.envfs.SetLogLevel" => match params.get("level").and_then(Json::as_str) {
+            Some(level) => execute(fs, "log-level", level).map(|_| String::from("{}")),
+            None => invalid_parameter("level"),
+        },
+        _ => Err((
+            String::from("org.varlink.service.MethodNotFound"),
+            format!("{{\"method\":{}}}", json_string(method)),
+        )),
+    }
+}
+
+/// Answers one call, `None` if the client asked for no reply.
+fn handle_call(fs: &EnvFs, data: &[u8]) -> Option<String> {
+    let request = parse_json(data);
+    let method = request
+        .as_ref()
+        .and_then(|r| r.get("method"))
+        .and_then(Json::as_str);
+    let reply = match (request.as_ref(), method) {
+        (Some(request), Some(method)) => {
+            debug!("varlink call: {}", method);
+            let empty = Json::Object(vec![]);
+            let params = request.get("parameters").unwrap_or(&empty);
+            if request.get("oneway") == Some(&Json::Bool(true)) {
+                let _ = call(fs, method, params);
+                return None;
+            }
+            call(fs, method, params)
+        }
+        _ => invalid_parameter("method"),
+    };
+    Some(match reply {
+        Ok(params) => format!("{{\"parameters\":{}}}", params),
+        Err((error, params)) => format!(
+            "{{\"error\":{},\"parameters\":{}}}",
+            json_string(&error),
+            params
+        ),
+    })
+}
+
+fn handle_client(stream: UnixStream, fs: &EnvFs) -> Result<()> {
+    let mut reader = BufReader::new(try_with!(stream.try_clone(), "cannot clone stream"));
+    let mut writer = stream;
+    loop {
+        let mut data = vec![];
+        let n = try_with!(
+            reader
+                .by_ref()
+                .take(MAX_CALL as u64)
+                .read_until(0, &mut data),
+            "cannot read call"
+        );
+        if n == 0 {
+            return Ok(());
+        }
+        if data.pop() != Some(0) {
+            simple_error::bail!("call too long or incomplete");
+        }
+        if let Some(mut reply) = handle_call(fs, &data) {
+            reply.push('\0');
+            try_with!(writer.write_all(reply.as_bytes()), "cannot write reply");
+        }
+    }
+}
+
+pub struct VarlinkServer {
+    path: PathBuf,
+    /// Moved to the serving thread by `serve`
+    listener: Option<UnixListener>,
+}
+
+impl Drop for VarlinkServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Creates the socket without serving calls yet, see `VarlinkServer::serve`.
+pub fn bind(path: &Path) -> Result<VarlinkServer> {
+    if let Some(parent) = path.parent() {
+        try_with!(
+            fs::create_dir_all(parent),
+            "cannot create {}",
+            parent.display()
+        );
+    }
+    let _ = fs::remove_file(path);
+    let listener = try_with!(
+        UnixListener::bind(path),
+        "cannot listen on {}",
+        path.display()
+    );
+    try_with!(
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)),
+        "cannot restrict permissions of {}",
+        path.display()
+    );
+    Ok(VarlinkServer {
+        path: path.to_path_buf(),
+        listener: Some(listener),
+    })
+}
+
+impl VarlinkServer {
+    /// Serves calls in a background thread.
+    pub fn serve(&mut self, fs: EnvFs) {
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => return,
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let fs = fs.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle_client(stream, &fs) {
+                                debug!("varlink client failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("failed to accept varlink connection: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json() {
+        let call = parse_json(
+            br#" {"method": "io.systemd.envfs.Resolve", "parameters": {"pid": 42, "name": "sh\n"}, "more": false, "x": [1.5, null]} "#,
+        )
+        .unwrap();
+        assert_eq!(
+            call.get("method").and_then(Json::as_str),
+            Some("io.systemd.envfs.Resolve")
+        );
+        let params = call.get("parameters").unwrap();
+        assert_eq!(params.get("pid").and_then(Json::as_int), Some(42));
+        assert_eq!(params.get("name").and_then(Json::as_str), Some("sh\n"));
+        assert_eq!(
+            parse_json(br#""\ud83d\ude00""#),
+            Some(Json::Str("😀".into()))
+        );
+        assert_eq!(parse_json(br#"{"a":1,}"#), None);
+        assert_eq!(parse_json(b"{} x"), None);
+        assert_eq!(parse_json(&[b'['; 64]), None);
+    }
+}
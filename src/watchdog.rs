@@ -0,0 +1,124 @@
+//! Detects when envfs stops answering lookups.
+
+use log::{debug, warn};
+use nix::unistd;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use envfs::resolve::Trace;
+use envfs::{upgrade, EnvFs};
+
+use crate::systemd;
+
+/// Name looked up by the health check, it is not expected to resolve to anything.
+const WATCHDOG_PROBE: &str = ".envfs-watchdog";
+
+pub struct Config {
+    pub interval: Duration,
+    /// Also stat a file below the mountpoint, which goes through the kernel and the FUSE session
+    pub stat_mountpoint: bool,
+    /// Replace the process with a fresh instance after a hang
+    pub restart: bool,
+    /// Ping the systemd watchdog after each successful check
+    pub notify_systemd: bool,
+}
+
+/// Resolves the probe name like a lookup would and, if configured, stats it
+/// through the mountpoint.
+fn check(fs: &EnvFs, mountpoint: &Path, stat_mountpoint: bool) {
+    // The result does not matter, only that the filesystem answers.
+    let _ = fs.resolve(
+        unistd::getpid(),
+        OsStr::new(WATCHDOG_PROBE),
+        &Trace::disabled(),
+    );
+    if stat_mountpoint {
+        let _ = fs::symlink_metadata(mountpoint.join(WATCHDOG_PROBE));
+    }
+}
+
+/// Logs what each thread of envfs is waiting for.
+fn log_threads() {
+    let tasks = match fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            warn!("cannot list threads: {}", e);
+            return;
+        }
+    };
+    for task in tasks.flatten() {
+        let path = task.path();
+        let read = |file: &str| {
+            fs::read_to_string(path.join(file))
+                .map(|s| s.trim_end().to_string())
+                .unwrap_or_default()
+        };
+        warn!(
+            "thread {} ({}): waiting in {}",
+            task.file_name().to_string_lossy(),
+            read("comm"),
+            read("wchan")
+        );
+    }
+}
+
+fn hang_detected(fs: &EnvFs, config: &Config, what: &str) {
+    fs.stats().record_hang();
+    warn!("{}", what);
+    log_threads();
+    if config.restart {
+        warn!("restarting after hang");
+        // the binary this process runs, even if it was replaced on disk
+        if let Err(e) = upgrade::upgrade(fs, Path::new("/proc/self/exe")) {
+            warn!("cannot restart: {}", e);
+        }
+    }
+}
+
+/// Checks every `config.interval` that lookups are answered.
+pub fn spawn(fs: EnvFs, config: Config) {
+    let mountpoint = PathBuf::from(&fs.mountpoints()[0]);
+    let check_running = Arc::new(AtomicBool::new(false));
+
+    thread::spawn(move || loop {
+        thread::sleep(config.interval);
+
+        if check_running.swap(true, Ordering::SeqCst) {
+            hang_detected(&fs, &config, "previous health check still hangs");
+            continue;
+        }
+        let (tx, rx) = mpsc::channel();
+        let running = Arc::clone(&check_running);
+        let (check_fs, check_mountpoint) = (fs.clone(), mountpoint.clone());
+        let stat_mountpoint = config.stat_mountpoint;
+        thread::spawn(move || {
+            check(&check_fs, &check_mountpoint, stat_mountpoint);
+            running.store(false, Ordering::SeqCst);
+            let _ = tx.send(());
+        });
+
+        match rx.recv_timeout(config.interval) {
+            Ok(()) => {
+                if config.notify_systemd {
+                    if let Err(e) = systemd::notify("WATCHDOG=1") {
+                        debug!("failed to ping watchdog: {}", e);
+                    }
+                }
+            }
+            Err(_) => hang_detected(
+                &fs,
+                &config,
+                &format!(
+                    "health check of {} did not finish within {:?}",
+                    mountpoint.display(),
+                    config.interval
+                ),
+            ),
+        }
+    });
+}
@@ -0,0 +1,235 @@
+//! Threads that answer FUSE requests while the session thread reads the next ones.
+//!
+//! fuser reads and dispatches requests from `/dev/fuse` on a single thread,
+//! lookups which wait for the calling process are moved to this pool so they
+//! do not hold up requests of other processes. Reading requests is not spread
+//! over several threads: that needs a cloned FUSE device per reader
+//! (`FUSE_DEV_IOC_CLONE`), which fuser does not support.
+//!
+//! Jobs are queued per process and the processes take turns. A process may
+//! occupy at most half of the workers, so one that is stuck, e.g. in
+//! userspace while envfs waits for its system call, cannot delay the lookups
+//! of all others.
+
+use log::warn;
+use std::collections::{BTreeMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Prefix of the names of the worker threads.
+pub const WORKER_THREAD_NAME: &str = "envfs-worker";
+
+#[derive(Default)]
+struct Queue {
+    /// Waiting jobs of each process, oldest first
+    waiting: BTreeMap<i32, VecDeque<Job>>,
+    /// Processes with waiting jobs, in the order they are served
+    turns: VecDeque<i32>,
+    /// Jobs of each process currently running
+    running: BTreeMap<i32, usize>,
+    /// Number of waiting jobs
+    len: usize,
+    /// Set when the pool is dropped, workers exit once the queue is empty
+    closed: bool,
+}
+
+impl Queue {
+    fn push(&mut self, key: i32, job: Job) {
+        let jobs = self.waiting.entry(key).or_default();
+        jobs.push_back(job);
+        if jobs.len() == 1 {
+            self.turns.push_back(key);
+        }
+        self.len += 1;
+    }
+
+    /// Takes the next job of the first process in line that is below `limit` running jobs.
+    fn take(&mut self, limit: usize) -> Option<(i32, Job)> {
+        let running = &self.running;
+        let turn = self
+            .turns
+            .iter()
+            .position(|key| running.get(key).copied().unwrap_or(0) < limit)?;
+        let key = self.turns.remove(turn)?;
+        let jobs = self.waiting.get_mut(&key)?;
+        let job = jobs.pop_front()?;
+        if jobs.is_empty() {
+            self.waiting.remove(&key);
+        } else {
+            self.turns.push_back(key);
+        }
+        self.len -= 1;
+        *self.running.entry(key).or_insert(0) += 1;
+        Some((key, job))
+    }
+
+    fn finish(&mut self, key: i32) {
+        if let Some(count) = self.running.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                self.running.remove(&key);
+            }
+        }
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled when a job is queued or finished
+    changed: Condvar,
+    /// Jobs one process may run at the same time
+    per_process: usize,
+}
+
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    threads: usize,
+    /// Number of threads that could be started
+    started_threads: Mutex<usize>,
+    started: Once,
+}
+
+fn work(shared: &Shared) {
+    let mut queue = shared.queue.lock().unwrap();
+    loop {
+        let (key, job) = match queue.take(shared.per_process) {
+            Some(next) => next,
+            None if queue.closed && queue.len == 0 => return,
+            None => {
+                queue = shared.changed.wait(queue).unwrap();
+                continue;
+            }
+        };
+        drop(queue);
+        // a panicking job fails its own request, see `crash::install`
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        queue = shared.queue.lock().unwrap();
+        queue.finish(key);
+        if !queue.turns.is_empty() {
+            // the process may be below its limit again
+            shared.changed.notify_all();
+        }
+    }
+}
+
+impl WorkerPool {
+    /// The threads are started with the first job, so that they inherit the
+    /// credentials of the process at that time.
+    pub fn new(threads: usize) -> WorkerPool {
+        WorkerPool {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue::default()),
+                changed: Condvar::new(),
+                per_process: (threads / 2).max(1),
+            }),
+            threads,
+            started_threads: Mutex::new(0),
+            started: Once::new(),
+        }
+    }
+
+    fn start(&self) {
+        let mut started = self.started_threads.lock().unwrap();
+        for i in 0..self.threads {
+            let shared = Arc::clone(&self.shared);
+            let res = thread::Builder::new()
+                .name(format!("{}-{}", WORKER_THREAD_NAME, i))
+                .spawn(move || work(&shared));
+            match res {
+                Ok(_) => *started += 1,
+                Err(e) => warn!("cannot spawn worker thread: {}", e),
+            }
+        }
+    }
+
+    /// Runs `job` for process `pid` on one of the workers, or on the current
+    /// thread if none of them could be started.
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, pid: i32, job: F) {
+        self.started.call_once(|| self.start());
+        if *self.started_threads.lock().unwrap() == 0 {
+            job();
+            return;
+        }
+        self.shared.queue.lock().unwrap().push(pid, Box::new(job));
+        self.shared.changed.notify_one();
+    }
+
+    /// Number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.shared.queue.lock().unwrap().len
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn take_key(queue: &mut Queue, limit: usize) -> Option<i32> {
+        queue.take(limit).map(|(key, _)| key)
+    }
+
+    #[test]
+    fn processes_take_turns() {
+        let mut queue = Queue::default();
+        for key in [1, 1, 1, 2, 3, 3] {
+            queue.push(key, Box::new(|| {}));
+        }
+        let order: Vec<i32> = std::iter::from_fn(|| take_key(&mut queue, usize::MAX)).collect();
+        assert_eq!(order, [1, 2, 3, 1, 3, 1]);
+        assert_eq!(queue.len, 0);
+        assert!(queue.waiting.is_empty());
+    }
+
+    #[test]
+    fn process_limited_to_its_share_of_workers() {
+        let mut queue = Queue::default();
+        for key in [1, 1, 1, 2] {
+            queue.push(key, Box::new(|| {}));
+        }
+        assert_eq!(take_key(&mut queue, 2), Some(1));
+        assert_eq!(take_key(&mut queue, 2), Some(2));
+        assert_eq!(take_key(&mut queue, 2), Some(1));
+        // process 1 runs two jobs, its third waits even though workers are idle
+        assert_eq!(take_key(&mut queue, 2), None);
+        assert_eq!(queue.len, 1);
+        queue.finish(2);
+        assert_eq!(take_key(&mut queue, 2), None);
+        queue.finish(1);
+        assert_eq!(take_key(&mut queue, 2), Some(1));
+        queue.finish(1);
+        queue.finish(1);
+        assert!(queue.running.is_empty());
+    }
+
+    #[test]
+    fn stuck_process_does_not_block_others() {
+        let pool = WorkerPool::new(2);
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+        // process 1 hangs in every lookup
+        for _ in 0..4 {
+            let blocked = Arc::clone(&blocked);
+            pool.execute(1, move || {
+                let _ = blocked.lock().unwrap().recv();
+            });
+        }
+        let (done, finished) = mpsc::channel();
+        pool.execute(2, move || done.send(()).unwrap());
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+        for _ in 0..4 {
+            release.send(()).unwrap();
+        }
+    }
+}